@@ -2,56 +2,881 @@ use anyhow::{Result};
 use std::{fs};
 
 use std::{
-    path::{Path},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Instant,
 };
 
+use serde_json::Value;
 use structopt::StructOpt;
 use indicatif::{ProgressBar, ProgressStyle};
+use tracing_subscriber::EnvFilter;
 
-use nary_lib::{calculate_depends, path_to_dependencies, path_to_root_dependency, install_dep};
+use nary_lib::{dist_tags, link, scripts};
+use nary_lib::{
+    add, audit, calculate_depends, check_peers, collect_licenses, create_package_tarball, deprecate, execute_plan,
+    find_workspace, info, init, install_frozen, install_global, list_global, outdated, parse_spec,
+    path_to_root_dependency, plan_install, project_dependencies, publish, read_lockfile, read_or_import, read_overrides,
+    search, uninstall, uninstall_global, unpublish, update, use_link, verify_install, write_lockfile, DependencyKind,
+    Engines, Freshness, GlobalPrefix, HttpRegistry, Info, InitOptions, InstallOptions, InstallPlan, InstallReporter,
+    InstallStats, InstallStrategy, Layout, Lockfile, MismatchReason, NoHooks, Phase, Platform, PublishOptions,
+    RegistryConfig, ResolutionOptions, ResolutionStrategy, ResolvedGraph, SearchOptions, SilentReporter,
+    TerminalReporter, LOCKFILE,
+};
 
 /// nary
 #[derive(StructOpt, Debug)]
 #[structopt(name = "basic")]
 struct Opt {
-    /// Verbose mode (-v, -vv, -vvv, etc.)
+    /// Verbose mode (-v, -vv, -vvv, etc.). -vv logs what the resolver, fetcher and cache do, -vvv everything;
+    /// NARY_LOG takes a tracing filter like `nary_lib::cache=trace` instead
     #[structopt(short, long, parse(from_occurrences))]
     verbose: u8,
 
     /// Don't install any dev dependencies
     #[structopt(long = "prod")]
     production: bool,
+
+    /// Only use the cache, never the network
+    #[structopt(long)]
+    offline: bool,
+
+    /// Use cached metadata when present instead of asking the registry
+    #[structopt(long)]
+    prefer_offline: bool,
+
+    /// Ask the registry about all metadata, even what was fetched in the last few minutes
+    #[structopt(long, conflicts_with_all = &["offline", "prefer-offline"])]
+    force_refresh: bool,
+
+    /// When cached metadata is used rather than checked with the registry: prefer-online, prefer-offline or
+    /// max-age=<duration>, like max-age=10m
+    #[structopt(long)]
+    metadata_freshness: Option<String>,
+
+    /// Install for this operating system instead of the current one (linux, darwin, win32, ...)
+    #[structopt(long)]
+    os: Option<String>,
+
+    /// Install for this CPU architecture instead of the current one (x64, arm64, ...)
+    #[structopt(long)]
+    cpu: Option<String>,
+
+    /// Install for this C library instead of the current one (glibc, musl)
+    #[structopt(long)]
+    libc: Option<String>,
+
+    /// Fail on packages whose `engines` field rules out the Node version instead of warning
+    #[structopt(long)]
+    engine_strict: bool,
+
+    /// Resolve peer dependencies that nothing else brings in as dependencies of the packages asking for them
+    #[structopt(long)]
+    install_peers: bool,
+
+    /// Settle on one version of each package that every range, peer and override on it allows, or explain why
+    /// there's none
+    #[structopt(long)]
+    strict: bool,
+    /// How many packages to fetch metadata for at once while resolving [default: 16]
+    #[structopt(long)]
+    parallelism: Option<usize>,
+
+    /// How many packages to unpack at once while installing [default: one per CPU]
+    #[structopt(long)]
+    extract_parallelism: Option<usize>,
+
+    /// Hard-link packages from the global store instead of unpacking them into node_modules
+    #[structopt(long)]
+    hard_links: bool,
+
+    /// Reflink packages from the global store, where the filesystem supports copy-on-write
+    #[structopt(long, conflicts_with = "hard-links")]
+    reflinks: bool,
+
+    /// Only let packages see their own dependencies, through symlinks into node_modules/.nary
+    #[structopt(long)]
+    isolated: bool,
+
+    /// Put every package in a directory of its own, `<name>@<version>`, with nothing linked between them. For
+    /// bundling packages' files into projects that aren't run with Node, usually along with --modules-dir.
+    #[structopt(long, conflicts_with = "isolated")]
+    vendored: bool,
+
+    /// Install into this directory of the project instead of node_modules
+    #[structopt(long)]
+    modules_dir: Option<PathBuf>,
+
+    /// Show what would be installed, updated and removed without changing node_modules
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Collapse duplicate versions of packages into as few as the declared ranges allow before installing
+    #[structopt(long)]
+    dedupe: bool,
+
+    /// Print what the install did as JSON: how many packages, how many bytes downloaded and how long each phase took
+    #[structopt(long)]
+    stats: bool,
+
+    /// Check node_modules against the lockfile instead of installing
+    #[structopt(long)]
+    verify: bool,
+
+    /// Replace node_modules with exactly what the lockfile says, failing if it's missing or out of date
+    #[structopt(long, alias = "frozen-lockfile", conflicts_with = "dry-run")]
+    ci: bool,
+
+    /// Save a dependency on these packages (name, name@range, name@tag, or a git repository, tarball URL or file:
+    /// directory) to package.json, then install them in one go
+    #[structopt(long, min_values = 1, conflicts_with = "ci")]
+    add: Vec<String>,
+
+    /// Save added packages as optional dependencies
+    #[structopt(long)]
+    save_optional: bool,
+
+    /// Save added packages at the exact version they resolve to instead of a ^ range
+    #[structopt(short = "E", long)]
+    save_exact: bool,
+
+    /// Add and remove packages in the global prefix instead of the project, linking their executables into its bin
+    /// directory. Without --add or --remove, list the global packages.
+    #[structopt(short, long, conflicts_with = "ci")]
+    global: bool,
+
+    /// Register the project for linking, or with names, symlink those registered packages into node_modules, instead
+    /// of installing
+    #[structopt(long, min_values = 0, conflicts_with = "ci")]
+    link: Option<Vec<String>>,
+
+    /// Run a package.json script, with any further values passed on to it, instead of installing
+    #[structopt(long, min_values = 1, allow_hyphen_values = true, conflicts_with = "ci")]
+    run: Vec<String>,
+
+    /// Remove these packages from package.json, and from node_modules with whatever only they depended on
+    #[structopt(long, min_values = 1, conflicts_with = "ci")]
+    remove: Vec<String>,
+
+    /// List dependencies with newer versions instead of installing
+    #[structopt(long)]
+    outdated: bool,
+
+    /// With --outdated, list the dependencies of every installed package too
+    #[structopt(long, requires = "outdated")]
+    all: bool,
+
+    /// Pack the project into a tarball next to its package.json instead of installing
+    #[structopt(long, conflicts_with = "ci")]
+    pack: bool,
+
+    /// Pack the project and publish it to its registry instead of installing. --dry-run only shows what would be.
+    #[structopt(long, conflicts_with = "ci")]
+    publish: bool,
+
+    /// With --publish, the dist-tag to point at the new version
+    #[structopt(long, default_value = "latest")]
+    tag: String,
+
+    /// With --publish, `public` or `restricted`
+    #[structopt(long, requires = "publish")]
+    access: Option<String>,
+
+    /// List the dist-tags of a package in the registry
+    #[structopt(long, value_name = "name")]
+    dist_tag_ls: Option<String>,
+
+    /// Point a dist-tag of a package in the registry at a version: `--dist-tag-add name@version tag`
+    #[structopt(long, number_of_values = 2, value_names = &["name@version", "tag"])]
+    dist_tag_add: Option<Vec<String>>,
+
+    /// Take a dist-tag off a package in the registry: `--dist-tag-rm name tag`
+    #[structopt(long, number_of_values = 2, value_names = &["name", "tag"])]
+    dist_tag_rm: Option<Vec<String>>,
+
+    /// Deprecate the versions of a package in a range with a message, or take it off with an empty one:
+    /// `--deprecate name@range message`
+    #[structopt(long, number_of_values = 2, value_names = &["name@range", "message"])]
+    deprecate: Option<Vec<String>>,
+
+    /// Take a version of a package, or the whole package, off the registry. Without --yes it only shows what
+    /// would go.
+    #[structopt(long, value_name = "name[@version]")]
+    unpublish: Option<String>,
+
+    /// With --unpublish, confirm it. With --init, take the defaults instead of asking.
+    #[structopt(long)]
+    yes: bool,
+
+    /// Write a new package.json, asking for its name, version, license and so on, instead of installing
+    #[structopt(long, conflicts_with = "ci")]
+    init: bool,
+
+    /// Show what the registry has about the version of a package a spec asks for, or one field of it like
+    /// `dist.tarball`: `--info name[@range] [field]`
+    #[structopt(long, min_values = 1, max_values = 2, value_names = &["name[@range]", "field"])]
+    info: Option<Vec<String>>,
+
+    /// Search the registry for packages
+    #[structopt(long, min_values = 1, value_name = "query")]
+    search: Option<Vec<String>>,
+
+    /// With --search, how many results to show
+    #[structopt(long, default_value = "20")]
+    search_size: usize,
+
+    /// With --search, how many results to skip
+    #[structopt(long, default_value = "0")]
+    search_from: usize,
+
+    /// With --search, print the results as JSON
+    #[structopt(long, requires = "search")]
+    json: bool,
+
+    /// Check the resolved packages against the registry's security advisories instead of installing
+    #[structopt(long)]
+    audit: bool,
+    /// List the licenses of the installed packages instead of installing, failing on any `license-allow` and
+    /// `license-deny` in .npmrc rule out
+    #[structopt(long)]
+    licenses: bool,
+
+    /// Resolve these packages, or every package when none are given, to the newest versions in range, then install
+    #[structopt(long, min_values = 0, conflicts_with = "ci")]
+    update: Option<Vec<String>>,
+
+    /// With --update, bump ranges in package.json that don't allow the latest version
+    #[structopt(long, requires = "update")]
+    major: bool,
+}
+
+/// Logs go to stderr, as NARY_LOG filters them or else by how verbose nary was asked to be
+fn init_tracing(verbose: u8) {
+    let filter = match EnvFilter::try_from_env("NARY_LOG") {
+        Ok(filter) => filter,
+        Err(_) if verbose >= 3 => EnvFilter::new("nary_lib=trace"),
+        Err(_) if verbose == 2 => EnvFilter::new("nary_lib=debug"),
+        Err(_) => return,
+    };
+    tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr).init();
 }
 
 fn main() -> Result<()> {
     let opt = Opt::from_args();
+    init_tracing(opt.verbose);
+    if opt.init {
+        return init_package(Path::new("."), opt.yes);
+    }
     let install_dev_dependencies = !opt.production;
+    let config = RegistryConfig::load(Path::new("."))?;
+
+    let freshness = match &opt.metadata_freshness {
+        Some(freshness) => Some(
+            Freshness::parse(freshness)
+                .ok_or_else(|| anyhow::anyhow!("{} isn't a metadata freshness policy", freshness))?,
+        ),
+        None => config.freshness,
+    };
+    let options = InstallOptions {
+        offline: opt.offline,
+        prefer_offline: opt.prefer_offline,
+        force_refresh: opt.force_refresh,
+        freshness: freshness.unwrap_or_default(),
+        strategy: if opt.reflinks {
+            InstallStrategy::Reflink
+        } else if opt.hard_links {
+            InstallStrategy::HardLink
+        } else {
+            InstallStrategy::Extract
+        },
+        layout: if opt.isolated {
+            Layout::Isolated
+        } else if opt.vendored {
+            Layout::Vendored
+        } else {
+            Layout::Hoisted
+        },
+        parallelism: opt.extract_parallelism.unwrap_or_default(),
+        modules_dir: opt.modules_dir.clone(),
+        ..InstallOptions::default()
+    };
 
-    install(&Path::new("."), !install_dev_dependencies)
+    let current = Platform::current();
+    let resolution = ResolutionOptions {
+        platform: Platform {
+            libc: opt.libc.or(current.libc),
+            os: opt.os.unwrap_or(current.os),
+            cpu: opt.cpu.unwrap_or(current.cpu),
+        },
+        engines: Engines {
+            node: config.node_version.clone().or_else(|| Engines::current().node),
+            npm: None,
+            strict: opt.engine_strict || config.engine_strict,
+        },
+        workspace: find_workspace(Path::new("."))?.map(|workspace| workspace.members).unwrap_or_default(),
+        overrides: read_overrides(Path::new("."))?,
+        parallelism: opt.parallelism.unwrap_or_default(),
+        install_peers: opt.install_peers || config.install_peers,
+        strategy: if opt.strict { ResolutionStrategy::Strict } else { ResolutionStrategy::Highest },
+        ..ResolutionOptions::default()
+    };
+
+    if opt.verify {
+        return verify(Path::new("."));
+    }
+    if opt.ci {
+        return ci(Path::new("."), &options, opt.verbose > 0);
+    }
+    if opt.outdated {
+        return print_outdated(Path::new("."), &options, &resolution, opt.all);
+    }
+    if opt.pack {
+        println!("{}", create_package_tarball(Path::new("."))?.display());
+        return Ok(());
+    }
+    if opt.publish {
+        let publish_options = PublishOptions {
+            tag: opt.tag.clone(),
+            access: opt.access.clone(),
+            dry_run: opt.dry_run,
+        };
+        return print_publish(Path::new("."), &options, &publish_options);
+    }
+    if opt.dist_tag_ls.is_some() || opt.dist_tag_add.is_some() || opt.dist_tag_rm.is_some() {
+        let registry = HttpRegistry::new(config, options.clone());
+        return dist_tag(opt.dist_tag_ls.as_deref(), opt.dist_tag_add.as_deref(), opt.dist_tag_rm.as_deref(), &registry);
+    }
+    if let Some([spec, field @ ..]) = opt.info.as_deref() {
+        let registry = HttpRegistry::new(config, options.clone());
+        return print_info(spec, field.first().map(String::as_str), &registry, &resolution);
+    }
+    if let Some(query) = &opt.search {
+        let registry = HttpRegistry::new(config, options.clone());
+        let search_options = SearchOptions {
+            size: opt.search_size,
+            from: opt.search_from,
+        };
+        return print_search(&query.join(" "), &search_options, opt.json, &registry);
+    }
+    if let Some([spec, message]) = opt.deprecate.as_deref() {
+        let registry = HttpRegistry::new(config, options.clone());
+        for version in deprecate(spec, message, &registry)? {
+            println!("{}@{}", parse_spec(spec).name, version);
+        }
+        return Ok(());
+    }
+    if let Some(spec) = &opt.unpublish {
+        let registry = HttpRegistry::new(config, options.clone());
+        return print_unpublish(spec, opt.yes, &registry);
+    }
+    if opt.audit {
+        return print_audit(Path::new("."), &options, &resolution, opt.verbose > 0);
+    }
+    if opt.licenses {
+        return print_licenses(Path::new("."), &options);
+    }
+
+    if let Some((script, args)) = opt.run.split_first() {
+        return Ok(scripts::run(Path::new("."), script, args, &NoHooks, &TerminalReporter)?);
+    }
+    if let Some(names) = &opt.link {
+        if names.is_empty() {
+            let linked = link::link_package(Path::new("."))?;
+            println!("{}@{} -> {}", linked.name, linked.version, linked.source.display());
+        }
+        for name in names {
+            println!("{}", use_link(Path::new("."), name)?.display());
+        }
+        return Ok(());
+    }
+    if opt.global {
+        let global = GlobalPrefix::from_config(&config)?;
+        let registry = HttpRegistry::new(config, options.clone());
+        let reporter: &dyn InstallReporter = if opt.verbose > 0 { &TerminalReporter } else { &SilentReporter };
+        return global_packages(&global, &opt.add, &opt.remove, &registry, &options, &resolution, reporter);
+    }
+
+    if !opt.add.is_empty() || !opt.remove.is_empty() {
+        let registry = HttpRegistry::new(RegistryConfig::load(Path::new("."))?, options.clone());
+        let kind = if opt.save_optional { DependencyKind::Optional } else { DependencyKind::Normal };
+        let reporter: &dyn InstallReporter = if opt.verbose > 0 { &TerminalReporter } else { &SilentReporter };
+        if !opt.remove.is_empty() {
+            let uninstalled = uninstall(Path::new("."), &opt.remove, &registry, &options, &resolution, reporter)?;
+            for name in opt.remove.iter().filter(|name| !uninstalled.removed.contains(name)) {
+                println!("{} isn't a dependency", name);
+            }
+            for package in &uninstalled.pruned {
+                println!("Removed {}@{}", package.name, package.version.as_deref().unwrap_or("?"));
+            }
+        }
+        if !opt.add.is_empty() {
+            let added = add(Path::new("."), &opt.add, kind, opt.save_exact, &registry, &resolution, reporter)?;
+            for dependency in &added.dependencies {
+                println!("Added {}@{}", dependency.name, dependency.version);
+            }
+        }
+    }
+
+    if let Some(names) = &opt.update {
+        let registry = HttpRegistry::new(RegistryConfig::load(Path::new("."))?, options.clone());
+        let reporter: &dyn InstallReporter = if opt.verbose > 0 { &TerminalReporter } else { &SilentReporter };
+        let updated = update(Path::new("."), names, opt.major, &registry, &resolution, reporter)?;
+        for package in &updated.updated {
+            println!("{}: {} -> {}", package.name, versions(&package.from), versions(&package.to));
+        }
+    }
+
+    let stats = install(
+        Path::new("."),
+        !install_dev_dependencies,
+        &options,
+        &resolution,
+        opt.verbose > 0,
+        opt.dry_run,
+        opt.dedupe,
+    )?;
+    if let (Some(stats), true) = (stats, opt.stats) {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    }
+    Ok(())
 }
 
-fn install(root_path: &Path, _install_dev_dependencies: bool) -> Result<()> {
-    let _ = fs::create_dir("node_modules");
-    let dependencies = path_to_dependencies(&root_path)?;
-    let root = path_to_root_dependency(&root_path)?;
-    let depends = calculate_depends(&root, &dependencies)?;
+/// Advances the progress bar per installed package, and prints warnings above it instead of through it
+struct ProgressReporter {
+    pb: ProgressBar,
+}
+
+impl InstallReporter for ProgressReporter {
+    fn on_unpack(&self, name: &str, version: &str, _path: &Path) {
+        self.pb.inc(1);
+        self.pb.set_message(format!("{}@{}", name, version));
+    }
+
+    fn on_up_to_date(&self, name: &str, version: &str, path: &Path) {
+        self.on_unpack(name, version, path);
+    }
 
-    let pb = ProgressBar::new(depends.iter().len() as u64);
+    fn on_warning(&self, message: &str) {
+        self.pb.println(format!("Warning: {}", message));
+    }
+}
+
+/// What the install did, None for a dry run
+fn install(
+    root_path: &Path,
+    _install_dev_dependencies: bool,
+    options: &InstallOptions,
+    resolution: &ResolutionOptions,
+    verbose: bool,
+    dry_run: bool,
+    dedupe: bool,
+) -> Result<Option<InstallStats>> {
+    let node_modules = &options.modules_dir(root_path);
+    let config = RegistryConfig::load(root_path)?;
+    let license_policy = config.license_policy.clone();
+    let registry = HttpRegistry::new(config, options.clone());
+    let started = Instant::now();
+    let mut depends = resolve(root_path, &registry, resolution, verbose)?;
+    let resolved_in = started.elapsed();
+    if dedupe {
+        let deduped = depends.dedupe();
+        for merged in &deduped.merged {
+            println!("{}: {} -> {}", merged.name, merged.version, merged.into);
+        }
+        println!("{} fewer packages to install", deduped.removed.len());
+    }
+    let plan = plan_install(node_modules, &depends, &registry, options)?;
+
+    if dry_run {
+        print_plan(&plan);
+        return Ok(None);
+    }
+    let _ = fs::create_dir_all(node_modules);
+
+    let pb = if verbose {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(plan.installs().count() as u64)
+    };
 
     pb.set_style(ProgressStyle::default_bar()
         .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}"));
 
+    let progress_reporter = ProgressReporter { pb: pb.clone() };
+    let reporter: &dyn InstallReporter = if verbose { &TerminalReporter } else { &progress_reporter };
+
+    let mut stats = execute_plan(node_modules, &plan, &depends, &registry, options, reporter)?;
+    pb.finish_and_clear();
+    let resolve = Phase {
+        name: "resolve".to_string(),
+        elapsed: resolved_in,
+    };
+    stats.phases.insert(0, resolve);
+    license_policy.enforce(&collect_licenses(node_modules, &depends)?)?;
+
+    let mut lockfile = Lockfile::from_graph(&depends, &registry)?;
+    lockfile.record_overrides(&resolution.overrides);
+    lockfile.record_contents(node_modules)?;
+    write_lockfile(root_path, &lockfile)?;
+
+    Ok(Some(stats))
+}
+
+/// The locked graph as long as package.json still asks for what's locked, otherwise a fresh resolution
+fn resolve(
+    root_path: &Path,
+    registry: &HttpRegistry,
+    resolution: &ResolutionOptions,
+    verbose: bool,
+) -> Result<ResolvedGraph> {
+    let dependencies = project_dependencies(root_path)?;
+    let locked = read_or_import(root_path)?.filter(|lockfile| {
+        lockfile.check_sync(&dependencies).is_ok() && lockfile.check_overrides(&resolution.overrides).is_ok()
+    });
+    let reporter: &dyn InstallReporter = if verbose { &TerminalReporter } else { &SilentReporter };
+    if let Some(lockfile) = locked {
+        return Ok(lockfile.to_graph()?);
+    }
 
-    for dep in depends.iter() {
-        pb.inc(1);
+    let graph = calculate_depends(&path_to_root_dependency(root_path)?, &dependencies, registry, resolution, reporter)?;
+    for issue in check_peers(&graph, registry, resolution)? {
+        eprintln!("Warning: {}", issue);
+        for chain in &issue.chains {
+            eprintln!("  via {}", chain);
+        }
+    }
+    Ok(graph)
+}
 
-        let name = dep.0.name.to_string();
-        let ver = dep.0.version.to_string();
-        pb.set_message(format!("{}@{}", name, ver));
+fn global_packages(
+    global: &GlobalPrefix,
+    add: &[String],
+    remove: &[String],
+    registry: &HttpRegistry,
+    options: &InstallOptions,
+    resolution: &ResolutionOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<()> {
+    if add.is_empty() && remove.is_empty() {
+        for package in list_global(global)? {
+            let version = package.version.as_deref().unwrap_or("missing");
+            println!("{}@{} ({})", package.name, version, package.range);
+            for bin in &package.bins {
+                println!("  {}", global.bin_dir().join(bin).display());
+            }
+        }
+        return Ok(());
+    }
 
-        install_dep(&Path::new(&"./node_modules".to_string()), &dep.0)?;
+    let removed = uninstall_global(global, remove, registry, options, resolution, reporter)?;
+    for name in remove {
+        if !removed.contains(name) {
+            println!("{} isn't installed globally", name);
+        }
     }
+    for added in install_global(global, add, registry, options, resolution, reporter)? {
+        println!("Added {}@{} to {}", added.name, added.version, global.prefix.display());
+    }
+    Ok(())
+}
+
+/// Install from the lockfile alone, as pipelines do
+fn ci(root_path: &Path, options: &InstallOptions, verbose: bool) -> Result<()> {
+    let config = RegistryConfig::load(root_path)?;
+    let license_policy = config.license_policy.clone();
+    let registry = HttpRegistry::new(config, options.clone());
+    let pb = if verbose { ProgressBar::hidden() } else { ProgressBar::new_spinner() };
+    let progress_reporter = ProgressReporter { pb: pb.clone() };
+    let reporter: &dyn InstallReporter = if verbose { &TerminalReporter } else { &progress_reporter };
+
+    let graph = install_frozen(root_path, &registry, options, reporter)?;
     pb.finish_and_clear();
+    license_policy.enforce(&collect_licenses(&options.modules_dir(root_path), &graph)?)?;
+    Ok(())
+}
+
+/// Print how node_modules differs from the lockfile, failing when it does
+fn verify(root_path: &Path) -> Result<()> {
+    let lockfile =
+        read_lockfile(root_path)?.ok_or_else(|| anyhow::anyhow!("There's no {} to verify against", LOCKFILE))?;
+    let verification = verify_install(root_path, &lockfile)?;
 
+    for package in &verification.missing {
+        println!("missing {}@{}", package.name, package.version.as_deref().unwrap_or("?"));
+    }
+    for mismatch in &verification.mismatched {
+        let reason = match mismatch.reason {
+            MismatchReason::Version => format!("{} is installed", mismatch.installed.as_deref().unwrap_or("?")),
+            MismatchReason::Integrity => "unpacked from another tarball".to_string(),
+            MismatchReason::Contents => "files changed".to_string(),
+        };
+        println!("mismatched {}@{}: {}", mismatch.name, mismatch.expected, reason);
+    }
+    for package in &verification.extraneous {
+        println!("extraneous {}@{}", package.name, package.version.as_deref().unwrap_or("?"));
+    }
+
+    if !verification.is_ok() {
+        anyhow::bail!("node_modules doesn't match {}", LOCKFILE);
+    }
+    println!("node_modules matches {}", LOCKFILE);
     Ok(())
+}
+
+/// Print the installed packages by license, failing when the license policy rules any out
+fn print_licenses(root_path: &Path, options: &InstallOptions) -> Result<()> {
+    let lockfile = read_lockfile(root_path)?.ok_or_else(|| anyhow::anyhow!("There's no {} to go by", LOCKFILE))?;
+    let report = collect_licenses(&options.modules_dir(root_path), &lockfile.to_graph()?)?;
+    for (license, packages) in report.by_license() {
+        println!("{}", license);
+        for package in packages {
+            println!("  {}@{} {}", package.name, package.version, package.files.join(" "));
+        }
+    }
+    Ok(RegistryConfig::load(root_path)?.license_policy.enforce(&report)?)
+}
+
+/// Print what's behind, preferring cached packuments
+fn print_outdated(root_path: &Path, options: &InstallOptions, resolution: &ResolutionOptions, all: bool) -> Result<()> {
+    let options = InstallOptions {
+        prefer_offline: true,
+        ..options.clone()
+    };
+    let registry = HttpRegistry::new(RegistryConfig::load(root_path)?, options);
+    let unknown = |version: &Option<String>| version.clone().unwrap_or_else(|| "-".to_string());
+
+    println!("{:<30} {:<12} {:<12} {:<12} Depended on by", "Package", "Current", "Wanted", "Latest");
+    for dependency in outdated(root_path, &registry, resolution, all)? {
+        println!(
+            "{:<30} {:<12} {:<12} {:<12} {}",
+            dependency.name,
+            unknown(&dependency.current),
+            unknown(&dependency.wanted),
+            unknown(&dependency.latest),
+            dependency.dependent.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
+
+/// Write a new package.json, asking for each field on stdin with its default in parentheses unless `yes`
+fn init_package(dir: &Path, yes: bool) -> Result<()> {
+    let ask = |question: &str, default: &str| -> Result<String> {
+        if yes {
+            return Ok(String::new());
+        }
+        print!("{}: ({}) ", question, default);
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        Ok(answer)
+    };
+
+    let options = InitOptions::default();
+    let answer = ask("package name", &options.name_for(dir))?;
+    let options = options.with_name(&answer);
+    let answer = ask("version", &options.version)?;
+    let options = options.with_version(&answer);
+    let answer = ask("description", &options.description)?;
+    let options = options.with_description(&answer);
+    let answer = ask("entry point", &options.main)?;
+    let options = options.with_main(&answer);
+    let answer = ask("author", &options.author)?;
+    let options = options.with_author(&answer);
+    let answer = ask("license", &options.license)?;
+    let options = options.with_license(&answer);
+    let answer = ask("workspaces, like packages/*", "none")?;
+    let options = options.with_workspaces(&answer);
+
+    let manifest = init(dir, &options)?;
+    println!(
+        "Wrote package.json for {}@{}",
+        manifest.name.unwrap_or_default(),
+        manifest.version.unwrap_or_default()
+    );
+    Ok(())
+}
+
+/// Publish, printing what went into the tarball
+fn print_publish(root_path: &Path, options: &InstallOptions, publish_options: &PublishOptions) -> Result<()> {
+    let config = RegistryConfig::load(root_path)?;
+    let registry = HttpRegistry::new(config.clone(), options.clone());
+    let published = publish(root_path, &registry, &config, publish_options)?;
+
+    for file in &published.files {
+        println!("{}", file.display());
+    }
+    println!("{} files, {:.1} kB packed", published.files.len(), published.size as f64 / 1000.0);
+    println!("shasum:    {}", published.shasum);
+    println!("integrity: {}", published.integrity);
+    let dry_run = if publish_options.dry_run { " (dry run)" } else { "" };
+    println!("+ {}@{} ({}){}", published.name, published.version, published.tag, dry_run);
+    Ok(())
+}
+
+/// List, add or remove the dist-tags a flag asks for
+fn dist_tag(ls: Option<&str>, add: Option<&[String]>, rm: Option<&[String]>, registry: &HttpRegistry) -> Result<()> {
+    if let Some(name) = ls {
+        for (tag, version) in dist_tags::list(name, registry)? {
+            println!("{}: {}", tag, version);
+        }
+    }
+    if let Some([spec, tag]) = add {
+        let spec = parse_spec(spec);
+        if spec.version.is_empty() {
+            anyhow::bail!("--dist-tag-add needs a version, like {}@1.0.0", spec.name);
+        }
+        dist_tags::add(&spec.name, &spec.version, tag, registry)?;
+        println!("+{}: {}@{}", tag, spec.name, spec.version);
+    }
+    if let Some([name, tag]) = rm {
+        match dist_tags::rm(name, tag, registry)? {
+            Some(version) => println!("-{}: {}@{}", tag, name, version),
+            None => println!("{} has no dist-tag {}", name, tag),
+        }
+    }
+    Ok(())
+}
+
+/// Print a field as it is when it's a string and as JSON otherwise, or a summary of the whole version
+fn print_info(spec: &str, field: Option<&str>, registry: &HttpRegistry, resolution: &ResolutionOptions) -> Result<()> {
+    let package = match info(spec, field, registry, resolution)? {
+        Info::Package(package) => package,
+        Info::Field(Some(Value::String(value))) => {
+            println!("{}", value);
+            return Ok(());
+        }
+        Info::Field(Some(value)) => {
+            println!("{}", serde_json::to_string_pretty(&value)?);
+            return Ok(());
+        }
+        Info::Field(None) => return Ok(()),
+    };
+
+    println!("{}@{} | {} versions", package.name, package.version, package.versions.len());
+    if let Some(description) = package.manifest["description"].as_str() {
+        println!("{}", description);
+    }
+    let tags: Vec<String> = package.dist_tags.iter().map(|(tag, version)| format!("{}: {}", tag, version)).collect();
+    println!("dist-tags: {}", tags.join(", "));
+    println!("tarball: {}", package.metadata.dist.tarball);
+    if !package.metadata.dependencies.is_empty() {
+        let dependencies: Vec<String> =
+            package.metadata.dependencies.iter().map(|(name, range)| format!("{}@{}", name, range)).collect();
+        println!("dependencies: {}", dependencies.join(", "));
+    }
+    if let Some(deprecated) = package.metadata.deprecated.as_ref().and_then(Value::as_str) {
+        println!("deprecated: {}", deprecated);
+    }
+    Ok(())
+}
+
+/// Print a page of results, one package a line or as JSON
+fn print_search(query: &str, search_options: &SearchOptions, json: bool, registry: &HttpRegistry) -> Result<()> {
+    let found = search(query, search_options, registry)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&found)?);
+        return Ok(());
+    }
+    for result in &found.results {
+        let description = result.description.as_deref().unwrap_or_default();
+        println!("{}@{}  {}", result.name, result.version, description.trim());
+    }
+    if found.next_page(search_options).is_some() {
+        let shown = search_options.from + found.results.len();
+        println!("{} of {} shown, --search-from {} for more", shown, found.total, shown);
+    }
+    Ok(())
+}
+
+/// Unpublish once it's confirmed, printing what goes or would go
+fn print_unpublish(spec: &str, confirmed: bool, registry: &HttpRegistry) -> Result<()> {
+    let pending = unpublish(spec, registry)?;
+    let name = pending.name.clone();
+    if !confirmed {
+        for version in &pending.versions {
+            println!("would unpublish {}@{}", name, version);
+        }
+        println!("Pass --yes to unpublish");
+        return Ok(());
+    }
+    for version in pending.confirm(registry)? {
+        println!("- {}@{}", name, version);
+    }
+    Ok(())
+}
+
+/// Print the advisories against what's resolved, failing when there are any
+fn print_audit(
+    root_path: &Path,
+    options: &InstallOptions,
+    resolution: &ResolutionOptions,
+    verbose: bool,
+) -> Result<()> {
+    let options = InstallOptions {
+        prefer_offline: true,
+        ..options.clone()
+    };
+    let registry = HttpRegistry::new(RegistryConfig::load(root_path)?, options);
+    let graph = resolve(root_path, &registry, resolution, verbose)?;
+    let report = audit(&graph, &registry, resolution)?;
+
+    for vulnerability in &report.vulnerabilities {
+        let advisory = &vulnerability.advisory;
+        println!("{} {}@{}: {}", advisory.severity, vulnerability.name, vulnerability.version, advisory.title);
+        println!("  {}", advisory.url);
+        for chain in &vulnerability.chains {
+            let links: Vec<String> =
+                chain.links.iter().map(|link| format!("{}@{}", link.name, link.version)).collect();
+            println!("  via {}", links.join(" > "));
+        }
+        match &vulnerability.fix {
+            Some(fix) if vulnerability.fix_in_range => {
+                println!("  fixed in {}, run --update {}", fix, vulnerability.name)
+            }
+            Some(fix) => println!("  fixed in {}, outside the ranges depended on", fix),
+            None => println!("  no fix published"),
+        }
+    }
+
+    if report.vulnerabilities.is_empty() {
+        println!("No known vulnerabilities");
+        return Ok(());
+    }
+    let counts: Vec<String> =
+        report.counts().iter().rev().map(|(severity, count)| format!("{} {}", count, severity)).collect();
+    anyhow::bail!("{} vulnerabilities found: {}", report.vulnerabilities.len(), counts.join(", "));
+}
+
+fn versions(versions: &[String]) -> String {
+    if versions.is_empty() {
+        "-".to_string()
+    } else {
+        versions.join(", ")
+    }
+}
+
+fn print_plan(plan: &InstallPlan) {
+    for package in &plan.add {
+        println!("+ {}@{}", package.name, package.version);
+    }
+    for package in &plan.update {
+        let installed = package.installed.as_deref().unwrap_or("?");
+        println!("~ {}@{} -> {}", package.name, installed, package.version);
+    }
+    for package in &plan.remove {
+        println!("- {}@{}", package.name, package.version.as_deref().unwrap_or("?"));
+    }
+
+    let scripts: Vec<&str> = plan.scripts().map(|package| package.name.as_str()).collect();
+    if !scripts.is_empty() {
+        println!("Install scripts, which won't be run: {}", scripts.join(", "));
+    }
+    println!(
+        "{} to add, {} to update, {} to remove, {:.1} MB to download (unpacked)",
+        plan.add.len(),
+        plan.update.len(),
+        plan.remove.len(),
+        plan.download_size() as f64 / 1_000_000.0
+    );
 }
\ No newline at end of file
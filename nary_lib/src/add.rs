@@ -0,0 +1,105 @@
+use std::{fs, path::Path};
+
+use crate::{
+    calculate_depends, git,
+    lockfile::{write_lockfile, Lockfile},
+    manifest::{manifest_path, parse_spec, save_dependency, saved_dependency},
+    pack::read_manifest,
+    parse_url, path_to_root_dependency,
+    workspace::project_dependencies,
+    Dependency, DependencyKind, InstallReporter, Manifest, NaryError, PackageName, RegistryClient, ResolutionOptions,
+    ResolvedGraph, Result, Specifier,
+};
+
+/// What `add` saved, and the graph it resolved with them
+#[derive(Debug)]
+pub struct Added {
+    /// As they were saved in package.json, in the order they were given
+    pub dependencies: Vec<Dependency>,
+    pub graph: ResolvedGraph,
+    pub lockfile: Lockfile,
+}
+
+/// Save dependencies on all of `specs` in package.json as `add_dependency` saves one, then resolve the project
+/// with them in one go and write the lockfile, for an install to follow it. A spec is `name`, `name@range`,
+/// `name@tag` or `name@` anything else a dependency can ask for; a git repository, tarball URL or `file:` directory
+/// can also be given on its own, and is named by its package.json. When anything doesn't resolve, package.json is
+/// left as it was.
+pub fn add(
+    project_dir: &Path,
+    specs: &[String],
+    kind: DependencyKind,
+    save_exact: bool,
+    registry: &dyn RegistryClient,
+    options: &ResolutionOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<Added> {
+    let path = manifest_path(project_dir);
+    let original = fs::read_to_string(&path).map_err(|err| NaryError::io(&path, err))?;
+    let mut text = original.clone();
+    let mut dependencies = Vec::new();
+    for spec in specs {
+        let requested = requested(project_dir, spec, registry, reporter)?;
+        let dependency = saved_dependency(&requested, save_exact, registry, options)?;
+        text = save_dependency(&text, &path, &dependency, kind)?;
+        dependencies.push(dependency);
+    }
+    fs::write(&path, &text).map_err(|err| NaryError::io(&path, err))?;
+
+    let resolved = resolve(project_dir, registry, options, reporter);
+    if resolved.is_err() {
+        fs::write(&path, &original).map_err(|err| NaryError::io(&path, err))?;
+    }
+    let (graph, lockfile) = resolved?;
+    Ok(Added {
+        dependencies,
+        graph,
+        lockfile,
+    })
+}
+
+fn resolve(
+    project_dir: &Path,
+    registry: &dyn RegistryClient,
+    options: &ResolutionOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<(ResolvedGraph, Lockfile)> {
+    let root = path_to_root_dependency(project_dir)?;
+    let graph = calculate_depends(&root, &project_dependencies(project_dir)?, registry, options, reporter)?;
+    let mut lockfile = Lockfile::from_graph(&graph, registry)?;
+    lockfile.record_overrides(&options.overrides);
+    write_lockfile(project_dir, &lockfile)?;
+    Ok((graph, lockfile))
+}
+
+/// The dependency a spec asks for, with the name from the package's own package.json when it's a git repository,
+/// tarball URL or directory given without one
+fn requested(
+    project_dir: &Path,
+    spec: &str,
+    registry: &dyn RegistryClient,
+    reporter: &dyn InstallReporter,
+) -> Result<Dependency> {
+    let named = parse_spec(spec);
+    if let Specifier::File(_) | Specifier::Git(_) | Specifier::Url(_) = named.specifier() {
+        return Ok(named);
+    }
+    let name = match Specifier::parse(spec) {
+        // Relative to package.json, where it's saved
+        Specifier::File(local) => Manifest::read(&project_dir.join(local))?.name,
+        Specifier::Git(git) => git::read_manifest(&git)?["name"].as_str().map(str::to_string),
+        Specifier::Url(url) => {
+            let url = parse_url(&url)?;
+            // Only to cache the download by until its package.json names it
+            let stem = url.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or_default();
+            let placeholder = PackageName::parse(stem.trim_end_matches(".tgz"))?;
+            let tarball = registry.tarball(&placeholder, spec, &url, reporter)?;
+            read_manifest(&tarball, &url)?["name"].as_str().map(str::to_string)
+        }
+        _ => return Ok(named),
+    };
+    match name {
+        Some(name) => Ok(Dependency::new(name, spec.to_string())),
+        None => Err(NaryError::InvalidPackageName(spec.to_string())),
+    }
+}
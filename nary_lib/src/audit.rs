@@ -0,0 +1,205 @@
+use semver_rs::{Range, Version};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BTreeMap,
+    fmt,
+    io::Read,
+};
+
+use crate::{
+    DependencyChain, InstallOptions, NaryError, NodeId, PackageName, RegistryClient, RegistryConfig, ResolutionOptions,
+    ResolvedGraph, Result,
+};
+
+/// How bad an advisory is, least first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Low,
+    Moderate,
+    High,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Low => "low",
+            Severity::Moderate => "moderate",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        })
+    }
+}
+
+/// A security advisory against some versions of a package, as the registry has it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Advisory {
+    pub id: u64,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub url: String,
+    pub severity: Severity,
+    /// The range of affected versions
+    pub vulnerable_versions: String,
+}
+
+/// A resolved package version that an advisory affects
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Vulnerability {
+    pub name: String,
+    pub version: String,
+    pub advisory: Advisory,
+    /// How the root comes to depend on it
+    pub chains: Vec<DependencyChain>,
+    /// The lowest published version above it that no advisory of the package affects, None when there's none
+    pub fix: Option<String>,
+    /// Whether the fix is in every range it's depended on with, so that updating is enough
+    pub fix_in_range: bool,
+}
+
+/// Everything the registry's advisories say about a resolved graph, most severe first
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    pub vulnerabilities: Vec<Vulnerability>,
+}
+
+impl AuditReport {
+    /// How many vulnerabilities there are of each severity
+    pub fn counts(&self) -> BTreeMap<Severity, usize> {
+        let mut counts = BTreeMap::new();
+        for vulnerability in &self.vulnerabilities {
+            *counts.entry(vulnerability.advisory.severity).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// The severity of the worst vulnerability, None when there are none
+    pub fn highest(&self) -> Option<Severity> {
+        self.vulnerabilities.iter().map(|vulnerability| vulnerability.advisory.severity).max()
+    }
+}
+
+/// Ask the registry for advisories against every registry package in `graph`, and report which resolved versions
+/// they affect, how each is depended on, and the version that fixes it
+pub fn audit(graph: &ResolvedGraph, registry: &dyn RegistryClient, options: &ResolutionOptions) -> Result<AuditReport> {
+    let mut resolved: BTreeMap<String, Vec<(NodeId, String)>> = BTreeMap::new();
+    for (id, node) in graph.nodes() {
+        // The root, local directories, tarballs and git repositories have no registry version to be advised about
+        if id == ResolvedGraph::ROOT || Version::new(&node.version).parse().is_err() {
+            continue;
+        }
+        resolved.entry(node.package().to_string()).or_default().push((id, node.version.clone()));
+    }
+    let versions = resolved
+        .iter()
+        .map(|(name, versions)| (name.clone(), versions.iter().map(|(_, version)| version.clone()).collect()))
+        .collect();
+
+    let mut vulnerabilities = Vec::new();
+    for (name, advisories) in registry.advisories(&versions)? {
+        let resolved = match resolved.get(&name) {
+            Some(resolved) if !advisories.is_empty() => resolved,
+            _ => continue,
+        };
+        let ranges = advisories
+            .iter()
+            .map(|advisory| parse_range(&name, &advisory.vulnerable_versions, options))
+            .collect::<Result<Vec<_>>>()?;
+        let packument = registry.packument(&PackageName::parse(&name)?)?;
+        let mut published: Vec<Version> =
+            packument.versions.keys().filter_map(|version| Version::new(version).parse().ok()).collect();
+        published.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+        for (id, version) in resolved {
+            let parsed = Version::new(version).parse().map_err(|source| NaryError::VersionParse {
+                name: name.clone(),
+                version: version.clone(),
+                source,
+            })?;
+            let fix = published
+                .iter()
+                .filter(|candidate| *candidate > &parsed)
+                .find(|candidate| ranges.iter().all(|range| !range.test(candidate)))
+                .map(|fix| fix.to_string());
+            let node = graph.node(*id).expect("the id came from the graph");
+            let chains: Vec<DependencyChain> = graph
+                .why(&node.name)
+                .into_iter()
+                .filter(|chain| chain.links.last().is_some_and(|link| &link.version == version))
+                .collect();
+            let fix_in_range = match &fix {
+                Some(fix) => chains.iter().all(|chain| in_range(chain, fix, options)),
+                None => false,
+            };
+
+            for (advisory, range) in advisories.iter().zip(&ranges) {
+                if range.test(&parsed) {
+                    vulnerabilities.push(Vulnerability {
+                        name: node.name.clone(),
+                        version: version.clone(),
+                        advisory: advisory.clone(),
+                        chains: chains.clone(),
+                        fix: fix.clone(),
+                        fix_in_range,
+                    });
+                }
+            }
+        }
+    }
+
+    vulnerabilities.sort_by(|a, b| {
+        let order = |vulnerability: &Vulnerability| {
+            let advisory = &vulnerability.advisory;
+            (Reverse(advisory.severity), vulnerability.name.clone(), vulnerability.version.clone(), advisory.id)
+        };
+        order(a).cmp(&order(b))
+    });
+    Ok(AuditReport { vulnerabilities })
+}
+
+/// POST the versions of each package to the registry's bulk advisory endpoint, and return the advisories it has
+/// against any of them
+pub fn fetch_advisories(
+    versions: &BTreeMap<String, Vec<String>>,
+    config: &RegistryConfig,
+    options: &InstallOptions,
+) -> Result<BTreeMap<String, Vec<Advisory>>> {
+    if options.offline {
+        return Err(NaryError::NotCached { what: "Security advisories".to_string() });
+    }
+    if versions.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let url = format!("{}/-/npm/v1/security/advisories/bulk", config.registry.trim_end_matches('/'));
+    let request = serde_json::to_string(versions).map_err(|err| NaryError::json(&url, err))?;
+    let mut body = String::new();
+    config
+        .query_json(&url, &request)?
+        .read_to_string(&mut body)
+        .map_err(|err| NaryError::network(&url, err))?;
+
+    serde_json::from_str(&body).map_err(|err| NaryError::json(&url, err))
+}
+
+fn parse_range(name: &str, range: &str, options: &ResolutionOptions) -> Result<Range> {
+    Range::new(range).with_options(options.semver()).parse().map_err(|source| NaryError::VersionParse {
+        name: name.to_string(),
+        version: range.to_string(),
+        source,
+    })
+}
+
+/// Whether `version` is in the range the last link of `chain` was asked for with
+fn in_range(chain: &DependencyChain, version: &str, options: &ResolutionOptions) -> bool {
+    let range = chain.links.last().map(|link| link.range.as_str()).unwrap_or_default();
+    match (Range::new(range).with_options(options.semver()).parse(), Version::new(version).parse()) {
+        (Ok(range), Ok(version)) => range.test(&version),
+        _ => false,
+    }
+}
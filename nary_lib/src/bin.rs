@@ -0,0 +1,153 @@
+use serde_json::Value;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{InstallFs, Manifest, NaryError, RealFs, Result};
+
+/// Link the executables a package declares in its `bin` field into `node_modules/.bin`, returning what was
+/// created. Symlinks elsewhere; `.cmd` and `.ps1` shims on Windows, where symlinks need privileges.
+pub fn link_bins(node_modules: &Path, package_dir: &Path) -> Result<Vec<PathBuf>> {
+    link_bins_into(&node_modules.join(".bin"), package_dir)
+}
+
+/// Link a package's executables into `bin_dir`, as `link_bins` does into `node_modules/.bin`
+pub fn link_bins_into(bin_dir: &Path, package_dir: &Path) -> Result<Vec<PathBuf>> {
+    link_bins_with(&RealFs, bin_dir, package_dir)
+}
+
+/// Like `link_bins_into`, on `fs`
+pub(crate) fn link_bins_with(fs: &dyn InstallFs, bin_dir: &Path, package_dir: &Path) -> Result<Vec<PathBuf>> {
+    let manifest = match package_manifest(fs, package_dir)? {
+        Some(manifest) => manifest,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut linked = Vec::new();
+    for (name, target) in bins(&manifest) {
+        let target = package_dir.join(target);
+        if !fs.is_file(&target) {
+            continue;
+        }
+        if linked.is_empty() {
+            fs.create_dir_all(bin_dir).map_err(|err| NaryError::io(bin_dir, err))?;
+        }
+        linked.extend(link_bin(fs, bin_dir, &name, &target)?);
+    }
+
+    Ok(linked)
+}
+
+/// Remove the links `link_bins_into` made in `bin_dir` for a package, returning what was removed
+pub fn unlink_bins(bin_dir: &Path, package_dir: &Path) -> Result<Vec<PathBuf>> {
+    let manifest = match package_manifest(&RealFs, package_dir)? {
+        Some(manifest) => manifest,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut removed = Vec::new();
+    for (name, _) in bins(&manifest) {
+        for link in bin_links(bin_dir, &name) {
+            if fs::symlink_metadata(&link).is_ok() {
+                fs::remove_file(&link).map_err(|err| NaryError::io(&link, err))?;
+                removed.push(link);
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+fn package_manifest(fs: &dyn InstallFs, package_dir: &Path) -> Result<Option<Value>> {
+    let manifest_path = package_dir.join("package.json");
+    let manifest = match fs.read(&manifest_path) {
+        Ok(manifest) => manifest,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(NaryError::io(manifest_path, err)),
+    };
+    serde_json::from_slice(&manifest).map(Some).map_err(|err| NaryError::json(manifest_path.display(), err))
+}
+
+/// Names and package-relative paths of a package.json's `bin` field, as `Manifest::bins` has them
+pub fn bins(manifest: &Value) -> Vec<(String, PathBuf)> {
+    Manifest::from_value(manifest).bins()
+}
+
+#[cfg(not(windows))]
+fn bin_links(bin_dir: &Path, name: &str) -> Vec<PathBuf> {
+    vec![bin_dir.join(name)]
+}
+
+#[cfg(windows)]
+fn bin_links(bin_dir: &Path, name: &str) -> Vec<PathBuf> {
+    vec![bin_dir.join(format!("{}.cmd", name)), bin_dir.join(format!("{}.ps1", name))]
+}
+
+#[cfg(not(windows))]
+fn link_bin(fs: &dyn InstallFs, bin_dir: &Path, name: &str, target: &Path) -> Result<Vec<PathBuf>> {
+    let link = bin_dir.join(name);
+    fs.remove(&link).map_err(|err| NaryError::io(&link, err))?;
+    fs.symlink(&relative_to(bin_dir, target), &link).map_err(|err| NaryError::io(&link, err))?;
+    fs.set_executable(target).map_err(|err| NaryError::io(target, err))?;
+
+    Ok(vec![link])
+}
+
+#[cfg(windows)]
+fn link_bin(fs: &dyn InstallFs, bin_dir: &Path, name: &str, target: &Path) -> Result<Vec<PathBuf>> {
+    let target = relative_to(bin_dir, target);
+    let shims = [cmd_shim(&target), ps1_shim(&target)];
+
+    let mut linked = Vec::new();
+    for (path, shim) in bin_links(bin_dir, name).into_iter().zip(shims) {
+        fs.write_file(&path, &mut shim.as_bytes(), false).map_err(|err| NaryError::io(&path, err))?;
+        linked.push(path);
+    }
+    Ok(linked)
+}
+
+/// A `.cmd` shim running `target`, relative to the shim, with the node next to it or on the PATH
+pub fn cmd_shim(target: &Path) -> String {
+    let target = target.to_string_lossy().replace('/', "\\");
+    format!(
+        "@ECHO off\r\n\
+         SETLOCAL\r\n\
+         SET \"dp0=%~dp0\"\r\n\
+         IF EXIST \"%dp0%\\node.exe\" (\r\n  SET \"_prog=%dp0%\\node.exe\"\r\n) ELSE (\r\n  SET \"_prog=node\"\r\n  \
+         SET PATHEXT=%PATHEXT:;.JS;=;%\r\n)\r\n\
+         ENDLOCAL & \"%_prog%\" \"%dp0%\\{}\" %*\r\n",
+        target
+    )
+}
+
+/// A PowerShell shim running `target`, relative to the shim, with the node next to it or on the PATH
+pub fn ps1_shim(target: &Path) -> String {
+    let target = target.to_string_lossy().replace('\\', "/");
+    format!(
+        "#!/usr/bin/env pwsh\n\
+         $basedir=Split-Path $MyInvocation.MyCommand.Definition -Parent\n\
+         $exe=\"\"\n\
+         if ($PSVersionTable.PSVersion -lt \"6.0\" -or $IsWindows) {{\n  $exe=\".exe\"\n}}\n\
+         if (Test-Path \"$basedir/node$exe\") {{\n  & \"$basedir/node$exe\" \"$basedir/{0}\" $args\n}} else {{\n  \
+         & \"node$exe\" \"$basedir/{0}\" $args\n}}\n\
+         exit $LASTEXITCODE\n",
+        target
+    )
+}
+
+/// `target` as seen from `dir`, when both are below the same directory
+fn relative_to(dir: &Path, target: &Path) -> PathBuf {
+    let dir: Vec<_> = dir.components().collect();
+    let target: Vec<_> = target.components().collect();
+    let common = dir.iter().zip(&target).take_while(|(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..dir.len() {
+        relative.push("..");
+    }
+    for component in &target[common..] {
+        relative.push(component);
+    }
+    relative
+}
@@ -1,24 +1,243 @@
-use anyhow::{Context, Result};
-
-use hyper::{net::HttpsConnector, Client, Url};
-use hyper_native_tls::NativeTlsClient;
+use hyper::{
+    client::Response,
+    header::{
+        AcceptRanges, ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, ETag, EntityTag, Headers,
+        HttpDate, IfRange, LastModified, Range, RangeUnit,
+    },
+    status::StatusCode,
+    Url,
+};
+use serde_derive::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use static_init::dynamic;
 use std::{
-    fs,
-    fs::{create_dir_all, File},
-    io::{Read, Write},
-    path::PathBuf,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    io::{self, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tracing::{debug, debug_span, trace};
+
+use percent_encoding::{AsciiSet, CONTROLS};
 
+use crate::{InstallFs, InstallOptions, InstallReporter, NaryError, PackageName, RealFs, RegistryConfig, Result};
+
+/// `NARY_CACHE_DIR`, or `~/.nary_cache`
 pub fn get_cache_dir() -> Result<PathBuf> {
-    let mut cache_dir = dirs::home_dir().context("Couldn't find home dir")?;
+    get_cache_dir_with(&RealFs)
+}
 
-    cache_dir.push(".nary_cache");
-    create_dir_all(&cache_dir).context("Couldn't create cache")?;
+/// Like `get_cache_dir`, on `fs`
+pub(crate) fn get_cache_dir_with(fs: &dyn InstallFs) -> Result<PathBuf> {
+    let cache_dir = match std::env::var_os(CACHE_DIR_VAR) {
+        Some(dir) => PathBuf::from(dir),
+        None => dirs::home_dir().ok_or(NaryError::NoHomeDir)?.join(".nary_cache"),
+    };
+
+    fs.create_dir_all(&cache_dir).map_err(|err| NaryError::io(&cache_dir, err))?;
+    let mut migrated = MIGRATED.lock().unwrap();
+    let key = (fs as *const dyn InstallFs as *const () as usize, cache_dir.clone());
+    if !migrated.contains(&key) {
+        migrate(fs, &cache_dir)?;
+        migrated.insert(key);
+    }
 
     Ok(cache_dir)
 }
 
-use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+/// Cache directories brought up to `CacheVersion::CURRENT` this run, by the address of the filesystem they're on
+#[dynamic]
+static MIGRATED: Mutex<HashSet<(usize, PathBuf)>> = Mutex::new(HashSet::new());
+
+/// The layout of a cache directory, as its `cache-version` file says
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CacheVersion(pub u32);
+
+impl CacheVersion {
+    /// Packuments at `<name>/` in the cache directory, from before there was a `cache-version` file
+    pub const FLAT: CacheVersion = CacheVersion(1);
+    /// Packuments in `packuments-v1`, by `entry_name`
+    pub const CURRENT: CacheVersion = CacheVersion(2);
+}
+
+/// The version of the cache directory's layout. A directory without a `cache-version` file is `FLAT`.
+pub fn cache_version(cache_dir: &Path) -> Result<CacheVersion> {
+    cache_version_with(&RealFs, cache_dir)
+}
+
+/// Like `cache_version`, on `fs`
+fn cache_version_with(fs: &dyn InstallFs, cache_dir: &Path) -> Result<CacheVersion> {
+    let path = cache_dir.join(VERSION_FILE);
+    match fs.read(&path) {
+        Ok(version) => Ok(String::from_utf8_lossy(&version)
+            .trim()
+            .parse()
+            .map(CacheVersion)
+            .unwrap_or(CacheVersion::FLAT)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(CacheVersion::FLAT),
+        Err(err) => Err(NaryError::io(path, err)),
+    }
+}
+
+/// Move whatever an older layout put where the current one doesn't look, once. A cache written by a newer nary is
+/// refused rather than mixed with this one's layout.
+fn migrate(fs: &dyn InstallFs, cache_dir: &Path) -> Result<()> {
+    let version = cache_version_with(fs, cache_dir)?;
+    if version == CacheVersion::CURRENT {
+        return Ok(());
+    }
+
+    // Another process could be migrating the same directory
+    let locks = cache_dir.join(LOCK_DIR);
+    fs.create_dir_all(&locks).map_err(|err| NaryError::io(&locks, err))?;
+    let lock = locks.join("migrate");
+    let _lock = fs.lock(&lock).map_err(|err| NaryError::io(&lock, err))?;
+
+    let version = cache_version_with(fs, cache_dir)?;
+    if version > CacheVersion::CURRENT {
+        return Err(NaryError::UnsupportedCache {
+            path: cache_dir.to_path_buf(),
+            version: version.0,
+        });
+    }
+    if version == CacheVersion::FLAT {
+        migrate_flat_packuments(fs, cache_dir)?;
+    }
+
+    let temp = cache_dir.join(format!("{}.{}", VERSION_FILE, std::process::id()));
+    let path = cache_dir.join(VERSION_FILE);
+    let version = format!("{}\n", CacheVersion::CURRENT.0);
+    fs.write_file(&temp, &mut version.as_bytes(), false).map_err(|err| NaryError::io(&temp, err))?;
+    fs.rename(&temp, &path).map_err(|err| NaryError::io(&path, err))?;
+    Ok(())
+}
+
+/// Move the packuments of `<name>/` and `@<scope>/<name>/` into `packuments-v1`, leaving everything else alone
+fn migrate_flat_packuments(fs: &dyn InstallFs, cache_dir: &Path) -> Result<()> {
+    let mut packages = Vec::new();
+    for (file_name, path) in dirs_in(fs, cache_dir)? {
+        if file_name.starts_with('@') {
+            for (name, path) in dirs_in(fs, &path)? {
+                packages.push((format!("{}/{}", file_name, name), path));
+            }
+        } else {
+            packages.push((file_name, path));
+        }
+    }
+
+    for (name, path) in packages {
+        let mut moved = false;
+        for file_name in &[PACKUMENT_FILE, VALIDATORS_FILE] {
+            let old = path.join(file_name);
+            if !fs.is_file(&old) {
+                continue;
+            }
+            let dir = cache_dir.join(PACKUMENTS_DIR).join(entry_name(&name));
+            fs.create_dir_all(&dir).map_err(|err| NaryError::io(&dir, err))?;
+            fs.rename(&old, &dir.join(file_name)).map_err(|err| NaryError::io(&old, err))?;
+            moved = true;
+        }
+        if moved {
+            debug!(name = %name, "migrated packument");
+            // Only goes when nothing else was in it
+            let empty = |dir: &Path| fs.read_dir(dir).is_ok_and(|entries| entries.is_empty());
+            if empty(&path) {
+                let _ = fs.remove(&path);
+            }
+            if let Some(scope) = path.parent().filter(|scope| scope != &cache_dir && empty(scope)) {
+                let _ = fs.remove(scope);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The directories in `dir` with UTF-8 names
+fn dirs_in(fs: &dyn InstallFs, dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut dirs = Vec::new();
+    for path in fs.read_dir(dir).map_err(|err| NaryError::io(dir, err))? {
+        let name = path.file_name().and_then(|name| name.to_str()).map(str::to_string);
+        if let (Some(name), true) = (name, fs.is_dir(&path)) {
+            dirs.push((name, path));
+        }
+    }
+    Ok(dirs)
+}
+
+/// Longest name a cache entry gets before it's shortened
+const MAX_ENTRY_NAME: usize = 100;
+
+/// A file name that stands for `name` on any filesystem, and for nothing else: lowercase ASCII letters, digits,
+/// `.`, `_` and `-` are kept, an uppercase letter becomes `!` and its lowercase, as case-insensitive filesystems
+/// would mix them up, and everything else is percent-encoded, the `/` of a scoped name too. Windows device names
+/// and a leading `.` are encoded as well. A name longer than `MAX_ENTRY_NAME` is cut, with a hash of all of it after.
+pub fn entry_name(name: &str) -> String {
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        match byte {
+            b'.' if encoded.is_empty() => encoded.push_str("%2e"),
+            b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-' => encoded.push(byte as char),
+            b'A'..=b'Z' => {
+                encoded.push('!');
+                encoded.push(byte.to_ascii_lowercase() as char);
+            }
+            _ => encoded.push_str(&format!("%{:02x}", byte)),
+        }
+    }
+
+    let stem = encoded.split('.').next().unwrap_or_default();
+    let device = matches!(stem, "con" | "prn" | "aux" | "nul")
+        || (stem.len() == 4
+            && (stem.starts_with("com") || stem.starts_with("lpt"))
+            && stem.as_bytes()[3].is_ascii_digit());
+    if device {
+        let last = stem.as_bytes()[stem.len() - 1];
+        encoded.replace_range(stem.len() - 1..stem.len(), &format!("%{:02x}", last));
+    }
+
+    if encoded.len() > MAX_ENTRY_NAME {
+        let hash = hex(&Sha256::digest(name.as_bytes()));
+        encoded.truncate(MAX_ENTRY_NAME - 33);
+        encoded.push('-');
+        encoded.push_str(&hash[..32]);
+    }
+    encoded
+}
+
+/// Overrides where the cache lives
+pub const CACHE_DIR_VAR: &str = "NARY_CACHE_DIR";
+
+/// Tarballs, stored once per content hash
+const CONTENT_DIR: &str = "content-v1";
+/// What was asked for, pointing at the content that came back
+const INDEX_DIR: &str = "index-v1";
+/// Lock files of entries being written
+const LOCK_DIR: &str = "locks";
+/// Files being written, before they're renamed into place
+const TEMP_DIR: &str = "tmp";
+/// Tarball downloads that were cut off, kept to be resumed
+const PARTIAL_DIR: &str = "partial-v1";
+/// The signing keys of registries
+const KEYS_DIR: &str = "keys-v1";
+/// Tarballs set aside because they kept failing to unpack, each next to why
+const QUARANTINE_DIR: &str = "quarantine-v1";
+/// The `ResolutionMemo`
+const RESOLUTIONS_FILE: &str = "resolutions-v1.json";
+/// Packuments and their validators, in a directory per package
+const PACKUMENTS_DIR: &str = "packuments-v1";
+const PACKUMENT_FILE: &str = "packument.json";
+const VALIDATORS_FILE: &str = "packument.validators.json";
+/// The `CacheVersion`
+const VERSION_FILE: &str = "cache-version";
+
+/// How long a fetched packument is used as it is, without asking the registry whether it changed
+pub const PACKUMENT_MAX_AGE: Duration = Duration::from_secs(5 * 60);
 
 /// https://url.spec.whatwg.org/#path-percent-encode-set
 pub const PATH_SEGMENT_ENCODE_SET: &AsciiSet = &CONTROLS
@@ -32,48 +251,1076 @@ pub const PATH_SEGMENT_ENCODE_SET: &AsciiSet = &CONTROLS
     .add(b'{')
     .add(b'}');
 
- /// Cache the given package (key) at version from the given url, returning the (gzipped) tarball.
-pub fn cache(key: &str, version: &str, tarball_url: &Url) -> Result<Vec<u8>> {
-    let mut tarball_res = Vec::<u8>::new();
+/// Cache the given package (key) at version from the given url, returning the (gzipped) tarball. Tarballs, and the
+/// locks of entries being written, are on `InstallOptions::fs`.
+pub fn cache(
+    key: &str,
+    version: &str,
+    tarball_url: &Url,
+    config: &RegistryConfig,
+    options: &InstallOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<Vec<u8>> {
+    let mut tarball = Vec::new();
+    cache_reader(key, version, tarball_url, config, options, reporter)?
+        .read_to_end(&mut tarball)
+        .map_err(|err| NaryError::network(tarball_url.as_str(), err))?;
+
+    Ok(tarball)
+}
+
+/// Like `cache`, but streams the (gzipped) tarball instead of buffering it. A download is written to the cache as
+/// it's read, and only becomes a cache entry once it has been read to the end. One that's cut off is kept when the
+/// server takes range requests, and picks up where it left off the next time, unless the tarball changed since.
+pub fn cache_reader<'a>(
+    key: &str,
+    version: &str,
+    tarball_url: &Url,
+    config: &RegistryConfig,
+    options: &'a InstallOptions,
+    reporter: &'a dyn InstallReporter,
+) -> Result<Box<dyn Read + 'a>> {
+    cache_reader_for(key, version, tarball_url, None, config, options, reporter)
+}
+
+/// Like `cache_reader`, for a tarball whose `Dist::identity` is known. The same content cached for another name,
+/// version or URL is read instead of downloading it again, and becomes this one's entry too.
+pub fn cache_reader_for<'a>(
+    key: &str,
+    version: &str,
+    tarball_url: &Url,
+    identity: Option<&str>,
+    config: &RegistryConfig,
+    options: &'a InstallOptions,
+    reporter: &'a dyn InstallReporter,
+) -> Result<Box<dyn Read + 'a>> {
+    let fs = &*options.fs;
+    let index_key = tarball_key(key, version, tarball_url);
+    let _span = debug_span!("tarball", name = key, version, url = %tarball_url).entered();
+    // An entry cached under this URL before is only used when it's the content the identity names
+    let expected = |integrity: &str| identity.is_none_or(|identity| matches_identity(identity, integrity, None));
+    let cached = || -> Result<Option<Box<dyn Read + 'a>>> {
+        let entry = read_index_with(fs, &index_key)?.filter(|entry| expected(&entry.integrity));
+        if let Some(file) = entry.map(|entry| open_content(fs, &entry.integrity)).transpose()?.flatten() {
+            return Ok(Some(file));
+        }
+        for content_key in identity.map(content_keys).unwrap_or_default() {
+            let entry = match read_index_with(fs, &content_key)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if let Some(file) = open_content(fs, &entry.integrity)? {
+                debug!(integrity = %entry.integrity, "the same content is cached already");
+                write_index_with(fs, &index_key, &entry.integrity, entry.size)?;
+                return Ok(Some(file));
+            }
+        }
+        Ok(None)
+    };
+
+    if let Some(tarball) = cached()? {
+        trace!("cache hit");
+        reporter.on_cache_lookup(key, version, true);
+        return Ok(tarball);
+    }
+
+    // Whoever holds the lock downloads; everyone waiting on it finds the tarball cached afterwards
+    let lock = lock_with(fs, &index_key)?;
+    if let Some(tarball) = cached()? {
+        debug!("cached while waiting for the lock");
+        reporter.on_cache_lookup(key, version, true);
+        return Ok(tarball);
+    }
+
+    if options.offline {
+        return Err(NaryError::NotCached {
+            what: format!("{}@{} ({})", key, version, tarball_url),
+        });
+    }
+
+    reporter.on_cache_lookup(key, version, false);
+    let (partial_path, state_path) = partial_paths(fs, &index_key)?;
+    let partial = read_partial(fs, &partial_path, &state_path, tarball_url);
+    let resuming = partial.as_ref().map(|(state, kept)| (state, kept.len() as u64));
+    let (response, received) = start_download(tarball_url, config, resuming)?;
+    let length = response.headers.get::<ContentLength>().map(|length| length.0);
+    let total = match response.headers.get::<ContentRange>() {
+        Some(ContentRange(ContentRangeSpec::Bytes { instance_length: Some(total), .. })) => Some(*total),
+        _ => length.map(|length| received + length),
+    };
+    debug!(resumed_from = received, total, "downloading");
+
+    let (state, replay) = match partial {
+        Some((state, kept)) if received > 0 => (Some(state), kept),
+        _ => {
+            fs.remove(&partial_path).map_err(|err| NaryError::io(&partial_path, err))?;
+            (None, Vec::new())
+        }
+    };
+    let file = fs.append(&partial_path).map_err(|err| NaryError::io(&partial_path, err))?;
+    let state = resumable(&response.headers, response.status, state).map(|state| PartialDownload {
+        url: tarball_url.to_string(),
+        total,
+        ..state
+    });
+
+    Ok(Box::new(Download {
+        response,
+        fs,
+        file: Some(file),
+        temp: partial_path,
+        state_path,
+        state,
+        replay: Cursor::new(replay),
+        hasher: Sha512::new(),
+        sha1: Sha1::new(),
+        expected: identity.filter(|identity| !content_keys(identity).is_empty()).map(str::to_string),
+        downloaded: received,
+        received: 0,
+        total,
+        index_key,
+        key: key.to_string(),
+        version: version.to_string(),
+        reporter,
+        _lock: lock,
+    }))
+}
+
+/// A tarball being read from the registry, teed into a partial download that's moved into the cache at the end
+struct Download<'a> {
+    response: Response,
+    fs: &'a dyn InstallFs,
+    /// None once the download is complete
+    file: Option<Box<dyn Write + 'a>>,
+    temp: PathBuf,
+    state_path: PathBuf,
+    /// What to resume with if it's cut off, None when the server can't resume it
+    state: Option<PartialDownload>,
+    /// What was downloaded before, to be read back and hashed again rather than trusted
+    replay: Cursor<Vec<u8>>,
+    hasher: Sha512,
+    /// For finding the content by the sha1 shasum of registries that don't give an integrity
+    sha1: Sha1,
+    /// The `Dist::identity` the tarball has to match before it's committed, when that names a hash
+    expected: Option<String>,
+    /// The whole of the tarball so far, with what an earlier run downloaded
+    downloaded: u64,
+    /// Only what came from the registry this run
+    received: u64,
+    total: Option<u64>,
+    index_key: String,
+    key: String,
+    version: String,
+    reporter: &'a dyn InstallReporter,
+    _lock: EntryLock<'a>,
+}
+
+impl Download<'_> {
+    fn finish(&mut self) -> io::Result<()> {
+        if self.file.take().is_none() {
+            return Ok(());
+        }
+        self.reporter.on_download_end(&self.key, &self.version, self.received);
+
+        match self.total {
+            Some(total) if total != self.downloaded => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("got {} of {} bytes", self.downloaded, total),
+                ))
+            }
+            Some(_) => {}
+            None => {
+                self.reporter
+                    .on_download_progress(&self.key, &self.version, self.downloaded, Some(self.downloaded))
+            }
+        }
+
+        let integrity = format!("sha512-{}", base64::encode(self.hasher.clone().finalize()));
+        let shasum = format!("sha1:{}", hex(&self.sha1.clone().finalize()));
+        let mismatched = |expected: &&str| !matches_identity(expected, &integrity, Some(&shasum));
+        if let Some(expected) = self.expected.as_deref().filter(mismatched) {
+            let _ = self.fs.remove(&self.temp);
+            let _ = self.fs.remove(&self.state_path);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the tarball is {} rather than {}", integrity, expected),
+            ));
+        }
+        let committed = content_path(self.fs, &integrity).and_then(|path| {
+            let path = path.expect("sha512 integrity has a content path");
+            if let Some(parent) = path.parent() {
+                self.fs.create_dir_all(parent).map_err(|err| NaryError::io(parent, err))?;
+            }
+            self.fs.rename(&self.temp, &path).map_err(|err| NaryError::io(&path, err))?;
+            write_index_with(self.fs, &self.index_key, &integrity, self.downloaded)?;
+            for content_key in content_keys(&integrity).into_iter().chain(content_keys(&shasum)) {
+                write_index_with(self.fs, &content_key, &integrity, self.downloaded)?;
+            }
+            Ok(())
+        });
+        let _ = self.fs.remove(&self.state_path);
+        debug!(name = %self.key, version = %self.version, bytes = self.downloaded, integrity = %integrity, "cached");
+        committed.map_err(|err| io::Error::other(err.to_string()))
+    }
+}
+
+impl Read for Download<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let replayed = self.replay.read(buffer)?;
+        if replayed > 0 {
+            self.hasher.update(&buffer[..replayed]);
+            self.sha1.update(&buffer[..replayed]);
+            return Ok(replayed);
+        }
+
+        let read = self.response.read(buffer)?;
+        if read == 0 {
+            self.finish()?;
+            return Ok(0);
+        }
+
+        if let Some(file) = &mut self.file {
+            file.write_all(&buffer[..read])?;
+        }
+        self.hasher.update(&buffer[..read]);
+        self.sha1.update(&buffer[..read]);
+        self.downloaded += read as u64;
+        self.received += read as u64;
+        self.reporter
+            .on_download_progress(&self.key, &self.version, self.downloaded, self.total);
+
+        Ok(read)
+    }
+}
+
+impl Drop for Download<'_> {
+    fn drop(&mut self) {
+        // Whatever wasn't read to the end never becomes an entry, but is kept to resume when it can be
+        let unfinished = self.file.take().is_some();
+        if unfinished {
+            self.reporter.on_download_end(&self.key, &self.version, self.received);
+        }
+        let kept = match &self.state {
+            Some(state) if unfinished && self.downloaded > 0 => serde_json::to_vec(state)
+                .ok()
+                .is_some_and(|state| write_atomic(self.fs, &self.state_path, &state).is_ok()),
+            _ => false,
+        };
+        if !kept {
+            let _ = self.fs.remove(&self.temp);
+            let _ = self.fs.remove(&self.state_path);
+        }
+    }
+}
+
+/// What's needed to resume a download that was cut off, whose bytes so far are in the file next to it
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct PartialDownload {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    total: Option<u64>,
+}
+
+impl PartialDownload {
+    /// The If-Range header that has the server send the whole tarball again if it changed since
+    fn if_range(&self) -> Option<IfRange> {
+        match (&self.etag, &self.last_modified) {
+            (Some(etag), _) => etag.parse::<EntityTag>().ok().filter(|etag| !etag.weak).map(IfRange::EntityTag),
+            (None, Some(date)) => date.parse::<HttpDate>().ok().map(IfRange::Date),
+            (None, None) => None,
+        }
+    }
+}
+
+/// The partial download of an index key and its state file
+fn partial_paths(fs: &dyn InstallFs, index_key: &str) -> Result<(PathBuf, PathBuf)> {
+    let mut path = get_cache_dir_with(fs)?;
+    path.push(PARTIAL_DIR);
+    fs.create_dir_all(&path).map_err(|err| NaryError::io(&path, err))?;
+    let digest = hex(&Sha256::digest(index_key.as_bytes()));
+    Ok((path.join(&digest), path.join(format!("{}.json", digest))))
+}
+
+/// The state of the partial download of `url` and what of it there is, when there's one to resume
+fn read_partial(
+    fs: &dyn InstallFs,
+    partial_path: &Path,
+    state_path: &Path,
+    url: &Url,
+) -> Option<(PartialDownload, Vec<u8>)> {
+    let state: PartialDownload = serde_json::from_slice(&fs.read(state_path).ok()?).ok()?;
+    let received = fs.read(partial_path).ok()?;
+    let fits = state.total.is_none_or(|total| (received.len() as u64) < total);
+    let current = state.url == url.as_str() && !received.is_empty() && fits;
+    Some((state, received)).filter(|_| current)
+}
+
+/// GET the tarball, or only what's missing from its partial download. Returns the response with how many bytes
+/// of the partial download it continues, 0 when it's the whole tarball.
+fn start_download(
+    url: &Url,
+    config: &RegistryConfig,
+    partial: Option<(&PartialDownload, u64)>,
+) -> Result<(Response, u64)> {
+    if let Some((if_range, received)) = partial.and_then(|(state, received)| Some((state.if_range()?, received))) {
+        let resumed = config.fetch(url.as_str(), |request| {
+            request.header(Range::Bytes(vec![ByteRangeSpec::AllFrom(received)])).header(if_range.clone())
+        });
+        match resumed {
+            // The tarball changed or the server ignores ranges, and this is all of it
+            Ok(response) if response.status != StatusCode::PartialContent => return Ok((response, 0)),
+            Ok(response) if range_start(&response.headers) == Some(received) => return Ok((response, received)),
+            // Shorter than what's there already, or some other range than was asked for
+            Ok(_) | Err(NaryError::RegistryError { status: 416, .. }) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((config.fetch(url.as_str(), |request| request)?, 0))
+}
+
+fn range_start(headers: &Headers) -> Option<u64> {
+    match headers.get::<ContentRange>()? {
+        ContentRange(ContentRangeSpec::Bytes { range: Some((start, _)), .. }) => Some(*start),
+        _ => None,
+    }
+}
+
+/// How to resume a download of this response if it's cut off, when the server takes ranges and sent a validator
+/// to check the tarball is the same with. A resumed download keeps the validators it started with.
+fn resumable(
+    headers: &Headers,
+    status: StatusCode,
+    partial: Option<PartialDownload>,
+) -> Option<PartialDownload> {
+    if status == StatusCode::PartialContent {
+        return partial;
+    }
+    let ranges = headers.get::<AcceptRanges>().is_some_and(|ranges| ranges.0.contains(&RangeUnit::Bytes));
+    let state = PartialDownload {
+        etag: headers.get::<ETag>().map(|etag| etag.0.to_string()),
+        last_modified: headers.get::<LastModified>().map(|date| date.0.to_string()),
+        ..PartialDownload::default()
+    };
+    state.if_range().filter(|_| ranges).map(|_| state)
+}
+
+/// The index key of a tarball. Different URLs get their own entries, but identical content is stored once.
+pub fn tarball_key(name: &str, version: &str, tarball_url: &Url) -> String {
+    format!("{}@{} {}", name, version, tarball_url)
+}
+
+/// The index keys that find a tarball's content by its hashes, whatever name, version or URL it was cached for. From
+/// a `Dist::identity`: an integrity, which can list more than one hash, or a `sha1:` shasum. Other algorithms and
+/// URLs have none.
+pub fn content_keys(identity: &str) -> Vec<String> {
+    identity
+        .split_whitespace()
+        .filter_map(|hash| {
+            if hash.starts_with("sha512-") {
+                Some(hash.to_string())
+            } else if let Some(digest) = hash.strip_prefix("sha1-") {
+                base64::decode(digest).ok().map(|digest| format!("sha1:{}", hex(&digest)))
+            } else {
+                hash.strip_prefix("sha1:").map(|shasum| format!("sha1:{}", shasum.to_ascii_lowercase()))
+            }
+        })
+        .map(|hash| format!("content {}", hash))
+        .collect()
+}
+
+/// Whether content with this `sha512-` integrity, and this `sha1:` shasum when it's known, is what a
+/// `Dist::identity` names. Hashes of other algorithms can't be checked and are passed over, so an identity with none,
+/// like a tarball URL, matches anything.
+pub(crate) fn matches_identity(identity: &str, integrity: &str, shasum: Option<&str>) -> bool {
+    let mut actual = content_keys(integrity);
+    actual.extend(shasum.map(content_keys).unwrap_or_default());
+    content_keys(identity)
+        .iter()
+        .filter(|key| shasum.is_some() || !key.starts_with("content sha1:"))
+        .all(|key| actual.contains(key))
+}
+
+/// Subresource Integrity string, `sha512-<base64>`, of some content
+pub fn integrity_of(content: &[u8]) -> String {
+    format!("sha512-{}", base64::encode(Sha512::digest(content)))
+}
+
+/// Where content with the given `sha512-` integrity is stored on `fs`; None for other algorithms
+fn content_path(fs: &dyn InstallFs, integrity: &str) -> Result<Option<PathBuf>> {
+    let digest = match integrity
+        .strip_prefix("sha512-")
+        .and_then(|digest| base64::decode(digest).ok())
+    {
+        Some(digest) => hex(&digest),
+        None => return Ok(None),
+    };
+
+    let mut path = get_cache_dir_with(fs)?;
+    path.push(CONTENT_DIR);
+    path.push("sha512");
+    path.push(&digest[0..2]);
+    path.push(&digest[2..4]);
+    path.push(&digest[4..]);
+    Ok(Some(path))
+}
+
+/// Cached content, checked against its integrity. Missing and corrupt content are both None.
+pub fn read_content(integrity: &str) -> Result<Option<Vec<u8>>> {
+    let path = match content_path(&RealFs, integrity)? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    match fs::read(&path) {
+        Ok(content) if integrity_of(&content) == integrity => Ok(Some(content)),
+        Ok(_) => Ok(None),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(NaryError::io(path, err)),
+    }
+}
+
+/// Cached content on `fs`, checked against its integrity without holding it all in memory, then opened again to
+/// be read. Missing and corrupt content are both None.
+fn open_content<'a>(fs: &'a dyn InstallFs, integrity: &str) -> Result<Option<Box<dyn Read + 'a>>> {
+    let path = match content_path(fs, integrity)? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let mut file = match fs.open(&path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(NaryError::io(path, err)),
+    };
+    let mut hasher = Sha512::new();
+    io::copy(&mut file, &mut hasher).map_err(|err| NaryError::io(&path, err))?;
+    if format!("sha512-{}", base64::encode(hasher.finalize())) != integrity {
+        return Ok(None);
+    }
+
+    fs.open(&path).map(Some).map_err(|err| NaryError::io(path, err))
+}
+
+/// Store content under its hash, returning its integrity
+pub fn write_content(content: &[u8]) -> Result<String> {
+    let integrity = integrity_of(content);
+    let path = content_path(&RealFs, &integrity)?.expect("sha512 integrity has a content path");
+
+    write_atomic(&RealFs, &path, content)?;
+
+    Ok(integrity)
+}
+
+/// What the index knows about one key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub key: String,
+    pub integrity: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+}
+
+fn index_path(fs: &dyn InstallFs, key: &str) -> Result<PathBuf> {
+    let digest = hex(&Sha256::digest(key.as_bytes()));
+
+    let mut path = get_cache_dir_with(fs)?;
+    path.push(INDEX_DIR);
+    path.push(&digest[0..2]);
+    path.push(&digest[2..]);
+    Ok(path)
+}
+
+pub fn read_index(key: &str) -> Result<Option<IndexEntry>> {
+    read_index_with(&RealFs, key)
+}
+
+/// Like `read_index`, on `fs`
+pub(crate) fn read_index_with(fs: &dyn InstallFs, key: &str) -> Result<Option<IndexEntry>> {
+    let path = index_path(fs, key)?;
+
+    match fs.read(&path) {
+        Ok(entry) => Ok(serde_json::from_slice::<IndexEntry>(&entry)
+            .ok()
+            .filter(|entry| entry.key == key)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(NaryError::io(path, err)),
+    }
+}
+
+pub fn write_index(key: &str, integrity: &str, size: u64) -> Result<()> {
+    write_index_with(&RealFs, key, integrity, size)
+}
+
+/// Like `write_index`, on `fs`
+fn write_index_with(fs: &dyn InstallFs, key: &str, integrity: &str, size: u64) -> Result<()> {
+    let path = index_path(fs, key)?;
+    let entry = IndexEntry {
+        key: key.to_string(),
+        integrity: integrity.to_string(),
+        size,
+        time: now(),
+    };
+
+    let entry = serde_json::to_string(&entry).map_err(|err| NaryError::json(path.display(), err))?;
+    write_atomic(fs, &path, entry.as_bytes())
+}
+
+/// What `verify` found
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyStats {
+    /// Content files whose hash matched
+    pub verified: usize,
+    /// Content files that didn't match their hash, and were removed
+    pub corrupted: usize,
+    /// Index entries pointing at content that is gone, and were removed
+    pub dangling_entries: usize,
+}
+
+/// Rehash all cached content, removing whatever is corrupt along with index entries that lost their content
+pub fn verify() -> Result<VerifyStats> {
+    verify_with(&RealFs)
+}
+
+/// Like `verify`, on `fs`
+pub fn verify_with(fs: &dyn InstallFs) -> Result<VerifyStats> {
+    let mut stats = VerifyStats::default();
+
+    for path in files_below(fs, &get_cache_dir_with(fs)?.join(CONTENT_DIR))? {
+        let content = fs.read(&path).map_err(|err| NaryError::io(&path, err))?;
+        if content_path(fs, &integrity_of(&content))?.as_ref() == Some(&path) {
+            stats.verified += 1;
+        } else {
+            fs.remove(&path).map_err(|err| NaryError::io(&path, err))?;
+            stats.corrupted += 1;
+        }
+    }
+
+    for (path, entry) in index_entries(fs)? {
+        let exists = match entry.as_ref().map(|entry| content_path(fs, &entry.integrity)) {
+            Some(content) => content?.is_some_and(|content| fs.is_file(&content)),
+            None => false,
+        };
+        if !exists {
+            fs.remove(&path).map_err(|err| NaryError::io(&path, err))?;
+            stats.dangling_entries += 1;
+        }
+    }
+
+    Ok(stats)
+}
+
+/// What `gc` removed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub removed: usize,
+    pub freed_bytes: u64,
+}
+
+/// Remove content no index entry points at, and downloads that were cut off
+pub fn gc() -> Result<GcStats> {
+    gc_with(&RealFs)
+}
+
+/// Like `gc`, on `fs`
+pub fn gc_with(fs: &dyn InstallFs) -> Result<GcStats> {
+    let mut referenced = HashSet::new();
+    for (_, entry) in index_entries(fs)? {
+        if let Some(path) = entry.map(|entry| content_path(fs, &entry.integrity)).transpose()?.flatten() {
+            referenced.insert(path);
+        }
+    }
+
+    let mut stats = GcStats::default();
+    let cache_dir = get_cache_dir_with(fs)?;
+    let partial = files_below(fs, &cache_dir.join(PARTIAL_DIR))?;
+    for path in files_below(fs, &cache_dir.join(CONTENT_DIR))?.into_iter().chain(partial) {
+        if !referenced.contains(&path) {
+            let size = fs.size(&path).unwrap_or_default();
+            fs.remove(&path).map_err(|err| NaryError::io(&path, err))?;
+            stats.removed += 1;
+            stats.freed_bytes += size;
+        }
+    }
+
+    Ok(stats)
+}
+
+/// How much the cache holds
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub index_entries: usize,
+    pub content_files: usize,
+    /// Size of the stored tarballs
+    pub content_bytes: u64,
+    /// Size of everything in the cache directory, including packuments and git repositories
+    pub total_bytes: u64,
+}
+
+pub fn stats() -> Result<CacheStats> {
+    stats_with(&RealFs)
+}
+
+/// Like `stats`, on `fs`
+pub fn stats_with(fs: &dyn InstallFs) -> Result<CacheStats> {
+    let cache_dir = get_cache_dir_with(fs)?;
+    let size = |path: &PathBuf| fs.size(path).unwrap_or_default();
+
+    let content = files_below(fs, &cache_dir.join(CONTENT_DIR))?;
+    Ok(CacheStats {
+        index_entries: files_below(fs, &cache_dir.join(INDEX_DIR))?.len(),
+        content_files: content.len(),
+        content_bytes: content.iter().map(size).sum(),
+        total_bytes: files_below(fs, &cache_dir)?.iter().map(size).sum(),
+    })
+}
+
+/// What `prune` keeps
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PruneLimit {
+    /// Entries cached longer ago than this are dropped
+    MaxAge(Duration),
+    /// The oldest entries are dropped until the tarballs they point at fit in this many bytes
+    MaxSize(u64),
+}
+
+/// What `prune` removed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PruneStats {
+    pub removed_entries: usize,
+    /// Content that was only referenced by removed entries
+    pub removed_content: usize,
+    pub freed_bytes: u64,
+}
+
+/// Drop index entries beyond the limit, then the content nothing points at anymore
+pub fn prune(limit: PruneLimit) -> Result<PruneStats> {
+    prune_with(&RealFs, limit)
+}
+
+/// Like `prune`, on `fs`
+pub fn prune_with(fs: &dyn InstallFs, limit: PruneLimit) -> Result<PruneStats> {
+    let mut entries: Vec<(PathBuf, IndexEntry)> = Vec::new();
+    let mut stats = PruneStats::default();
+    for (path, entry) in index_entries(fs)? {
+        match entry {
+            Some(entry) => entries.push((path, entry)),
+            None => {
+                fs.remove(&path).map_err(|err| NaryError::io(&path, err))?;
+                stats.removed_entries += 1;
+            }
+        }
+    }
+    entries.sort_by_key(|(_, entry)| entry.time);
+
+    let doomed = match limit {
+        PruneLimit::MaxAge(max_age) => {
+            let now = now();
+            entries
+                .iter()
+                .take_while(|(_, entry)| now.saturating_sub(entry.time) > max_age.as_secs())
+                .count()
+        }
+        PruneLimit::MaxSize(max_size) => {
+            // Content shared by several entries only counts once, and only goes away with the last of them
+            let mut references: HashMap<&str, (usize, u64)> = HashMap::new();
+            for (_, entry) in &entries {
+                references.entry(&entry.integrity).or_insert((0, entry.size)).0 += 1;
+            }
+            let mut size: u64 = references.values().map(|(_, size)| size).sum();
+
+            let mut doomed = 0;
+            for (_, entry) in &entries {
+                if size <= max_size {
+                    break;
+                }
+                let (count, content_size) = references.get_mut(entry.integrity.as_str()).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    size -= *content_size;
+                }
+                doomed += 1;
+            }
+            doomed
+        }
+    };
+
+    for (path, _) in &entries[..doomed] {
+        fs.remove(path).map_err(|err| NaryError::io(path, err))?;
+        stats.removed_entries += 1;
+    }
+
+    let collected = gc_with(fs)?;
+    stats.removed_content = collected.removed;
+    stats.freed_bytes = collected.freed_bytes;
+    Ok(stats)
+}
+
+/// Remove everything in the cache: tarballs, packuments and git repositories
+pub fn clear() -> Result<()> {
+    clear_with(&RealFs)
+}
+
+/// Like `clear`, on `fs`
+pub fn clear_with(fs: &dyn InstallFs) -> Result<()> {
+    let cache_dir = get_cache_dir_with(fs)?;
+
+    for path in fs.read_dir(&cache_dir).map_err(|err| NaryError::io(&cache_dir, err))? {
+        fs.remove(&path).map_err(|err| NaryError::io(&path, err))?;
+    }
+
+    Ok(())
+}
+
+/// Drop the entry cached under an index key along with its content, which other keys may point at too, so the
+/// tarball is downloaded again. Returns the entry, None when nothing was cached under the key.
+pub fn evict(key: &str) -> Result<Option<IndexEntry>> {
+    evict_with(&RealFs, key)
+}
+
+/// Like `evict`, on `fs`
+pub(crate) fn evict_with(fs: &dyn InstallFs, key: &str) -> Result<Option<IndexEntry>> {
+    let _lock = lock_with(fs, key)?;
+    let entry = match read_index_with(fs, key)? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let index = index_path(fs, key)?;
+    fs.remove(&index).map_err(|err| NaryError::io(&index, err))?;
+    if let Some(content) = content_path(fs, &entry.integrity)? {
+        fs.remove(&content).map_err(|err| NaryError::io(&content, err))?;
+    }
+    debug!(key, integrity = %entry.integrity, "evicted");
+    Ok(Some(entry))
+}
+
+/// Why a cached tarball was quarantined, kept next to it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quarantined {
+    pub key: String,
+    pub integrity: String,
+    pub reason: String,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+}
+
+/// Like `evict`, but keeps the content in the quarantine directory with a `Quarantined` of `reason`, to look into
+/// what keeps failing. Returns where that is, the content being the same path without `.json`. None when nothing
+/// was cached under the key.
+pub fn quarantine(key: &str, reason: &str) -> Result<Option<PathBuf>> {
+    quarantine_with(&RealFs, key, reason)
+}
+
+/// Like `quarantine`, on `fs`
+pub(crate) fn quarantine_with(fs: &dyn InstallFs, key: &str, reason: &str) -> Result<Option<PathBuf>> {
+    let _lock = lock_with(fs, key)?;
+    let entry = match read_index_with(fs, key)? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let mut path = get_cache_dir_with(fs)?;
+    path.push(QUARANTINE_DIR);
+    path.push(hex(&Sha256::digest(format!("{} {}", key, entry.integrity).as_bytes())));
+
+    if let Some(content) = content_path(fs, &entry.integrity)?.filter(|content| fs.is_file(content)) {
+        if let Some(parent) = path.parent() {
+            fs.create_dir_all(parent).map_err(|err| NaryError::io(parent, err))?;
+        }
+        fs.rename(&content, &path).map_err(|err| NaryError::io(&path, err))?;
+    }
+    let diagnostics = Quarantined {
+        key: key.to_string(),
+        integrity: entry.integrity,
+        reason: reason.to_string(),
+        time: now(),
+    };
+    let json = path.with_extension("json");
+    let body = serde_json::to_vec_pretty(&diagnostics).map_err(|err| NaryError::json(json.display(), err))?;
+    write_atomic(fs, &json, &body)?;
+    let index = index_path(fs, key)?;
+    fs.remove(&index).map_err(|err| NaryError::io(&index, err))?;
+    debug!(key, path = %json.display(), "quarantined");
+    Ok(Some(json))
+}
+
+/// Every index file, with its entry when it parses
+fn index_entries(fs: &dyn InstallFs) -> Result<Vec<(PathBuf, Option<IndexEntry>)>> {
+    files_below(fs, &get_cache_dir_with(fs)?.join(INDEX_DIR))?
+        .into_iter()
+        .map(|path| {
+            let entry = fs.read(&path).map_err(|err| NaryError::io(&path, err))?;
+            Ok((path, serde_json::from_slice(&entry).ok()))
+        })
+        .collect()
+}
+
+fn files_below(fs: &dyn InstallFs, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = match fs.read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(NaryError::io(&dir, err)),
+        };
+        for path in entries {
+            if fs.is_dir(&path) {
+                pending.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// An exclusive advisory lock on a cache entry, released when dropped
+pub struct EntryLock<'a> {
+    _guard: Box<dyn Send + 'a>,
+    path: PathBuf,
+}
+
+impl fmt::Debug for EntryLock<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EntryLock").field("path", &self.path).finish_non_exhaustive()
+    }
+}
+
+/// Blocks until no other thread or process holds the lock on `key`
+pub fn lock(key: &str) -> Result<EntryLock<'static>> {
+    lock_with(&RealFs, key)
+}
+
+/// Like `lock`, on `fs`
+pub(crate) fn lock_with<'a>(fs: &'a dyn InstallFs, key: &str) -> Result<EntryLock<'a>> {
+    let mut path = get_cache_dir_with(fs)?;
+    path.push(LOCK_DIR);
+    fs.create_dir_all(&path).map_err(|err| NaryError::io(&path, err))?;
+    path.push(hex(&Sha256::digest(key.as_bytes())));
+    let guard = fs.lock(&path).map_err(|err| NaryError::io(&path, err))?;
+    Ok(EntryLock { _guard: guard, path })
+}
+
+/// Write to a temporary file and rename it into place, so readers never see a partial file
+fn write_atomic(fs: &dyn InstallFs, path: &Path, content: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs.create_dir_all(parent).map_err(|err| NaryError::io(parent, err))?;
+    }
+
+    let temp = temp_path(fs)?;
+    fs.write_file(&temp, &mut &content[..], false).map_err(|err| NaryError::io(&temp, err))?;
+    fs.rename(&temp, path).map_err(|err| {
+        let _ = fs.remove(&temp);
+        NaryError::io(path, err)
+    })
+}
+
+/// A path on `fs` nothing else is using, in the cache so it can be renamed into place
+pub(crate) fn temp_path(fs: &dyn InstallFs) -> Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut temp = get_cache_dir_with(fs)?;
+    temp.push(TEMP_DIR);
+    fs.create_dir_all(&temp).map_err(|err| NaryError::io(&temp, err))?;
+    temp.push(format!(
+        "{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, AtomicOrdering::Relaxed)
+    ));
+    Ok(temp)
+}
+
+/// Seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Lowercase hex of some bytes, like a digest
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// HTTP validators the registry sent along with a cached packument
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheValidators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Which registry sent it
+    #[serde(default)]
+    pub registry: Option<String>,
+    /// When it was fetched or last found unchanged, in seconds since the Unix epoch
+    #[serde(default)]
+    pub fetched: Option<u64>,
+}
+
+impl CacheValidators {
+    pub fn from_headers(headers: &Headers) -> CacheValidators {
+        CacheValidators {
+            etag: headers.get::<ETag>().map(|etag| etag.0.to_string()),
+            last_modified: headers.get::<LastModified>().map(|date| date.0.to_string()),
+            registry: None,
+            fetched: Some(now()),
+        }
+    }
+
+    /// The same validators, for a packument the registry just said is unchanged
+    pub fn revalidated(&self) -> CacheValidators {
+        CacheValidators {
+            fetched: Some(now()),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the packument was fetched less than `PACKUMENT_MAX_AGE` ago
+    pub fn is_fresh(&self) -> bool {
+        self.fetched_within(PACKUMENT_MAX_AGE)
+    }
+
+    /// Whether the packument was fetched less than `max_age` ago
+    pub fn fetched_within(&self, max_age: Duration) -> bool {
+        self.fetched.is_some_and(|fetched| now().saturating_sub(fetched) < max_age.as_secs())
+    }
+}
+
+fn packument_path(name: &PackageName, file_name: &str) -> Result<PathBuf> {
+    let mut path = get_cache_dir()?;
+    path.push(PACKUMENTS_DIR);
+    path.push(entry_name(&name.to_string()));
+    fs::create_dir_all(&path).map_err(|err| NaryError::io(&path, err))?;
+    path.push(file_name);
+
+    Ok(path)
+}
+
+/// The packument body last fetched for the given package, if any
+pub fn read_cached_packument(name: &PackageName) -> Result<Option<String>> {
+    let path = packument_path(name, PACKUMENT_FILE)?;
+
+    match fs::read_to_string(&path) {
+        Ok(body) => Ok(Some(body)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(NaryError::io(path, err)),
+    }
+}
+
+/// Validators of the cached packument; empty when there are none or they're unreadable
+pub fn read_packument_validators(name: &PackageName) -> Result<CacheValidators> {
+    let path = packument_path(name, VALIDATORS_FILE)?;
+
+    Ok(fs::read_to_string(&path)
+        .ok()
+        .and_then(|validators| serde_json::from_str(&validators).ok())
+        .unwrap_or_default())
+}
+
+pub fn write_cached_packument(name: &PackageName, body: &str, validators: &CacheValidators) -> Result<()> {
+    let path = packument_path(name, PACKUMENT_FILE)?;
+    write_atomic(&RealFs, &path, body.as_bytes())?;
+    write_packument_validators(name, validators)
+}
+
+pub fn write_packument_validators(name: &PackageName, validators: &CacheValidators) -> Result<()> {
+    let path = packument_path(name, VALIDATORS_FILE)?;
+    let validators = serde_json::to_string(validators).map_err(|err| NaryError::json(path.display(), err))?;
+    write_atomic(&RealFs, &path, validators.as_bytes())
+}
+
+/// Where the full metadata of a version is kept, beside the abbreviated packument that leaves most of it out
+fn version_path(name: &PackageName, version: &str) -> Result<PathBuf> {
+    packument_path(name, &format!("version-{}.json", entry_name(version)))
+}
+
+/// The full metadata of a version last fetched, if any
+pub fn read_cached_version(name: &PackageName, version: &str) -> Result<Option<String>> {
+    let path = version_path(name, version)?;
+
+    match fs::read_to_string(&path) {
+        Ok(body) => Ok(Some(body)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(NaryError::io(path, err)),
+    }
+}
+
+pub fn write_cached_version(name: &PackageName, version: &str, body: &str) -> Result<()> {
+    write_atomic(&RealFs, &version_path(name, version)?, body.as_bytes())
+}
+
+fn signing_keys_path(registry: &str) -> Result<PathBuf> {
     let mut path = get_cache_dir()?;
-    path.push(&utf8_percent_encode(key, PATH_SEGMENT_ENCODE_SET).to_string());
-    let _ = fs::create_dir(&path);
-    path.push(&version);
-    let _ = fs::create_dir(&path);
-    path.push("package.tgz");
-
-    let cache_file = File::open(&path);
-
-    match cache_file {
-        Ok(mut cache_file) => {
-            cache_file
-                .read_to_end(&mut tarball_res)
-                .context("Couldn't cache file")?;
-            println!("Read {} from cache", path.to_string_lossy());
-            Ok(tarball_res)
-        }
-        Err(_) => {
-            let ssl = NativeTlsClient::new().context("Unable to create a NativeTlsClient")?;
-            let connector = HttpsConnector::new(ssl);
-            let client = Client::with_connector(connector);
-
-            client
-                .get(tarball_url.clone())
-                // .header(AcceptEncoding(vec![qitem(Encoding::Gzip)]))
-                .send()
-                .with_context(|| format!("Couldn't get tarball: {:?}", &tarball_url))?
-                .read_to_end(&mut tarball_res)
-                .with_context(|| format!("Couldn't read to string tarball: {:?}", &tarball_url))?;
-
-            // client.get(&*url).send().context(format!("Couldn't GET URL: {}", url))?.read_to_string(&mut body)
-            // .context(format!("Couldn't ready body of: {}", url))?;
-
-            let mut cache_file = File::create(&path).context("Couldn't cache file")?;
-            println!("Caching {}", path.to_string_lossy());
-            cache_file
-                .write(tarball_res.as_slice())
-                .context("Couldn't write to cache file")?;
-            Ok(tarball_res)
+    path.push(KEYS_DIR);
+    path.push(format!("{}.json", hex(&Sha256::digest(registry.as_bytes()))));
+    Ok(path)
+}
+
+/// The `/-/npm/v1/keys` body last fetched from the registry, if any
+pub fn read_signing_keys(registry: &str) -> Result<Option<String>> {
+    let path = signing_keys_path(registry)?;
+
+    match fs::read_to_string(&path) {
+        Ok(body) => Ok(Some(body)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(NaryError::io(path, err)),
+    }
+}
+
+pub fn write_signing_keys(registry: &str, body: &str) -> Result<()> {
+    write_atomic(&RealFs, &signing_keys_path(registry)?, body.as_bytes())
+}
+
+/// Versions that ranges resolved to before, by `ResolutionMemo::key`. Each is kept with the `modified` time of the
+/// packument it was picked from, and only holds while the packument is unchanged.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResolutionMemo {
+    pub resolutions: BTreeMap<String, MemoizedResolution>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoizedResolution {
+    pub version: String,
+    pub modified: String,
+}
+
+impl ResolutionMemo {
+    /// `<registry> <name>@<range>`, with ` prerelease` after it when prereleases were let in
+    pub fn key(registry: &str, name: &str, range: &str, include_prerelease: bool) -> String {
+        let key = format!("{} {}@{}", registry, name, range);
+        if include_prerelease {
+            key + " prerelease"
+        } else {
+            key
         }
     }
+
+    /// The version remembered for `key`, when the packument is still the one it was picked from
+    pub fn get(&self, key: &str, modified: &str) -> Option<&str> {
+        let resolution = self.resolutions.get(key)?;
+        Some(resolution.version.as_str()).filter(|_| resolution.modified == modified)
+    }
+
+    pub fn insert(&mut self, key: String, version: String, modified: String) {
+        self.resolutions.insert(key, MemoizedResolution { version, modified });
+    }
+}
+
+/// The memoized resolutions; empty when there are none or they're unreadable
+pub fn read_resolution_memo() -> Result<ResolutionMemo> {
+    let path = get_cache_dir()?.join(RESOLUTIONS_FILE);
+    Ok(fs::read_to_string(&path)
+        .ok()
+        .and_then(|memo| serde_json::from_str(&memo).ok())
+        .unwrap_or_default())
+}
+
+pub fn write_resolution_memo(memo: &ResolutionMemo) -> Result<()> {
+    let path = get_cache_dir()?.join(RESOLUTIONS_FILE);
+    let memo = serde_json::to_string(memo).map_err(|err| NaryError::json(path.display(), err))?;
+    write_atomic(&RealFs, &path, memo.as_bytes())
 }
@@ -1,157 +1,723 @@
-use anyhow::{anyhow, Result};
-
-use petgraph;
-use petgraph::graphmap::DiGraphMap;
-
-use bidir_map::BidirMap;
-
-use indexmap::IndexMap;
+use semver_rs::{Range, Version};
 use serde_json::Value;
-use std::{fs::File, io, path::Path};
-
-use crate::{fetch_package_root_metadata, fetch_matching_version_metadata, fetch_package_version_metadata};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    path::Path,
+    rc::Rc,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    thread,
+    time::Instant,
+};
+use tracing::{debug, debug_span, info_span};
+
+use crate::{
+    cache::{self, ResolutionMemo},
+    fetch_matching_version_metadata, git,
+    lockfile::{import_shrinkwrap, LockedDependency, Lockfile},
+    manifest::{bundled_names, map_dependencies, runtime_dependencies_of},
+    overrides::overrides_for,
+    pack::{read_manifest, read_shrinkwrap},
+    parse_url,
+    peers::declared_peers,
+    semver_order, InstallReporter, Manifest, NaryError, NodeId, PackageName, Packument, PackumentVersion,
+    RegistryClient, ResolutionOptions, ResolutionStrategy, ResolvedGraph, ResolvedNode, Result, Specifier,
+};
+
+mod strict;
+pub use self::strict::{ConflictingRequirement, ResolutionConflict};
+
+/// Which field of package.json a dependency comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DependencyKind {
+    /// `dependencies`
+    Normal,
+    /// `optionalDependencies`, which may be left out
+    Optional,
+}
 
+/// A package depended on, with what its version asks for parsed once, up front
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Dependency {
     pub name: String,
+    /// As package.json spells it
     pub version: String,
+    specifier: Specifier,
 }
 
-type DependencyId = i32;
+impl Dependency {
+    pub fn new(name: impl Into<String>, version: impl Into<String>) -> Dependency {
+        let version = version.into();
+        Dependency {
+            name: name.into(),
+            specifier: Specifier::parse(&version),
+            version,
+        }
+    }
+
+    pub fn specifier(&self) -> &Specifier {
+        &self.specifier
+    }
+
+    /// The package to look up in the registry with the version to look for: an alias's target, otherwise itself
+    pub fn registry_target(&self) -> Dependency {
+        match &self.specifier {
+            Specifier::Alias(target) => Dependency::clone(target),
+            _ => self.clone(),
+        }
+    }
+
+    pub fn package_name(&self) -> Result<PackageName> {
+        PackageName::parse(&self.name)
+    }
+}
 
+/// Resolves `deps` and everything they depend on to exact versions, leaving out optional ones that fail. With the
+/// strict strategy the versions are worked out together first, backtracking until each package has one that every
+/// requirement on it allows.
 pub fn calculate_depends(
     root_pkg: &Dependency,
-    deps: &Vec<Dependency>,
-) -> Result<IndexMap<Dependency, ()>> {
-    let mut graph: DiGraphMap<DependencyId, i32> = DiGraphMap::new();
+    deps: &[(Dependency, DependencyKind)],
+    registry: &dyn RegistryClient,
+    options: &ResolutionOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<ResolvedGraph> {
+    let graph = ResolvedGraph::new(ResolvedNode {
+        name: root_pkg.name.clone(),
+        version: root_pkg.version.clone(),
+        alias_of: None,
+    });
+    let _span = info_span!("resolve", root = %root_pkg.name, version = %root_pkg.version).entered();
+
+    let deps = deps.to_vec();
+
+    reporter.on_resolve_start(root_pkg, deps.len());
+    let started = Instant::now();
+    let (requests, queue) = mpsc::channel::<String>();
+    let (fetched, results) = mpsc::channel();
+    let queue = Mutex::new(queue);
+    let mut graph = thread::scope(|scope| {
+        for _ in 0..options.parallelism() {
+            let fetched = fetched.clone();
+            let queue = &queue;
+            scope.spawn(move || {
+                loop {
+                    // The lock is only held while waiting for a name, not while fetching
+                    let name = match queue.lock().unwrap().recv() {
+                        Ok(name) => name,
+                        Err(_) => break,
+                    };
+                    let packument = PackageName::parse(&name).and_then(|parsed| registry.packument(&parsed));
+                    if fetched.send((name, packument)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
 
-    // String doesn't implement Copy and graphmap requires Copy
-    let mut map: BidirMap<Dependency, DependencyId> = BidirMap::new();
+        let mut resolver = Resolver {
+            registry,
+            options,
+            reporter,
+            packuments: HashMap::new(),
+            prefetch: Prefetch {
+                requests,
+                results,
+                in_flight: HashSet::new(),
+                failed: HashMap::new(),
+            },
+            memo: None,
+            memo_changed: false,
+            resolved: HashMap::new(),
+            pinned: BTreeMap::new(),
+            left_out: HashSet::new(),
+            shrinkwrapped: HashMap::new(),
+            graph,
+        };
+        if options.strategy == ResolutionStrategy::Strict {
+            resolver.solve(&deps)?;
+        }
+        resolver.resolve(ResolvedGraph::ROOT, Vec::new(), deps)?;
+        if options.install_peers {
+            resolver.install_peers()?;
+        }
+        if let (Some(memo), true) = (&resolver.memo, resolver.memo_changed) {
+            cache::write_resolution_memo(memo)?;
+        }
+        // Dropping the rest of the resolver stops the workers once they're done with what they're fetching
+        Ok::<_, NaryError>(resolver.graph)
+    })?;
 
-    map.insert(root_pkg.clone(), 0);
+    graph.finish();
+    reporter.on_phase("resolve", started.elapsed());
+    Ok(graph)
+}
 
-    calculate_depends_rec(root_pkg, deps, &mut map, &mut graph)?;
+/// What a package depends on, with the field of package.json each comes from
+type RuntimeDependencies = Vec<(Dependency, DependencyKind)>;
+
+/// A package whose dependencies are being resolved
+struct Frame {
+    node: NodeId,
+    /// The nodes from the root's dependency down to this one, which overrides may be scoped to
+    ancestors: Vec<NodeId>,
+    pending: std::vec::IntoIter<(Dependency, DependencyKind)>,
+    /// Where to go back to when anything below fails, for the package of an optional dependency
+    optional: Option<Checkpoint>,
+}
 
-    let dependency_ids = petgraph::algo::toposort(&graph, None).or_else(|err| {
-        Err(anyhow!("Cyclic dependency {:?}", map.get_by_second(&err.node_id())))
-    })?;
+/// An optional dependency with the resolution from before it
+struct Checkpoint {
+    dependency: Dependency,
+    graph: ResolvedGraph,
+    resolved: HashMap<Dependency, NodeId>,
+}
 
-    let mut ordered_dependencies: IndexMap<Dependency, ()> = IndexMap::new();
+/// Packuments being fetched by the workers ahead of the resolver needing them
+struct Prefetch {
+    requests: Sender<String>,
+    results: Receiver<(String, Result<Packument>)>,
+    in_flight: HashSet<String>,
+    /// Failures kept for when the resolver gets to the package, which it may never do
+    failed: HashMap<String, NaryError>,
+}
 
-    for i in dependency_ids {
-        let second = map.get_by_second(&i).unwrap();
+struct Resolver<'a> {
+    registry: &'a dyn RegistryClient,
+    options: &'a ResolutionOptions,
+    reporter: &'a dyn InstallReporter,
+    /// Packuments by name, each fetched once
+    packuments: HashMap<String, Rc<Packument>>,
+    prefetch: Prefetch,
+    /// What earlier runs resolved registry packages to, read once the first one comes up
+    memo: Option<ResolutionMemo>,
+    memo_changed: bool,
+    /// Requested name and range pairs that were already resolved
+    resolved: HashMap<Dependency, NodeId>,
+    /// The one version of each registry package the strict strategy settled on, picked over any other in range
+    pinned: BTreeMap<String, Vec<String>>,
+    /// Optional dependencies the strict strategy left out
+    left_out: HashSet<String>,
+    /// What the packages below a dependency with an npm-shrinkwrap.json depend on, as it pins them
+    shrinkwrapped: HashMap<ResolvedNode, RuntimeDependencies>,
+    graph: ResolvedGraph,
+}
 
-        if !ordered_dependencies.contains_key(second) {
-            if let Some((dep, _)) = map.remove_by_second(&i) {
-                ordered_dependencies.insert(dep.clone(), ());
+impl Resolver<'_> {
+    /// Resolves the dependencies of a node, the root's to begin with, depth first, from a stack of the packages on
+    /// the way down rather than by recursion, so that a deep graph can't overflow the stack. The packuments of a
+    /// package's dependencies are fetched in parallel as soon as it's reached, so that one branch is fetched while
+    /// another is resolved. Each package's dependencies are taken by name rather than in the order its package.json
+    /// or the registry lists them, so the graph comes out the same however they're written.
+    fn resolve(&mut self, node: NodeId, ancestors: Vec<NodeId>, mut deps: RuntimeDependencies) -> Result<()> {
+        by_name(&mut deps);
+        self.prefetch(&deps);
+        let mut stack = vec![Frame {
+            node,
+            ancestors,
+            pending: deps.into_iter(),
+            optional: None,
+        }];
+
+        while let Some(frame) = stack.last_mut() {
+            let (dependency, kind) = match frame.pending.next() {
+                Some(next) => next,
+                None => {
+                    stack.pop();
+                    continue;
+                }
+            };
+
+            // A failure anywhere below an optional dependency leaves all of it out, as if it was never asked for
+            let checkpoint = if kind == DependencyKind::Optional {
+                Some(Checkpoint {
+                    dependency: dependency.clone(),
+                    graph: self.graph.clone(),
+                    resolved: self.resolved.clone(),
+                })
+            } else {
+                None
+            };
+            match self.resolve_dependency(frame.node, &frame.ancestors, &dependency, kind) {
+                Ok(Some((node, mut deps))) => {
+                    by_name(&mut deps);
+                    self.prefetch(&deps);
+                    let ancestors = frame.ancestors.iter().cloned().chain(Some(node)).collect();
+                    stack.push(Frame {
+                        node,
+                        ancestors,
+                        pending: deps.into_iter(),
+                        optional: checkpoint,
+                    });
+                }
+                Ok(None) => {}
+                Err(err) => match checkpoint {
+                    Some(checkpoint) => self.restore(checkpoint, &err),
+                    None => self.unwind(&mut stack, err)?,
+                },
             }
         }
+
+        Ok(())
     }
 
-    Ok(ordered_dependencies)
-}
+    /// Resolves the peer dependencies that no version in the graph provides as dependencies of the registry
+    /// packages declaring them, over again for the peers the new packages declare. Optional peers are left out.
+    fn install_peers(&mut self) -> Result<()> {
+        loop {
+            let nodes: Vec<(NodeId, ResolvedNode)> =
+                self.graph.nodes().skip(1).map(|(id, node)| (id, node.clone())).collect();
+            let mut missing: Vec<(NodeId, Dependency)> = Vec::new();
+            for (id, node) in nodes {
+                if !Specifier::parse(&node.version).is_registry()
+                    || self.options.workspace.iter().any(|member| member.name == node.name)
+                {
+                    continue;
+                }
+                let packument = self.packument(node.package())?;
+                let metadata = match packument.versions.get(&node.version) {
+                    Some(metadata) => metadata,
+                    None => continue,
+                };
+                for (peer, optional) in declared_peers(metadata) {
+                    let provided = self.graph.find(&peer.name).next().is_some()
+                        || missing.iter().any(|(_, missing)| missing.name == peer.name);
+                    if !optional && !provided {
+                        missing.push((id, peer));
+                    }
+                }
+            }
+            if missing.is_empty() {
+                return Ok(());
+            }
+
+            for (id, peer) in missing {
+                let ancestors = self.graph.paths_to(id).into_iter().next().unwrap_or_default().into_iter().skip(1);
+                self.resolve(id, ancestors.collect(), vec![(peer, DependencyKind::Normal)])?;
+            }
+        }
+    }
+
+    /// Gives up on the packages on the stack down to the nearest optional one and goes back to before it. Fails
+    /// with `err` when none of them is optional.
+    fn unwind(&mut self, stack: &mut Vec<Frame>, err: NaryError) -> Result<()> {
+        while let Some(frame) = stack.pop() {
+            if let Some(checkpoint) = frame.optional {
+                self.restore(checkpoint, &err);
+                return Ok(());
+            }
+        }
+        Err(err)
+    }
+
+    fn restore(&mut self, checkpoint: Checkpoint, err: &NaryError) {
+        let dependency = &checkpoint.dependency;
+        self.reporter.on_warning(&format!(
+            "Skipping optional dependency {}@{}: {}",
+            dependency.name, dependency.version, err
+        ));
+        self.graph = checkpoint.graph;
+        self.resolved = checkpoint.resolved;
+    }
+
+    /// Adds the edge from `parent` to what `requested` resolves to. Returns the node with its dependencies when
+    /// they're still to be resolved, None when it was in the graph already or is left out.
+    fn resolve_dependency(
+        &mut self,
+        parent: NodeId,
+        ancestors: &[NodeId],
+        requested: &Dependency,
+        kind: DependencyKind,
+    ) -> Result<Option<(NodeId, RuntimeDependencies)>> {
+        if kind == DependencyKind::Optional && self.left_out.contains(&requested.name) {
+            return Ok(None);
+        }
+        let dependency = &self.overridden(requested, kind, ancestors)?;
+        if let Some(node) = self.resolved.get(dependency) {
+            self.graph.add_edge(parent, *node, &requested.version, kind);
+            return Ok(None);
+        }
+
+        let (resolved_node, source) = match self.resolve_node(dependency, kind)? {
+            Some(resolved) => resolved,
+            None => return Ok(None),
+        };
+        let new_deps = if self.graph.contains(&resolved_node) {
+            None
+        } else {
+            Some(self.dependencies_of(&resolved_node)?)
+        };
 
-pub fn calculate_depends_rec(
-    dependency: &Dependency,
-    deps: &Vec<Dependency>,
-    map: &mut BidirMap<Dependency, DependencyId>,
-    graph: &mut DiGraphMap<DependencyId, i32>,
-) -> Result<()> {
-    let curr_node = *map.get_by_first(dependency).unwrap();
+        let node = self.graph.add_node(resolved_node);
+        if let Some(source) = source {
+            self.graph.set_registry(node, source);
+        }
+        self.resolved.insert(dependency.clone(), node);
+        self.graph.add_edge(parent, node, &requested.version, kind);
+
+        Ok(new_deps.map(|deps| (node, deps)))
+    }
 
-    if deps.len() == 0 {
-        return Ok(());
+    /// `dependency` with the version an override forces on it, when one applies. An override of a target's range
+    /// is checked against what the dependency resolves to without it.
+    fn overridden(
+        &mut self,
+        dependency: &Dependency,
+        kind: DependencyKind,
+        ancestors: &[NodeId],
+    ) -> Result<Dependency> {
+        if self.options.overrides.is_empty() {
+            return Ok(dependency.clone());
+        }
+        let ancestors: Vec<ResolvedNode> = ancestors.iter().filter_map(|id| self.graph.node(*id)).cloned().collect();
+        self.overridden_below(dependency, kind, &ancestors.iter().collect::<Vec<_>>())
     }
 
-    let mut remaining_deps = deps.clone();
+    /// Like `overridden`, for a dependency of the last of `ancestors`
+    fn overridden_below(
+        &mut self,
+        dependency: &Dependency,
+        kind: DependencyKind,
+        ancestors: &[&ResolvedNode],
+    ) -> Result<Dependency> {
+        for candidate in overrides_for(&self.options.overrides, &dependency.name, ancestors) {
+            let applies = candidate.target.range.is_none()
+                || match self.resolve_node(dependency, kind)? {
+                    Some((node, _)) => candidate.target.matches(&node.name, &node.version),
+                    None => false,
+                };
+            if applies {
+                return Ok(Dependency::new(dependency.name.clone(), candidate.version.clone()));
+            }
+        }
+        Ok(dependency.clone())
+    }
 
-    while !remaining_deps.is_empty() {
-        let index = remaining_deps.len() - 1;
-        let dependency = remaining_deps.remove(index);
+    /// Pins `dependency` to an exact version, along with the registry that had it when one did. Local
+    /// directories, tarball URLs, git repositories and workspace packages in range stand for themselves. Optional
+    /// dependencies that don't support the target platform are skipped. Packages whose `engines` don't allow the
+    /// target versions are warned about, or with strict engines fail, and are skipped when optional.
+    fn resolve_node(
+        &mut self,
+        dependency: &Dependency,
+        kind: DependencyKind,
+    ) -> Result<Option<(ResolvedNode, Option<String>)>> {
+        let options = self.options;
+        let version = &dependency.version;
+        let _span = debug_span!("resolve_node", name = %dependency.name, range = %version).entered();
+        let specifier = dependency.specifier();
+        if let Specifier::File(_) | Specifier::Git(_) | Specifier::Url(_) = specifier {
+            let node = ResolvedNode {
+                name: dependency.name.clone(),
+                version: version.clone(),
+                alias_of: None,
+            };
+            return Ok(Some((node, None)));
+        }
+        if let Some(member) = options.workspace.iter().find(|member| member.name == dependency.name) {
+            if member.satisfies(version) {
+                let node = ResolvedNode {
+                    name: dependency.name.clone(),
+                    version: member.dependency().version,
+                    alias_of: None,
+                };
+                return Ok(Some((node, None)));
+            }
+        }
+        if let Specifier::Workspace(range) = specifier {
+            return Err(NaryError::NotInWorkspace {
+                name: dependency.name.clone(),
+                range: range.clone(),
+            });
+        }
 
-        println!("{} {}", dependency.name, dependency.version);
+        let target = dependency.registry_target();
+        let packument = self.packument(&target.name)?;
+        let preferred = if self.pinned.is_empty() { &options.preferred } else { &self.pinned };
+        let (version, metadata) = match preferred_version(&target, &packument, preferred, options) {
+            Some(preferred) => preferred,
+            None => self.matching_version(&target, &packument)?,
+        };
+
+        if !options.platform.supports(metadata) {
+            if kind == DependencyKind::Optional {
+                return Ok(None);
+            }
+            return Err(NaryError::UnsupportedPlatform {
+                name: target.name.clone(),
+                version: version.clone(),
+                platform: options.platform.to_string(),
+            });
+        }
+        for (engine, wanted) in options.engines.unsatisfied(metadata) {
+            let current = if engine == "node" { &options.engines.node } else { &options.engines.npm };
+            let err = NaryError::UnsupportedEngine {
+                name: target.name.clone(),
+                version: version.clone(),
+                engine,
+                wanted,
+                current: current.clone().unwrap_or_default(),
+            };
+            if !options.engines.strict {
+                self.reporter.on_warning(&err.to_string());
+            } else if kind == DependencyKind::Optional {
+                return Ok(None);
+            } else {
+                return Err(err);
+            }
+        }
+        debug!(version = %version, registry = packument.registry.as_deref(), "resolved");
+        self.reporter.on_package_resolved(dependency, version);
+
+        let node = ResolvedNode {
+            name: dependency.name.clone(),
+            version: version.clone(),
+            alias_of: Some(target.name.clone()).filter(|target| *target != dependency.name),
+        };
+        Ok(Some((node, packument.registry.clone())))
+    }
 
-        if !map.contains_first_key(&dependency) {
-            let dependency_node = map.len() as i32;
-            graph.add_node(dependency_node);
-            map.insert(dependency, dependency_node);
+    /// The highest version in range, as an earlier run picked it when the packument hasn't changed since. Only
+    /// packuments from a registry over HTTP, which have a `modified` time, are memoized.
+    fn matching_version<'p>(
+        &mut self,
+        target: &'p Dependency,
+        packument: &'p Packument,
+    ) -> Result<(&'p String, &'p PackumentVersion)> {
+        let (registry, modified) = match (&packument.registry, &packument.modified) {
+            (Some(registry), Some(modified)) => (registry, modified),
+            _ => return fetch_matching_version_metadata(target, packument, self.options),
+        };
+        let key = ResolutionMemo::key(registry, &target.name, &target.version, self.options.include_prerelease);
+        let memo = self.memo.get_or_insert_with(|| cache::read_resolution_memo().unwrap_or_default());
+        if let Some(found) = memo.get(&key, modified).and_then(|version| packument.versions.get_key_value(version)) {
+            return Ok(found);
+        }
 
-            graph.add_edge(dependency_node, curr_node, 0);
-            let dependency = map.get_mut_by_second(&dependency_node).unwrap().clone();
+        let found = fetch_matching_version_metadata(target, packument, self.options)?;
+        memo.insert(key, found.0.clone(), modified.clone());
+        self.memo_changed = true;
+        Ok(found)
+    }
 
-            let root_metadata = fetch_package_root_metadata(&dependency)?;
-            // println!("{}", root_metadata);
+    /// Starts fetching the packuments of the registry packages among `deps` that haven't been fetched yet
+    fn prefetch(&mut self, deps: &[(Dependency, DependencyKind)]) {
+        for (dependency, _) in deps {
+            if !dependency.specifier().is_registry()
+                || self.options.workspace.iter().any(|member| member.name == dependency.name)
+            {
+                continue;
+            }
+            let name = dependency.registry_target().name;
+            let prefetch = &mut self.prefetch;
+            let known = self.packuments.contains_key(&name)
+                || prefetch.in_flight.contains(&name)
+                || prefetch.failed.contains_key(&name);
+            if known {
+                continue;
+            }
+            if prefetch.requests.send(name.clone()).is_ok() {
+                prefetch.in_flight.insert(name);
+            }
+        }
+    }
 
-            // let versions = &metadata["versions"];
-            let matching_version = fetch_matching_version_metadata(&dependency, &root_metadata)?;
-            println!("Found version: {}", matching_version.0);
+    /// The packument of a package, from the registry the first time it's asked for. One that's being prefetched
+    /// is waited for.
+    fn packument(&mut self, name: &str) -> Result<Rc<Packument>> {
+        while self.prefetch.in_flight.contains(name) {
+            let (fetched, packument) = match self.prefetch.results.recv() {
+                Ok(result) => result,
+                Err(_) => break,
+            };
+            self.prefetch.in_flight.remove(&fetched);
+            match packument {
+                Ok(packument) => {
+                    self.packuments.insert(fetched, Rc::new(packument));
+                }
+                Err(err) => {
+                    self.prefetch.failed.insert(fetched, err);
+                }
+            }
+        }
+        if let Some(err) = self.prefetch.failed.remove(name) {
+            return Err(err);
+        }
 
-            let package_metadata = fetch_package_version_metadata(&dependency, &matching_version.0)?;
-            // pick the version, then install it to get its ["dependencies"]
+        if let Some(packument) = self.packuments.get(name) {
+            return Ok(Rc::clone(packument));
+        }
+        let packument = Rc::new(self.registry.packument(&PackageName::parse(name)?)?);
+        self.packuments.insert(name.to_string(), Rc::clone(&packument));
+        Ok(packument)
+    }
 
-            // println!("{}", package_metadata);
-            let new_deps = serde_json_value_to_dependencies(&package_metadata["dependencies"])?;
+    /// The dependencies of a resolved package. A registry package's come from its packument, which has them for
+    /// every version, unless it ships a shrinkwrap. Those a tarball bundles in its own node_modules are left to it.
+    fn dependencies_of(&mut self, node: &ResolvedNode) -> Result<RuntimeDependencies> {
+        if let Some(dependencies) = self.shrinkwrapped.get(node) {
+            return Ok(dependencies.clone());
+        }
+        let version = &node.version;
+        let name = PackageName::parse(node.package())?;
+
+        match Specifier::parse(version) {
+            Specifier::File(local) => {
+                let package = local.join("package.json");
+                let mut dependencies = Manifest::read(&package)?.runtime_dependencies();
+                for (dependency, _) in &mut dependencies {
+                    absolutize_file_specifier(&package, dependency);
+                }
+                Ok(dependencies)
+            }
+            Specifier::Git(spec) => manifest_dependencies(&git::read_manifest(&spec)?),
+            Specifier::Url(url) => {
+                let url = parse_url(&url)?;
+                let tarball = self.registry.tarball(&name, version, &url, self.reporter)?;
+                manifest_dependencies(&read_manifest(&tarball, &url)?)
+            }
+            _ => match self.packument(node.package())?.versions.get(version) {
+                Some(metadata) => {
+                    let bundled = bundled_names(metadata.bundle_dependencies.as_ref(), &metadata.dependencies);
+                    if let Some(lockfile) = self.shrinkwrap(&name, metadata)? {
+                        return Ok(without_bundled(self.pin_shrinkwrapped(&lockfile), &bundled));
+                    }
+                    Ok(without_bundled(
+                        runtime_dependencies_of(&metadata.dependencies, &metadata.optional_dependencies),
+                        &bundled,
+                    ))
+                }
+                None => manifest_dependencies(&self.registry.version_metadata(&name, version)?),
+            },
+        }
+    }
 
-            calculate_depends_rec(&dependency, &new_deps, map, graph)?;
-        } else {
-            let dependency_node = *map.get_by_first(&dependency).unwrap();
-            graph.add_edge(dependency_node, curr_node, 0);
+    /// The npm-shrinkwrap.json in the tarball of a registry version that says it has one. One that can't be read
+    /// is warned about and left out, so the package resolves like any other.
+    fn shrinkwrap(&self, name: &PackageName, metadata: &PackumentVersion) -> Result<Option<Lockfile>> {
+        if !metadata.has_shrinkwrap {
+            return Ok(None);
+        }
+        let url = parse_url(&metadata.dist.tarball)?;
+        let tarball = self.registry.tarball(name, &metadata.version, &url, self.reporter)?;
+        let source = format!("the npm-shrinkwrap.json of {}@{}", name, metadata.version);
+        match read_shrinkwrap(&tarball, &url)?.map(|contents| import_shrinkwrap(&contents, &source)).transpose() {
+            Ok(lockfile) => {
+                debug!(name = %name, version = %metadata.version, found = lockfile.is_some(), "shrinkwrap");
+                Ok(lockfile)
+            }
+            Err(err) => {
+                self.reporter.on_warning(&format!("Ignoring {}: {}", source, err));
+                Ok(None)
+            }
         }
     }
 
-    Ok(())
+    /// The root's dependencies in a shrinkwrap, each pinned to the version in it. What every package in it depends
+    /// on is pinned the same way, for when they're reached.
+    fn pin_shrinkwrapped(&mut self, lockfile: &Lockfile) -> RuntimeDependencies {
+        let pinned = |dependencies: &BTreeMap<String, LockedDependency>| -> RuntimeDependencies {
+            dependencies
+                .iter()
+                .filter_map(|(name, dependency)| {
+                    let package = lockfile.packages.get(&dependency.package)?;
+                    let node = ResolvedNode {
+                        name: name.clone(),
+                        version: package.version.clone(),
+                        alias_of: package.alias_of.clone(),
+                    };
+                    let kind = if dependency.optional { DependencyKind::Optional } else { DependencyKind::Normal };
+                    Some((node.dependency(), kind))
+                })
+                .collect()
+        };
+
+        for package in lockfile.packages.values() {
+            let node = ResolvedNode {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                alias_of: package.alias_of.clone(),
+            };
+            self.shrinkwrapped.entry(node).or_insert_with(|| pinned(&package.dependencies));
+        }
+        pinned(&lockfile.dependencies)
+    }
 }
 
-pub fn path_to_root_dependency<'a>(file: &Path) -> Result<Dependency> {
-    let mut package = file.to_path_buf();
+fn by_name(deps: &mut RuntimeDependencies) {
+    deps.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+}
 
-    if !package.ends_with("package.json") {
-        package.push("package.json");
-    }
+/// The highest of the versions resolution prefers for `dep` that's in its range and published
+fn preferred_version<'a>(
+    dep: &Dependency,
+    packument: &'a Packument,
+    preferred: &BTreeMap<String, Vec<String>>,
+    options: &ResolutionOptions,
+) -> Option<(&'a String, &'a PackumentVersion)> {
+    let range = Range::new(&dep.version).with_options(options.semver()).parse().ok()?;
+    preferred
+        .get(&dep.name)?
+        .iter()
+        .filter_map(|version| packument.versions.get_key_value(version))
+        .filter_map(|found| Some((Version::new(found.0).with_options(options.semver()).parse().ok()?, found)))
+        .filter(|(parsed, _)| range.test(parsed))
+        .max_by(|(a, found_a), (b, found_b)| semver_order(a, b).then_with(|| found_a.0.cmp(found_b.0)))
+        .map(|(_, found)| found)
+}
 
-    let package_json = File::open(package)?;
-    let root: Value = serde_json::from_reader(package_json)?;
+/// `dependencies` and `optionalDependencies` of a package.json, but for those it bundles. A package listed in both
+/// is optional.
+fn manifest_dependencies(manifest: &Value) -> Result<Vec<(Dependency, DependencyKind)>> {
+    let manifest = Manifest::from_value(manifest);
+    Ok(without_bundled(manifest.runtime_dependencies(), &manifest.bundled()))
+}
 
-    Ok(Dependency {
-        name: root["name"].as_str().unwrap().to_string(),
-        version: root["version"].as_str().unwrap().to_string()
-    })
+fn without_bundled(dependencies: RuntimeDependencies, bundled: &[String]) -> RuntimeDependencies {
+    dependencies.into_iter().filter(|(dependency, _)| !bundled.contains(&dependency.name)).collect()
 }
 
-pub fn path_to_dependencies<'a>(file: &Path) -> Result<Vec<Dependency>> {
-    let mut package = file.to_path_buf();
+pub fn path_to_root_dependency(file: &Path) -> Result<Dependency> {
+    Ok(Manifest::read(file)?.dependency())
+}
 
-    if !package.ends_with("package.json") {
-        package.push("package.json");
+/// `dependencies` and `optionalDependencies` of a package.json, or of the one in a directory
+pub fn path_to_dependencies(file: &Path) -> Result<Vec<(Dependency, DependencyKind)>> {
+    let package = if file.ends_with("package.json") { file.to_path_buf() } else { file.join("package.json") };
+    let mut dependencies = Manifest::read(&package)?.runtime_dependencies();
+    for (dependency, _) in &mut dependencies {
+        absolutize_file_specifier(&package, dependency);
     }
 
-    let package_json = File::open(package)?;
+    Ok(dependencies)
+}
 
-    json_to_dependencies(&package_json)
+/// file: paths are relative to the package.json they are written in
+fn absolutize_file_specifier(package_json: &Path, dependency: &mut Dependency) {
+    if let Specifier::File(local) = dependency.specifier().clone() {
+        let dir = package_json.parent().unwrap_or_else(|| Path::new(""));
+        let local = dir.join(local);
+        let local = local.canonicalize().unwrap_or(local);
+        *dependency = Dependency::new(dependency.name.clone(), format!("file:{}", local.display()));
+    }
 }
 
-pub fn json_to_dependencies(mut reader: impl io::Read) -> Result<Vec<Dependency>> {
+/// Like `path_to_dependencies`, for a package.json read from `reader`
+pub fn json_to_dependencies(mut reader: impl io::Read) -> Result<Vec<(Dependency, DependencyKind)>> {
     let mut buffer = String::new();
-    reader.read_to_string(&mut buffer)?;
+    reader
+        .read_to_string(&mut buffer)
+        .map_err(|err| NaryError::io("package.json", err))?;
 
-    let root: Value = serde_json::from_str(&buffer)?;
-    serde_json_value_to_dependencies(&root["dependencies"])
+    let root: Value = serde_json::from_str(&buffer).map_err(|err| NaryError::json("package.json", err))?;
+    Ok(Manifest::from_value(&root).runtime_dependencies())
 }
 
+/// The dependencies in a package.json section. Versions that aren't strings are left out.
 pub fn serde_json_value_to_dependencies(root: &serde_json::Value) -> Result<Vec<Dependency>> {
-    let mut vec = Vec::new();
-
-    if let Some(dependencies) = root.as_object() {
-        for dependency in dependencies.iter() {
-            println!("{} {} ", dependency.0, dependency.1);
-            if !dependency.0.starts_with("_") {
-                vec.push(Dependency {
-                    name: dependency.0.to_string(),
-                    version: dependency.1.as_str().unwrap().to_string(),
-                });
-            }
-        }
-    };
-
-    Ok(vec)
-}
\ No newline at end of file
+    let section = serde_json::json!({ "dependencies": root });
+    Ok(map_dependencies(&Manifest::from_value(&section).dependencies))
+}
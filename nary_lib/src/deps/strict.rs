@@ -0,0 +1,428 @@
+use semver_rs::{Range, Version};
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use super::{by_name, Resolver, RuntimeDependencies};
+use crate::{
+    peers::declared_peers, semver_order, ChainLink, Dependency, DependencyChain, DependencyKind, NaryError,
+    ResolvedNode, Result, Specifier,
+};
+
+/// Candidate versions tried before giving up on finding ones that go together
+const MAX_ATTEMPTS: usize = 100_000;
+
+/// Requirements on one package that no version of it meets all of
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolutionConflict {
+    pub name: String,
+    /// As few as conflict: without any one of them some version would do, unless resolution gave up first
+    pub requirements: Vec<ConflictingRequirement>,
+}
+
+/// A range of a package, and the way down from the root to what asks for it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConflictingRequirement {
+    pub range: String,
+    /// Asked for as a peer dependency
+    pub peer: bool,
+    /// From the root's direct dependency to the package asking, empty when it's the root
+    pub chain: DependencyChain,
+}
+
+impl fmt::Display for ResolutionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.requirements.is_empty() {
+            return write!(f, "Gave up looking for versions that meet every requirement on them");
+        }
+        write!(f, "No version of {} meets every requirement on it:", self.name)?;
+        for requirement in &self.requirements {
+            let asked = if requirement.peer { "as a peer of" } else { "from" };
+            if requirement.chain.links.is_empty() {
+                write!(f, "\n  {} {} the root", requirement.range, asked)?;
+            } else {
+                write!(f, "\n  {} {} {}", requirement.range, asked, requirement.chain)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The packages from the root's dependency down to one, each with the range it was asked for with
+type Path = Rc<Vec<(ResolvedNode, String)>>;
+
+/// A range a package has to be in
+struct Requirement {
+    dependency: Dependency,
+    kind: DependencyKind,
+    peer: bool,
+    /// Brings the package in, rather than only holding it to the range once something else does
+    forcing: bool,
+    /// Down to the package asking
+    path: Path,
+}
+
+/// A package being tried at its candidate versions, leaving it out last when that's allowed
+struct Choice {
+    name: String,
+    candidates: std::vec::IntoIter<Option<ResolvedNode>>,
+    /// How many requirements there were before its candidates added theirs
+    requirements: usize,
+}
+
+/// What a package version asks for itself and of its peers
+type Asks = Rc<(RuntimeDependencies, Vec<Dependency>)>;
+
+#[derive(Default)]
+struct Search {
+    requirements: Vec<Requirement>,
+    /// The version each package is at for now, None when it's left out
+    decisions: HashMap<String, Option<ResolvedNode>>,
+    asks: HashMap<ResolvedNode, Asks>,
+    /// The last conflict found, to explain a failure with
+    conflict: Option<ResolutionConflict>,
+}
+
+impl Search {
+    /// The first package a requirement brings in that has no version yet
+    fn undecided(&self) -> Option<String> {
+        self.requirements
+            .iter()
+            .find(|requirement| requirement.forcing && !self.decisions.contains_key(&requirement.dependency.name))
+            .map(|requirement| requirement.dependency.name.clone())
+    }
+
+    fn on<'s>(&'s self, name: &str) -> Vec<&'s Requirement> {
+        self.requirements.iter().filter(|requirement| requirement.dependency.name == name).collect()
+    }
+
+    /// Leaving a package out is only allowed when everything bringing it in does so optionally
+    fn optional(requirements: &[&Requirement]) -> bool {
+        requirements.iter().all(|requirement| !requirement.forcing || requirement.kind == DependencyKind::Optional)
+    }
+}
+
+impl Resolver<'_> {
+    /// Settles on one version of every package for the strict strategy, trying them depth first from the highest
+    /// and going back to the next of the latest choice when a requirement rules out what's been picked. `resolve`
+    /// then builds the graph from the pinned versions.
+    pub(super) fn solve(&mut self, deps: &[(Dependency, DependencyKind)]) -> Result<()> {
+        let mut search = Search::default();
+        let mut deps = deps.to_vec();
+        by_name(&mut deps);
+        self.require(&mut search, &deps, &[], &Path::default())?;
+
+        let mut stack: Vec<Choice> = Vec::new();
+        let mut attempts = 0;
+        while let Some(name) = search.undecided() {
+            let candidates = self.candidates(&mut search, &name)?;
+            stack.push(Choice {
+                name,
+                candidates: candidates.into_iter(),
+                requirements: search.requirements.len(),
+            });
+
+            // The next candidate of the latest choice, going back to the choices before it once it has none left
+            loop {
+                let choice = match stack.last_mut() {
+                    Some(choice) => choice,
+                    None => return Err(unresolvable(search.conflict)),
+                };
+                search.requirements.truncate(choice.requirements);
+                search.decisions.remove(&choice.name);
+                let candidate = match choice.candidates.next() {
+                    Some(candidate) => candidate,
+                    None => {
+                        stack.pop();
+                        continue;
+                    }
+                };
+                attempts += 1;
+                if attempts > MAX_ATTEMPTS {
+                    return Err(unresolvable(search.conflict));
+                }
+                let name = choice.name.clone();
+                if self.decide(&mut search, &name, candidate)? {
+                    break;
+                }
+            }
+        }
+
+        for (name, decision) in search.decisions {
+            match decision {
+                Some(node) if Version::new(&node.version).parse().is_ok() => {
+                    self.pinned.entry(node.package().to_string()).or_default().push(node.version);
+                }
+                Some(_) => {}
+                None => {
+                    self.left_out.insert(name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tries `name` at the candidate, adding what it asks for. False when that rules out a version picked already.
+    fn decide(&mut self, search: &mut Search, name: &str, candidate: Option<ResolvedNode>) -> Result<bool> {
+        search.decisions.insert(name.to_string(), candidate.clone());
+        let node = match candidate {
+            Some(node) => node,
+            None => return Ok(true),
+        };
+        let requirements = search.on(name);
+        let optional = Search::optional(&requirements);
+        let (mut path, range) = match requirements.iter().find(|requirement| requirement.forcing) {
+            Some(first) => (first.path.as_ref().clone(), first.dependency.version.clone()),
+            None => (Vec::new(), String::new()),
+        };
+        path.push((node.clone(), range));
+
+        let asks = match search.asks.get(&node) {
+            Some(asks) => Rc::clone(asks),
+            None => match self.asks_of(&node) {
+                Ok(asks) => {
+                    search.asks.insert(node.clone(), Rc::clone(&asks));
+                    asks
+                }
+                // A package that can't be looked into is only skipped when it's optional
+                Err(_) if optional => return Ok(false),
+                Err(err) => return Err(err),
+            },
+        };
+        self.require(search, &asks.0, &asks.1, &Rc::new(path))
+    }
+
+    /// The dependencies of a package and the peers it doesn't do without
+    fn asks_of(&mut self, node: &ResolvedNode) -> Result<Asks> {
+        let mut dependencies = self.dependencies_of(node)?;
+        by_name(&mut dependencies);
+        let peers = match Specifier::parse(&node.version) {
+            Specifier::SemverRange(_) => match self.packument(node.package())?.versions.get(&node.version) {
+                Some(metadata) => declared_peers(metadata)
+                    .into_iter()
+                    .filter(|(_, optional)| !optional)
+                    .map(|(peer, _)| peer)
+                    .collect(),
+                None => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        Ok(Rc::new((dependencies, peers)))
+    }
+
+    /// Adds what a package asks for, overridden as it says, as requirements. False when one rules out the version
+    /// of a package picked already.
+    fn require(
+        &mut self,
+        search: &mut Search,
+        dependencies: &[(Dependency, DependencyKind)],
+        peers: &[Dependency],
+        path: &Path,
+    ) -> Result<bool> {
+        self.prefetch(dependencies);
+        let ancestors: Vec<&ResolvedNode> = path.iter().map(|(node, _)| node).collect();
+        let start = search.requirements.len();
+        for (dependency, kind) in dependencies {
+            if !self.options.overrides.is_empty() {
+                let overridden = self.overridden_below(dependency, *kind, &ancestors)?;
+                search.requirements.push(requirement(overridden, *kind, false, true, path));
+            } else {
+                search.requirements.push(requirement(dependency.clone(), *kind, false, true, path));
+            }
+        }
+        for peer in peers {
+            let forcing = self.options.install_peers;
+            search.requirements.push(requirement(peer.clone(), DependencyKind::Normal, true, forcing, path));
+        }
+
+        for index in start..search.requirements.len() {
+            let requirement = &search.requirements[index];
+            let allowed = match search.decisions.get(&requirement.dependency.name) {
+                None => true,
+                Some(None) => !requirement.forcing || requirement.kind == DependencyKind::Optional,
+                Some(Some(node)) => {
+                    let (node, dependency) = (node.clone(), requirement.dependency.clone());
+                    self.satisfies(&node, &dependency)?
+                }
+            };
+            if !allowed {
+                let name = search.requirements[index].dependency.name.clone();
+                self.record_conflict(search, &name)?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// What `name` can be tried at, in order: the versions every requirement on it allows, preferred ones first
+    /// and then from the highest, and leaving it out last when it's only optional
+    fn candidates(&mut self, search: &mut Search, name: &str) -> Result<Vec<Option<ResolvedNode>>> {
+        let requirements = search.on(name);
+        let optional = Search::optional(&requirements);
+        let found = match self.allowed(name, &requirements) {
+            Ok(found) => found,
+            Err(_) if optional => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        let mut candidates: Vec<Option<ResolvedNode>> = found.into_iter().map(Some).collect();
+        if optional {
+            candidates.push(None);
+        } else if candidates.is_empty() {
+            self.record_conflict(search, name)?;
+        }
+        Ok(candidates)
+    }
+
+    /// The versions of `name` all of the requirements allow
+    fn allowed(&mut self, name: &str, requirements: &[&Requirement]) -> Result<Vec<ResolvedNode>> {
+        let first = match requirements.iter().find(|requirement| requirement.forcing).or(requirements.first()) {
+            Some(first) => &first.dependency,
+            None => return Ok(Vec::new()),
+        };
+        let options = self.options;
+        let member = options.workspace.iter().find(|member| member.name == name && member.satisfies(&first.version));
+        let mut proposed = if member.is_some() || !first.specifier().is_registry() {
+            self.resolve_node(first, DependencyKind::Normal)?.map(|(node, _)| node).into_iter().collect()
+        } else {
+            let target = first.registry_target();
+            let packument = self.packument(&target.name)?;
+            let preferred = options.preferred.get(&target.name);
+            let mut versions: Vec<(Option<Version>, &String)> = packument
+                .versions
+                .iter()
+                .filter(|(_, metadata)| options.platform.supports(metadata))
+                .filter(|(_, metadata)| !options.engines.strict || options.engines.unsatisfied(metadata).is_empty())
+                .map(|(version, _)| (Version::new(version).with_options(options.semver()).parse().ok(), version))
+                .collect();
+            let unpreferred = |version: &String| !preferred.is_some_and(|preferred| preferred.contains(version));
+            versions.sort_by(|(a, version_a), (b, version_b)| {
+                unpreferred(version_a)
+                    .cmp(&unpreferred(version_b))
+                    .then_with(|| match (a, b) {
+                        (Some(a), Some(b)) => semver_order(b, a),
+                        _ => b.is_some().cmp(&a.is_some()),
+                    })
+                    .then_with(|| version_b.cmp(version_a))
+            });
+            versions
+                .into_iter()
+                .map(|(_, version)| ResolvedNode {
+                    name: name.to_string(),
+                    version: version.clone(),
+                    alias_of: Some(target.name.clone()).filter(|target| target != name),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut allowed = Vec::new();
+        for node in proposed.drain(..) {
+            let mut satisfied = true;
+            for requirement in requirements {
+                if !self.satisfies(&node, &requirement.dependency)? {
+                    satisfied = false;
+                    break;
+                }
+            }
+            if satisfied {
+                allowed.push(node);
+            }
+        }
+        Ok(allowed)
+    }
+
+    /// Whether the dependency can be met by the node: the same local directory, tarball or repository, the
+    /// workspace member in range, or a version of the same registry package in range or with the tag
+    fn satisfies(&mut self, node: &ResolvedNode, dependency: &Dependency) -> Result<bool> {
+        let options = self.options;
+        if let Some(member) = options.workspace.iter().find(|member| member.name == dependency.name) {
+            if member.satisfies(&dependency.version) {
+                return Ok(node.version == member.dependency().version);
+            }
+        }
+        if !dependency.specifier().is_registry() {
+            return Ok(node.alias_of.is_none() && node.version == dependency.version);
+        }
+
+        let target = dependency.registry_target();
+        if node.package() != target.name {
+            return Ok(false);
+        }
+        let version = match Version::new(&node.version).with_options(options.semver()).parse() {
+            Ok(version) => version,
+            Err(_) => return Ok(false),
+        };
+        if target.version.trim().is_empty() {
+            return Ok(true);
+        }
+        match Range::new(&target.version).with_options(options.semver()).parse() {
+            Ok(range) => Ok(range.test(&version)),
+            Err(_) => {
+                let packument = self.packument(&target.name)?;
+                Ok(packument.dist_tags.get(&target.version) == Some(&node.version))
+            }
+        }
+    }
+
+    /// Keeps the requirements on `name` that leave it without a version as what to explain a failure with. Those
+    /// that don't are only kept when there's nothing better, as it's the versions picked that they rule out.
+    fn record_conflict(&mut self, search: &mut Search, name: &str) -> Result<()> {
+        let mut requirements = search.on(name);
+        let conflicting = self.allowed(name, &requirements).map(|allowed| allowed.is_empty()).unwrap_or(true);
+        if !conflicting && search.conflict.is_some() {
+            return Ok(());
+        }
+        if conflicting {
+            // Each one goes when the rest still conflict
+            let mut index = 0;
+            while index < requirements.len() && requirements.len() > 1 {
+                let mut without = requirements.clone();
+                without.remove(index);
+                if self.allowed(name, &without).map(|allowed| allowed.is_empty()).unwrap_or(true) {
+                    requirements = without;
+                } else {
+                    index += 1;
+                }
+            }
+        }
+
+        search.conflict = Some(ResolutionConflict {
+            name: name.to_string(),
+            requirements: requirements
+                .iter()
+                .map(|requirement| ConflictingRequirement {
+                    range: requirement.dependency.version.clone(),
+                    peer: requirement.peer,
+                    chain: DependencyChain {
+                        links: requirement
+                            .path
+                            .iter()
+                            .map(|(node, range)| ChainLink {
+                                name: node.name.clone(),
+                                version: node.version.clone(),
+                                range: range.clone(),
+                            })
+                            .collect(),
+                    },
+                })
+                .collect(),
+        });
+        Ok(())
+    }
+}
+
+fn requirement(dependency: Dependency, kind: DependencyKind, peer: bool, forcing: bool, path: &Path) -> Requirement {
+    Requirement {
+        dependency,
+        kind,
+        peer,
+        forcing,
+        path: Rc::clone(path),
+    }
+}
+
+fn unresolvable(conflict: Option<ResolutionConflict>) -> NaryError {
+    NaryError::ResolutionConflict {
+        conflict: Box::new(conflict.unwrap_or_else(|| ResolutionConflict {
+            name: String::new(),
+            requirements: Vec::new(),
+        })),
+    }
+}
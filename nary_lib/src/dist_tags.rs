@@ -0,0 +1,48 @@
+use indexmap::IndexMap;
+use semver_rs::Range;
+
+use crate::{NaryError, PackageName, RegistryClient, Result};
+
+/// The package's dist-tags, mapped to the versions they point at
+pub fn list(name: &str, registry: &dyn RegistryClient) -> Result<IndexMap<String, String>> {
+    registry.dist_tags(&PackageName::parse(name)?)
+}
+
+/// Point `tag` at `version`, which the package has to have as the registry has it now, not as it's cached. A tag
+/// that reads as a range isn't one, since installing it would match versions instead.
+pub fn add(name: &str, version: &str, tag: &str, registry: &dyn RegistryClient) -> Result<()> {
+    if tag.trim().is_empty() || Range::new(tag).parse().is_ok() {
+        return Err(NaryError::InvalidDistTag {
+            tag: tag.to_string(),
+            reason: "reads as a version range".to_string(),
+        });
+    }
+    let package = PackageName::parse(name)?;
+    let packument = registry.full_packument(&package)?;
+    if !packument.versions.contains_key(version) {
+        return Err(NaryError::NoMatchingVersion {
+            name: name.to_string(),
+            range: version.to_string(),
+            available: packument.versions.keys().cloned().collect(),
+        });
+    }
+    registry.set_dist_tag(&package, tag, version)
+}
+
+/// Take `tag` off the package, returning the version it pointed at as the registry has it now, or None when it had
+/// no such tag. `latest` stays, as registries need it.
+pub fn rm(name: &str, tag: &str, registry: &dyn RegistryClient) -> Result<Option<String>> {
+    if tag == "latest" {
+        return Err(NaryError::InvalidDistTag {
+            tag: tag.to_string(),
+            reason: "can't be removed".to_string(),
+        });
+    }
+    let package = PackageName::parse(name)?;
+    let version = match registry.full_packument(&package)?.dist_tags.shift_remove(tag) {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    registry.remove_dist_tag(&package, tag)?;
+    Ok(Some(version))
+}
@@ -0,0 +1,221 @@
+use std::{error::Error as StdError, io, path::PathBuf};
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, NaryError>;
+
+/// Every way nary_lib can fail, so callers can match on the failure mode
+#[derive(Debug, Error)]
+pub enum NaryError {
+    #[error("Couldn't GET URL: {url}")]
+    NetworkError {
+        url: String,
+        #[source]
+        source: Box<dyn StdError + Send + Sync>,
+    },
+
+    #[error("{url} responded with HTTP {status}")]
+    RegistryError { url: String, status: u16 },
+
+    #[error("Version {version} of {name} didn't parse")]
+    VersionParse {
+        name: String,
+        version: String,
+        #[source]
+        source: semver_rs::Error,
+    },
+
+    #[error("No version of {name} matches {range}")]
+    NoMatchingVersion {
+        name: String,
+        range: String,
+        available: Vec<String>,
+    },
+
+    #[error("Couldn't unpack {url}: {reason}")]
+    UnpackError {
+        url: String,
+        reason: String,
+        #[source]
+        source: Option<io::Error>,
+    },
+
+    #[error("{name}@{version} doesn't support {platform}")]
+    UnsupportedPlatform {
+        name: String,
+        version: String,
+        platform: String,
+    },
+
+    #[error("{name}@{version} wants {engine} {wanted}, but it's {current}")]
+    UnsupportedEngine {
+        name: String,
+        version: String,
+        engine: String,
+        wanted: String,
+        current: String,
+    },
+
+    #[error("{0} is not a valid package name")]
+    InvalidPackageName(String),
+
+    #[error("{what} isn't in the cache and nary is offline")]
+    NotCached { what: String },
+
+    #[error("{package} {reason}")]
+    Unverified { package: String, reason: String },
+
+    #[error("Couldn't parse URL {url}")]
+    InvalidUrl {
+        url: String,
+        #[source]
+        source: hyper::error::ParseError,
+    },
+
+    #[error("Couldn't JSON parse {origin}")]
+    Json {
+        origin: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error("Couldn't YAML parse {origin}")]
+    Yaml {
+        origin: String,
+        #[source]
+        source: serde_yaml::Error,
+    },
+
+    #[error("Couldn't access {}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Git operation on {url} failed")]
+    Git {
+        url: String,
+        #[source]
+        source: git2::Error,
+    },
+
+    #[error("Couldn't find the home directory")]
+    NoHomeDir,
+
+    #[error("The cache at {} has layout version {version}, which is newer than this nary knows", path.display())]
+    UnsupportedCache { path: PathBuf, version: u32 },
+
+    #[error("Lockfile is invalid: {reason}")]
+    InvalidLockfile { reason: String },
+
+    #[error("There's no lockfile at {}", path.display())]
+    NoLockfile { path: PathBuf },
+
+    #[error("The lockfile is out of sync with package.json: {reason}")]
+    LockfileOutOfSync { reason: String },
+
+    #[error("{name}@{version} is {actual} in the registry, but {expected} in the lockfile")]
+    IntegrityMismatch {
+        name: String,
+        version: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("{} is invalid: {reason}", path.display())]
+    InvalidManifest { path: PathBuf, reason: String },
+
+    #[error("{} already exists", path.display())]
+    ManifestExists { path: PathBuf },
+
+    #[error("Override {key} is invalid: {reason}")]
+    InvalidOverride { key: String, reason: String },
+
+    #[error("{name}@{range} isn't a package of the workspace")]
+    NotInWorkspace { name: String, range: String },
+
+    #[error("{name} isn't linked, it has to be linked from its own directory first")]
+    NotLinked { name: String },
+
+    #[error("There's no script called {name} in package.json")]
+    MissingScript { name: String },
+
+    #[error("Script {name} failed{}", code.map(|code| format!(" with exit code {}", code)).unwrap_or_default())]
+    ScriptFailed { name: String, code: Option<i32> },
+
+    #[error("Refused by an install hook: {reason}")]
+    Refused { reason: String },
+
+    #[error("Licenses that aren't allowed: {}", packages.join(", "))]
+    DisallowedLicenses { packages: Vec<String> },
+
+    #[error("Dist-tag {tag} {reason}")]
+    InvalidDistTag { tag: String, reason: String },
+
+    #[error("The registry refused to {action} {package}: {reason}")]
+    RegistryRefused {
+        action: String,
+        package: String,
+        reason: String,
+    },
+
+    #[error("{package} was published at {published}, too long ago to unpublish; deprecate it instead")]
+    UnpublishWindow { package: String, published: String },
+
+    #[error("{conflict}")]
+    ResolutionConflict { conflict: Box<crate::ResolutionConflict> },
+
+    #[error("The tarball of {package} is still corrupt downloaded again ({reason}), see {}", quarantined.display())]
+    CorruptTarball {
+        package: String,
+        reason: String,
+        quarantined: PathBuf,
+    },
+
+    #[error("{what} isn't something this registry can do")]
+    Unsupported { what: String },
+
+    #[error("Couldn't set up TLS with the configured certificates")]
+    Tls {
+        #[source]
+        source: native_tls::Error,
+    },
+}
+
+impl NaryError {
+    pub(crate) fn network<E: StdError + Send + Sync + 'static>(url: &str, source: E) -> NaryError {
+        NaryError::NetworkError {
+            url: url.to_string(),
+            source: Box::new(source),
+        }
+    }
+
+    pub(crate) fn io<P: Into<PathBuf>>(path: P, source: io::Error) -> NaryError {
+        NaryError::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub(crate) fn json<S: ToString>(origin: S, source: serde_json::Error) -> NaryError {
+        NaryError::Json {
+            origin: origin.to_string(),
+            source,
+        }
+    }
+
+    pub(crate) fn yaml<S: ToString>(origin: S, source: serde_yaml::Error) -> NaryError {
+        NaryError::Yaml {
+            origin: origin.to_string(),
+            source,
+        }
+    }
+
+    pub(crate) fn unpack<S: ToString>(url: S, reason: String, source: Option<io::Error>) -> NaryError {
+        NaryError::UnpackError {
+            url: url.to_string(),
+            reason,
+            source,
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use git2::{build::CheckoutBuilder, FetchOptions, Oid, Repository};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use semver_rs::{Range, Version};
+use serde_json::Value;
+use std::{fs, path::Path};
+
+use crate::{cache, get_cache_dir, NaryError, Result};
+
+/// A dependency on a git repository, as written in package.json
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GitSpec {
+    /// What git clones from
+    pub url: String,
+    pub reference: GitReference,
+}
+
+/// The part after `#`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum GitReference {
+    /// The remote's default branch
+    Head,
+    /// A commit, tag or branch
+    Committish(String),
+    /// The highest tag satisfying a range, from `#semver:^1.2.0`
+    Semver(String),
+}
+
+impl GitSpec {
+    /// Parses `git://`, `git+https://`, `git+ssh://`, `github:user/repo`, `gitlab:`, `bitbucket:` and bare `user/repo`
+    /// specifiers. Anything else isn't a git dependency.
+    pub fn parse(spec: &str) -> Option<GitSpec> {
+        let (location, fragment) = match spec.find('#') {
+            Some(index) => (&spec[..index], Some(&spec[index + 1..])),
+            None => (spec, None),
+        };
+
+        let url = if let Some(url) = location.strip_prefix("git+") {
+            scp_like(url)
+        } else if location.starts_with("git://") {
+            location.to_string()
+        } else if let Some((host, path)) = hosted(location) {
+            format!("https://{}/{}.git", host, path.trim_end_matches(".git"))
+        } else {
+            return None;
+        };
+
+        let reference = match fragment {
+            None | Some("") => GitReference::Head,
+            Some(fragment) => match fragment.strip_prefix("semver:") {
+                Some(range) => GitReference::Semver(range.to_string()),
+                None => GitReference::Committish(fragment.to_string()),
+            },
+        };
+
+        Some(GitSpec { url, reference })
+    }
+}
+
+/// `ssh://git@host:user/repo` isn't a valid URL, but is what people copy from hosting sites. git understands it
+/// without the scheme.
+fn scp_like(url: &str) -> String {
+    if let Some(rest) = url.strip_prefix("ssh://") {
+        let authority = rest.split('/').next().unwrap_or(rest);
+        if let Some(index) = authority.rfind(':') {
+            if !authority[index + 1..].chars().all(|c| c.is_ascii_digit()) || index + 1 == authority.len() {
+                return rest.to_string();
+            }
+        }
+    }
+    url.to_string()
+}
+
+/// Host and `user/repo` of a hosted git shorthand
+fn hosted(location: &str) -> Option<(&'static str, &str)> {
+    let (host, path) = if let Some(path) = location.strip_prefix("github:") {
+        ("github.com", path)
+    } else if let Some(path) = location.strip_prefix("gitlab:") {
+        ("gitlab.com", path)
+    } else if let Some(path) = location.strip_prefix("bitbucket:") {
+        ("bitbucket.org", path)
+    } else {
+        ("github.com", location)
+    };
+
+    let mut segments = path.split('/');
+    let valid_segment = |segment: Option<&str>| {
+        segment
+            .map(|segment| {
+                !segment.is_empty()
+                    && !segment.starts_with('.')
+                    && segment.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+            })
+            .unwrap_or(false)
+    };
+
+    if valid_segment(segments.next()) && valid_segment(segments.next()) && segments.next().is_none() {
+        Some((host, path))
+    } else {
+        None
+    }
+}
+
+/// Copies the commit `spec` refers to into `path`, replacing whatever was there. The repository is fetched into the
+/// shared cache first.
+pub fn checkout(spec: &GitSpec, path: &Path) -> Result<()> {
+    let (repo, commit) = fetch(spec)?;
+    let git_err = |source| NaryError::Git {
+        url: spec.url.clone(),
+        source,
+    };
+
+    if path.exists() {
+        fs::remove_dir_all(path).map_err(|err| NaryError::io(path, err))?;
+    }
+    fs::create_dir_all(path).map_err(|err| NaryError::io(path, err))?;
+
+    let object = repo.find_object(commit, None).map_err(git_err)?;
+    repo.checkout_tree(&object, Some(CheckoutBuilder::new().target_dir(path).force()))
+        .map_err(git_err)?;
+
+    Ok(())
+}
+
+/// The package.json of the commit `spec` refers to, read straight from the cached repository
+pub fn read_manifest(spec: &GitSpec) -> Result<Value> {
+    let (repo, commit) = fetch(spec)?;
+    let git_err = |source| NaryError::Git {
+        url: spec.url.clone(),
+        source,
+    };
+
+    let tree = repo.find_commit(commit).and_then(|commit| commit.tree()).map_err(git_err)?;
+    let entry = tree.get_path(Path::new("package.json")).map_err(git_err)?;
+    let blob = repo.find_blob(entry.id()).map_err(git_err)?;
+
+    serde_json::from_slice(blob.content()).map_err(|err| NaryError::json(&spec.url, err))
+}
+
+/// The cache's bare repository for `spec`, updated from the remote, and the commit `spec` refers to
+fn fetch(spec: &GitSpec) -> Result<(Repository, Oid)> {
+    let git_err = |source| NaryError::Git {
+        url: spec.url.clone(),
+        source,
+    };
+
+    // Concurrent fetches into the same repository would trip over each other's ref locks
+    let _lock = cache::lock(&format!("git {}", spec.url))?;
+    let mut path = get_cache_dir()?;
+    path.push("_git");
+    path.push(utf8_percent_encode(&spec.url, NON_ALPHANUMERIC).to_string());
+    let repo = Repository::open_bare(&path)
+        .or_else(|_| Repository::init_bare(&path))
+        .map_err(git_err)?;
+
+    // A full commit hash that was fetched before can't have moved
+    if let GitReference::Committish(committish) = &spec.reference {
+        if let Ok(oid) = Oid::from_str(committish) {
+            if committish.len() == 40 && repo.find_commit(oid).is_ok() {
+                return Ok((repo, oid));
+            }
+        }
+    }
+
+    let commit = {
+        let mut remote = repo.remote_anonymous(&spec.url).map_err(git_err)?;
+        let refspecs = [
+            "+HEAD:refs/remotes/origin/HEAD",
+            "+refs/heads/*:refs/remotes/origin/*",
+            "+refs/tags/*:refs/tags/*",
+        ];
+
+        // Only the tips are needed, unless the reference is a commit further back. libgit2 can't fetch shallowly
+        // from a local path.
+        let shallow = !spec.url.starts_with("file://") && !Path::new(&spec.url).exists();
+        let depth = if shallow { 1 } else { 0 };
+        remote
+            .fetch(&refspecs, Some(FetchOptions::new().depth(depth)), None)
+            .map_err(git_err)?;
+
+        match resolve(&repo, spec) {
+            Err(_) if shallow => {
+                remote
+                    .fetch(&refspecs, Some(FetchOptions::new().depth(i32::MAX)), None)
+                    .map_err(git_err)?;
+                resolve(&repo, spec)?
+            }
+            resolved => resolved?,
+        }
+    };
+
+    Ok((repo, commit))
+}
+
+fn resolve(repo: &Repository, spec: &GitSpec) -> Result<Oid> {
+    let git_err = |source| NaryError::Git {
+        url: spec.url.clone(),
+        source,
+    };
+
+    match &spec.reference {
+        GitReference::Head => find_committish(repo, "HEAD").map_err(git_err),
+        GitReference::Committish(committish) => find_committish(repo, committish).map_err(git_err),
+        GitReference::Semver(range) => find_semver_tag(repo, range)?.ok_or_else(|| NaryError::NoMatchingVersion {
+            name: spec.url.clone(),
+            range: range.clone(),
+            available: tag_names(repo),
+        }),
+    }
+}
+
+/// Tags and commits resolve as written, branches and the remote's HEAD are fetched as remote-tracking refs
+fn find_committish(repo: &Repository, committish: &str) -> std::result::Result<Oid, git2::Error> {
+    repo.revparse_single(&format!("refs/remotes/origin/{}", committish))
+        .or_else(|_| repo.revparse_single(committish))
+        .and_then(|object| object.peel_to_commit())
+        .map(|commit| commit.id())
+}
+
+fn find_semver_tag(repo: &Repository, range: &str) -> Result<Option<Oid>> {
+    let parsed_range = Range::new(range).parse().map_err(|source| NaryError::VersionParse {
+        name: range.to_string(),
+        version: range.to_string(),
+        source,
+    })?;
+
+    let mut best: Option<(Version, String)> = None;
+    for tag in tag_names(repo) {
+        let version = match Version::new(tag.trim_start_matches('v')).parse() {
+            Ok(version) => version,
+            Err(_) => continue,
+        };
+        let is_better = best.as_ref().map(|(best, _)| version > *best).unwrap_or(true);
+        if parsed_range.test(&version) && is_better {
+            best = Some((version, tag));
+        }
+    }
+
+    match best {
+        Some((_, tag)) => find_committish(repo, &tag).map(Some).map_err(|source| NaryError::Git {
+            url: tag,
+            source,
+        }),
+        None => Ok(None),
+    }
+}
+
+fn tag_names(repo: &Repository) -> Vec<String> {
+    repo.tag_names(None)
+        .map(|tags| tags.iter().flatten().map(|tag| tag.to_string()).collect())
+        .unwrap_or_default()
+}
@@ -0,0 +1,211 @@
+use std::{
+    fs::{self, create_dir_all},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    add_dependency,
+    bin::{link_bins_into, unlink_bins},
+    calculate_depends, execute_plan, path_to_dependencies, path_to_root_dependency, plan_install, read_or_import,
+    remove_dependency, write_lockfile, Dependency, DependencyKind, InstallOptions, InstallReporter, Lockfile, Manifest,
+    NaryError, RegistryClient, RegistryConfig, ResolutionOptions, Result,
+};
+
+/// Where global packages and their executables go. The global packages are the dependencies of a package.json in
+/// `lib_dir`, so they're resolved, locked and installed like any project's.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GlobalPrefix {
+    pub prefix: PathBuf,
+}
+
+impl GlobalPrefix {
+    pub fn new(prefix: impl Into<PathBuf>) -> GlobalPrefix {
+        GlobalPrefix { prefix: prefix.into() }
+    }
+
+    /// The configured `prefix`, otherwise `%APPDATA%\npm` on Windows as npm has it and `~/.local` elsewhere, whose
+    /// `bin` is usually on the PATH already and needs no root
+    pub fn from_config(config: &RegistryConfig) -> Result<GlobalPrefix> {
+        if let Some(prefix) = &config.prefix {
+            return Ok(GlobalPrefix::new(prefix));
+        }
+        let prefix = if cfg!(windows) {
+            dirs::data_dir().map(|dir| dir.join("npm"))
+        } else {
+            dirs::home_dir().map(|home| home.join(".local"))
+        };
+        prefix.map(GlobalPrefix::new).ok_or(NaryError::NoHomeDir)
+    }
+
+    /// `<prefix>/lib`, or the prefix itself on Windows
+    pub fn lib_dir(&self) -> PathBuf {
+        if cfg!(windows) {
+            self.prefix.clone()
+        } else {
+            self.prefix.join("lib")
+        }
+    }
+
+    pub fn node_modules(&self) -> PathBuf {
+        self.lib_dir().join("node_modules")
+    }
+
+    /// `<prefix>/bin`, or the prefix itself on Windows
+    pub fn bin_dir(&self) -> PathBuf {
+        if cfg!(windows) {
+            self.prefix.clone()
+        } else {
+            self.prefix.join("bin")
+        }
+    }
+
+    fn package_dir(&self, name: &str) -> PathBuf {
+        self.node_modules().join(name)
+    }
+}
+
+/// A globally installed package
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GlobalPackage {
+    pub name: String,
+    /// What it was installed with
+    pub range: String,
+    /// The installed version, None when it's missing from the prefix
+    pub version: Option<String>,
+    /// The executables it has in the global bin directory
+    pub bins: Vec<String>,
+}
+
+/// Install packages (name, name@range or name@tag) globally, and link their executables into the global bin
+/// directory. Packages already installed globally stay as they are. Returns the dependencies as they were saved.
+pub fn install_global(
+    global: &GlobalPrefix,
+    specs: &[String],
+    registry: &dyn RegistryClient,
+    options: &InstallOptions,
+    resolution: &ResolutionOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<Vec<Dependency>> {
+    if specs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let lib_dir = global.lib_dir();
+    let manifest_path = lib_dir.join("package.json");
+    if !manifest_path.is_file() {
+        create_dir_all(&lib_dir).map_err(|err| NaryError::io(&lib_dir, err))?;
+        let manifest = "{\n  \"name\": \"nary-global\",\n  \"private\": true,\n  \"dependencies\": {}\n}\n";
+        fs::write(&manifest_path, manifest).map_err(|err| NaryError::io(&manifest_path, err))?;
+    }
+
+    let resolution = global_resolution(resolution);
+    let mut added = Vec::new();
+    for spec in specs {
+        added.push(add_dependency(&lib_dir, spec, DependencyKind::Normal, false, registry, &resolution)?);
+    }
+    sync(global, registry, options, &resolution, reporter)?;
+
+    let bin_dir = global.bin_dir();
+    for dependency in &added {
+        link_bins_into(&bin_dir, &global.package_dir(&dependency.name))?;
+    }
+    Ok(added)
+}
+
+/// Remove global packages and their executables, returning the names of those that were installed
+pub fn uninstall_global(
+    global: &GlobalPrefix,
+    names: &[String],
+    registry: &dyn RegistryClient,
+    options: &InstallOptions,
+    resolution: &ResolutionOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<Vec<String>> {
+    let lib_dir = global.lib_dir();
+    if !lib_dir.join("package.json").is_file() {
+        return Ok(Vec::new());
+    }
+
+    let mut removed = Vec::new();
+    for name in names {
+        unlink_bins(&global.bin_dir(), &global.package_dir(name))?;
+        if remove_dependency(&lib_dir, name)? {
+            removed.push(name.clone());
+        }
+    }
+    if !removed.is_empty() {
+        sync(global, registry, options, &global_resolution(resolution), reporter)?;
+    }
+    Ok(removed)
+}
+
+/// The global packages, by name
+pub fn list_global(global: &GlobalPrefix) -> Result<Vec<GlobalPackage>> {
+    let lib_dir = global.lib_dir();
+    if !lib_dir.join("package.json").is_file() {
+        return Ok(Vec::new());
+    }
+
+    let mut packages = Vec::new();
+    for (dependency, _) in path_to_dependencies(&lib_dir)? {
+        let manifest = Manifest::read(&global.package_dir(&dependency.name)).ok();
+        let bin_dir = global.bin_dir();
+        let bins = manifest
+            .as_ref()
+            .map(Manifest::bins)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| is_linked(&bin_dir, name))
+            .collect();
+        packages.push(GlobalPackage {
+            version: manifest.and_then(|manifest| manifest.version),
+            name: dependency.name,
+            range: dependency.version,
+            bins,
+        });
+    }
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(packages)
+}
+
+/// Global packages are nobody's workspace and nothing overrides what they depend on
+fn global_resolution(resolution: &ResolutionOptions) -> ResolutionOptions {
+    ResolutionOptions {
+        workspace: Vec::new(),
+        overrides: Vec::new(),
+        ..resolution.clone()
+    }
+}
+
+/// Resolve the global package.json, keeping the locked versions that are still in range, and bring its
+/// node_modules and lockfile in line
+fn sync(
+    global: &GlobalPrefix,
+    registry: &dyn RegistryClient,
+    options: &InstallOptions,
+    resolution: &ResolutionOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<()> {
+    let lib_dir = global.lib_dir();
+    let mut resolution = resolution.clone();
+    for package in read_or_import(&lib_dir)?.map(|lockfile| lockfile.packages).unwrap_or_default().into_values() {
+        let name = package.alias_of.unwrap_or(package.name);
+        resolution.preferred.entry(name).or_default().push(package.version);
+    }
+    let root = path_to_root_dependency(&lib_dir)?;
+    let graph = calculate_depends(&root, &path_to_dependencies(&lib_dir)?, registry, &resolution, reporter)?;
+
+    let node_modules = global.node_modules();
+    create_dir_all(&node_modules).map_err(|err| NaryError::io(&node_modules, err))?;
+    let plan = plan_install(&node_modules, &graph, registry, options)?;
+    execute_plan(&node_modules, &plan, &graph, registry, options, reporter)?;
+
+    let mut lockfile = Lockfile::from_graph(&graph, registry)?;
+    lockfile.record_contents(&node_modules)?;
+    write_lockfile(&lib_dir, &lockfile)
+}
+
+fn is_linked(bin_dir: &Path, name: &str) -> bool {
+    let link = if cfg!(windows) { bin_dir.join(format!("{}.cmd", name)) } else { bin_dir.join(name) };
+    fs::symlink_metadata(link).is_ok()
+}
@@ -0,0 +1,520 @@
+use petgraph::graphmap::DiGraphMap;
+use semver_rs::{Range, Version};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use crate::{Dependency, DependencyKind, Specifier};
+
+pub mod export;
+
+/// Index of a node in a `ResolvedGraph`
+pub type NodeId = usize;
+
+/// A package pinned to the exact version it resolved to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ResolvedNode {
+    /// Where it goes in node_modules
+    pub name: String,
+    pub version: String,
+    /// The registry package behind an `npm:` alias
+    pub alias_of: Option<String>,
+}
+
+impl ResolvedNode {
+    /// The registry package, which differs from `name` for aliases
+    pub fn package(&self) -> &str {
+        self.alias_of.as_deref().unwrap_or(&self.name)
+    }
+
+    /// What to pass to `install_dep` to install exactly this node
+    pub fn dependency(&self) -> Dependency {
+        let version = match &self.alias_of {
+            Some(target) => format!("npm:{}@{}", target, self.version),
+            None => self.version.clone(),
+        };
+
+        Dependency::new(self.name.clone(), version)
+    }
+}
+
+/// `from` depends on `to` through the `range` it asked for
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ResolvedEdge {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub range: String,
+    pub kind: DependencyKind,
+}
+
+/// One step down a `DependencyChain`: the package, and the range its parent asked for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainLink {
+    pub name: String,
+    pub version: String,
+    pub range: String,
+}
+
+/// A route from the root to a package, starting at one of the root's direct dependencies
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DependencyChain {
+    pub links: Vec<ChainLink>,
+}
+
+impl fmt::Display for DependencyChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, link) in self.links.iter().enumerate() {
+            if i > 0 {
+                write!(f, " > ")?;
+            }
+            write!(f, "{}@{} ({})", link.name, link.version, link.range)?;
+        }
+        Ok(())
+    }
+}
+
+/// A version `dedupe` did away with, and the version of the same package that took its place
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergedVersion {
+    pub name: String,
+    pub version: String,
+    pub into: String,
+}
+
+/// What `dedupe` changed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Dedupe {
+    pub merged: Vec<MergedVersion>,
+    /// Every node left out: the merged versions, and whatever only they depended on
+    pub removed: Vec<ResolvedNode>,
+}
+
+/// Where a lockfile says a registry package's tarball came from, and what it must hash to
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LockedTarball {
+    pub resolved: Option<String>,
+    pub integrity: Option<String>,
+}
+
+/// The result of resolution: every package at its exact version, and the ranges that pulled it in
+#[derive(Clone, Debug)]
+pub struct ResolvedGraph {
+    nodes: Vec<ResolvedNode>,
+    edges: Vec<ResolvedEdge>,
+    ids: HashMap<ResolvedNode, NodeId>,
+    order: Vec<NodeId>,
+    /// Nodes the root reaches without going through an optional dependency
+    required: HashSet<NodeId>,
+    /// The registry each registry package was found in
+    registries: HashMap<NodeId, String>,
+    /// The tarball a lockfile pinned each package to, when the graph came from one
+    locked: HashMap<NodeId, LockedTarball>,
+}
+
+impl ResolvedGraph {
+    /// The project being resolved is always the first node
+    pub const ROOT: NodeId = 0;
+
+    pub(crate) fn new(root: ResolvedNode) -> ResolvedGraph {
+        let mut graph = ResolvedGraph {
+            nodes: Vec::new(),
+            edges: Vec::new(),
+            ids: HashMap::new(),
+            order: Vec::new(),
+            required: HashSet::new(),
+            registries: HashMap::new(),
+            locked: HashMap::new(),
+        };
+        graph.add_node(root);
+        graph
+    }
+
+    pub(crate) fn contains(&self, node: &ResolvedNode) -> bool {
+        self.ids.contains_key(node)
+    }
+
+    /// Returns the id of the node, which may have been added before
+    pub(crate) fn add_node(&mut self, node: ResolvedNode) -> NodeId {
+        if let Some(id) = self.ids.get(&node) {
+            return *id;
+        }
+
+        let id = self.nodes.len();
+        self.ids.insert(node.clone(), id);
+        self.nodes.push(node);
+        id
+    }
+
+    pub(crate) fn add_edge(&mut self, from: NodeId, to: NodeId, range: &str, kind: DependencyKind) {
+        let edge = ResolvedEdge {
+            from,
+            to,
+            range: range.to_string(),
+            kind,
+        };
+        if !self.edges.contains(&edge) {
+            self.edges.push(edge);
+        }
+    }
+
+    pub(crate) fn set_registry(&mut self, id: NodeId, registry: String) {
+        self.registries.insert(id, registry);
+    }
+
+    pub(crate) fn set_locked(&mut self, id: NodeId, locked: LockedTarball) {
+        self.locked.insert(id, locked);
+    }
+
+    /// Computes the install order once every node and edge is in place
+    pub(crate) fn finish(&mut self) {
+        let mut graph: DiGraphMap<NodeId, ()> = DiGraphMap::new();
+        for id in 0..self.nodes.len() {
+            graph.add_node(id);
+        }
+        for edge in &self.edges {
+            graph.add_edge(edge.from, edge.to, ());
+        }
+
+        // Tarjan's postorder puts dependencies before their dependents. Members of a cycle can't all come
+        // first, so they are ordered by when they were discovered.
+        self.order = tarjan_scc(&graph)
+            .into_iter()
+            .flat_map(|mut component| {
+                component.sort_unstable();
+                component
+            })
+            .collect();
+
+        self.required.clear();
+        let mut pending = vec![ResolvedGraph::ROOT];
+        while let Some(id) = pending.pop() {
+            if self.required.insert(id) {
+                pending.extend(
+                    self.dependencies(id)
+                        .filter(|edge| edge.kind != DependencyKind::Optional)
+                        .map(|edge| edge.to),
+                );
+            }
+        }
+    }
+
+    pub fn root(&self) -> &ResolvedNode {
+        &self.nodes[ResolvedGraph::ROOT]
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&ResolvedNode> {
+        self.nodes.get(id)
+    }
+
+    pub fn id_of(&self, node: &ResolvedNode) -> Option<NodeId> {
+        self.ids.get(node).copied()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (NodeId, &ResolvedNode)> {
+        self.nodes.iter().enumerate()
+    }
+
+    /// Which registry satisfied a registry package, as far as the registry client knows
+    pub fn registry_of(&self, id: NodeId) -> Option<&str> {
+        self.registries.get(&id).map(String::as_str)
+    }
+
+    /// The tarball the lockfile this graph was read from pinned a package to
+    pub fn locked_tarball_of(&self, node: &ResolvedNode) -> Option<&LockedTarball> {
+        self.locked.get(self.ids.get(node)?)
+    }
+
+    pub fn edges(&self) -> &[ResolvedEdge] {
+        &self.edges
+    }
+
+    /// Number of nodes, including the root
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Always false, the root is a node
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Ids of every resolved version of `name`
+    pub fn find<'a>(&'a self, name: &'a str) -> impl Iterator<Item = NodeId> + 'a {
+        self.nodes()
+            .filter(move |(_, node)| node.name == name)
+            .map(|(id, _)| id)
+    }
+
+    /// Exact versions `name` resolved to
+    pub fn versions_of(&self, name: &str) -> Vec<&str> {
+        self.find(name).map(|id| self.nodes[id].version.as_str()).collect()
+    }
+
+    /// Edges out of `id`, to the packages it depends on
+    pub fn dependencies(&self, id: NodeId) -> impl Iterator<Item = &ResolvedEdge> {
+        self.edges.iter().filter(move |edge| edge.from == id)
+    }
+
+    /// Edges into `id`, from the packages that depend on it
+    pub fn dependents(&self, id: NodeId) -> impl Iterator<Item = &ResolvedEdge> {
+        self.edges.iter().filter(move |edge| edge.to == id)
+    }
+
+    /// Packages that depend on any version of `name` directly
+    pub fn direct_dependents(&self, name: &str) -> Vec<&ResolvedNode> {
+        let mut dependents: Vec<NodeId> = self
+            .find(name)
+            .flat_map(|id| self.dependents(id).map(|edge| edge.from))
+            .collect();
+        dependents.sort_unstable();
+        dependents.dedup();
+        dependents.into_iter().map(|id| &self.nodes[id]).collect()
+    }
+
+    /// Every path from the root to `id` that doesn't visit a node twice, as node ids starting at the root
+    pub fn paths_to(&self, id: NodeId) -> Vec<Vec<NodeId>> {
+        let mut paths = Vec::new();
+        let mut path = vec![id];
+        self.collect_paths(&mut path, &mut paths);
+        paths
+    }
+
+    fn collect_paths(&self, path: &mut Vec<NodeId>, paths: &mut Vec<Vec<NodeId>>) {
+        let head = path[path.len() - 1];
+        if head == ResolvedGraph::ROOT {
+            paths.push(path.iter().rev().copied().collect());
+            return;
+        }
+
+        for edge in self.dependents(head) {
+            if !path.contains(&edge.from) {
+                path.push(edge.from);
+                self.collect_paths(path, paths);
+                path.pop();
+            }
+        }
+    }
+
+    /// Every chain from the root to any version of `package`, one per distinct route and requested range
+    pub fn why(&self, package: &str) -> Vec<DependencyChain> {
+        let mut chains = Vec::new();
+        for id in self.find(package) {
+            for path in self.paths_to(id) {
+                let mut links = Vec::new();
+                self.collect_chains(&path, &mut links, &mut chains);
+            }
+        }
+        chains
+    }
+
+    fn collect_chains(&self, path: &[NodeId], links: &mut Vec<ChainLink>, chains: &mut Vec<DependencyChain>) {
+        if path.len() < 2 {
+            chains.push(DependencyChain { links: links.clone() });
+            return;
+        }
+
+        let (from, to) = (path[0], path[1]);
+        for edge in self.edges.iter().filter(|edge| edge.from == from && edge.to == to) {
+            let node = &self.nodes[to];
+            links.push(ChainLink {
+                name: node.name.clone(),
+                version: node.version.clone(),
+                range: edge.range.clone(),
+            });
+            self.collect_chains(&path[1..], links, chains);
+            links.pop();
+        }
+    }
+
+    /// Optional when every path from the root to `node` goes through an optional dependency
+    pub fn kind_of(&self, node: &ResolvedNode) -> DependencyKind {
+        match self.ids.get(node) {
+            Some(id) if !self.required.contains(id) => DependencyKind::Optional,
+            _ => DependencyKind::Normal,
+        }
+    }
+
+    /// Collapse the versions of each registry package into the fewest that still satisfy every range they're
+    /// depended on with, preferring the highest, and drop what's no longer reachable from the root. Dependencies
+    /// whose range doesn't allow the version they resolved to, like overridden ones, and dist-tags keep their version.
+    pub fn dedupe(&mut self) -> Dedupe {
+        // Versions by the node of the package with its version left out
+        let mut packages: HashMap<ResolvedNode, Vec<(NodeId, Version)>> = HashMap::new();
+        for (id, node) in self.nodes().skip(1) {
+            if let Ok(version) = Version::new(&node.version).parse() {
+                let package = ResolvedNode { version: String::new(), ..node.clone() };
+                packages.entry(package).or_default().push((id, version));
+            }
+        }
+
+        let mut targets: Vec<NodeId> = self.edges.iter().map(|edge| edge.to).collect();
+        for mut versions in packages.into_values().filter(|versions| versions.len() > 1) {
+            versions.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+            // Each dependency on the package, with the versions it may move to
+            let mut pending: Vec<(usize, Vec<NodeId>)> = self
+                .edges
+                .iter()
+                .enumerate()
+                .filter(|(_, edge)| versions.iter().any(|(id, _)| *id == edge.to))
+                .map(|(index, edge)| {
+                    let specifier = Specifier::parse(&edge.range);
+                    let range = specifier.registry_range().and_then(|range| Range::new(range).parse().ok());
+                    let allowed = match range {
+                        Some(range) if versions.iter().any(|(id, version)| *id == edge.to && range.test(version)) => {
+                            versions.iter().filter(|(_, version)| range.test(version)).map(|(id, _)| *id).collect()
+                        }
+                        _ => vec![edge.to],
+                    };
+                    (index, allowed)
+                })
+                .collect();
+
+            // Keep the version most of the rest can move to, the highest on a tie, until they all have one
+            while !pending.is_empty() {
+                let count = |id: &NodeId| pending.iter().filter(|(_, allowed)| allowed.contains(id)).count();
+                let kept = match versions.iter().map(|(id, _)| *id).rev().max_by_key(count) {
+                    Some(kept) => kept,
+                    None => break,
+                };
+                pending.retain(|(index, allowed)| {
+                    if allowed.contains(&kept) {
+                        targets[*index] = kept;
+                    }
+                    !allowed.contains(&kept)
+                });
+            }
+        }
+
+        let used: HashSet<NodeId> = targets.iter().copied().collect();
+        let mut merged = Vec::new();
+        let mut seen = HashSet::new();
+        for (edge, into) in self.edges.iter().zip(&targets) {
+            if !used.contains(&edge.to) && seen.insert(edge.to) {
+                merged.push(MergedVersion {
+                    name: self.nodes[edge.to].name.clone(),
+                    version: self.nodes[edge.to].version.clone(),
+                    into: self.nodes[*into].version.clone(),
+                });
+            }
+        }
+        for (edge, to) in self.edges.iter_mut().zip(targets) {
+            edge.to = to;
+        }
+
+        let removed = self.retain_reachable();
+        Dedupe { merged, removed }
+    }
+
+    /// Drop the nodes the root no longer reaches, numbering the rest again, and return them
+    fn retain_reachable(&mut self) -> Vec<ResolvedNode> {
+        let mut reachable = HashSet::new();
+        let mut pending = vec![ResolvedGraph::ROOT];
+        while let Some(id) = pending.pop() {
+            if reachable.insert(id) {
+                pending.extend(self.dependencies(id).map(|edge| edge.to));
+            }
+        }
+
+        let mut renumbered = HashMap::new();
+        let mut removed = Vec::new();
+        for (id, node) in std::mem::take(&mut self.nodes).into_iter().enumerate() {
+            if reachable.contains(&id) {
+                renumbered.insert(id, self.nodes.len());
+                self.nodes.push(node);
+            } else {
+                removed.push(node);
+            }
+        }
+        let mut edges = Vec::new();
+        for edge in std::mem::take(&mut self.edges) {
+            if let (Some(from), Some(to)) = (renumbered.get(&edge.from), renumbered.get(&edge.to)) {
+                let edge = ResolvedEdge { from: *from, to: *to, ..edge };
+                if !edges.contains(&edge) {
+                    edges.push(edge);
+                }
+            }
+        }
+        self.edges = edges;
+        self.ids = self.nodes.iter().cloned().enumerate().map(|(id, node)| (node, id)).collect();
+        self.registries = std::mem::take(&mut self.registries)
+            .into_iter()
+            .filter_map(|(id, registry)| Some((*renumbered.get(&id)?, registry)))
+            .collect();
+        self.locked = std::mem::take(&mut self.locked)
+            .into_iter()
+            .filter_map(|(id, locked)| Some((*renumbered.get(&id)?, locked)))
+            .collect();
+
+        self.finish();
+        removed
+    }
+
+    /// Packages to install, dependencies before their dependents, without the root
+    pub fn install_order(&self) -> impl Iterator<Item = &ResolvedNode> {
+        self.order
+            .iter()
+            .filter(|id| **id != ResolvedGraph::ROOT)
+            .map(move |id| &self.nodes[*id])
+    }
+}
+
+/// The strongly connected components of a graph in postorder, as `petgraph::algo::tarjan_scc` finds them, but with
+/// a stack of its own rather than recursion, which a long enough chain of dependencies overflows
+fn tarjan_scc(graph: &DiGraphMap<NodeId, ()>) -> Vec<Vec<NodeId>> {
+    let mut index: HashMap<NodeId, usize> = HashMap::new();
+    let mut lowlink: HashMap<NodeId, usize> = HashMap::new();
+    let mut stack = Vec::new();
+    let mut on_stack: HashSet<NodeId> = HashSet::new();
+    let mut components = Vec::new();
+
+    for start in graph.nodes() {
+        if index.contains_key(&start) {
+            continue;
+        }
+        // The nodes being visited, with the neighbors of each still to look at
+        let mut visiting = vec![(start, graph.neighbors(start))];
+        index.insert(start, index.len());
+        lowlink.insert(start, index[&start]);
+        stack.push(start);
+        on_stack.insert(start);
+
+        while let Some((node, neighbors)) = visiting.last_mut() {
+            let node = *node;
+            if let Some(neighbor) = neighbors.next() {
+                match index.get(&neighbor) {
+                    None => {
+                        visiting.push((neighbor, graph.neighbors(neighbor)));
+                        index.insert(neighbor, index.len());
+                        lowlink.insert(neighbor, index[&neighbor]);
+                        stack.push(neighbor);
+                        on_stack.insert(neighbor);
+                    }
+                    Some(&neighbor_index) if on_stack.contains(&neighbor) => {
+                        lowlink.insert(node, lowlink[&node].min(neighbor_index));
+                    }
+                    Some(_) => {}
+                }
+                continue;
+            }
+
+            visiting.pop();
+            if lowlink[&node] == index[&node] {
+                let mut component = Vec::new();
+                while let Some(member) = stack.pop() {
+                    on_stack.remove(&member);
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                components.push(component);
+            }
+            if let Some((parent, _)) = visiting.last() {
+                lowlink.insert(*parent, lowlink[parent].min(lowlink[&node]));
+            }
+        }
+    }
+    components
+}
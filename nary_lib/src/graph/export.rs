@@ -0,0 +1,69 @@
+use serde_json::{json, Map, Value};
+use std::{collections::HashSet, fmt::Write};
+
+use crate::{NodeId, ResolvedGraph};
+
+/// The graph in Graphviz DOT, one node per resolved version, edges labelled with the requested range
+pub fn to_dot(graph: &ResolvedGraph) -> String {
+    let mut dot = String::from("digraph dependencies {\n");
+
+    for (id, node) in graph.nodes() {
+        let label = format!("{}@{}", node.name, node.dependency().version);
+        writeln!(dot, "    n{} [label=\"{}\"];", id, escape(&label)).unwrap();
+    }
+    for edge in graph.edges() {
+        writeln!(dot, "    n{} -> n{} [label=\"{}\"];", edge.from, edge.to, escape(&edge.range)).unwrap();
+    }
+
+    dot.push_str("}\n");
+    dot
+}
+
+/// The graph as nested JSON in the shape of `npm ls --json`. A package already printed higher up is
+/// repeated without its dependencies and marked `deduped`.
+pub fn to_json(graph: &ResolvedGraph) -> Value {
+    let root = graph.root();
+    let mut seen = HashSet::new();
+    seen.insert(ResolvedGraph::ROOT);
+
+    let mut tree = Map::new();
+    tree.insert("name".to_string(), json!(root.name));
+    tree.insert("version".to_string(), json!(root.version));
+    tree.insert(
+        "dependencies".to_string(),
+        Value::Object(subtree(graph, ResolvedGraph::ROOT, &mut seen)),
+    );
+    Value::Object(tree)
+}
+
+fn subtree(graph: &ResolvedGraph, id: NodeId, seen: &mut HashSet<NodeId>) -> Map<String, Value> {
+    let mut dependencies = Map::new();
+
+    for edge in graph.dependencies(id) {
+        let node = graph.node(edge.to).unwrap();
+        if dependencies.contains_key(&node.name) {
+            continue;
+        }
+
+        let mut entry = Map::new();
+        entry.insert("version".to_string(), json!(node.version));
+        if let Some(registry) = graph.registry_of(edge.to) {
+            entry.insert("registry".to_string(), json!(registry));
+        }
+        if seen.insert(edge.to) {
+            let children = subtree(graph, edge.to, seen);
+            if !children.is_empty() {
+                entry.insert("dependencies".to_string(), Value::Object(children));
+            }
+        } else {
+            entry.insert("deduped".to_string(), json!(true));
+        }
+        dependencies.insert(node.name.clone(), Value::Object(entry));
+    }
+
+    dependencies
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
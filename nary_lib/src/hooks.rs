@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use crate::{Dependency, PackumentVersion, ResolvedGraph, Result};
+
+/// Called at points of installation where an embedder may step in, given as `InstallOptions::hooks`, to enforce
+/// policies like allowed licenses or denied package names, or to collect telemetry. An error, like
+/// `NaryError::Refused`, fails the step it came from; for an optional dependency that only leaves the dependency out.
+/// Every method defaults to letting everything through.
+pub trait InstallHooks: Send + Sync {
+    /// A graph is about to be installed, and nothing of it is yet. That's the graph resolved, or the lockfile's when
+    /// it's installed as it is.
+    fn after_resolve(&self, _graph: &ResolvedGraph) -> Result<()> {
+        Ok(())
+    }
+
+    /// A package is about to be unpacked, linked or checked out. `metadata` is the registry version's, None for
+    /// local directories, tarball URLs and git repositories.
+    fn before_extract(&self, _dependency: &Dependency, _metadata: Option<&PackumentVersion>) -> Result<()> {
+        Ok(())
+    }
+
+    /// The package is in place at `path`, before its executables are linked. For a graph install that's where it's
+    /// staged, until every package is ready to be moved into node_modules.
+    fn after_extract(&self, _name: &str, _version: &str, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// A package.json script is about to run `command` in `package_dir` as the `script` stage, like `pretest`, from
+    /// `scripts::run`. Installs never run the lifecycle scripts of packages, so this isn't called during one.
+    fn before_script(&self, _package_dir: &Path, _script: &str, _command: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Every package of the graph is installed in `node_modules`
+    fn on_complete(&self, _node_modules: &Path, _graph: &ResolvedGraph) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Lets everything through
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoHooks;
+
+impl InstallHooks for NoHooks {}
@@ -0,0 +1,93 @@
+use indexmap::IndexMap;
+use serde_json::Value;
+
+use crate::{
+    fetch_matching_version_metadata, parse_spec, NaryError, PackageName, PackumentVersion, RegistryClient,
+    ResolutionOptions, Result,
+};
+
+/// What the registry has about one version of a package
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackageInfo {
+    pub name: String,
+    /// The version the spec resolved to
+    pub version: String,
+    /// The parts of its package.json installs use
+    pub metadata: PackumentVersion,
+    /// The whole package.json of the version, as the registry has it
+    pub manifest: Value,
+    pub dist_tags: IndexMap<String, String>,
+    /// Every version of the package, in the registry's order
+    pub versions: Vec<String>,
+    /// When each version was published, when the registry says. Only its full document does, which isn't read
+    /// for a field other than `time`, nor offline.
+    pub time: IndexMap<String, String>,
+}
+
+/// What `info` found: the whole of the version, or the one field asked for
+#[derive(Clone, Debug, PartialEq)]
+pub enum Info {
+    Package(Box<PackageInfo>),
+    /// None when the version has no such field
+    Field(Option<Value>),
+}
+
+impl PackageInfo {
+    /// A field of the version's package.json by a dotted path like `dist.tarball` or `maintainers.0.name`.
+    /// `versions`, `dist-tags` and `time` are those of the package, as `npm view` has them.
+    pub fn field(&self, path: &str) -> Option<Value> {
+        let mut names = path.split('.').filter(|name| !name.is_empty());
+        let first = names.next()?;
+        let root = match first {
+            "versions" => Value::from(self.versions.clone()),
+            "dist-tags" => serde_json::to_value(&self.dist_tags).ok()?,
+            "time" if self.manifest.get("time").is_none() => serde_json::to_value(&self.time).ok()?,
+            _ => self.manifest.get(first)?.clone(),
+        };
+        names.try_fold(root, |value, name| match value {
+            Value::Array(mut items) => {
+                let index = name.parse::<usize>().ok()?;
+                (index < items.len()).then(|| items.swap_remove(index))
+            }
+            Value::Object(mut fields) => fields.remove(name),
+            _ => None,
+        })
+    }
+}
+
+/// Look up the version of a package a `name@range` or `name@tag` spec asks for, `latest` without one, and return
+/// it whole, or just the field at `field_path` when one is given. The programmatic `npm view`.
+pub fn info(
+    spec: &str,
+    field_path: Option<&str>,
+    registry: &dyn RegistryClient,
+    options: &ResolutionOptions,
+) -> Result<Info> {
+    let dependency = parse_spec(spec).registry_target();
+    let name = PackageName::parse(&dependency.name)?;
+    let packument = registry.packument(&name)?;
+    let (version, metadata) = fetch_matching_version_metadata(&dependency, &packument, options)?;
+    let manifest = registry.version_metadata(&name, version)?;
+    let wants_time = field_path.is_none_or(|path| path.split('.').find(|name| !name.is_empty()) == Some("time"));
+    let time = match wants_time.then(|| registry.full_packument(&name)) {
+        Some(Ok(full)) => full.time,
+        // Offline the whole of it is still shown, only without when versions were published
+        Some(Err(NaryError::NotCached { .. })) if field_path.is_none() => packument.time.clone(),
+        Some(Err(err)) => return Err(err),
+        None => packument.time.clone(),
+    };
+
+    let info = PackageInfo {
+        name: dependency.name.clone(),
+        version: version.clone(),
+        metadata: metadata.clone(),
+        manifest,
+        dist_tags: packument.dist_tags.clone(),
+        versions: packument.versions.keys().cloned().collect(),
+        time,
+    };
+    Ok(match field_path {
+        Some(path) => Info::Field(info.field(path)),
+        None => Info::Package(Box::new(info)),
+    })
+}
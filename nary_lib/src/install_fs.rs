@@ -0,0 +1,574 @@
+use fs2::FileExt;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Cursor, Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::{Condvar, Mutex},
+};
+
+/// What installing does to the filesystem, so the pipeline can run against something other than the disk.
+/// `InstallOptions::fs` gives the one an install uses, for node_modules and the tarballs it caches and locks. The
+/// store, and the packuments and keys of the cache, always stay on disk.
+pub trait InstallFs: Send + Sync {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Write everything `content` reads to a new file at `path`, replacing whatever file or symlink is there rather
+    /// than writing through it. Returns how many bytes it was. Files are `0o644`, or `0o755` when executable, as npm
+    /// has them.
+    fn write_file(&self, path: &Path, content: &mut dyn Read, executable: bool) -> io::Result<u64>;
+
+    /// A symlink at `link` to `target`, which is relative to the link's directory unless it's absolute
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn hard_link(&self, target: &Path, link: &Path) -> io::Result<()>;
+
+    /// The contents of a file, following symlinks
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Like `read`, as the contents are read rather than all at once
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>>;
+
+    /// Something that writes to the end of the file at `path`, which is created when there's none
+    fn append(&self, path: &Path) -> io::Result<Box<dyn Write + '_>>;
+
+    /// The paths of what's in a directory, following a symlink to it
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Remove the file, symlink or directory at `path` with everything below it, doing nothing when there's none
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// The path with every symlink on the way resolved, which has to exist. `RealFs` makes it absolute.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Whether `path` is a file, following symlinks
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// Whether `path` is a directory, following symlinks
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Whether there's anything at `path` itself, a dangling symlink too
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Whether `path` itself is a symlink
+    fn is_symlink(&self, path: &Path) -> bool;
+
+    /// The size of a file, following symlinks
+    fn size(&self, path: &Path) -> io::Result<u64>;
+
+    /// Let everyone run the file, which is left as it is where there's no such thing
+    fn set_executable(&self, path: &Path) -> io::Result<()>;
+
+    /// Blocks until no other thread or process holds the lock at `path`, then holds it until what's returned is
+    /// dropped. It's only advisory: nothing stops anyone from ignoring it.
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn Send + '_>>;
+}
+
+/// The disk
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFs;
+
+impl InstallFs for RealFs {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn write_file(&self, path: &Path, content: &mut dyn Read, executable: bool) -> io::Result<u64> {
+        // A hard link into the store, or a symlink out of the package, is never written through
+        if fs::symlink_metadata(path).is_ok_and(|metadata| !metadata.is_dir()) {
+            fs::remove_file(path)?;
+        }
+        let mut file = File::create(path)?;
+        let written = io::copy(content, &mut file)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(if executable { 0o755 } else { 0o644 }))?;
+        }
+        #[cfg(not(unix))]
+        let _ = executable;
+        Ok(written)
+    }
+
+    #[cfg(unix)]
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, link)
+    }
+
+    /// Windows tells links to directories from links to files
+    #[cfg(windows)]
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        if link.parent().unwrap_or_else(|| Path::new("")).join(target).is_dir() {
+            std::os::windows::fs::symlink_dir(target, link)
+        } else {
+            std::os::windows::fs::symlink_file(target, link)
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn hard_link(&self, target: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(target, link)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn Write + '_>> {
+        Ok(Box::new(OpenOptions::new().create(true).append(true).open(path)?))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?.map(|entry| Ok(entry?.path())).collect()
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+            Ok(_) => fs::remove_file(path),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        fs::symlink_metadata(path).is_ok()
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn set_executable(&self, path: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mut permissions = fs::metadata(path)?.permissions();
+            permissions.set_mode(permissions.mode() | 0o111);
+            fs::set_permissions(path, permissions)?;
+        }
+        #[cfg(not(unix))]
+        let _ = path;
+        Ok(())
+    }
+
+    /// A lock file there, which another process can lock too
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn Send + '_>> {
+        loop {
+            let file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+            file.lock_exclusive()?;
+
+            // The holder this waited for removed the file, and someone else may have locked a new one there already
+            if is_still(&file, path) {
+                return Ok(Box::new(FileLock {
+                    file,
+                    path: path.to_path_buf(),
+                }));
+            }
+        }
+    }
+}
+
+/// A lock file of `RealFs`, released and removed when dropped
+struct FileLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Removed while still held, so a waiter that then gets it can tell it's stale
+        let _ = fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
+/// Whether `path` is still the file that was opened, rather than gone or replaced by another
+fn is_still(file: &File, path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (file.metadata(), fs::metadata(path)) {
+            (Ok(opened), Ok(current)) => (opened.dev(), opened.ino()) == (current.dev(), current.ino()),
+            _ => false,
+        }
+    }
+    // Files that are open can't be removed on Windows
+    #[cfg(not(unix))]
+    {
+        let _ = file;
+        path.exists()
+    }
+}
+
+/// What's at a path of a `MemoryFs`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemoryEntry {
+    Dir,
+    File { content: Vec<u8>, executable: bool },
+    /// Where it points, as it was given
+    Symlink(PathBuf),
+}
+
+/// A filesystem held in memory, for running installs in tests without touching the disk or cleaning up after them.
+/// Paths are taken as they come, relative ones included, with `.` and `..` resolved. A hard link is a copy, as
+/// nothing changes a file once it's installed.
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    entries: Mutex<BTreeMap<PathBuf, MemoryEntry>>,
+    /// The paths locked now, which aren't entries
+    locked: Mutex<HashSet<PathBuf>>,
+    unlocked: Condvar,
+}
+
+/// Symlinks followed on the way to a path before giving up, as Linux does
+const MAX_SYMLINKS: usize = 40;
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// What's at `path` itself, without following a symlink there
+    pub fn entry(&self, path: &Path) -> Option<MemoryEntry> {
+        let entries = self.entries.lock().unwrap();
+        let path = owned(&entries, path).ok()?;
+        entries.get(&path).cloned()
+    }
+
+    /// Every path there's something at, in order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.entries.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+impl InstallFs for MemoryFs {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let path = resolve(&entries, path, true)?;
+        let mut dirs: Vec<&Path> = path.ancestors().filter(|dir| !dir.as_os_str().is_empty()).collect();
+        dirs.reverse();
+        for dir in dirs {
+            match entries.get(dir) {
+                None => {
+                    entries.insert(dir.to_path_buf(), MemoryEntry::Dir);
+                }
+                Some(MemoryEntry::Dir) => {}
+                Some(_) => return Err(not_a_dir(dir)),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_file(&self, path: &Path, content: &mut dyn Read, executable: bool) -> io::Result<u64> {
+        let mut buffer = Vec::new();
+        content.read_to_end(&mut buffer)?;
+        let mut entries = self.entries.lock().unwrap();
+        let path = owned(&entries, path)?;
+        if entries.get(&path) == Some(&MemoryEntry::Dir) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is a directory", path.display())));
+        }
+        let written = buffer.len() as u64;
+        entries.insert(path, MemoryEntry::File { content: buffer, executable });
+        Ok(written)
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let link = owned(&entries, link)?;
+        if entries.contains_key(&link) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists", link.display())));
+        }
+        entries.insert(link, MemoryEntry::Symlink(target.to_path_buf()));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let (from, to) = (owned(&entries, from)?, owned(&entries, to)?);
+        if !entries.contains_key(&from) {
+            return Err(not_found(&from));
+        }
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(&from) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is inside itself", to.display())));
+        }
+        if below(&entries, &to).len() > 1 {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} isn't empty", to.display())));
+        }
+        entries.remove(&to);
+        for path in below(&entries, &from) {
+            let moved = to.join(path.strip_prefix(&from).unwrap_or(&path));
+            if let Some(entry) = entries.remove(&path) {
+                entries.insert(moved, entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn hard_link(&self, target: &Path, link: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let target = resolve(&entries, target, true)?;
+        let link = owned(&entries, link)?;
+        let file = match entries.get(&target) {
+            Some(file @ MemoryEntry::File { .. }) => file.clone(),
+            Some(_) => return Err(io::Error::other(format!("{} isn't a file", target.display()))),
+            None => return Err(not_found(&target)),
+        };
+        if entries.contains_key(&link) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists", link.display())));
+        }
+        entries.insert(link, file);
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let entries = self.entries.lock().unwrap();
+        let path = resolve(&entries, path, true)?;
+        match entries.get(&path) {
+            Some(MemoryEntry::File { content, .. }) => Ok(content.clone()),
+            Some(_) => Err(io::Error::other(format!("{} is a directory", path.display()))),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(Cursor::new(self.read(path)?)))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn Write + '_>> {
+        let mut entries = self.entries.lock().unwrap();
+        let path = resolve(&entries, path, true)?;
+        match entries.get(&path) {
+            Some(MemoryEntry::File { .. }) => {}
+            Some(_) => return Err(io::Error::other(format!("{} is a directory", path.display()))),
+            None => {
+                // Below a directory that has to be there already
+                owned(&entries, &path)?;
+                let content = Vec::new();
+                entries.insert(path.clone(), MemoryEntry::File { content, executable: false });
+            }
+        }
+        Ok(Box::new(MemoryAppend { fs: self, path }))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let entries = self.entries.lock().unwrap();
+        let dir = resolve(&entries, path, true)?;
+        match entries.get(&dir) {
+            Some(MemoryEntry::Dir) => {}
+            Some(_) => return Err(not_a_dir(&dir)),
+            None if dir.as_os_str().is_empty() => {}
+            None => return Err(not_found(&dir)),
+        }
+        let children = below(&entries, &dir).into_iter().filter(|child| child.parent() == Some(&dir));
+        Ok(children.filter_map(|child| Some(path.join(child.file_name()?))).collect())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let path = match owned(&entries, path) {
+            Ok(path) => path,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for path in below(&entries, &path) {
+            entries.remove(&path);
+        }
+        Ok(())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let entries = self.entries.lock().unwrap();
+        let path = resolve(&entries, path, true)?;
+        if !entries.contains_key(&path) {
+            return Err(not_found(&path));
+        }
+        Ok(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        let entries = self.entries.lock().unwrap();
+        let path = resolve(&entries, path, true);
+        matches!(path.map(|path| entries.get(&path)), Ok(Some(MemoryEntry::File { .. })))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        let entries = self.entries.lock().unwrap();
+        let path = resolve(&entries, path, true);
+        matches!(path.map(|path| entries.get(&path)), Ok(Some(MemoryEntry::Dir)))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let entries = self.entries.lock().unwrap();
+        owned(&entries, path).is_ok_and(|path| entries.contains_key(&path))
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        let entries = self.entries.lock().unwrap();
+        let path = owned(&entries, path);
+        matches!(path.map(|path| entries.get(&path)), Ok(Some(MemoryEntry::Symlink(_))))
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        let entries = self.entries.lock().unwrap();
+        let path = resolve(&entries, path, true)?;
+        match entries.get(&path) {
+            Some(MemoryEntry::File { content, .. }) => Ok(content.len() as u64),
+            Some(_) => Ok(0),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn set_executable(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let path = resolve(&entries, path, true)?;
+        match entries.get_mut(&path) {
+            Some(MemoryEntry::File { executable, .. }) => {
+                *executable = true;
+                Ok(())
+            }
+            Some(_) => Err(io::Error::other(format!("{} is a directory", path.display()))),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn Send + '_>> {
+        let path = clean(path);
+        let mut locked = self.locked.lock().unwrap();
+        while locked.contains(&path) {
+            locked = self.unlocked.wait(locked).unwrap();
+        }
+        locked.insert(path.clone());
+        Ok(Box::new(MemoryLock { fs: self, path }))
+    }
+}
+
+/// A lock of a `MemoryFs`, released when dropped
+struct MemoryLock<'a> {
+    fs: &'a MemoryFs,
+    path: PathBuf,
+}
+
+impl Drop for MemoryLock<'_> {
+    fn drop(&mut self) {
+        self.fs.locked.lock().unwrap().remove(&self.path);
+        self.fs.unlocked.notify_all();
+    }
+}
+
+/// Writes to the end of a file of a `MemoryFs`
+struct MemoryAppend<'a> {
+    fs: &'a MemoryFs,
+    path: PathBuf,
+}
+
+impl Write for MemoryAppend<'_> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self.fs.entries.lock().unwrap().get_mut(&self.path) {
+            Some(MemoryEntry::File { content, .. }) => content.extend_from_slice(buffer),
+            _ => return Err(not_found(&self.path)),
+        }
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `.` and `..` resolved without looking at what's there, keeping a `..` that climbs above where a relative path
+/// starts
+fn clean(path: &Path) -> PathBuf {
+    let mut cleaned = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if matches!(cleaned.components().next_back(), Some(Component::Normal(_))) => {
+                cleaned.pop();
+            }
+            component => cleaned.push(component),
+        }
+    }
+    cleaned
+}
+
+/// The path an entry is at once every symlink on the way is followed, and the last one too when `follow_last`
+fn resolve(entries: &BTreeMap<PathBuf, MemoryEntry>, path: &Path, follow_last: bool) -> io::Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    // What's left of the path, the next part last
+    let mut rest: Vec<PathBuf> = clean(path).components().rev().map(|part| PathBuf::from(part.as_os_str())).collect();
+    let mut followed = 0;
+    while let Some(part) = rest.pop() {
+        resolved.push(part);
+        let target = match entries.get(&resolved) {
+            Some(MemoryEntry::Symlink(target)) if follow_last || !rest.is_empty() => target,
+            Some(MemoryEntry::File { .. }) if !rest.is_empty() => return Err(not_a_dir(&resolved)),
+            _ => continue,
+        };
+        followed += 1;
+        if followed > MAX_SYMLINKS {
+            return Err(io::Error::other(format!("{} has too many levels of symlinks", path.display())));
+        }
+        resolved.pop();
+        let linked = clean(&resolved.join(target));
+        rest.extend(linked.components().rev().map(|part| PathBuf::from(part.as_os_str())));
+        resolved = PathBuf::new();
+    }
+    Ok(resolved)
+}
+
+/// Where an entry at `path` itself goes, below a directory that has to be there already
+fn owned(entries: &BTreeMap<PathBuf, MemoryEntry>, path: &Path) -> io::Result<PathBuf> {
+    let path = clean(path);
+    let name = match path.file_name() {
+        Some(name) => name,
+        None => return Ok(path),
+    };
+    let parent = resolve(entries, path.parent().unwrap_or_else(|| Path::new("")), true)?;
+    if !parent.as_os_str().is_empty() && !matches!(entries.get(&parent), Some(MemoryEntry::Dir)) {
+        return Err(not_found(&parent));
+    }
+    Ok(parent.join(name))
+}
+
+/// `path` and everything below it
+fn below(entries: &BTreeMap<PathBuf, MemoryEntry>, path: &Path) -> Vec<PathBuf> {
+    let paths = entries.range(path.to_path_buf()..).map(|(path, _)| path);
+    paths.take_while(|below| below.starts_with(path)).cloned().collect()
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't exist", path.display()))
+}
+
+fn not_a_dir(path: &Path) -> io::Error {
+    io::Error::other(format!("{} isn't a directory", path.display()))
+}
@@ -0,0 +1,130 @@
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bin::link_bins_with, install_deps, pack::link_dir, staging::staged, stats::StatsRecorder, InstallFs, InstallJob,
+    InstallOptions, InstallReporter, InstallStats, Layout, PackageName, RegistryClient, ResolvedGraph, ResolvedNode,
+    Result,
+};
+
+/// The virtual store below node_modules in the isolated layout
+pub const VIRTUAL_STORE_DIR: &str = ".nary";
+
+/// What stays readable of a version in a directory name
+const VERSION_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'.').remove(b'-').remove(b'_').remove(b'+');
+
+/// Install every package of a resolved graph into node_modules, arranged as `options.layout` asks. Packages are
+/// staged and only moved into place once all of them are, and a failure puts back what was there. Returns what it
+/// did.
+pub fn install_graph(
+    node_modules: &Path,
+    graph: &ResolvedGraph,
+    registry: &dyn RegistryClient,
+    options: &InstallOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<InstallStats> {
+    options.hooks.after_resolve(graph)?;
+    let recorder = StatsRecorder::new(graph, reporter);
+    let jobs = graph
+        .install_order()
+        .map(|node| {
+            Ok(InstallJob {
+                path: install_root(node_modules, node, options.layout),
+                package_dir: package_dir(node_modules, node, options.layout)?,
+                dependency: node.dependency(),
+                kind: graph.kind_of(node),
+                locked: graph.locked_tarball_of(node).cloned(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    staged(node_modules, &*options.fs, &recorder, |staging| {
+        recorder.time("extract", || install_deps(staging, &jobs, registry, options, &recorder))?;
+        if options.layout == Layout::Isolated {
+            recorder.time("link", || link_isolated(&*options.fs, node_modules, graph))?;
+        }
+        options.hooks.on_complete(node_modules, graph)
+    })?;
+
+    Ok(recorder.finish())
+}
+
+/// The layout node_modules was installed with, told by whether there's a virtual store, or directories named with
+/// versions, which no package's name has
+pub(crate) fn detect_layout(node_modules: &Path) -> Layout {
+    let versioned = |entry: fs::DirEntry| {
+        let name = entry.file_name();
+        name.to_str().and_then(|name| name.get(1..)).is_some_and(|name| name.contains('@'))
+    };
+    if node_modules.join(VIRTUAL_STORE_DIR).is_dir() {
+        Layout::Isolated
+    } else if fs::read_dir(node_modules).is_ok_and(|entries| entries.flatten().any(versioned)) {
+        Layout::Vendored
+    } else {
+        Layout::Hoisted
+    }
+}
+
+/// The node_modules directory a node's package goes into, or for the vendored layout the directory every package
+/// does
+pub(crate) fn install_root(node_modules: &Path, node: &ResolvedNode, layout: Layout) -> PathBuf {
+    match layout {
+        Layout::Hoisted | Layout::Vendored => node_modules.to_path_buf(),
+        Layout::Isolated => isolated_node_modules(node_modules, node),
+    }
+}
+
+/// The directory a node's package goes into
+pub fn package_dir(node_modules: &Path, node: &ResolvedNode, layout: Layout) -> Result<PathBuf> {
+    match layout {
+        Layout::Vendored => Ok(node_modules.join(versioned_name(node))),
+        _ => Ok(install_root(node_modules, node, layout).join(PackageName::parse(&node.name)?.to_path())),
+    }
+}
+
+/// `<name>@<version>`, with the `/` of a scope as `+` and the version encoded to be a file name
+fn versioned_name(node: &ResolvedNode) -> String {
+    let version = node.dependency().version;
+    format!("{}@{}", node.name.replace('/', "+"), utf8_percent_encode(&version, VERSION_ENCODE_SET))
+}
+
+/// Where a node's own node_modules is in the virtual store. The package is installed inside it, next to links to
+/// its dependencies, so Node's resolution finds exactly those.
+pub fn isolated_node_modules(node_modules: &Path, node: &ResolvedNode) -> PathBuf {
+    let mut path = node_modules.to_path_buf();
+    path.push(VIRTUAL_STORE_DIR);
+    path.push(versioned_name(node));
+    path.push("node_modules");
+    path
+}
+
+/// Link every package of the virtual store to its dependencies, and the root's direct dependencies into
+/// node_modules along with their bins
+pub(crate) fn link_isolated(fs: &dyn InstallFs, node_modules: &Path, graph: &ResolvedGraph) -> Result<()> {
+    for edge in graph.edges() {
+        let (from, to) = match (graph.node(edge.from), graph.node(edge.to)) {
+            (Some(from), Some(to)) => (from, to),
+            _ => continue,
+        };
+
+        let target = isolated_node_modules(node_modules, to).join(PackageName::parse(&to.name)?.to_path());
+        // A skipped optional dependency has nothing to link to
+        if !fs.is_dir(&target) {
+            continue;
+        }
+
+        let parent = if edge.from == ResolvedGraph::ROOT {
+            node_modules.to_path_buf()
+        } else {
+            isolated_node_modules(node_modules, from)
+        };
+        link_dir(fs, &parent.join(PackageName::parse(&to.name)?.to_path()), &target)?;
+        if edge.from == ResolvedGraph::ROOT {
+            link_bins_with(fs, &node_modules.join(".bin"), &target)?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,167 +1,660 @@
-use anyhow::{anyhow, Context, Result};
-
-use hyper::{net::HttpsConnector, Client, Url};
-use hyper_native_tls::NativeTlsClient;
+use hyper::{
+    header::{EntityTag, HttpDate, IfModifiedSince, IfNoneMatch},
+    status::StatusCode,
+    Url,
+};
 use semver_rs::{Range, Version};
-use serde_json::Value;
+use indexmap::IndexMap;
 use std::{
-    collections::{HashSet},
+    cmp::Ordering,
     io::Read,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Mutex,
+    },
+    thread,
 };
-use tar::Archive;
+use tracing::{debug, debug_span, dispatcher, Span};
+
+mod error;
+pub use crate::error::{NaryError, Result};
 
 mod pack;
-use crate::pack::{gunzip, unpack_archive};
+pub use crate::pack::{
+    create_package_tarball, link_package, long_path, pack_files, pack_project, package_files, read_manifest,
+    unpack_package, unpack_stream, PackedProject, INTEGRITY_FILE,
+};
+
+pub mod bin;
+pub use crate::bin::{link_bins, link_bins_into, unlink_bins};
+
+mod name;
+pub use crate::name::PackageName;
+
+mod config;
+pub use crate::config::{
+    Credentials, CredentialsCallback, FetchPolicy, RegistryConfig, DEFAULT_REGISTRY, NPM_TOKEN_VAR,
+};
+
+mod packument;
+pub use crate::packument::{
+    corgi_accept, Dist, DistAttestations, DistSignature, Packument, PackumentVersion, CORGI_MEDIA_TYPE,
+};
+
+mod reporter;
+pub use crate::reporter::{InstallReporter, SilentReporter, TerminalReporter};
+
+mod hooks;
+pub use crate::hooks::{InstallHooks, NoHooks};
+
+mod install_fs;
+pub use crate::install_fs::{InstallFs, MemoryEntry, MemoryFs, RealFs};
+
+mod staging;
+pub use crate::staging::STAGING_DIR;
+use crate::staging::Staging;
+
+mod stats;
+pub use crate::stats::{InstallStats, Phase};
+
+mod platform;
+pub use crate::platform::{Engines, Platform};
+
+mod options;
+pub use crate::options::{Freshness, InstallOptions, InstallStrategy, Layout, ResolutionOptions, ResolutionStrategy};
+
+pub mod git;
+pub use crate::git::{GitReference, GitSpec};
+
+mod registry;
+pub use crate::registry::{HttpRegistry, MemoryRegistry, RegistryClient};
+
+pub mod cache;
+pub use crate::cache::{
+    cache, get_cache_dir, read_cached_packument, read_packument_validators, write_cached_packument,
+    write_packument_validators, CacheValidators, PATH_SEGMENT_ENCODE_SET,
+};
+
+pub mod store;
+
+pub mod verify;
+pub use crate::verify::{fetch_signing_keys, verify_version, Enforcement, SigningKey, VerifyPolicy};
+
+pub mod layout;
+pub use crate::layout::install_graph;
+
+pub mod tree;
+pub use crate::tree::{read_tree, InstalledTree};
+
+pub mod plan;
+pub use crate::plan::{execute_plan, plan_install, prune, InstallPlan, InstalledPackage, PlannedPackage};
+
+pub mod lockfile;
+pub use crate::lockfile::{
+    import_npm, import_pnpm, import_yarn, install_frozen, read_lockfile, read_or_import, verify_install, write_lockfile,
+    LockedDependency, LockedPackage, Lockfile, Mismatch, MismatchReason, Verification, LOCKFILE,
+};
+
+pub mod audit;
+pub use crate::audit::{audit, fetch_advisories, Advisory, AuditReport, Severity, Vulnerability};
+
+#[cfg(feature = "nonblocking")]
+pub mod nonblocking;
+
+pub mod dist_tags;
+
+pub mod publish;
+pub use crate::publish::{publish, publish_payload, Publication, PublishOptions};
+
+pub mod info;
+pub use crate::info::{info, Info, PackageInfo};
+
+pub mod search;
+pub use crate::search::{
+    fetch_search, parse_search, search, Maintainer, SearchOptions, SearchResult, SearchResults, SearchScore,
+};
+
+pub mod unpublish;
+pub use crate::unpublish::{deprecate, unpublish, PendingUnpublish};
+
+pub mod scripts;
+
+pub mod link;
+pub use crate::link::{links_dir, use_link, Link, LINKS_DIR_VAR};
+
+pub mod global;
+pub use crate::global::{install_global, list_global, uninstall_global, GlobalPackage, GlobalPrefix};
+
+pub mod add;
+pub use crate::add::{add, Added};
+
+pub mod uninstall;
+pub use crate::uninstall::{uninstall, Uninstall};
 
-mod cache;
-pub use crate::cache::{cache, get_cache_dir, PATH_SEGMENT_ENCODE_SET};
+pub mod update;
+pub use crate::update::{update, Update, UpdatedPackage};
+
+pub mod workspace;
+pub use crate::workspace::{
+    find_workspace, project_dependencies, workspace_of, workspace_range, Workspace, WorkspaceMember,
+};
+
+pub mod manifest;
+pub use crate::manifest::{add_dependency, init, parse_spec, remove_dependency, Bin, InitOptions, Manifest};
+
+pub mod outdated;
+pub use crate::outdated::{outdated, OutdatedDependency};
+
+pub mod licenses;
+pub use crate::licenses::{collect_licenses, LicensePolicy, LicenseReport, PackageLicense};
+
+pub mod peers;
+pub use crate::peers::{check_peers, PeerIssue};
+
+pub mod overrides;
+pub use crate::overrides::{read_overrides, Override, PackageSelector};
 
 pub mod deps;
-pub use deps::{calculate_depends, path_to_root_dependency, path_to_dependencies, Dependency};
+pub use deps::{
+    calculate_depends, DependencyKind, path_to_root_dependency, path_to_dependencies, Dependency,
+    ConflictingRequirement, ResolutionConflict,
+};
+
+mod specifier;
+pub use crate::specifier::Specifier;
+
+pub mod graph;
+pub use crate::graph::{
+    ChainLink, Dedupe, DependencyChain, LockedTarball, MergedVersion, NodeId, ResolvedEdge, ResolvedGraph,
+    ResolvedNode,
+};
 
 use percent_encoding::utf8_percent_encode;
-use static_init::{dynamic};
 
-#[dynamic]
-static CLIENT_CONNECTOR: Client = Client::with_connector(HttpsConnector::new(NativeTlsClient::new().unwrap()));
+/// Install `dep` below the node_modules at `path`, linking its executables into `.bin`. An optional dependency
+/// that fails is reported as a warning and left out.
+pub fn install_dep(
+    path: &Path,
+    dep: &Dependency,
+    kind: DependencyKind,
+    registry: &dyn RegistryClient,
+    options: &InstallOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<()> {
+    let installed = dep
+        .package_name()
+        .map(|name| path.join(name.to_path()))
+        .and_then(|package_dir| install_package(&package_dir, &package_dir, dep, None, registry, options, reporter))
+        .and_then(|installed| bin::link_bins_with(&*options.fs, &path.join(".bin"), &installed).map(|_| ()));
+    skip_failed_optional(installed, dep, kind, reporter)
+}
 
-static REGISTRY: &'static str = "https://registry.npmjs.org";
-// static REGISTRY: &'static str = "http://127.0.0.1:5080";
+/// A package for `install_deps` to install into `package_dir`, linking its executables into the `.bin` of `path`
+pub(crate) struct InstallJob {
+    pub(crate) path: PathBuf,
+    pub(crate) package_dir: PathBuf,
+    pub(crate) dependency: Dependency,
+    pub(crate) kind: DependencyKind,
+    /// What the lockfile pinned the package's tarball to, which wins over what the registry says now
+    pub(crate) locked: Option<LockedTarball>,
+}
 
-pub fn install_dep(path: &Path, dep: &Dependency) -> Result<()> {
-    let required_version = Range::new(&dep.version)
-        .parse()
-        .with_context(|| format!("Version {} of {} didn't parse", dep.version, dep.name))?;
-
-    if dep.version.starts_with("git://") {
-        use git2::Repository;
-        let mut path = path.clone().to_path_buf();
-        path.push(dep.name.clone());
-
-        if let Some(x) = dep.version.rfind('#') {
-            let (repo, hash) = dep.version.split_at(x);
-            let repo_cloned = Repository::clone(repo, &path)?;
-            let mut hash = hash.clone().to_string();
-            hash.remove(0);
-            println!("hash: {}", hash);
-            let obj = repo_cloned.revparse_single(&hash)?;
-            repo_cloned.checkout_tree(&obj, None)?;
-        } else {
-            Repository::clone(&dep.version, &path)?;
+/// Install packages as `install_dep` does, unpacking up to `options.parallelism()` at once. Packages that go into
+/// the same directory are installed one after another in the order given, and executables are linked in that
+/// order once everything is unpacked, so node_modules ends up as if they'd been installed one by one. The vendored
+/// layout links none.
+pub(crate) fn install_deps(
+    staging: &mut Staging,
+    jobs: &[InstallJob],
+    registry: &dyn RegistryClient,
+    options: &InstallOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<()> {
+    let staged: Vec<PathBuf> = (0..jobs.len()).map(|index| staging.path(&index.to_string())).collect();
+    let mut lanes: IndexMap<PathBuf, Vec<usize>> = IndexMap::new();
+    for (index, job) in jobs.iter().enumerate() {
+        lanes.entry(job.package_dir.clone()).or_default().push(index);
+    }
+    let workers = options.parallelism().min(lanes.len());
+    let lanes = Mutex::new(lanes.into_values());
+    let results: Vec<Mutex<Option<Result<PathBuf>>>> = jobs.iter().map(|_| Mutex::new(None)).collect();
+    let failed = AtomicBool::new(false);
+
+    // Workers log to whatever the caller logs to, within its span
+    let dispatch = dispatcher::get_default(|dispatch| dispatch.clone());
+    let span = Span::current();
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                dispatcher::with_default(&dispatch, || {
+                    let _entered = span.enter();
+                    loop {
+                        let lane = match lanes.lock().unwrap().next() {
+                            Some(lane) => lane,
+                            None => break,
+                        };
+                        for index in lane {
+                            // No use unpacking more once the install has failed
+                            if failed.load(AtomicOrdering::SeqCst) {
+                                return;
+                            }
+                            let job = &jobs[index];
+                            let installed = install_package(
+                                &job.package_dir,
+                                &staged[index],
+                                &job.dependency,
+                                job.locked.as_ref(),
+                                registry,
+                                options,
+                                reporter,
+                            );
+                            if installed.is_err() && job.kind != DependencyKind::Optional {
+                                failed.store(true, AtomicOrdering::SeqCst);
+                            }
+                            *results[index].lock().unwrap() = Some(installed);
+                        }
+                    }
+                })
+            });
+        }
+    });
+
+    // Each directory gets the last package staged for it, or keeps what's there when that's up to date
+    let mut moves: IndexMap<PathBuf, String> = IndexMap::new();
+    let mut installed = Vec::new();
+    for (index, (job, result)) in jobs.iter().zip(results).enumerate() {
+        // Left alone after another failed, which is returned below
+        match result.into_inner().unwrap() {
+            Some(Ok(path)) if path == staged[index] => {
+                moves.insert(job.package_dir.clone(), index.to_string());
+                installed.push(job);
+            }
+            Some(Ok(_)) => {
+                moves.shift_remove(&job.package_dir);
+                installed.push(job);
+            }
+            Some(Err(err)) => skip_failed_optional(Err(err), &job.dependency, job.kind, reporter)?,
+            None => {}
         }
-        return Ok(())
     }
+    staging.swap(moves.into_iter().map(|(package_dir, name)| (name, package_dir)))?;
 
-    let metadata = fetch_package_root_metadata(&dep)?;
+    if options.layout == Layout::Vendored {
+        return Ok(());
+    }
+    for job in installed {
+        let linked = bin::link_bins_with(&*options.fs, &job.path.join(".bin"), &job.package_dir).map(|_| ());
+        skip_failed_optional(linked, &job.dependency, job.kind, reporter)?;
+    }
+    Ok(())
+}
 
-    let versions = &metadata["versions"]
-        .as_object()
-        .ok_or(anyhow!("Versions was not a JSON object"))?;
+/// An optional dependency that failed to install is reported as a warning and left out
+fn skip_failed_optional(
+    installed: Result<()>,
+    dep: &Dependency,
+    kind: DependencyKind,
+    reporter: &dyn InstallReporter,
+) -> Result<()> {
+    match installed {
+        Err(err) if kind == DependencyKind::Optional => {
+            reporter.on_warning(&format!("Skipping optional dependency {}@{}: {}", dep.name, dep.version, err));
+            Ok(())
+        }
+        installed => installed,
+    }
+}
 
-    let mut next_paths: HashSet<PathBuf> = HashSet::new();
-    for version in versions.iter().rev() {
-        if required_version.test(
-            &Version::new(version.0.as_str())
-                .parse()
-                .with_context(|| format!("{} didn't parse", version.0))?,
-        ) {
-            let dist = &version.1["dist"];
+/// Install `dep` for `package_dir` into `into`, which is the same unless it's staged elsewhere first, returning
+/// where it is. A package that's up to date is left in `package_dir`. A registry package comes from the tarball
+/// `locked` pins it to, and must hash to the integrity it was locked with.
+fn install_package(
+    package_dir: &Path,
+    into: &Path,
+    dep: &Dependency,
+    locked: Option<&LockedTarball>,
+    registry: &dyn RegistryClient,
+    options: &InstallOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<PathBuf> {
+    let name = dep.package_name()?;
+    let hooks = &options.hooks;
+    let _span = debug_span!("install", name = %dep.name, version = %dep.version).entered();
+
+    let specifier = dep.specifier();
+    if let Specifier::File(_) | Specifier::Git(_) | Specifier::Url(_) = specifier {
+        hooks.before_extract(dep, None)?;
+    }
+    match specifier {
+        Specifier::File(local) => {
+            let path = pack::link_dir(&*options.fs, into, local)?;
+            hooks.after_extract(&dep.name, &dep.version, &path)?;
+            reporter.on_unpack(&dep.name, &dep.version, package_dir);
+            return Ok(path);
+        }
+        Specifier::Git(spec) => {
+            let path = into.to_path_buf();
+            git::checkout(spec, &path)?;
+            hooks.after_extract(&dep.name, &dep.version, &path)?;
+            reporter.on_unpack(&dep.name, &dep.version, package_dir);
+            return Ok(path);
+        }
+        Specifier::Url(url) => {
+            let tarball_url = parse_url(url)?;
+            let path = refetching(registry, &name, &dep.version, &tarball_url, reporter, |again| {
+                let tarball = registry.tarball_reader(&name, &dep.version, &tarball_url, reporter)?;
+                place_package(into, again, tarball, &tarball_url, options, reporter)
+            })?;
+            hooks.after_extract(&dep.name, &dep.version, &path)?;
+            reporter.on_unpack(&dep.name, &dep.version, package_dir);
+            return Ok(path);
+        }
+        _ => {}
+    }
 
-            let tarball_url = Url::parse(
-                &dist["tarball"]
-                    .as_str()
-                    .ok_or(anyhow!("tarball URL didn't convert to string"))?,
-            )
-            .context("Couldn't parse URL")?;
+    // An alias installs its target's tarball under its own name
+    let target = dep.registry_target();
+    let package = target.package_name()?;
 
-            let tarball = gunzip(cache(&dep.name, &version.0, &tarball_url)?, &tarball_url)?;
-            let mut archive = Archive::new(tarball.as_slice());
+    let packument = registry.packument(&package)?;
+    let (version, metadata) = fetch_matching_version_metadata(&target, &packument, &ResolutionOptions::default())?;
+    let mut dist = metadata.dist.clone();
+    if let Some(locked) = locked {
+        dist.tarball = locked.resolved.clone().unwrap_or(dist.tarball);
+        dist.integrity = locked.integrity.clone().or(dist.integrity);
+    }
+    let tarball_url = parse_url(&dist.tarball)?;
 
-            let mut path = path.to_path_buf();
-            path.push(&dep.name);
+    let identity = dist.identity();
+    if pack::is_up_to_date(&*options.fs, package_dir, version, &identity) {
+        debug!(version = %version, "up to date");
+        reporter.on_up_to_date(&dep.name, version, package_dir);
+        return Ok(package_dir.to_path_buf());
+    }
 
-            unpack_archive(&mut archive, &path, &tarball_url)?;
+    hooks.before_extract(dep, Some(metadata))?;
+    verify_version(&package, version, &metadata.dist, registry, reporter)?;
+    let path = refetching(registry, &package, version, &tarball_url, reporter, |again| {
+        let tarball = registry.dist_tarball_reader(&package, version, &dist, reporter)?;
+        let tarball = verify::CheckedReader::new(tarball, &identity);
+        place_package(into, again, tarball, &tarball_url, options, reporter)
+    })?;
+    pack::record_identity(&*options.fs, &path, &identity)?;
+    hooks.after_extract(&dep.name, version, &path)?;
+    reporter.on_unpack(&dep.name, version, package_dir);
+
+    Ok(path)
+}
 
-            next_paths.insert(path);
+/// Install a tarball with `place`, and when it doesn't unpack or match its integrity, drop it from the registry's
+/// cache and `place` it once more, told it's downloaded again. A tarball that's corrupt again is quarantined.
+fn refetching<T>(
+    registry: &dyn RegistryClient,
+    name: &PackageName,
+    version: &str,
+    tarball_url: &Url,
+    reporter: &dyn InstallReporter,
+    mut place: impl FnMut(bool) -> Result<T>,
+) -> Result<T> {
+    let reason = match place(false) {
+        Err(NaryError::UnpackError { reason, .. }) if registry.evict_tarball(name, version, tarball_url)? => reason,
+        placed => return placed,
+    };
+    reporter.on_warning(&format!("The cached tarball of {}@{} {}, downloading it again", name, version, reason));
+
+    let err = match place(true) {
+        Err(err @ NaryError::UnpackError { .. }) => err,
+        placed => return placed,
+    };
+    let reason = match &err {
+        NaryError::UnpackError { reason, source: Some(source), .. } => format!("{}: {}", reason, source),
+        NaryError::UnpackError { reason, .. } => reason.clone(),
+        _ => err.to_string(),
+    };
+    match registry.quarantine_tarball(name, version, tarball_url, &reason)? {
+        Some(quarantined) => Err(NaryError::CorruptTarball {
+            package: format!("{}@{}", name, version),
+            reason,
+            quarantined,
+        }),
+        None => Err(err),
+    }
+}
 
-            break;
-        }
+/// Unpack a tarball into the package's directory as it streams in, or link it from the store, as the install
+/// strategy asks. A tarball downloaded `again` starts from an emptied directory, rid of what the first one left.
+fn place_package(
+    package_dir: &Path,
+    again: bool,
+    tarball: impl Read,
+    tarball_url: &Url,
+    options: &InstallOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<PathBuf> {
+    if again {
+        options.fs.remove(package_dir).map_err(|err| NaryError::io(package_dir, err))?;
+    }
+    if options.strategy == InstallStrategy::Extract {
+        pack::extract_stream(tarball, package_dir, tarball_url, &*options.fs, reporter)?;
+        return Ok(package_dir.to_path_buf());
     }
 
-    Ok(())
+    let stored = store::add_to_store(tarball, tarball_url, reporter)?;
+    store::link_from_store(&stored, package_dir, options.strategy)?;
+    Ok(package_dir.to_path_buf())
 }
 
 /// Metadata for a specific version of a package
-pub fn fetch_package_version_metadata(dep: &Dependency, version: &String) -> Result<serde_json::Value> {
-    let ssl = NativeTlsClient::new().context("Unable to create a NativeTlsClient")?;
-    let connector = HttpsConnector::new(ssl);
-    let client = Client::with_connector(connector);
+pub fn fetch_package_version_metadata(
+    dep: &Dependency,
+    version: &str,
+    config: &RegistryConfig,
+    options: &InstallOptions,
+) -> Result<serde_json::Value> {
+    let name = dep.package_name()?;
+    let _span = debug_span!("version_metadata", name = %name, version).entered();
+
+    if options.use_cached_metadata() {
+        // Not from the cached packument, which is abbreviated and leaves out most of what a version has
+        if let Some(body) = cache::read_cached_version(&name, version)? {
+            debug!("from the cache");
+            return serde_json::from_str(&body)
+                .map_err(|err| NaryError::json(format!("cached metadata of {}@{}", name, version), err));
+        }
+        if options.offline {
+            return Err(NaryError::NotCached { what: format!("Metadata for {}@{}", name, version) });
+        }
+    }
 
-    let url = format!("{}/{}/{}", REGISTRY,
-        utf8_percent_encode(&dep.name, PATH_SEGMENT_ENCODE_SET), utf8_percent_encode(&version, PATH_SEGMENT_ENCODE_SET));
+    from_registries(config, &name, |registry| {
+        let encoded = utf8_percent_encode(version, PATH_SEGMENT_ENCODE_SET);
+        let url = format!("{}/{}/{}", registry, name.registry_path(), encoded);
 
-    let mut body = String::new();
+        let mut body = String::new();
+        config.fetch(&url, |request| request)?
+            .read_to_string(&mut body)
+            .map_err(|err| NaryError::network(&url, err))?;
+        debug!(url = %url, bytes = body.len(), "fetched");
 
-    client
-        .get(&url)
-        .send()
-        .with_context(|| format!("Couldn't GET URL: {}", url))?
-        .read_to_string(&mut body)
-        .with_context(|| format!("Couldn't ready body of: {}", url))?;
+        let metadata = serde_json::from_str(&body).map_err(|err| NaryError::json(&url, err))?;
+        cache::write_cached_version(&name, version, &body)?;
+        Ok(metadata)
+    })
+}
 
-    let metadata: Value = serde_json::from_str(&body)
-        .with_context(|| format!("Couldn't JSON parse metadata from {}", url))?;
+/// Metadata for all versions, in the abbreviated packument format
+///
+/// A cached packument is revalidated with `If-None-Match`/`If-Modified-Since`, so its body is only
+/// downloaded again when it changed.
+pub fn fetch_package_root_metadata(
+    dep: &Dependency,
+    config: &RegistryConfig,
+    options: &InstallOptions,
+) -> Result<Packument> {
+    let name = dep.package_name()?;
+    let _span = debug_span!("packument", name = %name).entered();
+    let cached = read_cached_packument(&name)?;
+    let validators = match cached {
+        Some(_) => read_packument_validators(&name)?,
+        None => CacheValidators::default(),
+    };
+    let parse_cached = |body: &str| {
+        serde_json::from_str(body)
+            .map(|packument| Packument { registry: validators.registry.clone(), ..packument })
+            .map_err(|err| NaryError::json(format!("cached metadata of {}", name), err))
+    };
+
+    if let Some(body) = &cached {
+        if options.trusts_cached(&validators) {
+            debug!("from the cache");
+            return parse_cached(body);
+        }
+    }
+    if options.offline {
+        return Err(NaryError::NotCached { what: format!("Metadata for {}", name) });
+    }
 
-    Ok(metadata)
-}
+    let etag = validators.etag.as_ref().and_then(|etag| etag.parse::<EntityTag>().ok());
+    let last_modified = validators.last_modified.as_ref().and_then(|date| date.parse::<HttpDate>().ok());
+
+    from_registries(config, &name, |registry| {
+        let url = format!("{}/{}", registry, name.registry_path());
+        let mut response = config.fetch(&url, |mut request| {
+            request = request.header(corgi_accept());
+            if let Some(etag) = &etag {
+                request = request.header(IfNoneMatch::Items(vec![etag.clone()]));
+            }
+            if let Some(date) = &last_modified {
+                request = request.header(IfModifiedSince(*date));
+            }
+            request
+        })?;
+
+        if response.status == StatusCode::NotModified {
+            if let Some(body) = &cached {
+                write_packument_validators(&name, &validators.revalidated())?;
+                debug!(url = %url, "not modified");
+                return parse_cached(body);
+            }
+        }
+
+        let mut body = String::new();
+        response
+            .read_to_string(&mut body)
+            .map_err(|err| NaryError::network(&url, err))?;
+        debug!(url = %url, bytes = body.len(), "fetched");
 
-/// Metadata for all versions
-pub fn fetch_package_root_metadata(dep: &Dependency) -> Result<serde_json::Value> {
-    let url = format!("{}/{}", REGISTRY, utf8_percent_encode(&dep.name, PATH_SEGMENT_ENCODE_SET));
+        let packument: Packument = serde_json::from_str(&body).map_err(|err| NaryError::json(&url, err))?;
 
-    let mut body = String::new();
+        let validators = CacheValidators {
+            registry: Some(registry.to_string()),
+            ..CacheValidators::from_headers(&response.headers)
+        };
+        write_cached_packument(&name, &body, &validators)?;
+
+        Ok(Packument { registry: Some(registry.to_string()), ..packument })
+    })
+}
+
+/// The dist-tags of a package, like `latest` and `next`, mapped to the versions they point at
+pub fn fetch_dist_tags(
+    dep: &Dependency,
+    config: &RegistryConfig,
+    options: &InstallOptions,
+) -> Result<IndexMap<String, String>> {
+    let name = dep.package_name()?;
+
+    if options.use_cached_metadata() {
+        if let Some(body) = read_cached_packument(&name)? {
+            let packument: Packument = serde_json::from_str(&body)
+                .map_err(|err| NaryError::json(format!("cached metadata of {}", name), err))?;
+            return Ok(packument.dist_tags);
+        }
+        if options.offline {
+            return Err(NaryError::NotCached { what: format!("Dist-tags of {}", name) });
+        }
+    }
 
-    CLIENT_CONNECTOR
-        .get(&url)
-        .send()
-        .with_context(|| format!("Couldn't GET URL: {}", url))?
-        .read_to_string(&mut body)
-        .with_context(|| format!("Couldn't ready body of: {}", url))?;
+    from_registries(config, &name, |registry| {
+        let url = format!("{}/-/package/{}/dist-tags", registry, name.registry_path());
 
-    let body: Value = serde_json::from_str(&body)
-        .with_context(|| format!("Couldn't JSON parse metadata from {}", url))?;
+        let mut body = String::new();
+        config.fetch(&url, |request| request)?
+            .read_to_string(&mut body)
+            .map_err(|err| NaryError::network(&url, err))?;
 
-    Ok(body)
+        serde_json::from_str(&body).map_err(|err| NaryError::json(&url, err))
+    })
 }
 
-pub fn fetch_matching_version_metadata<'a>(dep: &'a Dependency, root_metadata: &'a serde_json::Value) -> Result<(&'a String, &'a Value)> {
-    let required_version = Range::new(&dep.version)
-        .parse()
-        .with_context(|| format!("Version {} of {} didn't parse", dep.version, dep.name))?;
-
-    let versions = &root_metadata["versions"]
-        .as_object()
-        .ok_or(anyhow!("Versions was not a JSON object"))?;
-
-    for version in versions.iter().rev() {
-        if required_version.test(
-            &Version::new(version.0.as_str())
-                .parse()
-                .with_context(|| format!("{} didn't parse", version.0))?,
-        ) {
-            // let dist = &version.1["dist"];
-
-            // let tarball_url = Url::parse(
-            //     &dist["tarball"]
-            //         .as_str()
-            //         .ok_or(anyhow!("tarball URL didn't convert to string"))?,
-            // )
-            // .context("Couldn't parse URL")?;
-
-            return Ok(version);
+/// The first success of `fetch` over the package's registries in order, or the last failure
+fn from_registries<T, F>(config: &RegistryConfig, name: &PackageName, mut fetch: F) -> Result<T>
+where
+    F: FnMut(&str) -> Result<T>,
+{
+    let mut registries = config.registries_for(name).into_iter();
+    let mut result = fetch(registries.next().unwrap_or(DEFAULT_REGISTRY));
+    for registry in registries {
+        if result.is_ok() {
+            break;
         }
+        result = fetch(registry);
     }
+    result
+}
+
+/// The version `dep` asks for: the one a dist-tag points at, `latest` when no version is given, or otherwise the
+/// highest one in range by semver order
+pub fn fetch_matching_version_metadata<'a>(
+    dep: &'a Dependency,
+    packument: &'a Packument,
+    options: &ResolutionOptions,
+) -> Result<(&'a String, &'a PackumentVersion)> {
+    let tag = match dep.version.trim() {
+        "" => "latest",
+        tag => tag,
+    };
+    if let Some(tagged) = packument.dist_tags.get(tag).and_then(|version| packument.versions.get_key_value(version)) {
+        return Ok(tagged);
+    }
+
+    let required_version = parse_range(dep, options)?;
+
+    // Versions that aren't valid semver can't be in any range. Those equal but for build metadata are told apart by
+    // name, so the registry's order of them doesn't matter.
+    let best = packument
+        .versions
+        .iter()
+        .filter_map(|version| Some((Version::new(version.0).with_options(options.semver()).parse().ok()?, version)))
+        .filter(|(parsed, _)| required_version.test(parsed))
+        .max_by(|(a, version_a), (b, version_b)| semver_order(a, b).then_with(|| version_a.0.cmp(version_b.0)));
+
+    best.map(|(_, version)| version).ok_or_else(|| NaryError::NoMatchingVersion {
+        name: dep.name.clone(),
+        range: dep.version.clone(),
+        available: packument.versions.keys().cloned().collect(),
+    })
+}
 
-    Err(anyhow!("ho matching version"))
-}
\ No newline at end of file
+pub(crate) fn semver_order(a: &Version, b: &Version) -> Ordering {
+    a.partial_cmp(b).unwrap_or(Ordering::Equal)
+}
+
+pub(crate) fn parse_url(url: &str) -> Result<Url> {
+    Url::parse(url).map_err(|source| NaryError::InvalidUrl {
+        url: url.to_string(),
+        source,
+    })
+}
+
+fn parse_range(dep: &Dependency, options: &ResolutionOptions) -> Result<Range> {
+    Range::new(&dep.version)
+        .with_options(options.semver())
+        .parse()
+        .map_err(|source| NaryError::VersionParse {
+            name: dep.name.clone(),
+            version: dep.version.clone(),
+            source,
+        })
+}
@@ -0,0 +1,223 @@
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    layout::detect_layout, plan::placements, tree::entries, workspace::wildcard_matches, InstallHooks, NaryError,
+    RealFs, ResolvedGraph, Result,
+};
+
+/// What a package without a `license` is listed and matched as
+pub const UNKNOWN_LICENSE: &str = "UNKNOWN";
+
+/// The license of an installed package
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PackageLicense {
+    pub name: String,
+    pub version: String,
+    /// The SPDX expression of its `license`, or of the old `licenses` list, None when it has neither
+    pub license: Option<String>,
+    /// LICENSE, LICENCE and COPYING files in the package directory, by name
+    pub files: Vec<String>,
+    pub path: PathBuf,
+}
+
+impl PackageLicense {
+    /// The license, or `UNKNOWN`
+    pub fn expression(&self) -> &str {
+        self.license.as_deref().unwrap_or(UNKNOWN_LICENSE)
+    }
+}
+
+/// The licenses of every package of a graph that's installed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LicenseReport {
+    /// By name, then version
+    pub packages: Vec<PackageLicense>,
+}
+
+impl LicenseReport {
+    /// The packages under each license expression
+    pub fn by_license(&self) -> BTreeMap<&str, Vec<&PackageLicense>> {
+        let mut licenses: BTreeMap<&str, Vec<&PackageLicense>> = BTreeMap::new();
+        for package in &self.packages {
+            licenses.entry(package.expression()).or_default().push(package);
+        }
+        licenses
+    }
+}
+
+/// Read the license of each installed package of the graph from its package.json and directory. Packages that
+/// aren't in node_modules, like optional ones that were left out, aren't reported.
+pub fn collect_licenses(node_modules: &Path, graph: &ResolvedGraph) -> Result<LicenseReport> {
+    let mut packages = Vec::new();
+    for (_, node, path) in placements(node_modules, graph, detect_layout(node_modules))? {
+        if let Some(package) = read_license(&node.name, &node.version, &path)? {
+            packages.push(package);
+        }
+    }
+    packages.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    Ok(LicenseReport { packages })
+}
+
+fn read_license(name: &str, version: &str, package_dir: &Path) -> Result<Option<PackageLicense>> {
+    let manifest_path = package_dir.join("package.json");
+    let manifest = match fs::read_to_string(&manifest_path) {
+        Ok(manifest) => manifest,
+        Err(_) => return Ok(None),
+    };
+    let manifest: Value = serde_json::from_str(&manifest).map_err(|err| NaryError::json(manifest_path.display(), err))?;
+
+    let files = entries(&RealFs, package_dir)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|file| {
+            let stem = file.split('.').next().unwrap_or_default().to_ascii_lowercase();
+            ["license", "licence", "copying"].contains(&stem.as_str()) && package_dir.join(file).is_file()
+        })
+        .collect();
+    Ok(Some(PackageLicense {
+        name: name.to_string(),
+        version: version.to_string(),
+        license: license_of(&manifest),
+        files,
+        path: package_dir.to_path_buf(),
+    }))
+}
+
+/// `license` as a string or an old `{"type": ...}` object, otherwise the `licenses` list as alternatives
+fn license_of(manifest: &Value) -> Option<String> {
+    let name = |license: &Value| match license {
+        Value::String(license) => Some(license.trim().to_string()),
+        Value::Object(license) => license.get("type")?.as_str().map(|license| license.trim().to_string()),
+        _ => None,
+    };
+    if let Some(license) = name(&manifest["license"]).filter(|license| !license.is_empty()) {
+        return Some(license);
+    }
+    let alternatives: Vec<String> = manifest["licenses"].as_array()?.iter().filter_map(name).collect();
+    match alternatives.len() {
+        0 => None,
+        1 => alternatives.into_iter().next(),
+        _ => Some(format!("({})", alternatives.join(" OR "))),
+    }
+}
+
+/// Licenses allowed and denied by SPDX identifier, with `*` and `?` wildcards and ignoring case. A denied license
+/// is never allowed; with an allow list only what's on it is. An expression passes when one side of each `OR` and
+/// both sides of each `AND` do. Packages without a license count as `UNKNOWN`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LicensePolicy {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl LicensePolicy {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether the license expression passes, None standing for a package without a license
+    pub fn allows(&self, license: Option<&str>) -> bool {
+        let license = license.unwrap_or(UNKNOWN_LICENSE);
+        let tokens: Vec<String> =
+            license.replace('(', " ( ").replace(')', " ) ").split_whitespace().map(str::to_string).collect();
+        let mut parser = Expression { tokens: &tokens, next: 0, policy: self };
+        match parser.any() {
+            Some(allowed) if parser.next == tokens.len() => allowed,
+            // Something like `SEE LICENSE IN LICENSE.txt` is taken as a whole
+            _ => self.allows_id(license),
+        }
+    }
+
+    fn allows_id(&self, id: &str) -> bool {
+        let id = id.trim_end_matches('+').to_ascii_lowercase();
+        let matches =
+            |patterns: &[String]| patterns.iter().any(|pattern| wildcard_matches(&pattern.to_ascii_lowercase(), &id));
+        !matches(&self.deny) && (self.allow.is_empty() || matches(&self.allow))
+    }
+
+    /// Fail when any package of the report has a license the policy doesn't allow, naming them all
+    pub fn enforce(&self, report: &LicenseReport) -> Result<()> {
+        let disallowed: Vec<String> = report
+            .packages
+            .iter()
+            .filter(|package| !self.allows(package.license.as_deref()))
+            .map(|package| format!("{}@{} ({})", package.name, package.version, package.expression()))
+            .collect();
+        if disallowed.is_empty() {
+            Ok(())
+        } else {
+            Err(NaryError::DisallowedLicenses { packages: disallowed })
+        }
+    }
+}
+
+/// Refuses each package as it's installed when its license isn't allowed
+impl InstallHooks for LicensePolicy {
+    fn after_extract(&self, name: &str, version: &str, path: &Path) -> Result<()> {
+        match read_license(name, version, path)? {
+            Some(package) => self.enforce(&LicenseReport { packages: vec![package] }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A recursive descent over the tokens of an SPDX expression, evaluating it against a policy as it goes. None
+/// when the expression doesn't parse.
+struct Expression<'a> {
+    tokens: &'a [String],
+    next: usize,
+    policy: &'a LicensePolicy,
+}
+
+impl Expression<'_> {
+    fn is_next(&self, operator: &str) -> bool {
+        self.tokens.get(self.next).is_some_and(|token| token.eq_ignore_ascii_case(operator))
+    }
+
+    /// Terms joined with `OR`
+    fn any(&mut self) -> Option<bool> {
+        let mut allowed = self.all()?;
+        while self.is_next("OR") {
+            self.next += 1;
+            allowed |= self.all()?;
+        }
+        Some(allowed)
+    }
+
+    /// Terms joined with `AND`
+    fn all(&mut self) -> Option<bool> {
+        let mut allowed = self.term()?;
+        while self.is_next("AND") {
+            self.next += 1;
+            allowed &= self.term()?;
+        }
+        Some(allowed)
+    }
+
+    /// A parenthesized expression, or a license with an optional `WITH` exception, which doesn't change it
+    fn term(&mut self) -> Option<bool> {
+        let token = self.tokens.get(self.next)?;
+        self.next += 1;
+        if token == "(" {
+            let allowed = self.any()?;
+            if self.tokens.get(self.next)? != ")" {
+                return None;
+            }
+            self.next += 1;
+            return Some(allowed);
+        }
+        if token == ")" || ["AND", "OR", "WITH"].iter().any(|operator| token.eq_ignore_ascii_case(operator)) {
+            return None;
+        }
+        if self.is_next("WITH") {
+            self.tokens.get(self.next + 1)?;
+            self.next += 2;
+        }
+        Some(self.policy.allows_id(token))
+    }
+}
@@ -0,0 +1,59 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{link_bins, path_to_root_dependency, NaryError, PackageName, Result};
+
+/// Overrides where linked packages are registered
+pub const LINKS_DIR_VAR: &str = "NARY_LINKS_DIR";
+
+/// A package registered for linking
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    pub name: String,
+    pub version: String,
+    /// The package's own directory
+    pub source: PathBuf,
+    /// Its entry in the links directory
+    pub path: PathBuf,
+}
+
+/// Where linked packages are registered, a symlink per name: `~/.nary_links` unless `NARY_LINKS_DIR` says otherwise
+pub fn links_dir() -> Result<PathBuf> {
+    match std::env::var_os(LINKS_DIR_VAR) {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => Ok(dirs::home_dir().ok_or(NaryError::NoHomeDir)?.join(".nary_links")),
+    }
+}
+
+/// Register the package in `source` under its name, replacing whatever was registered under it before, for
+/// `use_link` to link it into other projects. Changes to it show up in them without publishing.
+pub fn link_package(source: &Path) -> Result<Link> {
+    let package = path_to_root_dependency(source)?;
+    let name = PackageName::parse(&package.name)?;
+    let path = crate::link_package(&links_dir()?, &name, source)?;
+
+    Ok(Link {
+        name: package.name,
+        version: package.version,
+        source: source.canonicalize().map_err(|err| NaryError::io(source, err))?,
+        path,
+    })
+}
+
+/// Symlink the package registered as `name` into the project's node_modules, with its executables in
+/// `node_modules/.bin`, returning the link. package.json is left alone, so the next install puts back what it
+/// asks for.
+pub fn use_link(project_dir: &Path, name: &str) -> Result<PathBuf> {
+    let name = PackageName::parse(name)?;
+    let registered = links_dir()?.join(name.to_path());
+    if fs::metadata(&registered).is_err() {
+        return Err(NaryError::NotLinked { name: name.to_string() });
+    }
+
+    let node_modules = project_dir.join("node_modules");
+    let path = crate::link_package(&node_modules, &name, &registered)?;
+    link_bins(&node_modules, &path)?;
+    Ok(path)
+}
@@ -0,0 +1,502 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha512};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    install_graph,
+    layout::detect_layout,
+    overrides::{read_overrides, Override},
+    pack::INTEGRITY_FILE,
+    plan::{extraneous, placements},
+    tree::{entries, read_version},
+    workspace::project_dependencies,
+    Dependency, DependencyKind, InstallOptions, InstallReporter, InstalledPackage, LockedTarball, NaryError,
+    NodeId, PackageName, RealFs, RegistryClient, ResolvedGraph, ResolvedNode, Result, Specifier,
+};
+
+mod npm;
+mod pnpm;
+mod yarn;
+pub use self::npm::import_npm;
+pub(crate) use self::npm::import_shrinkwrap;
+pub use self::pnpm::import_pnpm;
+pub use self::yarn::import_yarn;
+
+/// The lockfile's name, next to package.json
+pub const LOCKFILE: &str = "nary-lock.json";
+
+/// The format written by this version of nary
+pub const LOCKFILE_VERSION: u32 = 1;
+
+/// A resolved graph as it's written to disk, with what's needed to check an install against it
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lockfile {
+    pub lockfile_version: u32,
+    pub name: String,
+    pub version: String,
+    /// The root's own dependencies
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, LockedDependency>,
+    /// Every other package, by `<name>@<version>`
+    #[serde(default)]
+    pub packages: BTreeMap<String, LockedPackage>,
+    /// The overrides resolution used, by what they select, see `Override`'s Display
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub overrides: BTreeMap<String, String>,
+}
+
+/// A package pinned by the lockfile
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedPackage {
+    pub name: String,
+    /// The exact version, or the specifier of a package that isn't from a registry
+    pub version: String,
+    /// The registry package behind an `npm:` alias
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias_of: Option<String>,
+    /// The tarball it's installed from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<String>,
+    /// The tarball's integrity, as the registry reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<String>,
+    /// The registry it was found in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    /// Hash of the installed files, see `contents_integrity`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contents: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, LockedDependency>,
+}
+
+/// A dependency as it was asked for, and the package it resolved to
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedDependency {
+    pub range: String,
+    /// Its key in `Lockfile::packages`
+    pub package: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub optional: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn overrides_by_key(overrides: &[Override]) -> BTreeMap<String, String> {
+    overrides.iter().map(|each| (each.to_string(), each.version.clone())).collect()
+}
+
+/// The key of a node in `Lockfile::packages`
+pub fn package_key(node: &ResolvedNode) -> String {
+    format!("{}@{}", node.name, node.dependency().version)
+}
+
+/// The package.json next to a lockfile that doesn't describe the root itself
+fn root_manifest(dir: &Path) -> Result<Value> {
+    let path = dir.join("package.json");
+    let manifest = fs::read_to_string(&path).map_err(|err| NaryError::io(&path, err))?;
+    serde_json::from_str(&manifest).map_err(|err| NaryError::json(path.display(), err))
+}
+
+/// The version of a local directory, relative to where a lockfile is
+fn local_version(dir: &Path, local: impl AsRef<Path>) -> String {
+    let local = dir.join(local);
+    format!("file:{}", local.canonicalize().unwrap_or(local).display())
+}
+
+/// A root dependency's range as path_to_dependencies reads it, with `file:` paths made absolute
+fn root_range(dir: &Path, range: &str) -> String {
+    match Specifier::parse(range) {
+        Specifier::File(local) => local_version(dir, local),
+        _ => range.to_string(),
+    }
+}
+
+/// A string, or a number YAML didn't leave as a string
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// `name@range` split at the `@` that isn't a scope's
+fn split_descriptor(descriptor: &str) -> Option<(&str, &str)> {
+    let at = descriptor[1..].find('@')? + 1;
+    Some((&descriptor[..at], &descriptor[at + 1..]))
+}
+
+impl Lockfile {
+    /// Lock a resolved graph. Registry packages get their tarball and integrity from the (cached) packuments.
+    pub fn from_graph(graph: &ResolvedGraph, registry: &dyn RegistryClient) -> Result<Lockfile> {
+        let root = graph.root();
+        let mut lockfile = Lockfile {
+            lockfile_version: LOCKFILE_VERSION,
+            name: root.name.clone(),
+            version: root.version.clone(),
+            dependencies: locked_dependencies(graph, ResolvedGraph::ROOT),
+            packages: BTreeMap::new(),
+            overrides: BTreeMap::new(),
+        };
+
+        for (id, node) in graph.nodes().filter(|(id, _)| *id != ResolvedGraph::ROOT) {
+            let mut package = LockedPackage {
+                name: node.name.clone(),
+                version: node.version.clone(),
+                alias_of: node.alias_of.clone(),
+                registry: graph.registry_of(id).map(str::to_string),
+                dependencies: locked_dependencies(graph, id),
+                ..LockedPackage::default()
+            };
+            let specifier = Specifier::parse(&node.version);
+            if let Specifier::Url(url) = specifier {
+                package.resolved = Some(url);
+            } else if specifier.is_registry() {
+                let packument = registry.packument(&PackageName::parse(node.package())?)?;
+                if let Some(metadata) = packument.versions.get(&node.version) {
+                    package.resolved = Some(metadata.dist.tarball.clone());
+                    package.integrity = Some(metadata.dist.identity());
+                }
+            }
+            lockfile.packages.insert(package_key(node), package);
+        }
+
+        Ok(lockfile)
+    }
+
+    /// The resolved graph the lockfile pins
+    pub fn to_graph(&self) -> Result<ResolvedGraph> {
+        let mut graph = ResolvedGraph::new(ResolvedNode {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            alias_of: None,
+        });
+
+        let mut ids = HashMap::new();
+        for (key, package) in &self.packages {
+            let node = ResolvedNode {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                alias_of: package.alias_of.clone(),
+            };
+            if *key != package_key(&node) {
+                return Err(NaryError::InvalidLockfile {
+                    reason: format!("{} is locked as {}", key, package_key(&node)),
+                });
+            }
+            let id = graph.add_node(node);
+            if let Some(registry) = &package.registry {
+                graph.set_registry(id, registry.clone());
+            }
+            if package.resolved.is_some() || package.integrity.is_some() {
+                let locked = LockedTarball { resolved: package.resolved.clone(), integrity: package.integrity.clone() };
+                graph.set_locked(id, locked);
+            }
+            ids.insert(key.as_str(), id);
+        }
+
+        let parents = std::iter::once((ResolvedGraph::ROOT, &self.dependencies))
+            .chain(self.packages.iter().map(|(key, package)| (ids[key.as_str()], &package.dependencies)));
+        for (from, dependencies) in parents {
+            for dependency in dependencies.values() {
+                let to = *ids.get(dependency.package.as_str()).ok_or_else(|| NaryError::InvalidLockfile {
+                    reason: format!("{} isn't among its packages", dependency.package),
+                })?;
+                let kind = if dependency.optional { DependencyKind::Optional } else { DependencyKind::Normal };
+                graph.add_edge(from, to, &dependency.range, kind);
+            }
+        }
+
+        graph.finish();
+        Ok(graph)
+    }
+
+    /// Whether the root's dependencies are still the ones in package.json, asking for the same ranges
+    pub fn check_sync(&self, dependencies: &[(Dependency, DependencyKind)]) -> Result<()> {
+        let out_of_sync = |reason: String| Err(NaryError::LockfileOutOfSync { reason });
+        for (dependency, kind) in dependencies {
+            match self.dependencies.get(&dependency.name) {
+                // Optional dependencies that were left out aren't locked
+                None if *kind == DependencyKind::Optional => {}
+                None => return out_of_sync(format!("{} isn't locked", dependency.name)),
+                Some(locked) if locked.range != dependency.version => {
+                    return out_of_sync(format!(
+                        "{} is locked for {}, but package.json asks for {}",
+                        dependency.name, locked.range, dependency.version
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        for name in self.dependencies.keys() {
+            if !dependencies.iter().any(|(dependency, _)| dependency.name == *name) {
+                return out_of_sync(format!("{} isn't in package.json any more", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail unless each registry package's tarball is still the one the lockfile has
+    pub fn check_integrity(&self, registry: &dyn RegistryClient) -> Result<()> {
+        for package in self.packages.values() {
+            let expected = match &package.integrity {
+                Some(expected) => expected,
+                None => continue,
+            };
+            let name = package.alias_of.as_deref().unwrap_or(&package.name);
+            let packument = registry.packument(&PackageName::parse(name)?)?;
+            let actual = packument.versions.get(&package.version).map(|metadata| metadata.dist.identity());
+            if actual.as_ref() != Some(expected) {
+                return Err(NaryError::IntegrityMismatch {
+                    name: name.to_string(),
+                    version: package.version.clone(),
+                    expected: expected.clone(),
+                    actual: actual.unwrap_or_else(|| "missing".to_string()),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the overrides the graph was resolved with
+    pub fn record_overrides(&mut self, overrides: &[Override]) {
+        self.overrides = overrides_by_key(overrides);
+    }
+
+    /// Whether package.json still has the overrides the graph was resolved with
+    pub fn check_overrides(&self, overrides: &[Override]) -> Result<()> {
+        if self.overrides != overrides_by_key(overrides) {
+            return Err(NaryError::LockfileOutOfSync {
+                reason: "the overrides in package.json changed".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Record the contents of each package as installed in node_modules, for `verify_install` to check later.
+    /// Linked local packages change as they're worked on, and are left out.
+    pub fn record_contents(&mut self, node_modules: &Path) -> Result<()> {
+        let graph = self.to_graph()?;
+        for (_, node, path) in placements(node_modules, &graph, detect_layout(node_modules))? {
+            if matches!(Specifier::parse(&node.version), Specifier::File(_)) || !path.is_dir() {
+                continue;
+            }
+            let contents = contents_integrity(&path)?;
+            if let Some(package) = self.packages.get_mut(&package_key(node)) {
+                package.contents = Some(contents);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the lockfile in a project directory, or None when there isn't one
+pub fn read_lockfile(project_dir: &Path) -> Result<Option<Lockfile>> {
+    let path = project_dir.join(LOCKFILE);
+    let lockfile = match fs::read_to_string(&path) {
+        Ok(lockfile) => lockfile,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(NaryError::io(path, err)),
+    };
+    serde_json::from_str(&lockfile).map(Some).map_err(|err| NaryError::json(path.display(), err))
+}
+
+/// The project's lockfile, or else one imported from npm's, pnpm's or yarn's, when there is one
+pub fn read_or_import(project_dir: &Path) -> Result<Option<Lockfile>> {
+    if let Some(lockfile) = read_lockfile(project_dir)? {
+        return Ok(Some(lockfile));
+    }
+    for file_name in &["npm-shrinkwrap.json", "package-lock.json", "pnpm-lock.yaml", "yarn.lock"] {
+        let path = project_dir.join(file_name);
+        if !path.is_file() {
+            continue;
+        }
+        let imported = match *file_name {
+            "pnpm-lock.yaml" => import_pnpm(&path),
+            "yarn.lock" => import_yarn(&path),
+            _ => import_npm(&path),
+        };
+        return imported.map(Some);
+    }
+    Ok(None)
+}
+
+/// Install exactly what the lockfile, or another tool's, says, like `npm ci`: refuse when it's missing, out of sync
+/// with package.json or pins tarballs the registry no longer has, then replace node_modules without resolving anything
+pub fn install_frozen(
+    project_dir: &Path,
+    registry: &dyn RegistryClient,
+    options: &InstallOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<ResolvedGraph> {
+    let lockfile = read_or_import(project_dir)?.ok_or_else(|| NaryError::NoLockfile {
+        path: project_dir.join(LOCKFILE),
+    })?;
+    lockfile.check_sync(&project_dependencies(project_dir)?)?;
+    lockfile.check_overrides(&read_overrides(project_dir)?)?;
+    lockfile.check_integrity(registry)?;
+    let graph = lockfile.to_graph()?;
+
+    let node_modules = options.modules_dir(project_dir);
+    options.fs.remove(&node_modules).map_err(|err| NaryError::io(&node_modules, err))?;
+    options.fs.create_dir_all(&node_modules).map_err(|err| NaryError::io(&node_modules, err))?;
+    install_graph(&node_modules, &graph, registry, options, reporter)?;
+
+    Ok(graph)
+}
+
+/// Write the lockfile, pretty printed so it diffs well
+pub fn write_lockfile(project_dir: &Path, lockfile: &Lockfile) -> Result<()> {
+    let path = project_dir.join(LOCKFILE);
+    let mut contents = serde_json::to_string_pretty(lockfile).map_err(|err| NaryError::json(path.display(), err))?;
+    contents.push('\n');
+    fs::write(&path, contents).map_err(|err| NaryError::io(path, err))
+}
+
+fn locked_dependencies(graph: &ResolvedGraph, id: NodeId) -> BTreeMap<String, LockedDependency> {
+    graph
+        .dependencies(id)
+        .filter_map(|edge| {
+            let node = graph.node(edge.to)?;
+            let dependency = LockedDependency {
+                range: edge.range.clone(),
+                package: package_key(node),
+                optional: edge.kind == DependencyKind::Optional,
+            };
+            Some((node.name.clone(), dependency))
+        })
+        .collect()
+}
+
+/// `sha512-<base64>` over the paths and contents of a package's files, in order, leaving out its node_modules and
+/// the integrity file nary writes. Symlinks count by their target.
+pub fn contents_integrity(package_dir: &Path) -> Result<String> {
+    let mut hasher = Sha512::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let dir = package_dir.join(&relative);
+        let mut children = Vec::new();
+        for (name, path) in entries(&RealFs, &dir)? {
+            if relative.as_os_str().is_empty() && (name == "node_modules" || name == INTEGRITY_FILE) {
+                continue;
+            }
+            let metadata = fs::symlink_metadata(&path).map_err(|err| NaryError::io(&path, err))?;
+            let entry = relative.join(&name);
+            let entry_name = entry.to_string_lossy().replace('\\', "/");
+
+            if metadata.file_type().is_symlink() {
+                let target = fs::read_link(&path).map_err(|err| NaryError::io(&path, err))?;
+                hasher.update(format!("l {}\0{}\0", entry_name, target.to_string_lossy()));
+            } else if metadata.is_dir() {
+                children.push(entry);
+            } else {
+                let content = fs::read(&path).map_err(|err| NaryError::io(&path, err))?;
+                hasher.update(format!("f {}\0{}\0", entry_name, content.len()));
+                hasher.update(&content);
+            }
+        }
+        // Popped in name order
+        pending.extend(children.into_iter().rev());
+    }
+    Ok(format!("sha512-{}", base64::encode(hasher.finalize())))
+}
+
+/// How node_modules differs from a lockfile
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// Locked packages that aren't installed where they belong. Optional ones may be left out and aren't listed.
+    pub missing: Vec<InstalledPackage>,
+    /// Installed packages that aren't what the lockfile says
+    pub mismatched: Vec<Mismatch>,
+    /// Installed packages the lockfile doesn't have
+    pub extraneous: Vec<InstalledPackage>,
+}
+
+impl Verification {
+    /// node_modules is exactly what the lockfile says
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty() && self.extraneous.is_empty()
+    }
+}
+
+/// An installed package that differs from its lockfile entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub name: String,
+    /// The locked version
+    pub expected: String,
+    /// The version in its package.json
+    pub installed: Option<String>,
+    pub path: PathBuf,
+    pub reason: MismatchReason,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MismatchReason {
+    /// Another version is installed
+    Version,
+    /// It was unpacked from another tarball
+    Integrity,
+    /// Its files were changed after it was installed
+    Contents,
+}
+
+/// Check the node_modules of a project against a lockfile: every locked package has to be installed where the
+/// layout puts it, at its version, from its tarball and with its files unchanged, and nothing else may be there
+pub fn verify_install(project_dir: &Path, lockfile: &Lockfile) -> Result<Verification> {
+    let node_modules = project_dir.join("node_modules");
+    let layout = detect_layout(&node_modules);
+    let graph = lockfile.to_graph()?;
+    let mut verification = Verification::default();
+
+    for (_, node, path) in placements(&node_modules, &graph, layout)? {
+        let locked = &lockfile.packages[&package_key(node)];
+        if fs::symlink_metadata(&path).is_err() {
+            if graph.kind_of(node) != DependencyKind::Optional {
+                verification.missing.push(InstalledPackage {
+                    name: node.name.clone(),
+                    version: Some(node.dependency().version),
+                    path,
+                });
+            }
+            continue;
+        }
+
+        let installed = read_version(&RealFs, &path);
+        let from_registry = locked.integrity.is_some();
+        let recorded = fs::read_to_string(path.join(INTEGRITY_FILE)).ok();
+        let reason = if from_registry && installed.as_deref() != Some(node.version.as_str()) {
+            Some(MismatchReason::Version)
+        } else if recorded.is_some() && recorded != locked.integrity {
+            Some(MismatchReason::Integrity)
+        } else if locked.contents.is_some() && locked.contents != Some(contents_integrity(&path)?) {
+            Some(MismatchReason::Contents)
+        } else {
+            None
+        };
+
+        if let Some(reason) = reason {
+            verification.mismatched.push(Mismatch {
+                name: node.name.clone(),
+                expected: node.dependency().version,
+                installed,
+                path,
+                reason,
+            });
+        }
+    }
+
+    verification.extraneous = extraneous(&RealFs, &node_modules, &graph, layout)?;
+    Ok(verification)
+}
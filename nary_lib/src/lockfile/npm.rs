@@ -0,0 +1,215 @@
+use semver_rs::Version;
+use serde_json::{Map, Value};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use crate::{
+    lockfile::{local_version, package_key, root_range, LockedDependency, LockedPackage, Lockfile, LOCKFILE_VERSION},
+    NaryError, ResolvedNode, Result, Specifier,
+};
+
+/// Read npm's package-lock.json or npm-shrinkwrap.json, in the `packages` format of lockfileVersion 2 and 3, keeping
+/// every version it pins. Only what the root's `dependencies` reach is kept, as that's all nary installs.
+pub fn import_npm(path: &Path) -> Result<Lockfile> {
+    let contents = fs::read_to_string(path).map_err(|err| NaryError::io(path, err))?;
+    let lock: Value = serde_json::from_str(&contents).map_err(|err| NaryError::json(path.display(), err))?;
+    import_lock(&lock, path.parent().unwrap_or_else(|| Path::new("")), &path.display().to_string(), false)
+}
+
+/// Read the npm-shrinkwrap.json a package ships in its tarball, whose root is the package itself. Its optional
+/// dependencies are kept along with the rest.
+pub(crate) fn import_shrinkwrap(contents: &str, source: &str) -> Result<Lockfile> {
+    let lock: Value = serde_json::from_str(contents).map_err(|err| NaryError::json(source, err))?;
+    import_lock(&lock, Path::new(""), source, true)
+}
+
+fn import_lock(lock: &Value, dir: &Path, source: &str, root_optional: bool) -> Result<Lockfile> {
+    let packages = lock["packages"].as_object().ok_or_else(|| NaryError::InvalidLockfile {
+        reason: format!("{} has no packages, only lockfileVersion 2 and 3 can be imported", source),
+    })?;
+    let importer = Importer {
+        dir,
+        packages,
+        root_optional,
+    };
+
+    let root = &packages.get("").unwrap_or(&Value::Null);
+    let mut lockfile = Lockfile {
+        lockfile_version: LOCKFILE_VERSION,
+        name: root["name"].as_str().or_else(|| lock["name"].as_str()).unwrap_or_default().to_string(),
+        version: root["version"].as_str().or_else(|| lock["version"].as_str()).unwrap_or_default().to_string(),
+        ..Lockfile::default()
+    };
+
+    // Packages by their place in the tree, and by their key
+    let mut keys = HashMap::new();
+    for place in packages.keys().filter(|place| place.contains("node_modules/")) {
+        if let Some((node, package)) = importer.package(place)? {
+            keys.insert(place.as_str(), package_key(&node));
+            lockfile.packages.entry(package_key(&node)).or_insert(package);
+        }
+    }
+
+    // A version placed in several spots is one package, with the dependencies found from the first
+    lockfile.dependencies = importer.dependencies("", root, &keys, true)?;
+    let mut done = HashSet::new();
+    for (place, key) in packages.keys().filter_map(|place| Some((place, keys.get(place.as_str())?))) {
+        if !done.insert(key) {
+            continue;
+        }
+        let (tree_place, entry) = importer.target(place);
+        let dependencies = importer.dependencies(tree_place, entry, &keys, false)?;
+        if let Some(package) = lockfile.packages.get_mut(key) {
+            package.dependencies = dependencies;
+        }
+    }
+
+    retain_reachable(&mut lockfile);
+    Ok(lockfile)
+}
+
+struct Importer<'a> {
+    dir: &'a Path,
+    packages: &'a Map<String, Value>,
+    /// Whether the root's `optionalDependencies` are read too
+    root_optional: bool,
+}
+
+impl<'a> Importer<'a> {
+    /// The node at a place in the tree, and its lockfile entry without dependencies
+    fn package(&self, place: &str) -> Result<Option<(ResolvedNode, LockedPackage)>> {
+        let entry = &self.packages[place];
+        let name = place.rsplit("node_modules/").next().unwrap_or(place).to_string();
+        let resolved = entry["resolved"].as_str();
+        let specifier = resolved.map(Specifier::parse);
+        let tarball_url = resolved.filter(|_| matches!(specifier, Some(Specifier::Url(_))));
+
+        let version = if entry["link"].as_bool().unwrap_or(false) {
+            let target = resolved.ok_or_else(|| NaryError::InvalidLockfile {
+                reason: format!("{} links nowhere", place),
+            })?;
+            local_version(self.dir, target)
+        } else if let (Some(git), Some(Specifier::Git(_))) = (resolved, &specifier) {
+            git.to_string()
+        } else if let Some(version) = entry["version"].as_str().filter(|version| Version::new(version).parse().is_ok())
+        {
+            version.to_string()
+        } else if let Some(url) = tarball_url {
+            url.to_string()
+        } else {
+            return Ok(None);
+        };
+
+        let node = ResolvedNode {
+            alias_of: entry["name"].as_str().filter(|target| *target != name).map(str::to_string),
+            name,
+            version,
+        };
+        let package = LockedPackage {
+            name: node.name.clone(),
+            version: node.version.clone(),
+            alias_of: node.alias_of.clone(),
+            resolved: tarball_url.map(str::to_string),
+            integrity: entry["integrity"].as_str().map(str::to_string),
+            ..LockedPackage::default()
+        };
+        Ok(Some((node, package)))
+    }
+
+    /// Where a link's package really is, or the place itself
+    fn target(&self, place: &'a str) -> (&'a str, &'a Value) {
+        let entry = &self.packages[place];
+        if entry["link"].as_bool().unwrap_or(false) {
+            let target = entry["resolved"].as_str().and_then(|target| self.packages.get_key_value(target));
+            if let Some((target, entry)) = target {
+                return (target, entry);
+            }
+        }
+        (place, entry)
+    }
+
+    /// An entry's dependencies, found the way Node finds them from its place in the tree. The root has only its
+    /// `dependencies`, like the package.json nary reads, unless it's a package's shrinkwrap; other packages have
+    /// their optional and peer dependencies too, which may be missing.
+    fn dependencies(
+        &self,
+        place: &str,
+        entry: &Value,
+        keys: &HashMap<&str, String>,
+        root: bool,
+    ) -> Result<BTreeMap<String, LockedDependency>> {
+        let mut fields = vec![("dependencies", false)];
+        if !root {
+            fields.extend(&[("optionalDependencies", true), ("peerDependencies", false)]);
+        } else if self.root_optional {
+            fields.push(("optionalDependencies", true));
+        }
+
+        let mut dependencies = BTreeMap::new();
+        for (field, optional) in fields {
+            for (name, range) in entry[field].as_object().into_iter().flatten() {
+                let optional = optional
+                    || field == "peerDependencies" && entry["peerDependenciesMeta"][name]["optional"] == true;
+                let package = match self.locate(place, name).and_then(|found| keys.get(found.as_str())) {
+                    Some(package) => package.clone(),
+                    None if optional || field == "peerDependencies" => continue,
+                    None => {
+                        let dependent = if root { "the root" } else { place };
+                        return Err(NaryError::InvalidLockfile {
+                            reason: format!("{} of {} isn't in the lockfile", name, dependent),
+                        });
+                    }
+                };
+
+                let range = range.as_str().unwrap_or("*");
+                let range = if root { root_range(self.dir, range) } else { range.to_string() };
+                dependencies.entry(name.clone()).or_insert(LockedDependency {
+                    range,
+                    package,
+                    optional,
+                });
+            }
+        }
+        Ok(dependencies)
+    }
+
+    /// The place Node's resolution finds `name` from `place`: its own node_modules, then each one further up
+    fn locate(&self, place: &str, name: &str) -> Option<String> {
+        let mut base = place;
+        loop {
+            let candidate = if base.is_empty() {
+                format!("node_modules/{}", name)
+            } else {
+                format!("{}/node_modules/{}", base, name)
+            };
+            if self.packages.contains_key(&candidate) {
+                return Some(candidate);
+            }
+            if base.is_empty() {
+                return None;
+            }
+            base = match base.rfind("node_modules/") {
+                Some(index) => base[..index].trim_end_matches('/'),
+                None => "",
+            };
+        }
+    }
+}
+
+/// Drop the packages the root doesn't reach, like those only dev dependencies pull in
+fn retain_reachable(lockfile: &mut Lockfile) {
+    let mut reachable = HashSet::new();
+    let mut pending: Vec<String> =
+        lockfile.dependencies.values().map(|dependency| dependency.package.clone()).collect();
+    while let Some(key) = pending.pop() {
+        if let Some(package) = lockfile.packages.get(&key) {
+            if reachable.insert(key) {
+                pending.extend(package.dependencies.values().map(|dependency| dependency.package.clone()));
+            }
+        }
+    }
+    lockfile.packages.retain(|key, _| reachable.contains(key));
+}
@@ -0,0 +1,251 @@
+use semver_rs::Version;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+
+use crate::{
+    lockfile::{
+        local_version, package_key, root_manifest, root_range, scalar, split_descriptor, LockedDependency,
+        LockedPackage, Lockfile, LOCKFILE_VERSION,
+    },
+    pack::normalize,
+    NaryError, ResolvedNode, Result,
+};
+
+/// Read a pnpm-lock.yaml, lockfileVersion 5, 6 or 9. Only the root's `dependencies` and what they reach are
+/// imported; peer dependency variants of a package are one package to nary.
+pub fn import_pnpm(path: &Path) -> Result<Lockfile> {
+    let contents = fs::read_to_string(path).map_err(|err| NaryError::io(path, err))?;
+    let lock: Value = serde_yaml::from_str(&contents).map_err(|err| NaryError::yaml(path.display(), err))?;
+    let major = scalar(&lock["lockfileVersion"])
+        .and_then(|version| version.split('.').next()?.parse::<u32>().ok())
+        .unwrap_or_default();
+    if major < 5 {
+        return Err(NaryError::InvalidLockfile {
+            reason: format!("{} is too old, only lockfileVersion 5 and later can be imported", path.display()),
+        });
+    }
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let manifest = root_manifest(dir)?;
+    let mut importer = Importer {
+        dir,
+        major,
+        lock: &lock,
+        visited: HashMap::new(),
+        lockfile: Lockfile {
+            lockfile_version: LOCKFILE_VERSION,
+            name: manifest["name"].as_str().unwrap_or_default().to_string(),
+            version: manifest["version"].as_str().unwrap_or_default().to_string(),
+            ..Lockfile::default()
+        },
+    };
+
+    let dependencies = importer.project_dependencies(".", false)?;
+    for (name, dependency) in dependencies {
+        let dependency = LockedDependency {
+            range: root_range(dir, &dependency.range),
+            ..dependency
+        };
+        importer.lockfile.dependencies.insert(name, dependency);
+    }
+
+    Ok(importer.lockfile)
+}
+
+struct Importer<'a> {
+    dir: &'a Path,
+    major: u32,
+    lock: &'a Value,
+    /// Keys of the packages already imported, by name and pnpm's key
+    visited: HashMap<(String, String), String>,
+    lockfile: Lockfile,
+}
+
+impl<'a> Importer<'a> {
+    /// A project's dependencies, from `importers` in a workspace and from the top level otherwise. The root's
+    /// optional dependencies are left out like nary leaves them out of package.json.
+    fn project_dependencies(&mut self, project: &str, optional: bool) -> Result<BTreeMap<String, LockedDependency>> {
+        let lock = self.lock;
+        let importer = if lock["importers"].is_object() { &lock["importers"][project] } else { lock };
+        let mut fields = vec![("dependencies", false)];
+        if optional {
+            fields.push(("optionalDependencies", true));
+        }
+
+        let mut dependencies = BTreeMap::new();
+        for (field, optional) in fields {
+            for (name, value) in importer[field].as_object().into_iter().flatten() {
+                // Since lockfileVersion 6 each dependency has its specifier, before then they're listed apart
+                let (range, reference) = match value {
+                    Value::Object(_) => (scalar(&value["specifier"]), scalar(&value["version"])),
+                    _ => (scalar(&importer["specifiers"][name]), scalar(value)),
+                };
+                let reference = reference.unwrap_or_default();
+                let package = match self.visit(name, &reference, project)? {
+                    Some(package) => package,
+                    None if optional => continue,
+                    None => return Err(self.missing(name, &reference)),
+                };
+                let range = range.unwrap_or_else(|| reference.clone());
+                dependencies.insert(name.clone(), LockedDependency { range, package, optional });
+            }
+        }
+        Ok(dependencies)
+    }
+
+    /// Import the package a dependency refers to, and what it depends on, returning its key. `project` is the
+    /// workspace project `link:` references are relative to.
+    fn visit(&mut self, name: &str, reference: &str, project: &str) -> Result<Option<String>> {
+        if let Some(link) = reference.strip_prefix("link:") {
+            return self.visit_link(name, link, project);
+        }
+
+        let pnpm_key = match self.pnpm_key(name, reference) {
+            Some(pnpm_key) => pnpm_key,
+            None => return Ok(None),
+        };
+        let visit = (name.to_string(), pnpm_key.clone());
+        if let Some(key) = self.visited.get(&visit) {
+            return Ok(Some(key.clone()));
+        }
+
+        // Since lockfileVersion 9 what a package depends on is in `snapshots`, per peer dependency variant
+        let lock = self.lock;
+        let (entry, snapshot) = if self.major >= 9 {
+            (&lock["packages"][without_peers(&pnpm_key, self.major)], &lock["snapshots"][&pnpm_key])
+        } else {
+            (&lock["packages"][&pnpm_key], &lock["packages"][&pnpm_key])
+        };
+        let (target, version) = match self.version(&pnpm_key, entry) {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+        let node = ResolvedNode {
+            name: name.to_string(),
+            version,
+            alias_of: target.filter(|target| target != name),
+        };
+
+        let key = package_key(&node);
+        self.visited.insert(visit, key.clone());
+        if self.lockfile.packages.contains_key(&key) {
+            return Ok(Some(key));
+        }
+        let package = LockedPackage {
+            name: node.name.clone(),
+            version: node.version.clone(),
+            alias_of: node.alias_of.clone(),
+            resolved: scalar(&entry["resolution"]["tarball"]),
+            integrity: scalar(&entry["resolution"]["integrity"]),
+            ..LockedPackage::default()
+        };
+        self.lockfile.packages.insert(key.clone(), package);
+
+        let mut dependencies = BTreeMap::new();
+        for (field, optional) in &[("dependencies", false), ("optionalDependencies", true)] {
+            for (name, reference) in snapshot[*field].as_object().into_iter().flatten() {
+                let reference = scalar(reference).unwrap_or_default();
+                let package = match self.visit(name, &reference, project)? {
+                    Some(package) => package,
+                    None if *optional => continue,
+                    None => return Err(self.missing(name, &reference)),
+                };
+                // Only versions are locked for dependencies below the projects
+                let range = without_peers(&reference, self.major).to_string();
+                dependencies.insert(name.clone(), LockedDependency { range, package, optional: *optional });
+            }
+        }
+        if let Some(package) = self.lockfile.packages.get_mut(&key) {
+            package.dependencies = dependencies;
+        }
+
+        Ok(Some(key))
+    }
+
+    /// A workspace project or local directory, with the dependencies pnpm locked for it
+    fn visit_link(&mut self, name: &str, link: &str, project: &str) -> Result<Option<String>> {
+        let linked = normalize(&Path::new(project).join(link))
+            .map(|linked| linked.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_else(|| link.to_string());
+        let linked = if linked.is_empty() { ".".to_string() } else { linked };
+        let node = ResolvedNode {
+            name: name.to_string(),
+            version: local_version(self.dir, &linked),
+            alias_of: None,
+        };
+
+        let key = package_key(&node);
+        if self.lockfile.packages.contains_key(&key) {
+            return Ok(Some(key));
+        }
+        let package = LockedPackage {
+            name: node.name.clone(),
+            version: node.version.clone(),
+            ..LockedPackage::default()
+        };
+        self.lockfile.packages.insert(key.clone(), package);
+
+        if self.lock["importers"][&linked].is_object() {
+            let dependencies = self.project_dependencies(&linked, true)?;
+            if let Some(package) = self.lockfile.packages.get_mut(&key) {
+                package.dependencies = dependencies;
+            }
+        }
+        Ok(Some(key))
+    }
+
+    /// Where `packages` has what a reference points at. References are versions, with peers in parentheses since
+    /// lockfileVersion 6 and after an underscore before that, or keys themselves for aliases and other sources.
+    fn pnpm_key(&self, name: &str, reference: &str) -> Option<String> {
+        let packages = if self.major >= 9 { &self.lock["snapshots"] } else { &self.lock["packages"] };
+        let candidates = match self.major {
+            5 => [reference.to_string(), format!("/{}/{}", name, reference)],
+            6..=8 => [reference.to_string(), format!("/{}@{}", name, reference)],
+            _ => [reference.to_string(), format!("{}@{}", name, reference)],
+        };
+        candidates.iter().find(|candidate| packages.get(candidate).is_some()).cloned()
+    }
+
+    /// The registry package behind a key and its version, or the source of anything else
+    fn version(&self, pnpm_key: &str, entry: &Value) -> Option<(Option<String>, String)> {
+        let resolution = &entry["resolution"];
+        if let Some(directory) = scalar(&resolution["directory"]) {
+            return Some((None, local_version(self.dir, &directory)));
+        }
+        if let (Some(repo), Some(commit)) = (scalar(&resolution["repo"]), scalar(&resolution["commit"])) {
+            return Some((None, format!("git+{}#{}", repo.trim_start_matches("git+"), commit)));
+        }
+
+        let key = without_peers(pnpm_key, self.major).trim_start_matches('/');
+        let split = if self.major == 5 { key.rsplit_once('/') } else { split_descriptor(key) };
+        let (target, version) = match (scalar(&entry["name"]), scalar(&entry["version"])) {
+            (Some(target), Some(version)) => (target, version),
+            _ => split.map(|(target, version)| (target.to_string(), version.to_string()))?,
+        };
+        if Version::new(&version).parse().is_ok() {
+            return Some((Some(target), version));
+        }
+        scalar(&resolution["tarball"]).map(|tarball| (None, tarball))
+    }
+
+    fn missing(&self, name: &str, reference: &str) -> NaryError {
+        NaryError::InvalidLockfile {
+            reason: format!("{} at {} isn't in pnpm-lock.yaml", name, reference),
+        }
+    }
+}
+
+/// A key or reference without its peer dependency suffix
+fn without_peers(key: &str, major: u32) -> &str {
+    let suffix = if major == 5 {
+        // The underscore after the version, not one in a name
+        key.rfind('/').and_then(|slash| key[slash..].find('_').map(|underscore| slash + underscore))
+    } else {
+        key.find('(')
+    };
+    suffix.map_or(key, |suffix| &key[..suffix])
+}
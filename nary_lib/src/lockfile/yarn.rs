@@ -0,0 +1,285 @@
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+
+use crate::{
+    lockfile::{
+        local_version, package_key, root_manifest, root_range, scalar, split_descriptor, LockedDependency,
+        LockedPackage, Lockfile, LOCKFILE_VERSION,
+    },
+    GitSpec, NaryError, ResolvedNode, Result, Specifier,
+};
+
+/// What a yarn.lock resolves one or more descriptors to
+#[derive(Debug, Default)]
+struct Entry {
+    version: String,
+    /// `resolved` in yarn 1, `resolution` in yarn 2 and later
+    resolved: Option<String>,
+    integrity: Option<String>,
+    /// Names and ranges, and whether they're optional
+    dependencies: Vec<(String, String, bool)>,
+}
+
+/// Read a yarn.lock, from yarn 1 or from yarn 2 and later (berry). yarn.lock doesn't hold the root, so its
+/// package.json is read from next to it.
+pub fn import_yarn(path: &Path) -> Result<Lockfile> {
+    let contents = fs::read_to_string(path).map_err(|err| NaryError::io(path, err))?;
+    let berry = contents.lines().any(|line| line.starts_with("__metadata:"));
+    let mut descriptors = HashMap::new();
+    let mut entries = Vec::new();
+    let parsed = if berry { parse_berry(&contents, path)? } else { parse_classic(&contents) };
+    for (keys, entry) in parsed {
+        for key in keys {
+            descriptors.insert(key, entries.len());
+        }
+        entries.push(entry);
+    }
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let manifest = root_manifest(dir)?;
+    let mut importer = Importer {
+        dir,
+        berry,
+        descriptors,
+        entries,
+        visited: HashMap::new(),
+        lockfile: Lockfile {
+            lockfile_version: LOCKFILE_VERSION,
+            name: manifest["name"].as_str().unwrap_or_default().to_string(),
+            version: manifest["version"].as_str().unwrap_or_default().to_string(),
+            ..Lockfile::default()
+        },
+    };
+
+    for (name, range) in manifest["dependencies"].as_object().into_iter().flatten() {
+        let range = range.as_str().unwrap_or("*");
+        let package = importer.visit(name, range)?.ok_or_else(|| NaryError::InvalidLockfile {
+            reason: format!("{}@{} isn't in {}", name, range, path.display()),
+        })?;
+        let dependency = LockedDependency {
+            range: root_range(dir, range),
+            package,
+            optional: false,
+        };
+        importer.lockfile.dependencies.insert(name.clone(), dependency);
+    }
+
+    Ok(importer.lockfile)
+}
+
+/// yarn 1's own format: unindented descriptor lists, with indented fields and dependency sections below them
+fn parse_classic(contents: &str) -> Vec<(Vec<String>, Entry)> {
+    let mut parsed: Vec<(Vec<String>, Entry)> = Vec::new();
+    let mut section = None;
+
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+
+        if indent == 0 {
+            let keys = trimmed.trim_end_matches(':').split(", ").map(|key| unquote(key).to_string()).collect();
+            parsed.push((keys, Entry::default()));
+            continue;
+        }
+        let entry = match parsed.last_mut() {
+            Some((_, entry)) => entry,
+            None => continue,
+        };
+
+        if let Some(field) = trimmed.strip_suffix(':') {
+            section = match field {
+                "dependencies" => Some(false),
+                "optionalDependencies" => Some(true),
+                _ => None,
+            };
+            continue;
+        }
+        let (key, value) = match trimmed.split_once(' ') {
+            Some((key, value)) => (unquote(key), unquote(value.trim())),
+            None => continue,
+        };
+        match (indent, section) {
+            (2, _) => {
+                section = None;
+                match key {
+                    "version" => entry.version = value.to_string(),
+                    "resolved" => entry.resolved = Some(value.to_string()),
+                    "integrity" => entry.integrity = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            (_, Some(optional)) => entry.dependencies.push((key.to_string(), value.to_string(), optional)),
+            _ => {}
+        }
+    }
+
+    parsed
+}
+
+/// yarn 2 and later write YAML, keyed by comma separated descriptors
+fn parse_berry(contents: &str, path: &Path) -> Result<Vec<(Vec<String>, Entry)>> {
+    let lock: Value = serde_yaml::from_str(contents).map_err(|err| NaryError::yaml(path.display(), err))?;
+    let mut parsed = Vec::new();
+
+    for (keys, value) in lock.as_object().into_iter().flatten() {
+        if keys == "__metadata" {
+            continue;
+        }
+        let mut entry = Entry {
+            version: scalar(&value["version"]).unwrap_or_default(),
+            resolved: scalar(&value["resolution"]),
+            ..Entry::default()
+        };
+        for (name, range) in value["dependencies"].as_object().into_iter().flatten() {
+            let optional = value["dependenciesMeta"][name]["optional"] == true;
+            entry.dependencies.push((name.clone(), scalar(range).unwrap_or_default(), optional));
+        }
+        parsed.push((keys.split(", ").map(str::to_string).collect(), entry));
+    }
+
+    Ok(parsed)
+}
+
+fn unquote(value: &str) -> &str {
+    value.trim_matches('"')
+}
+
+struct Importer<'a> {
+    dir: &'a Path,
+    berry: bool,
+    descriptors: HashMap<String, usize>,
+    entries: Vec<Entry>,
+    /// Keys of the packages already imported, by the descriptor that pulled them in
+    visited: HashMap<String, String>,
+    lockfile: Lockfile,
+}
+
+impl<'a> Importer<'a> {
+    /// Import what `name@range` resolves to along with its dependencies, returning its key. None when the lockfile
+    /// doesn't have it, or resolves it in a way nary can't install.
+    fn visit(&mut self, name: &str, range: &str) -> Result<Option<String>> {
+        let descriptor = self.descriptor(name, range);
+        if let Some(key) = self.visited.get(&descriptor) {
+            return Ok(Some(key.clone()));
+        }
+        let index = match self.descriptors.get(&descriptor) {
+            Some(index) => *index,
+            None => return Ok(None),
+        };
+        let node = match self.node(name, range, &self.entries[index]) {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+
+        let key = package_key(&node);
+        self.visited.insert(descriptor, key.clone());
+        if self.lockfile.packages.contains_key(&key) {
+            return Ok(Some(key));
+        }
+        let entry = &self.entries[index];
+        let package = LockedPackage {
+            name: node.name.clone(),
+            version: node.version.clone(),
+            alias_of: node.alias_of.clone(),
+            resolved: entry.resolved.as_deref().filter(|resolved| is_url(resolved)).map(|resolved| {
+                // yarn 1 appends the sha1 as a fragment
+                resolved.split('#').next().unwrap_or(resolved).to_string()
+            }),
+            integrity: entry.integrity.clone(),
+            ..LockedPackage::default()
+        };
+        self.lockfile.packages.insert(key.clone(), package);
+
+        let mut dependencies = BTreeMap::new();
+        for (name, range, optional) in self.entries[index].dependencies.clone() {
+            let package = match self.visit(&name, &range)? {
+                Some(package) => package,
+                None if optional => continue,
+                None => {
+                    return Err(NaryError::InvalidLockfile {
+                        reason: format!("{}@{}, a dependency of {}, isn't locked", name, range, key),
+                    })
+                }
+            };
+            dependencies.insert(name, LockedDependency { range, package, optional });
+        }
+        if let Some(package) = self.lockfile.packages.get_mut(&key) {
+            package.dependencies = dependencies;
+        }
+
+        Ok(Some(key))
+    }
+
+    /// The key a dependency is found under: berry spells out the npm protocol, yarn 1 doesn't
+    fn descriptor(&self, name: &str, range: &str) -> String {
+        let has_protocol = range.split_once(':').is_some_and(|(protocol, _)| {
+            !protocol.is_empty() && protocol.chars().all(|c| c.is_ascii_lowercase() || c == '+')
+        });
+        if self.berry && !has_protocol {
+            format!("{}@npm:{}", name, range)
+        } else {
+            format!("{}@{}", name, range)
+        }
+    }
+
+    fn node(&self, name: &str, range: &str, entry: &Entry) -> Option<ResolvedNode> {
+        let (target, version) = if self.berry {
+            self.berry_version(entry)?
+        } else {
+            self.classic_version(name, range, entry)
+        };
+        Some(ResolvedNode {
+            name: name.to_string(),
+            version,
+            alias_of: target.filter(|target| target != name),
+        })
+    }
+
+    /// The package behind an alias and the version, from yarn 1's range and entry
+    fn classic_version(&self, name: &str, range: &str, entry: &Entry) -> (Option<String>, String) {
+        let resolved = entry.resolved.as_deref().unwrap_or_default();
+        if let Some(local) = range.strip_prefix("link:") {
+            return (None, local_version(self.dir, local));
+        }
+        match Specifier::parse(range) {
+            Specifier::File(local) => (None, local_version(self.dir, local)),
+            Specifier::Git(_) if GitSpec::parse(resolved).is_some() => (None, resolved.to_string()),
+            Specifier::Git(_) | Specifier::Url(_) => (None, range.to_string()),
+            Specifier::Alias(target) => (Some(target.name.clone()), entry.version.clone()),
+            _ => (Some(name.to_string()), entry.version.clone()),
+        }
+    }
+
+    /// The package and version from berry's `resolution`, which is `<package>@<protocol>:<reference>`. Patches and
+    /// other protocols nary can't install come back None.
+    fn berry_version(&self, entry: &Entry) -> Option<(Option<String>, String)> {
+        let (target, reference) = split_descriptor(entry.resolved.as_deref()?)?;
+        if let Some(version) = reference.strip_prefix("npm:") {
+            return Some((Some(target.to_string()), version.to_string()));
+        }
+        let local = ["workspace:", "link:", "portal:", "file:"]
+            .iter()
+            .find_map(|protocol| reference.strip_prefix(protocol));
+        if let Some(local) = local {
+            return Some((None, local_version(self.dir, local)));
+        }
+        if let Some((url, commit)) = reference.split_once("#commit=") {
+            return Some((None, format!("git+{}#{}", url.trim_start_matches("git+"), commit)));
+        }
+        if is_url(reference) {
+            return Some((None, reference.to_string()));
+        }
+        None
+    }
+}
+
+fn is_url(reference: &str) -> bool {
+    matches!(Specifier::parse(reference), Specifier::Url(_))
+}
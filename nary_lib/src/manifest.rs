@@ -0,0 +1,685 @@
+use indexmap::IndexMap;
+use semver_rs::Version;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    fetch_matching_version_metadata, pack::normalize, Dependency, DependencyKind, NaryError, RegistryClient,
+    PackageName, ResolutionOptions, Result, Specifier,
+};
+
+/// A package.json. Unknown fields are ignored and a field of the wrong type reads as missing, so one odd field in
+/// some package doesn't stop an install.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Manifest {
+    #[serde(deserialize_with = "lenient")]
+    pub name: Option<String>,
+    #[serde(deserialize_with = "lenient")]
+    pub version: Option<String>,
+    #[serde(deserialize_with = "lenient")]
+    pub description: Option<String>,
+    #[serde(deserialize_with = "lenient")]
+    pub main: Option<String>,
+    #[serde(deserialize_with = "lenient")]
+    pub private: bool,
+    #[serde(deserialize_with = "string_map")]
+    pub dependencies: IndexMap<String, String>,
+    #[serde(deserialize_with = "string_map")]
+    pub dev_dependencies: IndexMap<String, String>,
+    #[serde(deserialize_with = "string_map")]
+    pub optional_dependencies: IndexMap<String, String>,
+    #[serde(deserialize_with = "string_map")]
+    pub peer_dependencies: IndexMap<String, String>,
+    /// `bundleDependencies`, or `bundledDependencies`: the names of the dependencies shipped in the tarball's own
+    /// node_modules, or `true` for all of them
+    #[serde(alias = "bundledDependencies", deserialize_with = "lenient")]
+    pub bundle_dependencies: Option<Value>,
+    #[serde(deserialize_with = "string_map")]
+    pub scripts: IndexMap<String, String>,
+    #[serde(deserialize_with = "bin")]
+    pub bin: Option<Bin>,
+    /// Ranges of `node` and `npm` versions the package runs on
+    #[serde(deserialize_with = "string_map")]
+    pub engines: IndexMap<String, String>,
+    #[serde(deserialize_with = "string_list")]
+    pub os: Vec<String>,
+    #[serde(deserialize_with = "string_list")]
+    pub cpu: Vec<String>,
+    #[serde(deserialize_with = "string_list")]
+    pub libc: Vec<String>,
+    /// What goes in the package tarball, everything when None
+    #[serde(deserialize_with = "lenient")]
+    pub files: Option<Vec<String>>,
+    /// Globs of workspace directories, from a list or `{"packages": [...]}`
+    #[serde(deserialize_with = "workspaces")]
+    pub workspaces: Option<Vec<String>>,
+}
+
+/// The `bin` field: a path for one executable named after the package, or paths by name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Bin {
+    Single(String),
+    Named(IndexMap<String, String>),
+}
+
+impl Manifest {
+    /// Read package.json itself, or the one in a directory. Only a missing file or invalid JSON fail.
+    pub fn read(path: &Path) -> Result<Manifest> {
+        let path = manifest_path(path);
+        let text = fs::read_to_string(&path).map_err(|err| NaryError::io(&path, err))?;
+        let manifest: Value = serde_json::from_str(&text).map_err(|err| NaryError::json(path.display(), err))?;
+        Ok(Manifest::from_value(&manifest))
+    }
+
+    /// A manifest from JSON already parsed, like registry metadata. Anything but an object reads as empty.
+    pub fn from_value(manifest: &Value) -> Manifest {
+        Manifest::deserialize(manifest).unwrap_or_default()
+    }
+
+    /// The package as a dependency on its own version, with the name and version empty when they're missing
+    pub fn dependency(&self) -> Dependency {
+        Dependency::new(self.name.clone().unwrap_or_default(), self.version.clone().unwrap_or_default())
+    }
+
+    /// `dependencies` and `optionalDependencies`. A package listed in both is optional.
+    pub fn runtime_dependencies(&self) -> Vec<(Dependency, DependencyKind)> {
+        runtime_dependencies_of(&self.dependencies, &self.optional_dependencies)
+    }
+
+    /// The names of the dependencies the package ships in its own node_modules
+    pub fn bundled(&self) -> Vec<String> {
+        bundled_names(self.bundle_dependencies.as_ref(), &self.dependencies)
+    }
+
+    /// Names and package-relative paths of the executables in `bin`. A single path is named after the package.
+    /// Names with path separators and targets outside the package are left out.
+    pub fn bins(&self) -> Vec<(String, PathBuf)> {
+        let declared: Vec<(String, &str)> = match &self.bin {
+            Some(Bin::Single(target)) => {
+                let name = self.name.as_deref().unwrap_or_default();
+                vec![(name.rsplit('/').next().unwrap_or(name).to_string(), target.as_str())]
+            }
+            Some(Bin::Named(bins)) => bins.iter().map(|(name, target)| (name.clone(), target.as_str())).collect(),
+            None => Vec::new(),
+        };
+
+        declared
+            .into_iter()
+            .filter(|(name, _)| !name.is_empty() && !name.contains(['/', '\\']) && name != "." && name != "..")
+            .filter_map(|(name, target)| Some((name, normalize(Path::new(target))?)))
+            .filter(|(_, target)| !target.as_os_str().is_empty())
+            .collect()
+    }
+}
+
+/// The dependencies of a package.json section, leaving out keys starting with `_`, which are npm's own bookkeeping
+pub(crate) fn map_dependencies(dependencies: &IndexMap<String, String>) -> Vec<Dependency> {
+    dependencies
+        .iter()
+        .filter(|(name, _)| !name.starts_with('_'))
+        .map(|(name, version)| Dependency::new(name.clone(), version.clone()))
+        .collect()
+}
+
+/// `dependencies` and `optionalDependencies` sections as one list, with a package listed in both as optional
+pub(crate) fn runtime_dependencies_of(
+    dependencies: &IndexMap<String, String>,
+    optional: &IndexMap<String, String>,
+) -> Vec<(Dependency, DependencyKind)> {
+    let mut runtime: Vec<(Dependency, DependencyKind)> = map_dependencies(dependencies)
+        .into_iter()
+        .filter(|dependency| !optional.contains_key(&dependency.name))
+        .map(|dependency| (dependency, DependencyKind::Normal))
+        .collect();
+    runtime.extend(map_dependencies(optional).into_iter().map(|dependency| (dependency, DependencyKind::Optional)));
+    runtime
+}
+
+/// The names a `bundleDependencies` field lists, or every name in `dependencies` for `true`
+pub(crate) fn bundled_names(bundled: Option<&Value>, dependencies: &IndexMap<String, String>) -> Vec<String> {
+    match bundled {
+        Some(Value::Bool(true)) => dependencies.keys().cloned().collect(),
+        Some(names @ Value::Array(_)) => strings(names.clone()),
+        _ => Vec::new(),
+    }
+}
+
+fn lenient<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + Default,
+{
+    let value = Value::deserialize(deserializer)?;
+    Ok(T::deserialize(value).unwrap_or_default())
+}
+
+/// The members of an object whose values are strings
+fn string_map<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<IndexMap<String, String>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Object(members) => members
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                Value::String(value) => Some((key, value)),
+                _ => None,
+            })
+            .collect(),
+        _ => IndexMap::new(),
+    })
+}
+
+/// The strings of a list, or a single string as a list of one
+fn string_list<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<String>, D::Error> {
+    Ok(strings(Value::deserialize(deserializer)?))
+}
+
+fn strings(value: Value) -> Vec<String> {
+    match value {
+        Value::String(value) => vec![value],
+        Value::Array(values) => values
+            .into_iter()
+            .filter_map(|value| match value {
+                Value::String(value) => Some(value),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn bin<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Bin>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(target) => Some(Bin::Single(target)),
+        bins @ Value::Object(_) => Some(Bin::Named(string_map(bins).unwrap_or_default())),
+        _ => None,
+    })
+}
+
+fn workspaces<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Vec<String>>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        patterns @ Value::Array(_) => Some(strings(patterns)),
+        Value::Object(mut workspaces) => match workspaces.remove("packages") {
+            Some(patterns @ Value::Array(_)) => Some(strings(patterns)),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// What `init` writes into a new package.json. The `with_` setters take an answer as it's typed at a prompt, with
+/// an empty one keeping what's there, so the current values can be shown as the defaults.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InitOptions {
+    /// Inferred from the directory's name when None
+    pub name: Option<String>,
+    pub version: String,
+    pub description: String,
+    /// The entry point
+    pub main: String,
+    pub author: String,
+    pub license: String,
+    /// Globs of workspace directories, making the package a private workspace root
+    pub workspaces: Option<Vec<String>>,
+    /// Replace a package.json that's already there
+    pub force: bool,
+}
+
+impl Default for InitOptions {
+    fn default() -> InitOptions {
+        InitOptions {
+            name: None,
+            version: "1.0.0".to_string(),
+            description: String::new(),
+            main: "index.js".to_string(),
+            author: String::new(),
+            license: "ISC".to_string(),
+            workspaces: None,
+            force: false,
+        }
+    }
+}
+
+impl InitOptions {
+    pub fn with_name(mut self, name: &str) -> InitOptions {
+        if let Some(name) = answered(name) {
+            self.name = Some(name);
+        }
+        self
+    }
+
+    pub fn with_version(mut self, version: &str) -> InitOptions {
+        self.version = answered(version).unwrap_or(self.version);
+        self
+    }
+
+    pub fn with_description(mut self, description: &str) -> InitOptions {
+        self.description = answered(description).unwrap_or(self.description);
+        self
+    }
+
+    pub fn with_main(mut self, main: &str) -> InitOptions {
+        self.main = answered(main).unwrap_or(self.main);
+        self
+    }
+
+    pub fn with_author(mut self, author: &str) -> InitOptions {
+        self.author = answered(author).unwrap_or(self.author);
+        self
+    }
+
+    pub fn with_license(mut self, license: &str) -> InitOptions {
+        self.license = answered(license).unwrap_or(self.license);
+        self
+    }
+
+    /// Workspace globs separated by commas or spaces, like `packages/*, apps/*`
+    pub fn with_workspaces(mut self, workspaces: &str) -> InitOptions {
+        let separator = |c: char| c == ',' || c.is_whitespace();
+        let globs: Vec<String> =
+            workspaces.split(separator).filter(|glob| !glob.is_empty()).map(str::to_string).collect();
+        if !globs.is_empty() {
+            self.workspaces = Some(globs);
+        }
+        self
+    }
+
+    /// The name `init` gives the package in `dir`
+    pub fn name_for(&self, dir: &Path) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => inferred_name(dir),
+        }
+    }
+}
+
+fn answered(answer: &str) -> Option<String> {
+    Some(answer.trim()).filter(|answer| !answer.is_empty()).map(str::to_string)
+}
+
+/// The directory's name as a package name: lowercased, with anything npm wouldn't take in a name made a `-`
+fn inferred_name(dir: &Path) -> String {
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    let name: String = dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-._~".contains(c) { c } else { '-' })
+        .collect();
+    let name = name.trim_start_matches(['.', '_']);
+    if name.is_empty() {
+        "package".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Write a new package.json into `dir`, creating the directory, and the base directory of each workspace glob,
+/// when they're missing. An existing package.json is only replaced when `force`. Returns what was written.
+pub fn init(dir: &Path, options: &InitOptions) -> Result<Manifest> {
+    let path = manifest_path(dir);
+    if path.exists() && !options.force {
+        return Err(NaryError::ManifestExists { path });
+    }
+    let invalid = |reason: String| NaryError::InvalidManifest {
+        path: path.clone(),
+        reason,
+    };
+    let name = options.name_for(dir);
+    PackageName::parse(&name).map_err(|_| invalid(format!("{} isn't a package name", name)))?;
+    // Anything parses, as an empty version when it isn't one
+    let version = Version::new(&options.version).parse().ok().map(|version| version.to_string());
+    if version.as_ref() != Some(&options.version) {
+        return Err(invalid(format!("{} isn't a version", options.version)));
+    }
+
+    let mut manifest: IndexMap<&str, Value> = IndexMap::new();
+    manifest.insert("name", json!(name));
+    manifest.insert("version", json!(options.version));
+    if let Some(workspaces) = &options.workspaces {
+        manifest.insert("private", json!(true));
+        manifest.insert("workspaces", json!(workspaces));
+    }
+    manifest.insert("description", json!(options.description));
+    manifest.insert("main", json!(options.main));
+    manifest.insert("scripts", json!({"test": "echo \"Error: no test specified\" && exit 1"}));
+    manifest.insert("keywords", json!([]));
+    manifest.insert("author", json!(options.author));
+    manifest.insert("license", json!(options.license));
+    let text = serde_json::to_string_pretty(&manifest).map_err(|err| NaryError::json(path.display(), err))?;
+
+    fs::create_dir_all(dir).map_err(|err| NaryError::io(dir, err))?;
+    for glob in options.workspaces.iter().flatten() {
+        // Everything before the first wildcard, like `packages` of `packages/*`
+        let base: PathBuf = Path::new(glob)
+            .components()
+            .take_while(|part| !part.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+            .collect();
+        if !base.as_os_str().is_empty() {
+            let base = dir.join(base);
+            fs::create_dir_all(&base).map_err(|err| NaryError::io(&base, err))?;
+        }
+    }
+    fs::write(&path, format!("{}\n", text)).map_err(|err| NaryError::io(&path, err))?;
+
+    Ok(Manifest::from_value(&json!(manifest)))
+}
+
+/// The sections of package.json a dependency can be saved in, by kind
+const SECTIONS: [(DependencyKind, &str); 2] =
+    [(DependencyKind::Normal, "dependencies"), (DependencyKind::Optional, "optionalDependencies")];
+
+/// A `name@range` spec as typed on the command line. The range is empty when there's none, like for `name` alone.
+pub fn parse_spec(spec: &str) -> Dependency {
+    // Skip the @ of a scope
+    match spec.char_indices().skip(1).find(|(_, c)| *c == '@') {
+        Some((index, _)) => Dependency::new(spec[..index].to_string(), spec[index + 1..].to_string()),
+        None => Dependency::new(spec.to_string(), ""),
+    }
+}
+
+/// Save a dependency on `spec` in the section of package.json for `kind`, moving it out of any other. A range is
+/// saved as it's given; a dist-tag or no version at all is resolved and saved as `^<version>`, or the version alone
+/// when `save_exact`, which also pins given ranges. Local directories, tarballs and git repositories are saved as
+/// they are. The rest of package.json is left as it's written. Returns the dependency as it was saved.
+pub fn add_dependency(
+    path: &Path,
+    spec: &str,
+    kind: DependencyKind,
+    save_exact: bool,
+    registry: &dyn RegistryClient,
+    options: &ResolutionOptions,
+) -> Result<Dependency> {
+    let dependency = saved_dependency(&parse_spec(spec), save_exact, registry, options)?;
+
+    let path = manifest_path(path);
+    let text = fs::read_to_string(&path).map_err(|err| NaryError::io(&path, err))?;
+    let text = save_dependency(&text, &path, &dependency, kind)?;
+    fs::write(&path, text).map_err(|err| NaryError::io(&path, err))?;
+
+    Ok(dependency)
+}
+
+/// The dependency as `add_dependency` saves it
+pub(crate) fn saved_dependency(
+    requested: &Dependency,
+    save_exact: bool,
+    registry: &dyn RegistryClient,
+    options: &ResolutionOptions,
+) -> Result<Dependency> {
+    requested.package_name()?;
+    Ok(Dependency::new(requested.name.clone(), saved_range(requested, save_exact, registry, options)?))
+}
+
+/// The text of package.json with `dependency` in the section for `kind` and out of any other
+pub(crate) fn save_dependency(
+    text: &str,
+    path: &Path,
+    dependency: &Dependency,
+    kind: DependencyKind,
+) -> Result<String> {
+    let mut text = text.to_string();
+    for (other, section) in &SECTIONS {
+        if *other != kind {
+            text = edit(&text, path, section, &dependency.name, None)?;
+        }
+    }
+    let section = SECTIONS.iter().find(|(section_kind, _)| *section_kind == kind).map(|(_, section)| *section);
+    edit(&text, path, section.unwrap_or("dependencies"), &dependency.name, Some(&dependency.version))
+}
+
+/// Remove the dependency on `name` from every section of package.json it's in, leaving the rest as it's written.
+/// Returns whether there was one.
+pub fn remove_dependency(path: &Path, name: &str) -> Result<bool> {
+    let path = manifest_path(path);
+    let original = fs::read_to_string(&path).map_err(|err| NaryError::io(&path, err))?;
+    let mut text = original.clone();
+    for (_, section) in &SECTIONS {
+        text = edit(&text, &path, section, name, None)?;
+    }
+    if text == original {
+        return Ok(false);
+    }
+    fs::write(&path, text).map_err(|err| NaryError::io(&path, err))?;
+    Ok(true)
+}
+
+/// package.json itself, or the one in a directory
+pub(crate) fn manifest_path(path: &Path) -> PathBuf {
+    if path.ends_with("package.json") {
+        path.to_path_buf()
+    } else {
+        path.join("package.json")
+    }
+}
+
+fn saved_range(
+    requested: &Dependency,
+    save_exact: bool,
+    registry: &dyn RegistryClient,
+    options: &ResolutionOptions,
+) -> Result<String> {
+    let version = &requested.version;
+    let alias = match requested.specifier() {
+        Specifier::File(_) | Specifier::Git(_) | Specifier::Url(_) => return Ok(version.clone()),
+        Specifier::Alias(target) => Some(target),
+        _ => None,
+    };
+    let target = alias.map_or_else(|| requested.clone(), |target| Dependency::clone(target));
+    let packument = registry.packument(&target.package_name()?)?;
+    // Resolved either way, so that nothing that can't be installed is saved
+    let (resolved, _) = fetch_matching_version_metadata(&target, &packument, options)?;
+    let tagged = target.version.is_empty() || packument.dist_tags.contains_key(&target.version);
+    if !tagged && !save_exact {
+        return Ok(version.clone());
+    }
+
+    let range = if save_exact { resolved.clone() } else { format!("^{}", resolved) };
+    Ok(match alias {
+        Some(alias) => format!("npm:{}@{}", alias.name, range),
+        None => range,
+    })
+}
+
+/// A member of a JSON object, by where it is in the text
+struct Member {
+    key: String,
+    /// Where its key's opening quote is
+    start: usize,
+    /// Where its value ends
+    end: usize,
+    /// Where its value starts
+    value: usize,
+}
+
+/// An object in the text, by its braces and members
+struct Object {
+    open: usize,
+    close: usize,
+    members: Vec<Member>,
+}
+
+/// Set `name` in the `section` object of package.json to `version`, or take it out when None, changing nothing
+/// else. A new member goes where it sorts when the section is sorted, and last when not; a missing section is added
+/// last, indented like the rest of the file.
+fn edit(text: &str, path: &Path, section: &str, name: &str, version: Option<&str>) -> Result<String> {
+    let json = |err| NaryError::json(path.display(), err);
+    serde_json::from_str::<Value>(text).map_err(json)?;
+    let malformed = || NaryError::InvalidManifest {
+        path: path.to_path_buf(),
+        reason: format!("{} isn't an object", section),
+    };
+    let root = object(text, 0).ok_or_else(malformed)?;
+    let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let entry = match version {
+        Some(version) => format!("{}: {}", quote(name), quote(version)),
+        None => String::new(),
+    };
+
+    let member = match root.members.iter().find(|member| member.key == section) {
+        Some(member) => member,
+        None if version.is_none() => return Ok(text.to_string()),
+        None => {
+            // A section of its own, after the last member
+            let (at, lead) = match root.members.last() {
+                Some(last) => (last.end, format!(",{}", leading(text, last.start))),
+                None => (root.open + 1, format!("{}  ", newline)),
+            };
+            let indent = lead.rsplit('\n').next().unwrap_or_default().to_string();
+            let unit = indent_unit(text, &root);
+            let pretty = lead.contains('\n');
+            let opened = if pretty { format!("{}{}{}", newline, indent, unit) } else { String::new() };
+            let closed = if pretty { format!("{}{}", newline, indent) } else { String::new() };
+            let addition = format!("{}{}: {{{}{}{}}}", lead, quote(section), opened, entry, closed);
+            // An empty root gets its closing brace on a line of its own
+            let closing = if root.members.is_empty() && pretty { newline } else { "" };
+            return Ok(format!("{}{}{}{}", &text[..at], addition, closing, &text[at..]));
+        }
+    };
+    let dependencies = object(text, member.value).ok_or_else(malformed)?;
+    let members = &dependencies.members;
+
+    let existing = members.iter().position(|member| member.key == name);
+    let (range, replacement) = match (existing, version) {
+        (Some(index), Some(version)) => (members[index].value..members[index].end, quote(version)),
+        (Some(index), None) => {
+            let range = if index + 1 < members.len() {
+                members[index].start..members[index + 1].start
+            } else if index > 0 {
+                members[index - 1].end..members[index].end
+            } else {
+                dependencies.open + 1..dependencies.close
+            };
+            (range, String::new())
+        }
+        (None, None) => return Ok(text.to_string()),
+        (None, Some(_)) => {
+            let sorted = members.windows(2).all(|pair| pair[0].key <= pair[1].key);
+            let before = if sorted { members.iter().find(|member| member.key.as_str() > name) } else { None };
+            match (before, members.last()) {
+                (Some(before), _) => {
+                    (before.start..before.start, format!("{},{}", entry, leading(text, before.start)))
+                }
+                (None, Some(last)) => (last.end..last.end, format!(",{}{}", leading(text, last.start), entry)),
+                (None, None) => {
+                    let indent = leading(text, member.start);
+                    let inner = format!("{}{}", indent, indent_unit(text, &root));
+                    let replacement = if indent.contains('\n') {
+                        format!("{}{}{}", inner, entry, indent)
+                    } else {
+                        entry
+                    };
+                    (dependencies.open + 1..dependencies.close, replacement)
+                }
+            }
+        }
+    };
+    Ok(format!("{}{}{}", &text[..range.start], replacement, &text[range.end..]))
+}
+
+/// The whitespace between a member and whatever is before it
+fn leading(text: &str, start: usize) -> &str {
+    let before = &text[..start];
+    let trimmed = before.trim_end_matches(|c: char| c.is_ascii_whitespace());
+    &before[trimmed.len()..]
+}
+
+/// How far the members of the root are indented, two spaces when that can't be told
+fn indent_unit(text: &str, root: &Object) -> String {
+    root.members
+        .first()
+        .map(|first| leading(text, first.start))
+        .and_then(|lead| lead.rsplit_once('\n').map(|(_, indent)| indent.to_string()))
+        .filter(|indent| !indent.is_empty())
+        .unwrap_or_else(|| "  ".to_string())
+}
+
+fn quote(value: &str) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+/// The object whose opening brace is the first non-whitespace at or after `from`
+fn object(text: &str, from: usize) -> Option<Object> {
+    let bytes = text.as_bytes();
+    let open = skip_whitespace(bytes, from);
+    if bytes.get(open) != Some(&b'{') {
+        return None;
+    }
+
+    let mut members = Vec::new();
+    let mut at = skip_whitespace(bytes, open + 1);
+    while bytes.get(at) == Some(&b'"') {
+        let key_end = skip_string(bytes, at)?;
+        let key = serde_json::from_str(&text[at..key_end]).ok()?;
+        let colon = skip_whitespace(bytes, key_end);
+        let value = skip_whitespace(bytes, colon + 1);
+        let end = skip_value(bytes, value)?;
+        members.push(Member { key, start: at, end, value });
+
+        at = skip_whitespace(bytes, end);
+        if bytes.get(at) == Some(&b',') {
+            at = skip_whitespace(bytes, at + 1);
+        }
+    }
+    match bytes.get(at) {
+        Some(b'}') => Some(Object { open, close: at, members }),
+        _ => None,
+    }
+}
+
+fn skip_whitespace(bytes: &[u8], mut at: usize) -> usize {
+    while bytes.get(at).is_some_and(u8::is_ascii_whitespace) {
+        at += 1;
+    }
+    at
+}
+
+/// Past the closing quote of the string starting at `at`
+fn skip_string(bytes: &[u8], mut at: usize) -> Option<usize> {
+    at += 1;
+    loop {
+        match bytes.get(at)? {
+            b'\\' => at += 2,
+            b'"' => return Some(at + 1),
+            _ => at += 1,
+        }
+    }
+}
+
+/// Past the end of the value starting at `at`
+fn skip_value(bytes: &[u8], mut at: usize) -> Option<usize> {
+    match bytes.get(at)? {
+        b'"' => skip_string(bytes, at),
+        b'{' | b'[' => {
+            let mut depth = 0;
+            loop {
+                match bytes.get(at)? {
+                    b'"' => {
+                        at = skip_string(bytes, at)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(at + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                at += 1;
+            }
+        }
+        _ => {
+            while bytes.get(at).is_some_and(|byte| !b",}] \t\r\n".contains(byte)) {
+                at += 1;
+            }
+            Some(at)
+        }
+    }
+}
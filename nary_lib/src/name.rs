@@ -0,0 +1,74 @@
+use percent_encoding::utf8_percent_encode;
+use std::{fmt, path::PathBuf};
+
+use crate::{NaryError, Result, PATH_SEGMENT_ENCODE_SET};
+
+/// A package name, optionally scoped (`@scope/name`)
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PackageName {
+    pub scope: Option<String>,
+    pub name: String,
+}
+
+impl PackageName {
+    pub fn parse(full_name: &str) -> Result<PackageName> {
+        let (scope, name) = if let Some(scoped) = full_name.strip_prefix('@') {
+            let slash = scoped
+                .find('/')
+                .ok_or_else(|| NaryError::InvalidPackageName(full_name.to_string()))?;
+            let (scope, name) = scoped.split_at(slash);
+            (Some(scope.to_string()), name[1..].to_string())
+        } else {
+            (None, full_name.to_string())
+        };
+
+        if let Some(scope) = &scope {
+            validate_segment(full_name, scope)?;
+        }
+        validate_segment(full_name, &name)?;
+
+        Ok(PackageName { scope, name })
+    }
+
+    /// Path segment used in registry URLs: `@scope%2fname` for scoped packages
+    pub fn registry_path(&self) -> String {
+        let name = utf8_percent_encode(&self.name, PATH_SEGMENT_ENCODE_SET).to_string();
+        match &self.scope {
+            Some(scope) => format!("@{}%2f{}", utf8_percent_encode(scope, PATH_SEGMENT_ENCODE_SET), name),
+            None => name,
+        }
+    }
+
+    /// Relative directory of the package, `@scope/name` for scoped packages
+    pub fn to_path(&self) -> PathBuf {
+        let mut path = PathBuf::new();
+        if let Some(scope) = &self.scope {
+            path.push(format!("@{}", scope));
+        }
+        path.push(&self.name);
+        path
+    }
+}
+
+impl fmt::Display for PackageName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.scope {
+            Some(scope) => write!(f, "@{}/{}", scope, self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Scope and name segments end up as directory names, so they must not be able to escape
+fn validate_segment(full_name: &str, segment: &str) -> Result<()> {
+    if segment.is_empty()
+        || segment.starts_with('.')
+        || segment.contains('/')
+        || segment.contains('\\')
+        || segment.contains('\0')
+    {
+        return Err(NaryError::InvalidPackageName(full_name.to_string()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use crate::{
+    AuditReport, Dependency, DependencyKind, InstallOptions, InstallReporter, RegistryClient, ResolutionOptions,
+    ResolvedGraph, Result,
+};
+
+/// Runs blocking work somewhere it's allowed to block, so the functions here work with any executor: with tokio
+/// that's `tokio::task::spawn_blocking`, and `ThreadPerCall` needs none at all.
+pub trait SpawnBlocking: Send + Sync {
+    fn spawn_blocking(&self, work: Box<dyn FnOnce() + Send>);
+}
+
+/// Spawns a new OS thread for every call
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadPerCall;
+
+impl SpawnBlocking for ThreadPerCall {
+    fn spawn_blocking(&self, work: Box<dyn FnOnce() + Send>) {
+        thread::spawn(work);
+    }
+}
+
+/// `crate::calculate_depends` handed to `spawner`, for embedders that can't block the task they're on. This isn't
+/// async I/O: the fetch engine still blocks whatever thread `spawner` runs it on until it's done.
+pub fn calculate_depends(
+    spawner: &dyn SpawnBlocking,
+    root_pkg: Dependency,
+    deps: Vec<(Dependency, DependencyKind)>,
+    registry: Arc<dyn RegistryClient>,
+    options: ResolutionOptions,
+    reporter: Arc<dyn InstallReporter>,
+) -> Blocking<Result<ResolvedGraph>> {
+    spawn(spawner, move || crate::calculate_depends(&root_pkg, &deps, &*registry, &options, &*reporter))
+}
+
+/// `crate::install_dep` handed to `spawner`
+pub fn install_dep(
+    spawner: &dyn SpawnBlocking,
+    path: PathBuf,
+    dep: Dependency,
+    kind: DependencyKind,
+    registry: Arc<dyn RegistryClient>,
+    options: InstallOptions,
+    reporter: Arc<dyn InstallReporter>,
+) -> Blocking<Result<()>> {
+    spawn(spawner, move || crate::install_dep(&path, &dep, kind, &*registry, &options, &*reporter))
+}
+
+/// `crate::audit` handed to `spawner`
+pub fn audit(
+    spawner: &dyn SpawnBlocking,
+    graph: Arc<ResolvedGraph>,
+    registry: Arc<dyn RegistryClient>,
+    options: ResolutionOptions,
+) -> Blocking<Result<AuditReport>> {
+    spawn(spawner, move || crate::audit(&graph, &*registry, &options))
+}
+
+fn spawn<T: Send + 'static>(spawner: &dyn SpawnBlocking, work: impl FnOnce() -> T + Send + 'static) -> Blocking<T> {
+    let shared = Arc::new(Mutex::new(Shared {
+        result: None,
+        waker: None,
+    }));
+    let completion = Completion(Some(shared.clone()));
+    spawner.spawn_blocking(Box::new(move || {
+        // A panic is handed to the caller, rather than leaving it waiting forever
+        let result = panic::catch_unwind(AssertUnwindSafe(work));
+        completion.complete(result);
+    }));
+    Blocking { shared }
+}
+
+/// The result of work handed to a `SpawnBlocking`, ready once the work is done. A panic in the work, or work the
+/// spawner dropped without running, panics whoever polls it.
+pub struct Blocking<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+struct Shared<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut shared = self.shared.lock().unwrap_or_else(PoisonError::into_inner);
+        match shared.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            Some(Err(panicked)) => panic::resume_unwind(panicked),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Hands the result of the work to its `Blocking`, once
+struct Completion<T>(Option<Arc<Mutex<Shared<T>>>>);
+
+impl<T> Completion<T> {
+    fn complete(mut self, result: thread::Result<T>) {
+        self.finish(result);
+    }
+
+    fn finish(&mut self, result: thread::Result<T>) {
+        if let Some(shared) = self.0.take() {
+            let mut shared = shared.lock().unwrap_or_else(PoisonError::into_inner);
+            shared.result = Some(result);
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Panics the `Blocking` of work that's dropped without being run
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        self.finish(Err(Box::new("the blocking work was dropped without being run")));
+    }
+}
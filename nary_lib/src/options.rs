@@ -0,0 +1,226 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    cache::PACKUMENT_MAX_AGE, CacheValidators, Engines, InstallFs, InstallHooks, NoHooks, Override, Platform, RealFs,
+    WorkspaceMember,
+};
+
+/// How resolution and installation are allowed to use the network, and how packages end up on disk
+#[derive(Clone)]
+pub struct InstallOptions {
+    /// Only use cached packuments and tarballs, failing when something isn't cached
+    pub offline: bool,
+    /// Use cached packuments when present, only going to the registry for the rest, whatever `freshness` says
+    pub prefer_offline: bool,
+    /// Ask the registry about every packument, like `Freshness::PreferOnline`, whatever `freshness` says
+    pub force_refresh: bool,
+    /// When a cached packument is used as it is rather than checked with the registry
+    pub freshness: Freshness,
+    pub strategy: InstallStrategy,
+    pub layout: Layout,
+    /// How many packages may be unpacked at once, 0 for one per CPU
+    pub parallelism: usize,
+    /// Where a project's packages go instead of its node_modules, relative to the project
+    pub modules_dir: Option<PathBuf>,
+    /// Called along the way of an install, to enforce policies or collect telemetry. `NoHooks` by default.
+    pub hooks: Arc<dyn InstallHooks>,
+    /// What packages are unpacked and linked into, and tarballs cached in. `RealFs` by default.
+    pub fs: Arc<dyn InstallFs>,
+}
+
+impl Default for InstallOptions {
+    fn default() -> InstallOptions {
+        InstallOptions {
+            offline: false,
+            prefer_offline: false,
+            force_refresh: false,
+            freshness: Freshness::default(),
+            strategy: InstallStrategy::default(),
+            layout: Layout::default(),
+            parallelism: 0,
+            modules_dir: None,
+            hooks: Arc::new(NoHooks),
+            fs: Arc::new(RealFs),
+        }
+    }
+}
+
+/// Everything but the hooks and the filesystem, which can't be printed
+impl fmt::Debug for InstallOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InstallOptions")
+            .field("offline", &self.offline)
+            .field("prefer_offline", &self.prefer_offline)
+            .field("force_refresh", &self.force_refresh)
+            .field("freshness", &self.freshness)
+            .field("strategy", &self.strategy)
+            .field("layout", &self.layout)
+            .field("parallelism", &self.parallelism)
+            .field("modules_dir", &self.modules_dir)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How node_modules is arranged
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// Every package directly in node_modules, where anything can require anything
+    #[default]
+    Hoisted,
+    /// Packages in a `.nary` virtual store, each seeing only its own dependencies through symlinks.
+    /// node_modules itself only links the root's direct dependencies.
+    Isolated,
+    /// Every package in a directory of its own, `<name>@<version>` with the `/` of a scope as `+`, and nothing
+    /// linked between them or into `.bin`. For bundling the files of packages rather than running them with Node.
+    Vendored,
+}
+
+/// How a registry package's files get into node_modules
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InstallStrategy {
+    /// Unpack the tarball into node_modules
+    #[default]
+    Extract,
+    /// Extract once into the global store and hard-link its files, copying across filesystems
+    HardLink,
+    /// Extract once into the global store and reflink its files, copying where copy-on-write isn't supported
+    Reflink,
+}
+
+/// When cached packuments are used as they are, instead of asking the registry whether they've changed. Trusting
+/// them for longer resolves faster, asking sooner sees new releases sooner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Freshness {
+    /// Ask about every one
+    PreferOnline,
+    /// Use any that's cached, only asking about the rest
+    PreferOffline,
+    /// Use those fetched less than this long ago
+    MaxAge(Duration),
+}
+
+/// `PACKUMENT_MAX_AGE`
+impl Default for Freshness {
+    fn default() -> Freshness {
+        Freshness::MaxAge(PACKUMENT_MAX_AGE)
+    }
+}
+
+impl Freshness {
+    /// `prefer-online`, `prefer-offline` or `max-age=<duration>`, the duration in seconds or with an `s`, `m`, `h`
+    /// or `d` after it
+    pub fn parse(value: &str) -> Option<Freshness> {
+        match value {
+            "prefer-online" => Some(Freshness::PreferOnline),
+            "prefer-offline" => Some(Freshness::PreferOffline),
+            _ => parse_duration(value.strip_prefix("max-age=")?).map(Freshness::MaxAge),
+        }
+    }
+}
+
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (amount, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.parse::<u64>().ok()?.checked_mul(seconds).map(Duration::from_secs)
+}
+
+impl InstallOptions {
+    pub(crate) fn use_cached_metadata(&self) -> bool {
+        self.offline || self.metadata_freshness() == Freshness::PreferOffline
+    }
+
+    /// `freshness`, unless `prefer_offline` or `force_refresh` overrule it
+    pub fn metadata_freshness(&self) -> Freshness {
+        if self.prefer_offline {
+            Freshness::PreferOffline
+        } else if self.force_refresh {
+            Freshness::PreferOnline
+        } else {
+            self.freshness
+        }
+    }
+
+    /// Whether a cached packument with these validators is used without asking the registry
+    pub(crate) fn trusts_cached(&self, validators: &CacheValidators) -> bool {
+        match self.metadata_freshness() {
+            _ if self.offline => true,
+            Freshness::PreferOffline => true,
+            Freshness::PreferOnline => false,
+            Freshness::MaxAge(max_age) => validators.fetched_within(max_age),
+        }
+    }
+
+    /// The directory a project's packages go into, node_modules unless `modules_dir` says otherwise
+    pub fn modules_dir(&self, project_dir: &Path) -> PathBuf {
+        project_dir.join(self.modules_dir.as_deref().unwrap_or_else(|| Path::new("node_modules")))
+    }
+
+    pub(crate) fn parallelism(&self) -> usize {
+        if self.parallelism == 0 {
+            thread::available_parallelism().map(|cpus| cpus.get()).unwrap_or(4)
+        } else {
+            self.parallelism
+        }
+    }
+}
+
+/// Which versions resolution is allowed to pick
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResolutionOptions {
+    /// Let ranges match prereleases even when they don't name a prerelease of the same version
+    pub include_prerelease: bool,
+    /// What `os`, `cpu` and `libc` fields are checked against, the current platform by default
+    pub platform: Platform,
+    /// What `engines` fields are checked against, nothing by default
+    pub engines: Engines,
+    /// Workspace packages, which satisfy dependencies on them in range from their own directories
+    pub workspace: Vec<WorkspaceMember>,
+    /// Versions forced on packages regardless of what their dependents ask for
+    pub overrides: Vec<Override>,
+    /// Versions to keep by package name, like those already locked, picked over newer ones while still in range
+    pub preferred: BTreeMap<String, Vec<String>>,
+    /// How many packuments may be fetched at once, 0 for the default of 16
+    pub parallelism: usize,
+    /// Resolve the peer dependencies nothing else brings in as dependencies of the packages asking for them, as
+    /// npm 7 and later do. Optional peers are still left out.
+    pub install_peers: bool,
+    pub strategy: ResolutionStrategy,
+}
+
+/// How versions are picked when packages ask for different ranges of the same one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResolutionStrategy {
+    /// The highest version in each range on its own, so a package can end up in the graph at several versions
+    #[default]
+    Highest,
+    /// One version of each package that every range, peer dependency and override on it allows, as well as the
+    /// platform and strict engines, backtracking to older versions of the packages asking until there is one
+    Strict,
+}
+
+impl ResolutionOptions {
+    pub(crate) fn semver(&self) -> semver_rs::Options {
+        semver_rs::Options::builder().include_prerelease(self.include_prerelease).build()
+    }
+
+    pub(crate) fn parallelism(&self) -> usize {
+        if self.parallelism == 0 {
+            16
+        } else {
+            self.parallelism
+        }
+    }
+}
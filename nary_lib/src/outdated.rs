@@ -0,0 +1,105 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    fetch_matching_version_metadata, tree::read_version, workspace::project_dependencies, Dependency, DependencyKind,
+    Manifest, NaryError, Packument, RealFs, RegistryClient, ResolutionOptions, Result,
+};
+
+/// A dependency with a newer version than the one installed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutdatedDependency {
+    pub name: String,
+    /// The package depending on it, None for the root
+    pub dependent: Option<String>,
+    /// The range it's declared with
+    pub range: String,
+    /// The version installed, None when it isn't
+    pub current: Option<String>,
+    /// The highest version the range allows, None when no version does
+    pub wanted: Option<String>,
+    /// The version the `latest` dist-tag points at
+    pub latest: Option<String>,
+}
+
+/// The root's registry dependencies that aren't at both the version their range wants and the latest one, or with
+/// `transitive` also those of every package installed below it, found the way Node finds them. Packuments are asked
+/// for once each; a registry set to prefer offline answers from the cache.
+pub fn outdated(
+    project_dir: &Path,
+    registry: &dyn RegistryClient,
+    options: &ResolutionOptions,
+    transitive: bool,
+) -> Result<Vec<OutdatedDependency>> {
+    let mut packuments: HashMap<String, Packument> = HashMap::new();
+    let mut outdated = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![(None, project_dir.to_path_buf(), project_dependencies(project_dir)?)];
+
+    while let Some((dependent, dir, dependencies)) = pending.pop() {
+        for (dependency, _) in dependencies {
+            let installed = locate(project_dir, &dir, &dependency.name);
+            let current = installed.as_deref().and_then(|path| read_version(&RealFs, path));
+            if transitive {
+                if let Some(installed) = installed.filter(|installed| visited.insert(installed.clone())) {
+                    let name = Some(dependency.name.clone());
+                    pending.push((name, installed.clone(), manifest_dependencies(&installed)?));
+                }
+            }
+
+            if !dependency.specifier().is_registry() {
+                continue;
+            }
+            let target = dependency.registry_target();
+            if !packuments.contains_key(&target.name) {
+                let packument = registry.packument(&target.package_name()?)?;
+                packuments.insert(target.name.clone(), packument);
+            }
+            let packument = &packuments[&target.name];
+
+            let wanted = match fetch_matching_version_metadata(&target, packument, options) {
+                Ok((wanted, _)) => Some(wanted.clone()),
+                Err(NaryError::NoMatchingVersion { .. }) | Err(NaryError::VersionParse { .. }) => None,
+                Err(err) => return Err(err),
+            };
+            let latest = packument.dist_tags.get("latest").cloned();
+            if current.is_some() && current == wanted && current == latest {
+                continue;
+            }
+            outdated.push(OutdatedDependency {
+                name: dependency.name,
+                dependent: dependent.clone(),
+                range: dependency.version,
+                current,
+                wanted,
+                latest,
+            });
+        }
+    }
+
+    outdated.sort_by(|a, b| (&a.dependent, &a.name).cmp(&(&b.dependent, &b.name)));
+    Ok(outdated)
+}
+
+/// Where Node finds `name` from a package in `dir`: its own node_modules, then the node_modules of each directory
+/// further up, as far as the project
+fn locate(project_dir: &Path, dir: &Path, name: &str) -> Option<PathBuf> {
+    let project_dir = project_dir.canonicalize().ok()?;
+    let mut dir = dir.canonicalize().ok()?;
+    loop {
+        let candidate = dir.join("node_modules").join(name);
+        if candidate.join("package.json").is_file() {
+            return Some(candidate.canonicalize().unwrap_or(candidate));
+        }
+        if dir == project_dir || !dir.pop() {
+            return None;
+        }
+    }
+}
+
+/// `dependencies` and `optionalDependencies` of an installed package
+fn manifest_dependencies(package_dir: &Path) -> Result<Vec<(Dependency, DependencyKind)>> {
+    Ok(Manifest::read(package_dir)?.runtime_dependencies())
+}
@@ -0,0 +1,181 @@
+use semver_rs::{Range, Version};
+use serde_json::{Map, Value};
+use std::{fmt, fs, path::Path};
+
+use crate::{NaryError, ResolvedNode, Result};
+
+/// A package an override applies to, by name and optionally by the versions it resolves to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PackageSelector {
+    pub name: String,
+    pub range: Option<String>,
+}
+
+impl PackageSelector {
+    /// `name` or `name@range`, scoped or not
+    pub fn parse(selector: &str) -> PackageSelector {
+        match selector.get(1..).and_then(|rest| rest.find('@')).map(|at| at + 1) {
+            Some(at) => PackageSelector {
+                name: selector[..at].to_string(),
+                range: Some(selector[at + 1..].to_string()),
+            },
+            None => PackageSelector {
+                name: selector.to_string(),
+                range: None,
+            },
+        }
+    }
+
+    /// Whether a package at `version` is selected. Versions that aren't semver only match selectors without a range.
+    pub fn matches(&self, name: &str, version: &str) -> bool {
+        if self.name != name {
+            return false;
+        }
+        match &self.range {
+            None => true,
+            Some(range) => match (Range::new(range).parse(), Version::new(version).parse()) {
+                (Ok(range), Ok(version)) => range.test(&version),
+                _ => false,
+            },
+        }
+    }
+}
+
+impl fmt::Display for PackageSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.range {
+            Some(range) => write!(f, "{}@{}", self.name, range),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// A version forced on a package wherever it's depended on, or only below the packages in `parents`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Override {
+    /// Packages the overridden one has to be below, outermost first
+    pub parents: Vec<PackageSelector>,
+    pub target: PackageSelector,
+    /// The specifier resolved instead of what the dependent asked for
+    pub version: String,
+}
+
+impl Override {
+    /// Whether the override applies below `ancestors`, the packages from the root's dependency down to the dependent.
+    /// Parents needn't be directly above each other. Each version of a package has one set of dependencies though, so
+    /// one found outside the parents first keeps what it resolved there.
+    pub fn applies_below(&self, ancestors: &[&ResolvedNode]) -> bool {
+        let mut ancestors = ancestors.iter();
+        self.parents
+            .iter()
+            .all(|parent| ancestors.any(|ancestor| parent.matches(&ancestor.name, &ancestor.version)))
+    }
+}
+
+impl fmt::Display for Override {
+    /// The parents and the target, separated by `>`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for parent in &self.parents {
+            write!(f, "{}>", parent)?;
+        }
+        write!(f, "{}", self.target)
+    }
+}
+
+/// The overrides that apply to a dependency on `name` below `ancestors`, those with the most parents first. Whether
+/// a target's range matches is left to the caller, as it's checked against the version resolved without them.
+pub(crate) fn overrides_for<'a>(
+    overrides: &'a [Override],
+    name: &str,
+    ancestors: &[&ResolvedNode],
+) -> impl Iterator<Item = &'a Override> {
+    let mut matching: Vec<&Override> = overrides
+        .iter()
+        .filter(|candidate| candidate.target.name == name && candidate.applies_below(ancestors))
+        .collect();
+    matching.sort_by_key(|candidate| std::cmp::Reverse(candidate.parents.len()));
+    matching.into_iter()
+}
+
+/// The root package.json's `overrides`, as npm nests them, and `resolutions`, as yarn writes them. In `overrides` a
+/// `$name` version refers to the root's own dependency on `name`, and `.` sets the version of the package whose
+/// overrides it's among. `resolutions` keys are paths like `parent/child` or `**/child`.
+pub fn read_overrides(project_dir: &Path) -> Result<Vec<Override>> {
+    let path = project_dir.join("package.json");
+    let manifest = fs::read_to_string(&path).map_err(|err| NaryError::io(&path, err))?;
+    let manifest: Value = serde_json::from_str(&manifest).map_err(|err| NaryError::json(path.display(), err))?;
+
+    let mut overrides = Vec::new();
+    if let Some(npm) = manifest["overrides"].as_object() {
+        npm_overrides(npm, &[], &manifest, &mut overrides)?;
+    }
+    for (key, version) in manifest["resolutions"].as_object().into_iter().flatten() {
+        let version = version.as_str().ok_or_else(|| invalid(key, "its version isn't a string"))?;
+        let mut selectors: Vec<PackageSelector> =
+            resolution_path(key).iter().map(|name| PackageSelector::parse(name)).collect();
+        let target = selectors.pop().ok_or_else(|| invalid(key, "it names no package"))?;
+        overrides.push(Override {
+            parents: selectors,
+            target,
+            version: version.to_string(),
+        });
+    }
+    Ok(overrides)
+}
+
+fn npm_overrides(
+    overrides: &Map<String, Value>,
+    parents: &[PackageSelector],
+    manifest: &Value,
+    into: &mut Vec<Override>,
+) -> Result<()> {
+    for (key, value) in overrides.iter().filter(|(key, _)| *key != ".") {
+        let target = PackageSelector::parse(key);
+        let (version, nested) = match value {
+            Value::String(version) => (Some(version.as_str()), None),
+            Value::Object(nested) => (nested.get(".").and_then(Value::as_str), Some(nested)),
+            _ => return Err(invalid(key, "it's neither a version nor nested overrides")),
+        };
+
+        if let Some(version) = version {
+            let version = match version.strip_prefix('$') {
+                Some(reference) => manifest["dependencies"][reference]
+                    .as_str()
+                    .ok_or_else(|| invalid(key, &format!("the root doesn't depend on {}", reference)))?,
+                None => version,
+            };
+            into.push(Override {
+                parents: parents.to_vec(),
+                target: target.clone(),
+                version: version.to_string(),
+            });
+        }
+        if let Some(nested) = nested {
+            let mut below = parents.to_vec();
+            below.push(target);
+            npm_overrides(nested, &below, manifest, into)?;
+        }
+    }
+    Ok(())
+}
+
+/// The package names in a `resolutions` key, which are separated by `/` unless it's a scope's. `**` segments can be
+/// left out, as parents needn't be directly above each other anyway.
+fn resolution_path(key: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut segments = key.split('/').filter(|segment| !segment.is_empty() && *segment != "**");
+    while let Some(segment) = segments.next() {
+        match segment.starts_with('@').then(|| segments.next()).flatten() {
+            Some(name) => names.push(format!("{}/{}", segment, name)),
+            None => names.push(segment.to_string()),
+        }
+    }
+    names
+}
+
+fn invalid(key: &str, reason: &str) -> NaryError {
+    NaryError::InvalidOverride {
+        key: key.to_string(),
+        reason: reason.to_string(),
+    }
+}
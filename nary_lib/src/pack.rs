@@ -1,80 +1,444 @@
-use anyhow::{anyhow, Context, Result};
-
 use hyper::Url;
-use std::{fs::create_dir_all, io::Read, path::PathBuf};
-use tar::Archive;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+use serde_json::Value;
+use tar::{Archive, Builder, Header};
+use tracing::{debug, debug_span};
+
+use crate::{workspace_of, InstallFs, InstallReporter, NaryError, PackageName, RealFs, Result};
+
+mod files;
+pub use self::files::package_files;
 // use indicatif::ProgressBar;
 
-pub fn gunzip(tarball: Vec<u8>, tarball_url: &Url) -> Result<Vec<u8>> {
-    use flate2::read::GzDecoder;
-    let mut vec = Vec::new();
-    let mut d = GzDecoder::new(tarball.as_slice());
-    let _ = d
-        .read_to_end(&mut vec)
-        .with_context(|| format!("Couldn't read to end of tarball: {}", tarball_url))?;
+/// Unpack a gzipped package tarball into its directory below node_modules, returning that directory
+pub fn unpack_package(
+    node_modules: &Path,
+    name: &PackageName,
+    tarball: Vec<u8>,
+    tarball_url: &Url,
+    reporter: &dyn InstallReporter,
+) -> Result<PathBuf> {
+    unpack_stream(node_modules, name, tarball.as_slice(), tarball_url, reporter)
+}
+
+/// Like `unpack_package`, but decompresses and extracts the tarball as it's read
+pub fn unpack_stream(
+    node_modules: &Path,
+    name: &PackageName,
+    tarball: impl Read,
+    tarball_url: &Url,
+    reporter: &dyn InstallReporter,
+) -> Result<PathBuf> {
+    let mut path = node_modules.to_path_buf();
+    path.push(name.to_path());
 
-    Ok(vec)
+    extract_stream(tarball, &path, tarball_url, &RealFs, reporter)?;
+
+    Ok(path)
 }
 
-pub fn unpack_archive(
-    archive: &mut Archive<&[u8]>,
-    destination_path: &PathBuf,
+/// Decompress and extract a gzipped tarball into `destination_path`, then read what's left of it, so a stream
+/// that's being cached is seen to its end
+pub(crate) fn extract_stream(
+    tarball: impl Read,
+    destination_path: &Path,
     tarball_url: &Url,
+    fs: &dyn InstallFs,
+    reporter: &dyn InstallReporter,
 ) -> Result<()> {
+    let _span = debug_span!("extract", url = %tarball_url, path = %destination_path.display()).entered();
+    // The gzip header is read up front
+    let decoder = GzDecoder::new(tarball);
+    if decoder.header().is_none() {
+        return Err(NaryError::unpack(tarball_url, "isn't gzipped".to_string(), None));
+    }
+
+    let mut archive = Archive::new(decoder);
+    unpack_archive(&mut archive, &long_path(destination_path), tarball_url, fs, reporter)?;
+
+    let mut rest = archive.into_inner().into_inner();
+    let trailing = io::copy(&mut rest, &mut io::sink())
+        .map_err(|err| NaryError::unpack(tarball_url, "couldn't be read to the end".to_string(), Some(err)))?;
+    debug!(trailing_bytes = trailing, "extracted");
+
+    Ok(())
+}
+
+/// Written into each installed registry package, holding the identity of the tarball it came from
+pub const INTEGRITY_FILE: &str = ".nary-integrity";
+
+/// Whether the package directory holds `version`, unpacked from the tarball with this identity
+pub(crate) fn is_up_to_date(fs: &dyn InstallFs, package_dir: &Path, version: &str, identity: &str) -> bool {
+    let recorded = fs.read(&package_dir.join(INTEGRITY_FILE)).ok();
+    let manifest = fs
+        .read(&package_dir.join("package.json"))
+        .ok()
+        .and_then(|manifest| serde_json::from_slice::<Value>(&manifest).ok());
+
+    recorded.as_deref() == Some(identity.as_bytes()) && manifest.is_some_and(|manifest| manifest["version"] == version)
+}
+
+pub(crate) fn record_identity(fs: &dyn InstallFs, package_dir: &Path, identity: &str) -> Result<()> {
+    let path = package_dir.join(INTEGRITY_FILE);
+    fs.write_file(&path, &mut identity.as_bytes(), false).map(|_| ()).map_err(|err| NaryError::io(path, err))
+}
+
+/// The package.json inside a gzipped package tarball
+pub fn read_manifest(tarball: &[u8], tarball_url: &Url) -> Result<Value> {
+    let manifest = read_package_file(tarball, tarball_url, "package.json")?
+        .ok_or_else(|| NaryError::unpack(tarball_url, "has no package/package.json".to_string(), None))?;
+    serde_json::from_str(&manifest).map_err(|err| NaryError::json(tarball_url, err))
+}
+
+/// The npm-shrinkwrap.json inside a gzipped package tarball, None when it doesn't ship one
+pub fn read_shrinkwrap(tarball: &[u8], tarball_url: &Url) -> Result<Option<String>> {
+    read_package_file(tarball, tarball_url, "npm-shrinkwrap.json")
+}
+
+/// A file at the top of the package inside a gzipped tarball
+fn read_package_file(tarball: &[u8], tarball_url: &Url, file_name: &str) -> Result<Option<String>> {
+    let mut archive = Archive::new(GzDecoder::new(tarball));
+    let entries = archive
+        .entries()
+        .map_err(|err| NaryError::unpack(tarball_url, "didn't provide file entries".to_string(), Some(err)))?;
+    let wanted = Path::new("package").join(file_name);
+
+    for entry in entries {
+        let mut entry =
+            entry.map_err(|err| NaryError::unpack(tarball_url, "couldn't read an entry".to_string(), Some(err)))?;
+        if entry.path().map(|path| path == wanted).unwrap_or(false) {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).map_err(|err| {
+                NaryError::unpack(tarball_url, format!("couldn't read {}", file_name), Some(err))
+            })?;
+            return Ok(Some(contents));
+        }
+    }
+
+    Ok(None)
+}
+
+/// A project packed as it's published
+#[derive(Clone, Debug)]
+pub struct PackedProject {
+    /// package.json as it's packed
+    pub manifest: Value,
+    pub name: String,
+    pub version: String,
+    /// What was packed, relative to the project
+    pub files: Vec<PathBuf>,
+    /// The gzipped tarball
+    pub tarball: Vec<u8>,
+}
+
+/// Pack the project in `project_dir` with the files `package_files` picks. A workspace package's `workspace:`
+/// specifiers are replaced with the versions they stand for in the packed package.json.
+pub fn pack_project(project_dir: &Path) -> Result<PackedProject> {
+    let path = project_dir.join("package.json");
+    let manifest = fs::read_to_string(&path).map_err(|err| NaryError::io(&path, err))?;
+    let manifest: Value = serde_json::from_str(&manifest).map_err(|err| NaryError::json(path.display(), err))?;
+    let invalid = |reason: &str| NaryError::InvalidManifest {
+        path: path.clone(),
+        reason: reason.to_string(),
+    };
+    let name = manifest["name"].as_str().ok_or_else(|| invalid("it has no name"))?.to_string();
+    let version = manifest["version"].as_str().ok_or_else(|| invalid("it has no version"))?.to_string();
+    PackageName::parse(&name)?;
+    if semver_rs::Version::new(&version).parse().is_err() {
+        return Err(invalid("its version isn't semver"));
+    }
+
+    let rewritten = match workspace_of(project_dir)? {
+        Some(workspace) => Some(workspace.publish_manifest(&manifest)?).filter(|rewritten| *rewritten != manifest),
+        None => None,
+    };
+    let files = package_files(project_dir)?;
+    let tarball = pack_files(project_dir, &files, rewritten.as_ref())?;
+    Ok(PackedProject {
+        manifest: rewritten.unwrap_or(manifest),
+        name,
+        version,
+        files,
+        tarball,
+    })
+}
+
+/// Pack the project in `path` into `<name>-<version>.tgz` next to its package.json, the way `npm pack` names it
+/// with a scope's `@` dropped and its `/` as a `-`. Returns where the tarball is.
+pub fn create_package_tarball(path: &Path) -> Result<PathBuf> {
+    let mut packed = pack_project(path)?;
+    let file_name = format!("{}-{}.tgz", packed.name.trim_start_matches('@').replace('/', "-"), packed.version);
+    let tarball_path = path.join(&file_name);
+    // One left from packing before isn't part of the package
+    if packed.files.contains(&PathBuf::from(&file_name)) {
+        fs::remove_file(&tarball_path).map_err(|err| NaryError::io(&tarball_path, err))?;
+        packed = pack_project(path)?;
+    }
+    fs::write(&tarball_path, &packed.tarball).map_err(|err| NaryError::io(&tarball_path, err))?;
+    Ok(tarball_path)
+}
+
+/// A gzipped tarball of `files` from `project_dir`, each below `package/` the way npm packs them, with package.json
+/// written from `manifest` when there is one. Timestamps and owners are fixed, so the same files always pack to the
+/// same tarball.
+pub fn pack_files(project_dir: &Path, files: &[PathBuf], manifest: Option<&Value>) -> Result<Vec<u8>> {
+    let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for file in files {
+        let path = project_dir.join(file);
+        let contents = match manifest.filter(|_| file == Path::new("package.json")) {
+            Some(manifest) => {
+                let json = serde_json::to_string_pretty(manifest).map_err(|err| NaryError::json(path.display(), err))?;
+                format!("{}\n", json).into_bytes()
+            }
+            None => fs::read(&path).map_err(|err| NaryError::io(&path, err))?,
+        };
+
+        let mut header = Header::new_ustar();
+        header.set_size(contents.len() as u64);
+        header.set_mode(if is_executable(&path) { 0o755 } else { 0o644 });
+        // What npm uses, as that's 0 in DOS time
+        header.set_mtime(499_162_500);
+        let entry_path = Path::new("package").join(file);
+        builder
+            .append_data(&mut header, &entry_path, contents.as_slice())
+            .map_err(|err| NaryError::io(&path, err))?;
+    }
+
+    let packed = builder.into_inner().and_then(GzEncoder::finish);
+    packed.map_err(|err| NaryError::io(project_dir, err))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    false
+}
+
+/// Symlink a local package directory into node_modules, returning the link
+pub fn link_package(node_modules: &Path, name: &PackageName, target: &Path) -> Result<PathBuf> {
+    link_dir(&RealFs, &node_modules.join(name.to_path()), target)
+}
+
+/// Symlink `path` to a local package directory
+pub(crate) fn link_dir(fs: &dyn InstallFs, path: &Path, target: &Path) -> Result<PathBuf> {
+    let target = fs.canonicalize(target).map_err(|err| NaryError::io(target, err))?;
+    let path = path.to_path_buf();
+
+    if let Some(parent) = path.parent() {
+        fs.create_dir_all(parent).map_err(|err| NaryError::io(parent, err))?;
+    }
+    fs.remove(&path).map_err(|err| NaryError::io(&path, err))?;
+
+    #[cfg(unix)]
+    fs.symlink(&target, &path).map_err(|err| NaryError::io(&path, err))?;
+    // Symlinks need developer mode or elevation on Windows, junctions don't. A copy is the last resort.
+    #[cfg(windows)]
+    {
+        let linked = fs.symlink(&target, &path).or_else(|_| junction::create(&target, &path));
+        if linked.is_err() {
+            crate::store::link_from_store(&target, &long_path(&path), crate::InstallStrategy::Extract)?;
+        }
+    }
+
+    Ok(path)
+}
+
+/// On Windows, the `\\?\` form of an absolute path, which isn't limited to 260 characters. Deep node_modules
+/// trees easily go past that.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let absolute = absolute.to_string_lossy().into_owned();
+
+    if absolute.starts_with(r"\\?\") {
+        PathBuf::from(absolute)
+    } else if let Some(share) = absolute.strip_prefix(r"\\") {
+        PathBuf::from(format!(r"\\?\UNC\{}", share))
+    } else {
+        PathBuf::from(format!(r"\\?\{}", absolute))
+    }
+}
+
+/// Other platforms have no path length limit, and get the path back as is
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+pub fn unpack_archive<R: Read>(
+    archive: &mut Archive<R>,
+    destination_path: &Path,
+    tarball_url: &Url,
+    fs: &dyn InstallFs,
+    reporter: &dyn InstallReporter,
+) -> Result<()> {
+    // Lowercased entry paths, to notice entries that would overwrite each other on Windows and macOS
+    let mut seen: HashMap<String, PathBuf> = HashMap::new();
+    // Directories made so far, so a package of many small files doesn't stat its way up to them for each one
+    let mut created: HashSet<PathBuf> = HashSet::new();
+
     for (key, file) in archive
         .entries() // https://docs.rs/tar/0.4.26/tar/struct.Entries.html
-        .with_context(|| format!("{} didn't provide file entries", tarball_url))?
+        .map_err(|err| NaryError::unpack(tarball_url, "didn't provide file entries".to_string(), Some(err)))?
         .enumerate()
     {
-        // Make sure there wasn't an I/O error
-
-        if file.is_ok() {
-            let mut entry = file.ok().unwrap();
-            // Inspect metadata about the file
-            // println!("{:?}", entry.header().path().unwrap());
-            // println!("{}", entry.header().size().unwrap());
-
-            let mut entry_header = entry
-                .header()
-                .path()
-                .with_context(|| format!("Tarball {} had a bad entry path: {}", tarball_url, key))?
-                .into_owned();
+        // A truncated or corrupt tarball fails here, rather than installing what came before
+        let mut entry =
+            file.map_err(|err| NaryError::unpack(tarball_url, format!("has a bad entry {}", key), Some(err)))?;
+        let entry_header = entry
+            .header()
+            .path()
+            .map_err(|err| NaryError::unpack(tarball_url, format!("bad entry path: {}", key), Some(err)))?
+            .into_owned();
 
-            if entry_header.is_absolute() {
-                return Err(anyhow!(
-                    "{:?} is absolute from {}",
-                    entry_header,
-                    tarball_url
-                ));
+        let relative_path = match package_relative(&entry_header)
+            .map_err(|reason| NaryError::unpack(tarball_url, reason, None))?
+        {
+            Some(relative_path) => relative_path,
+            // The package directory itself
+            None => continue,
+        };
+
+        let folded = relative_path.to_string_lossy().to_lowercase();
+        match seen.get(&folded) {
+            Some(previous) if *previous != relative_path => reporter.on_warning(&format!(
+                "Tarball {} has both {} and {}, which collide on case-insensitive filesystems",
+                tarball_url,
+                previous.display(),
+                relative_path.display()
+            )),
+            _ => {
+                seen.insert(folded, relative_path.clone());
             }
+        }
+
+        let mut file_path = destination_path.to_path_buf();
+        file_path.push(&relative_path);
 
-            if entry_header.strip_prefix("package/").is_ok() {
-                entry_header = entry_header
-                    .strip_prefix("package/")
-                    .with_context(|| {
-                        format!("Tarball {} had no package/ prefix for {}", tarball_url, key)
-                    })?
-                    .to_path_buf();
+        let mut dir_path = file_path.clone();
+        dir_path.pop();
+        if !created.contains(&dir_path) {
+            fs.create_dir_all(&dir_path).map_err(|err| NaryError::io(&dir_path, err))?;
+            let made = dir_path.ancestors().take_while(|dir| dir.starts_with(destination_path));
+            created.extend(made.map(Path::to_path_buf));
+        }
+
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let link_name = entry
+                .link_name()
+                .map_err(|err| NaryError::unpack(tarball_url, format!("bad link name: {}", key), Some(err)))?
+                .ok_or_else(|| NaryError::unpack(tarball_url, format!("{:?} links nowhere", entry_header), None))?
+                .into_owned();
+            let escapes = || {
+                NaryError::unpack(
+                    tarball_url,
+                    format!("{:?} links outside the package to {:?}", entry_header, link_name),
+                    None,
+                )
+            };
+
+            if entry_type.is_symlink() {
+                // Relative to the link's own directory, which has to stay inside the package
+                let parent = relative_path.parent().unwrap_or_else(|| Path::new(""));
+                if link_name.is_absolute() || normalize(&parent.join(&link_name)).is_none() {
+                    return Err(escapes());
+                }
+                link_file(fs, &link_name, &file_path)?;
+            } else {
+                // Named like any other entry, not relative to the link
+                let target = package_relative(&link_name).ok().flatten().ok_or_else(escapes)?;
+                let target = destination_path.join(target);
+                fs.remove(&file_path).map_err(|err| NaryError::io(&file_path, err))?;
+                fs.hard_link(&target, &file_path).map_err(|err| NaryError::io(&file_path, err))?;
             }
+            continue;
+        }
+
+        if entry_type.is_dir() {
+            fs.create_dir_all(&file_path).map_err(|err| NaryError::io(&file_path, err))?;
+            created.insert(file_path);
+            continue;
+        }
+        // Devices and fifos have no place in a package
+        if !entry_type.is_file() {
+            continue;
+        }
 
-            // println!("Entry header: {:?}", entry_header);
+        // setuid, setgid and sticky bits never come along, nor anything but whether it's executable
+        let executable = entry.header().mode().is_ok_and(|mode| mode & 0o111 != 0);
+        fs.write_file(&file_path, &mut entry, executable).map_err(|err| {
+            NaryError::unpack(tarball_url, format!("couldn't unpack {}", file_path.display()), Some(err))
+        })?;
+    }
 
-            let mut file_path = destination_path.clone();
-            file_path.push(entry_header);
+    Ok(())
+}
 
-            // println!("Creating {:?}", file_path);
+/// Where an entry goes inside the package: npm packs everything below one top-level directory, usually `package/`,
+/// which is dropped whatever it's called. None for that directory itself. Absolute paths and `..` are refused.
+fn package_relative(entry_path: &Path) -> std::result::Result<Option<PathBuf>, String> {
+    let mut relative_path = PathBuf::new();
+    let mut top_level = true;
 
-            let mut dir_path = file_path.clone();
-            dir_path.pop();
-            create_dir_all(&dir_path).with_context(|| {
-                format!("Couldn't create dir {} for {}", file_path.display(), key)
-            })?;
-            entry
-                .unpack(&file_path)
-                .with_context(|| format!("Couldn't unpack {} for {}", file_path.display(), key))?;
-        } else {
-            eprintln!("Tarball {} had a bad entry {}", tarball_url, key);
-            // let mut entry = entry.with_context(|_| format!("Tarball {} had a bad entry {}", tarball_url, key))?;
+    for component in entry_path.components() {
+        match component {
+            Component::Normal(_) if top_level => top_level = false,
+            Component::Normal(part) => relative_path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => return Err(format!("{:?} climbs out of the package", entry_path)),
+            Component::RootDir | Component::Prefix(_) => return Err(format!("{:?} is absolute", entry_path)),
+        }
+    }
+
+    Ok(if relative_path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(relative_path)
+    })
+}
+
+/// Resolves `.` and `..` without touching the filesystem, or None when the path climbs above where it starts
+pub(crate) fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// A symlink from a tarball, which means a copy where symlinks can't be created
+fn link_file(fs: &dyn InstallFs, link_name: &Path, path: &Path) -> Result<()> {
+    fs.remove(path).map_err(|err| NaryError::io(path, err))?;
+
+    #[cfg(unix)]
+    fs.symlink(link_name, path).map_err(|err| NaryError::io(path, err))?;
+    #[cfg(windows)]
+    {
+        let target = path.parent().unwrap_or_else(|| Path::new("")).join(link_name);
+        if fs.symlink(link_name, path).is_err() && fs.is_file(&target) {
+            let content = fs.read(&target).map_err(|err| NaryError::io(&target, err))?;
+            fs.write_file(path, &mut content.as_slice(), false).map_err(|err| NaryError::io(path, err))?;
         }
     }
 
@@ -0,0 +1,243 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{tree::entries, workspace::wildcard_matches, Manifest, NaryError, RealFs, Result};
+
+/// Left out of every package whatever the project says, as npm does
+const ALWAYS_IGNORED: &str = "
+.npmignore
+.gitignore
+.git
+.svn
+.hg
+CVS
+node_modules
+.npmrc
+.DS_Store
+._*
+.*.swp
+*.orig
+npm-debug.log
+/.lock-wscript
+/build/config.gypi
+/nary-lock.json
+/package-lock.json
+/yarn.lock
+/pnpm-lock.yaml
+";
+
+/// One line of an ignore file, or of the `files` field
+#[derive(Clone)]
+struct Rule {
+    segments: Vec<String>,
+    negated: bool,
+    /// Only matches directories, written with a trailing `/`
+    directory: bool,
+    /// Matches from its directory down, rather than a name anywhere below it, as a pattern with a `/` does
+    anchored: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Rule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let pattern = pattern.strip_prefix("./").unwrap_or(pattern);
+        let directory = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        let segments: Vec<String> =
+            pattern.split('/').filter(|segment| !segment.is_empty()).map(str::to_string).collect();
+        if segments.is_empty() {
+            return None;
+        }
+        Some(Rule {
+            segments,
+            negated,
+            directory,
+            anchored,
+        })
+    }
+
+    /// Whether the rule matches the entry at `path`, given as names relative to the rule's directory
+    fn matches(&self, path: &[String], is_dir: bool) -> bool {
+        if self.directory && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            segments_match(&self.segments, path)
+        } else {
+            path.last().is_some_and(|name| wildcard_matches(&self.segments[0], name))
+        }
+    }
+}
+
+/// Rules from one file, which apply below the directory it's in
+#[derive(Clone)]
+struct Rules {
+    base: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    fn parse(base: PathBuf, text: &str) -> Rules {
+        Rules {
+            base,
+            rules: text.lines().filter_map(Rule::parse).collect(),
+        }
+    }
+
+    /// True or false when the last rule matching the entry ignores it or lets it in, None when none match
+    fn verdict(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.base).ok()?;
+        let names: Vec<String> = relative.iter().map(|name| name.to_string_lossy().into_owned()).collect();
+        self.rules.iter().rev().find(|rule| rule.matches(&names, is_dir)).map(|rule| !rule.negated)
+    }
+}
+
+/// `**` matches any number of names, including none
+fn segments_match(pattern: &[String], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => (0..=path.len()).any(|skip| segments_match(rest, &path[skip..])),
+        Some((first, rest)) => {
+            path.split_first().is_some_and(|(name, path)| wildcard_matches(first, name) && segments_match(rest, path))
+        }
+    }
+}
+
+/// Whether the root entry is one npm packs even when it's ignored: package.json, the readme and the license
+fn always_packed(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let stem = name.split('.').next().unwrap_or_default();
+    name == "package.json" || ["readme", "license", "licence"].contains(&stem)
+}
+
+/// The files of a project that go in its package tarball, relative to it and sorted. With a `files` field in
+/// package.json only what it lists is, otherwise everything but what the root `.npmignore` ignores, or the
+/// `.gitignore` when there's no `.npmignore`. Either file further down ignores within its directory in both cases.
+/// package.json, the readme, the license and the `main` file are always packed; version control directories,
+/// node_modules and lockfiles never are, but for the bundled dependencies in node_modules, which are packed whole.
+pub fn package_files(project_dir: &Path) -> Result<Vec<PathBuf>> {
+    let manifest = Manifest::read(project_dir)?;
+    let bundled = bundled_paths(project_dir, &manifest);
+    let listed = manifest.files.map(|files| Rules::parse(PathBuf::new(), &files.join("\n")));
+    let main = manifest.main.as_deref().and_then(Rule::parse);
+
+    let always_ignored = Rules::parse(PathBuf::new(), ALWAYS_IGNORED);
+    let mut files = Vec::new();
+    // Directories to go through, with the ignore rules above them, and whether they're ignored but hold the main file
+    let mut pending = vec![(PathBuf::new(), Vec::new(), false)];
+    while let Some((relative, mut ignores, ignored_dir)) = pending.pop() {
+        let dir = project_dir.join(&relative);
+        if bundled.iter().any(|bundled| relative.starts_with(bundled)) {
+            files.extend(files_below(project_dir, &relative)?);
+            continue;
+        }
+        // The root's ignore file gives way to `files`
+        if listed.is_none() || !relative.as_os_str().is_empty() {
+            for ignore_file in &[".npmignore", ".gitignore"] {
+                if let Ok(text) = fs::read_to_string(dir.join(ignore_file)) {
+                    ignores.push(Rules::parse(relative.clone(), &text));
+                    break;
+                }
+            }
+        }
+
+        for (name, path) in entries(&RealFs, &dir)? {
+            let metadata = fs::symlink_metadata(&path).map_err(|err| NaryError::io(&path, err))?;
+            let is_dir = metadata.is_dir();
+            if !is_dir && !metadata.is_file() {
+                continue;
+            }
+            let entry = relative.join(&name);
+            // node_modules itself, a scope in it or a bundled package
+            if is_dir && bundled.iter().any(|bundled| bundled.starts_with(&entry)) {
+                pending.push((entry, ignores.clone(), ignored_dir));
+                continue;
+            }
+            if relative.starts_with("node_modules") || always_ignored.verdict(&entry, is_dir) == Some(true) {
+                continue;
+            }
+
+            let names: Vec<String> = entry.iter().map(|name| name.to_string_lossy().into_owned()).collect();
+            let is_main = |main: &Rule| {
+                if is_dir {
+                    main.segments.len() > names.len() && segments_match(&main.segments[..names.len()], &names)
+                } else {
+                    segments_match(&main.segments, &names)
+                }
+            };
+            let holds_main = main.as_ref().is_some_and(is_main);
+            let forced = holds_main || (!is_dir && relative.as_os_str().is_empty() && always_packed(&name));
+            let ignored = ignored_dir
+                || ignores.iter().rev().find_map(|rules| rules.verdict(&entry, is_dir)) == Some(true)
+                || listed.as_ref().is_some_and(|listed| !is_dir && !listed_in(listed, &names));
+            if ignored && !forced {
+                continue;
+            }
+
+            if is_dir {
+                pending.push((entry, ignores.clone(), ignored));
+            } else {
+                files.push(entry);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Where the bundled dependencies are in node_modules, relative to the project, with the packages they depend on
+/// that are installed next to them rather than in their own node_modules
+fn bundled_paths(project_dir: &Path, manifest: &Manifest) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut pending = manifest.bundled();
+    while let Some(name) = pending.pop() {
+        let path = Path::new("node_modules").join(&name);
+        let package_dir = project_dir.join(&path);
+        if paths.contains(&path) || !package_dir.is_dir() {
+            continue;
+        }
+        if let Ok(bundled) = Manifest::read(&package_dir) {
+            let dependencies = bundled.dependencies.keys().chain(bundled.optional_dependencies.keys());
+            pending.extend(dependencies.filter(|name| !package_dir.join("node_modules").join(name).exists()).cloned());
+        }
+        paths.push(path);
+    }
+    paths
+}
+
+/// Every file below a directory of the project, relative to the project
+fn files_below(project_dir: &Path, relative: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![relative.to_path_buf()];
+    while let Some(relative) = pending.pop() {
+        for (name, path) in entries(&RealFs, &project_dir.join(&relative))? {
+            let metadata = fs::symlink_metadata(&path).map_err(|err| NaryError::io(&path, err))?;
+            if metadata.is_dir() {
+                pending.push(relative.join(name));
+            } else if metadata.is_file() {
+                files.push(relative.join(name));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Whether `files` lets a file in: it or a directory it's in is listed, and not taken out again by a later `!` line
+fn listed_in(listed: &Rules, names: &[String]) -> bool {
+    let verdict = (1..=names.len()).rev().find_map(|len| {
+        let is_dir = len < names.len();
+        let matches = |rule: &&Rule| segments_match(&rule.segments, &names[..len]) && (is_dir || !rule.directory);
+        listed.rules.iter().rev().find(matches)
+    });
+    verdict.is_some_and(|rule| !rule.negated)
+}
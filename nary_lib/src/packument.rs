@@ -0,0 +1,121 @@
+use hyper::{
+    header::{q, Accept, QualityItem},
+    mime::{Mime, SubLevel, TopLevel},
+};
+use indexmap::IndexMap;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub static CORGI_MEDIA_TYPE: &str = "vnd.npm.install-v1+json";
+
+/// Accept header asking for the abbreviated ("corgi") packument, falling back to the full document
+pub fn corgi_accept() -> Accept {
+    Accept(vec![
+        QualityItem::new(
+            Mime(TopLevel::Application, SubLevel::Ext(CORGI_MEDIA_TYPE.to_string()), vec![]),
+            q(1.0),
+        ),
+        QualityItem::new(Mime(TopLevel::Application, SubLevel::Json, vec![]), q(0.8)),
+        QualityItem::new(Mime(TopLevel::Star, SubLevel::Star, vec![]), q(0.1)),
+    ])
+}
+
+/// Registry metadata for all versions of a package, as returned in the abbreviated format
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Packument {
+    pub name: String,
+    #[serde(rename = "dist-tags", default)]
+    pub dist_tags: IndexMap<String, String>,
+    #[serde(default)]
+    pub versions: IndexMap<String, PackumentVersion>,
+    #[serde(default)]
+    pub modified: Option<String>,
+    /// When each version was published, which only the full document has
+    #[serde(default)]
+    pub time: IndexMap<String, String>,
+    /// The registry it came from, when it came from one over HTTP
+    #[serde(skip)]
+    pub registry: Option<String>,
+}
+
+/// The installation relevant subset of a version's package.json
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackumentVersion {
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub dependencies: IndexMap<String, String>,
+    #[serde(default)]
+    pub optional_dependencies: IndexMap<String, String>,
+    #[serde(default)]
+    pub peer_dependencies: IndexMap<String, String>,
+    /// Which peer dependencies are optional, as `{"<name>": {"optional": true}}`
+    #[serde(default)]
+    pub peer_dependencies_meta: Option<Value>,
+    #[serde(default, alias = "bundledDependencies")]
+    pub bundle_dependencies: Option<Value>,
+    #[serde(default)]
+    pub engines: Option<Value>,
+    #[serde(default)]
+    pub os: Option<Vec<String>>,
+    #[serde(default)]
+    pub cpu: Option<Vec<String>>,
+    #[serde(default)]
+    pub libc: Option<Vec<String>>,
+    #[serde(default)]
+    pub bin: Option<Value>,
+    #[serde(default)]
+    pub deprecated: Option<Value>,
+    #[serde(default)]
+    pub has_install_script: bool,
+    /// Whether the tarball ships an npm-shrinkwrap.json, which pins everything below the package
+    #[serde(default, rename = "_hasShrinkwrap")]
+    pub has_shrinkwrap: bool,
+    pub dist: Dist,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Dist {
+    pub tarball: String,
+    #[serde(default)]
+    pub shasum: Option<String>,
+    #[serde(default)]
+    pub integrity: Option<String>,
+    #[serde(default)]
+    pub file_count: Option<u64>,
+    #[serde(default)]
+    pub unpacked_size: Option<u64>,
+    /// The registry's signatures of the name, version and integrity
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<DistSignature>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attestations: Option<DistAttestations>,
+}
+
+/// A registry signature in a version's `dist`, base64 DER
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DistSignature {
+    pub keyid: String,
+    pub sig: String,
+}
+
+/// Where the attestations of a version are, in its `dist`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DistAttestations {
+    pub url: String,
+    #[serde(default)]
+    pub provenance: Option<Value>,
+}
+
+impl Dist {
+    /// What tells this tarball apart from others: the integrity, else the sha1 shasum, else the URL
+    pub fn identity(&self) -> String {
+        match (&self.integrity, &self.shasum) {
+            (Some(integrity), _) => integrity.clone(),
+            (None, Some(shasum)) => format!("sha1:{}", shasum),
+            (None, None) => self.tarball.clone(),
+        }
+    }
+}
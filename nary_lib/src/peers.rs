@@ -0,0 +1,116 @@
+use semver_rs::{Range, Version};
+use serde_json::Value;
+use std::{collections::HashMap, fmt, path::Path};
+
+use crate::{
+    plan::placements, Dependency, DependencyChain, Layout, PackageName, PackumentVersion, RegistryClient,
+    ResolutionOptions, ResolvedGraph, ResolvedNode, Result,
+};
+
+/// A peer dependency the package declaring it doesn't find a version in range of
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIssue {
+    pub name: String,
+    pub version: String,
+    pub peer: String,
+    /// The range the package asks for
+    pub range: String,
+    /// The version of the peer the package finds, None when there's none
+    pub found: Option<String>,
+    /// How the root comes to depend on the package
+    pub chains: Vec<DependencyChain>,
+}
+
+impl PeerIssue {
+    pub fn is_missing(&self) -> bool {
+        self.found.is_none()
+    }
+}
+
+impl fmt::Display for PeerIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{} wants {}@{} as a peer", self.name, self.version, self.peer, self.range)?;
+        match &self.found {
+            Some(found) => write!(f, ", but finds {}", found),
+            None => write!(f, ", which isn't installed"),
+        }
+    }
+}
+
+/// The peer dependencies of a version, each with whether `peerDependenciesMeta` makes it optional
+pub(crate) fn declared_peers(metadata: &PackumentVersion) -> Vec<(Dependency, bool)> {
+    let meta = metadata.peer_dependencies_meta.as_ref();
+    metadata
+        .peer_dependencies
+        .iter()
+        .map(|(name, range)| {
+            let optional = meta.is_some_and(|meta| meta[name]["optional"] == Value::Bool(true));
+            (Dependency::new(name.clone(), range.clone()), optional)
+        })
+        .collect()
+}
+
+/// Check the peer dependencies of every registry package in the graph against the version it finds: the one it
+/// depends on itself, like an installed peer, otherwise the one at the top of a hoisted node_modules. A missing
+/// optional peer is fine, one out of range isn't. Workspace packages, local directories, tarballs and git
+/// repositories aren't checked.
+pub fn check_peers(
+    graph: &ResolvedGraph,
+    registry: &dyn RegistryClient,
+    options: &ResolutionOptions,
+) -> Result<Vec<PeerIssue>> {
+    let hoisted: HashMap<&str, &ResolvedNode> = placements(Path::new(""), graph, Layout::Hoisted)?
+        .into_iter()
+        .map(|(_, node, _)| (node.name.as_str(), node))
+        .collect();
+
+    let mut issues = Vec::new();
+    for (id, node) in graph.nodes().skip(1) {
+        if Version::new(&node.version).parse().is_err()
+            || options.workspace.iter().any(|member| member.name == node.name)
+        {
+            continue;
+        }
+        let packument = registry.packument(&PackageName::parse(node.package())?)?;
+        let metadata = match packument.versions.get(&node.version) {
+            Some(metadata) => metadata,
+            None => continue,
+        };
+
+        for (peer, optional) in declared_peers(metadata) {
+            let found = graph
+                .dependencies(id)
+                .filter_map(|edge| graph.node(edge.to))
+                .find(|dependency| dependency.name == peer.name)
+                .or_else(|| hoisted.get(peer.name.as_str()).copied())
+                .map(|found| found.version.clone());
+            match &found {
+                None if optional => continue,
+                Some(found) if satisfies(&peer.version, found, options) => continue,
+                _ => {}
+            }
+            issues.push(PeerIssue {
+                name: node.name.clone(),
+                version: node.version.clone(),
+                peer: peer.name,
+                range: peer.version,
+                found,
+                chains: graph
+                    .why(&node.name)
+                    .into_iter()
+                    .filter(|chain| chain.links.last().is_some_and(|link| link.version == node.version))
+                    .collect(),
+            });
+        }
+    }
+    issues.sort_by(|a, b| (&a.name, &a.version, &a.peer).cmp(&(&b.name, &b.version, &b.peer)));
+    Ok(issues)
+}
+
+/// Ranges and versions that aren't semver, like tags and local directories, are taken to match
+fn satisfies(range: &str, version: &str, options: &ResolutionOptions) -> bool {
+    match (Range::new(range).with_options(options.semver()).parse(), Version::new(version).parse()) {
+        (Ok(range), Ok(version)) => range.test(&version),
+        _ => true,
+    }
+}
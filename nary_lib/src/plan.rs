@@ -0,0 +1,291 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cache, install_deps,
+    layout::{detect_layout, install_root, link_isolated, package_dir, VIRTUAL_STORE_DIR},
+    pack::is_up_to_date,
+    staging::staged,
+    stats::StatsRecorder,
+    tree::{entries, packages_in, read_version},
+    parse_url, InstallFs, InstallJob, InstallOptions, InstallReporter, InstallStats, Layout, NaryError, NodeId,
+    PackageName, RealFs, RegistryClient, ResolvedGraph, ResolvedNode, Result, Specifier,
+};
+
+/// What an install would change in node_modules, worked out without touching it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InstallPlan {
+    /// Packages that aren't installed yet, dependencies before their dependents
+    pub add: Vec<PlannedPackage>,
+    /// Installed packages to replace, because they're at another version or from another tarball, or can't be
+    /// compared
+    pub update: Vec<PlannedPackage>,
+    /// Installed packages the graph no longer has
+    pub remove: Vec<InstalledPackage>,
+}
+
+/// A package an install would put in place
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlannedPackage {
+    pub node: NodeId,
+    pub name: String,
+    /// The resolved version, or the specifier of a package that isn't from a registry
+    pub version: String,
+    /// The version there now, for updates
+    pub installed: Option<String>,
+    pub path: PathBuf,
+    /// The unpacked size the registry reports
+    pub unpacked_size: Option<u64>,
+    /// Whether the tarball has to be downloaded, rather than being in the cache already
+    pub download: bool,
+    /// It has `preinstall`, `install` or `postinstall` scripts, which nary doesn't run
+    pub has_install_script: bool,
+}
+
+/// A package found in node_modules
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InstalledPackage {
+    pub name: String,
+    /// From its package.json, when it has one
+    pub version: Option<String>,
+    pub path: PathBuf,
+}
+
+impl InstallPlan {
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.update.is_empty() && self.remove.is_empty()
+    }
+
+    /// Packages to add or update
+    pub fn installs(&self) -> impl Iterator<Item = &PlannedPackage> {
+        self.add.iter().chain(&self.update)
+    }
+
+    /// Unpacked size of what has to be downloaded, as far as the registry reports sizes
+    pub fn download_size(&self) -> u64 {
+        self.installs()
+            .filter(|package| package.download)
+            .filter_map(|package| package.unpacked_size)
+            .sum()
+    }
+
+    /// Packages to install that come with install scripts. The install leaves them unrun, so these are the packages
+    /// that may need them run some other way.
+    pub fn scripts(&self) -> impl Iterator<Item = &PlannedPackage> {
+        self.installs().filter(|package| package.has_install_script)
+    }
+}
+
+/// Compare what's in node_modules with a resolved graph laid out as `options.layout` asks. Only metadata is
+/// fetched, so this is what a dry run shows.
+pub fn plan_install(
+    node_modules: &Path,
+    graph: &ResolvedGraph,
+    registry: &dyn RegistryClient,
+    options: &InstallOptions,
+) -> Result<InstallPlan> {
+    let fs = &*options.fs;
+    let mut plan = InstallPlan::default();
+
+    for (id, node, path) in placements(node_modules, graph, options.layout)? {
+        let installed = fs.exists(&path);
+        let from_registry = Specifier::parse(&node.version).is_registry();
+        let name = PackageName::parse(node.package())?;
+        let packument = if from_registry { Some(registry.packument(&name)?) } else { None };
+        let metadata = packument.as_ref().and_then(|packument| packument.versions.get(&node.version));
+
+        if let Some(metadata) = metadata {
+            if is_up_to_date(fs, &path, &node.version, &metadata.dist.identity()) {
+                continue;
+            }
+        }
+
+        let mut package = PlannedPackage {
+            node: id,
+            name: node.name.clone(),
+            version: node.dependency().version,
+            installed: read_version(fs, &path),
+            path,
+            unpacked_size: None,
+            download: true,
+            has_install_script: false,
+        };
+        if let Some(metadata) = metadata {
+            package.unpacked_size = metadata.dist.unpacked_size;
+            package.has_install_script = metadata.has_install_script;
+            if let Ok(url) = parse_url(&metadata.dist.tarball) {
+                let key = cache::tarball_key(&name.to_string(), &node.version, &url);
+                package.download = cache::read_index_with(fs, &key)?.is_none();
+            }
+        }
+
+        if installed {
+            plan.update.push(package);
+        } else {
+            plan.add.push(package);
+        }
+    }
+
+    plan.remove = extraneous(fs, node_modules, graph, options.layout)?;
+    Ok(plan)
+}
+
+/// Remove the packages in node_modules that the graph doesn't have, then the bins and scope directories they
+/// leave behind, returning what was removed. The layout is told by whether there's a virtual store.
+pub fn prune(node_modules: &Path, graph: &ResolvedGraph) -> Result<Vec<InstalledPackage>> {
+    let removed = extraneous(&RealFs, node_modules, graph, detect_layout(node_modules))?;
+    remove_packages(&RealFs, node_modules, &removed)?;
+    Ok(removed)
+}
+
+/// Where each node's package goes, dependencies first. In the hoisted layout a node that comes later in the
+/// install order takes the place of an earlier one with the same name.
+pub(crate) fn placements<'a>(
+    node_modules: &Path,
+    graph: &'a ResolvedGraph,
+    layout: Layout,
+) -> Result<Vec<(NodeId, &'a ResolvedNode, PathBuf)>> {
+    let mut seen = HashSet::new();
+    let mut placements = Vec::new();
+    for node in graph.install_order().collect::<Vec<_>>().into_iter().rev() {
+        let path = package_dir(node_modules, node, layout)?;
+        if let Some(id) = graph.id_of(node).filter(|_| seen.insert(path.clone())) {
+            placements.push((id, node, path));
+        }
+    }
+    placements.reverse();
+    Ok(placements)
+}
+
+/// Installed packages on `fs` that have no place in the graph
+pub(crate) fn extraneous(
+    fs: &dyn InstallFs,
+    node_modules: &Path,
+    graph: &ResolvedGraph,
+    layout: Layout,
+) -> Result<Vec<InstalledPackage>> {
+    let mut wanted = HashSet::new();
+    for (_, node, path) in placements(node_modules, graph, layout)? {
+        // The virtual store entry holding the package
+        if layout == Layout::Isolated {
+            if let Some(entry) = path.ancestors().nth(node.name.matches('/').count() + 2) {
+                wanted.insert(entry.to_path_buf());
+            }
+        }
+        wanted.insert(path);
+    }
+    if layout == Layout::Isolated {
+        for edge in graph.dependencies(ResolvedGraph::ROOT) {
+            if let Some(node) = graph.node(edge.to) {
+                wanted.insert(node_modules.join(PackageName::parse(&node.name)?.to_path()));
+            }
+        }
+    }
+
+    Ok(installed_packages(fs, node_modules, layout)?
+        .into_iter()
+        .filter(|package| !wanted.contains(&package.path))
+        .collect())
+}
+
+/// Remove packages, then the bins pointing into them and scope directories they left empty
+fn remove_packages(fs: &dyn InstallFs, node_modules: &Path, packages: &[InstalledPackage]) -> Result<()> {
+    for package in packages {
+        fs.remove(&package.path).map_err(|err| NaryError::io(&package.path, err))?;
+    }
+    if packages.is_empty() {
+        return Ok(());
+    }
+    clean_up_removed(fs, node_modules)
+}
+
+/// Remove the bins left pointing into packages that are gone, and scope directories left empty
+fn clean_up_removed(fs: &dyn InstallFs, node_modules: &Path) -> Result<()> {
+    // Bins are symlinks everywhere but Windows, and dangle once their package is gone
+    for (_, bin) in entries(fs, &node_modules.join(".bin"))? {
+        let dangling = fs.is_symlink(&bin) && !fs.is_file(&bin) && !fs.is_dir(&bin);
+        if dangling {
+            fs.remove(&bin).map_err(|err| NaryError::io(&bin, err))?;
+        }
+    }
+
+    for (name, scope) in entries(fs, node_modules)? {
+        if name.starts_with('@') && entries(fs, &scope)?.is_empty() {
+            fs.remove(&scope).map_err(|err| NaryError::io(&scope, err))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Carry out a plan: remove what it removes, then install what it adds and updates, staged as `install_graph`
+/// does so that a failure puts back what was there. Returns what it did.
+pub fn execute_plan(
+    node_modules: &Path,
+    plan: &InstallPlan,
+    graph: &ResolvedGraph,
+    registry: &dyn RegistryClient,
+    options: &InstallOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<InstallStats> {
+    options.hooks.after_resolve(graph)?;
+    let recorder = StatsRecorder::new(graph, reporter);
+    let removed: Vec<PathBuf> = plan.remove.iter().map(|package| package.path.clone()).collect();
+    let jobs = plan
+        .installs()
+        .filter_map(|package| graph.node(package.node))
+        .map(|node| {
+            Ok(InstallJob {
+                path: install_root(node_modules, node, options.layout),
+                package_dir: package_dir(node_modules, node, options.layout)?,
+                dependency: node.dependency(),
+                kind: graph.kind_of(node),
+                locked: graph.locked_tarball_of(node).cloned(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    staged(node_modules, &*options.fs, &recorder, |staging| {
+        recorder.time("remove", || staging.remove(&removed))?;
+        recorder.time("extract", || install_deps(staging, &jobs, registry, options, &recorder))?;
+        if !removed.is_empty() {
+            clean_up_removed(&*options.fs, node_modules)?;
+        }
+        if options.layout == Layout::Isolated {
+            recorder.time("link", || link_isolated(&*options.fs, node_modules, graph))?;
+        }
+        options.hooks.on_complete(node_modules, graph)
+    })?;
+
+    Ok(recorder.finish())
+}
+
+/// Packages directly in node_modules, and for the isolated layout the entries of the virtual store. A vendored
+/// package is named from its directory.
+fn installed_packages(fs: &dyn InstallFs, node_modules: &Path, layout: Layout) -> Result<Vec<InstalledPackage>> {
+    let mut packages = match layout {
+        Layout::Vendored => entries(fs, node_modules)?
+            .into_iter()
+            .filter(|(name, _)| !name.starts_with('.'))
+            .map(|(name, path)| {
+                let package = name.rsplit_once('@').map_or(name.as_str(), |(package, _)| package);
+                (package.replacen('+', "/", 1), path)
+            })
+            .collect(),
+        _ => packages_in(fs, node_modules)?,
+    };
+    if layout == Layout::Isolated {
+        packages.extend(entries(fs, &node_modules.join(VIRTUAL_STORE_DIR))?);
+    }
+
+    Ok(packages
+        .into_iter()
+        .map(|(name, path)| InstalledPackage {
+            name,
+            version: read_version(fs, &path),
+            path,
+        })
+        .collect())
+}
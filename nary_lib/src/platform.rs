@@ -0,0 +1,139 @@
+use semver_rs::{Options, Range, Version};
+use std::{fmt, process::Command};
+
+use crate::PackumentVersion;
+
+/// The platform packages are installed for, in the names npm uses for `process.platform` and `process.arch`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub cpu: String,
+    /// `glibc` or `musl`, only on Linux
+    pub libc: Option<String>,
+}
+
+impl Platform {
+    /// The platform nary is running on
+    pub fn current() -> Platform {
+        let os = match std::env::consts::OS {
+            "macos" => "darwin",
+            "windows" => "win32",
+            "solaris" | "illumos" => "sunos",
+            os => os,
+        };
+        let cpu = match std::env::consts::ARCH {
+            "x86_64" => "x64",
+            "x86" => "ia32",
+            "aarch64" => "arm64",
+            "powerpc" => "ppc",
+            "powerpc64" => "ppc64",
+            "loongarch64" => "loong64",
+            "riscv64" => "riscv64",
+            arch => arch,
+        };
+        let libc = if os != "linux" {
+            None
+        } else if cfg!(target_env = "musl") {
+            Some("musl")
+        } else {
+            Some("glibc")
+        };
+
+        Platform {
+            os: os.to_string(),
+            cpu: cpu.to_string(),
+            libc: libc.map(str::to_string),
+        }
+    }
+
+    /// Whether a package's `os`, `cpu` and `libc` fields allow this platform
+    pub fn supports(&self, metadata: &PackumentVersion) -> bool {
+        allows(&metadata.os, &self.os)
+            && allows(&metadata.cpu, &self.cpu)
+            && (self.os != "linux" || allows(&metadata.libc, self.libc.as_deref().unwrap_or_default()))
+    }
+}
+
+impl Default for Platform {
+    fn default() -> Platform {
+        Platform::current()
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.os, self.cpu)?;
+        if let Some(libc) = &self.libc {
+            write!(f, " {}", libc)?;
+        }
+        Ok(())
+    }
+}
+
+/// The runtime versions that packages' `engines` fields are checked against. An engine without a version isn't
+/// checked.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Engines {
+    /// Like `18.12.0`
+    pub node: Option<String>,
+    pub npm: Option<String>,
+    /// Fail on packages that don't allow these versions instead of warning, as `engine-strict` does
+    pub strict: bool,
+}
+
+impl Engines {
+    /// The version of the `node` on the PATH, when there is one
+    pub fn current() -> Engines {
+        let output = Command::new("node").arg("--version").output().ok().filter(|output| output.status.success());
+        let node = output.map(|output| {
+            let version = String::from_utf8_lossy(&output.stdout);
+            version.trim().trim_start_matches('v').to_string()
+        });
+        Engines {
+            node,
+            ..Engines::default()
+        }
+    }
+
+    /// The engines whose version a package's `engines` field doesn't allow, with the range it asks for. Ranges that
+    /// don't parse are ignored, like npm does, and prereleases are allowed so nightly builds of Node pass.
+    pub fn unsatisfied(&self, metadata: &PackumentVersion) -> Vec<(String, String)> {
+        let wanted = match metadata.engines.as_ref().and_then(|engines| engines.as_object()) {
+            Some(wanted) => wanted,
+            None => return Vec::new(),
+        };
+        let options = Options::builder().include_prerelease(true).build();
+
+        let mut unsatisfied = Vec::new();
+        for (engine, current) in &[("node", &self.node), ("npm", &self.npm)] {
+            let (range, current) = match (wanted.get(*engine).and_then(|range| range.as_str()), current) {
+                (Some(range), Some(current)) => (range, current),
+                _ => continue,
+            };
+            let range_parsed = Range::new(range).with_options(options.clone()).parse();
+            let allowed = match (range_parsed, Version::new(current).parse()) {
+                (Ok(range), Ok(current)) => range.test(&current),
+                _ => true,
+            };
+            if !allowed {
+                unsatisfied.push((engine.to_string(), range.to_string()));
+            }
+        }
+        unsatisfied
+    }
+}
+
+/// npm's rules: `!value` rules a value out, and once any value is listed plainly only the listed ones are allowed
+fn allows(field: &Option<Vec<String>>, value: &str) -> bool {
+    let field = match field {
+        Some(field) if !field.is_empty() => field,
+        _ => return true,
+    };
+
+    if field.iter().any(|entry| entry.strip_prefix('!') == Some(value)) {
+        return false;
+    }
+
+    let mut allowed = field.iter().filter(|entry| !entry.starts_with('!')).peekable();
+    allowed.peek().is_none() || allowed.any(|entry| entry == value)
+}
@@ -0,0 +1,118 @@
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    cache::{hex, integrity_of},
+    pack_project, NaryError, PackageName, RegistryClient, RegistryConfig, Result,
+};
+
+/// How to publish
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublishOptions {
+    /// The dist-tag to point at the new version
+    pub tag: String,
+    /// `public` or `restricted`, which the registry decides for itself when None
+    pub access: Option<String>,
+    /// Pack and build the request, but send nothing
+    pub dry_run: bool,
+}
+
+impl Default for PublishOptions {
+    fn default() -> PublishOptions {
+        PublishOptions {
+            tag: "latest".to_string(),
+            access: None,
+            dry_run: false,
+        }
+    }
+}
+
+/// A version as it was published, or would have been on a dry run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publication {
+    pub name: String,
+    pub version: String,
+    pub tag: String,
+    /// What was packed, relative to the project
+    pub files: Vec<PathBuf>,
+    /// Size of the gzipped tarball in bytes
+    pub size: usize,
+    pub shasum: String,
+    pub integrity: String,
+}
+
+/// Pack the project in `project_dir` with `pack_project` and publish it to its registry, with the tag pointing at
+/// the new version. Private packages are refused.
+pub fn publish(
+    project_dir: &Path,
+    registry: &dyn RegistryClient,
+    config: &RegistryConfig,
+    options: &PublishOptions,
+) -> Result<Publication> {
+    let packed = pack_project(project_dir)?;
+    if packed.manifest["private"] == true {
+        return Err(NaryError::InvalidManifest {
+            path: project_dir.join("package.json"),
+            reason: "it's private, so it can't be published".to_string(),
+        });
+    }
+    let name = PackageName::parse(&packed.name)?;
+    let payload = publish_payload(&packed.manifest, &packed.tarball, options, config.registry_for(&name));
+    if !options.dry_run {
+        registry.publish(&name, &payload)?;
+    }
+
+    Ok(Publication {
+        shasum: sha1_hex(&packed.tarball),
+        integrity: integrity_of(&packed.tarball),
+        size: packed.tarball.len(),
+        name: packed.name,
+        version: packed.version,
+        tag: options.tag.clone(),
+        files: packed.files,
+    })
+}
+
+/// The document the registry takes on a PUT to the package: the version's metadata with its `dist`, the dist-tag,
+/// and the tarball attached in base64
+pub fn publish_payload(
+    manifest: &Value,
+    tarball: &[u8],
+    options: &PublishOptions,
+    registry_url: &str,
+) -> Value {
+    let name = manifest["name"].as_str().unwrap_or_default();
+    let version = manifest["version"].as_str().unwrap_or_default();
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    let attachment = format!("{}-{}.tgz", name, version);
+
+    let mut metadata = manifest.clone();
+    metadata["_id"] = json!(format!("{}@{}", name, version));
+    metadata["dist"] = json!({
+        "shasum": sha1_hex(tarball),
+        "integrity": integrity_of(tarball),
+        "tarball": format!("{}/{}/-/{}-{}.tgz", registry_url.trim_end_matches('/'), name, file_name, version),
+    });
+
+    json!({
+        "_id": name,
+        "name": name,
+        "description": manifest["description"],
+        "dist-tags": { options.tag.as_str(): version },
+        "versions": { version: metadata },
+        "access": options.access,
+        "_attachments": {
+            attachment: {
+                "content_type": "application/octet-stream",
+                "data": base64::encode(tarball),
+                "length": tarball.len(),
+            },
+        },
+    })
+}
+
+/// Hex SHA-1, which registries still keep as the `shasum` next to the integrity
+fn sha1_hex(data: &[u8]) -> String {
+    hex(&Sha1::digest(data))
+}
@@ -0,0 +1,617 @@
+use hyper::Url;
+use indexmap::IndexMap;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_json::Value;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    io::{Cursor, Read},
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use crate::{
+    cache, fetch_advisories, fetch_dist_tags, fetch_package_root_metadata, fetch_package_version_metadata,
+    fetch_search, fetch_signing_keys, parse_url,
+    unpublish::{delete_versions, next_latest, put_deprecation, read_document},
+    Advisory, Dependency, Dist, InstallOptions, InstallReporter, NaryError, PackageName, Packument, PackumentVersion,
+    RegistryConfig, Result, SearchOptions, SearchResult, SearchResults, SearchScore, SigningKey, VerifyPolicy,
+};
+
+/// Where package metadata and tarballs come from
+pub trait RegistryClient: Send + Sync {
+    /// Metadata for all versions of the package
+    fn packument(&self, name: &PackageName) -> Result<Packument>;
+
+    /// Dist-tags mapped to the versions they point at
+    fn dist_tags(&self, name: &PackageName) -> Result<IndexMap<String, String>> {
+        Ok(self.packument(name)?.dist_tags)
+    }
+
+    /// The full package.json of one version
+    fn version_metadata(&self, name: &PackageName, version: &str) -> Result<Value>;
+
+    /// The full document of the package, read from the registry rather than any cache, with `time` and the rest
+    /// the abbreviated packument leaves out. What changes to the package are checked against.
+    fn full_packument(&self, name: &PackageName) -> Result<Packument> {
+        self.packument(name)
+    }
+
+    /// The gzipped tarball of one version
+    fn tarball(
+        &self,
+        name: &PackageName,
+        version: &str,
+        tarball_url: &Url,
+        reporter: &dyn InstallReporter,
+    ) -> Result<Vec<u8>>;
+
+    /// The gzipped tarball of one version, as a stream. Buffers the whole tarball unless the registry can do better.
+    fn tarball_reader<'a>(
+        &'a self,
+        name: &PackageName,
+        version: &str,
+        tarball_url: &Url,
+        reporter: &'a dyn InstallReporter,
+    ) -> Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(Cursor::new(self.tarball(name, version, tarball_url, reporter)?)))
+    }
+
+    /// The tarball of a registry version, as a stream. A registry with a cache can read one with the same content,
+    /// cached under another name, version or URL, instead of downloading it again.
+    fn dist_tarball_reader<'a>(
+        &'a self,
+        name: &PackageName,
+        version: &str,
+        dist: &Dist,
+        reporter: &'a dyn InstallReporter,
+    ) -> Result<Box<dyn Read + 'a>> {
+        self.tarball_reader(name, version, &parse_url(&dist.tarball)?, reporter)
+    }
+
+    /// Publish the versions, dist-tags and tarballs of a `publish_payload`
+    fn publish(&self, name: &PackageName, payload: &Value) -> Result<()>;
+
+    /// Point `tag` at `version` of the package
+    fn set_dist_tag(&self, name: &PackageName, _tag: &str, _version: &str) -> Result<()> {
+        Err(NaryError::Unsupported { what: format!("Changing the dist-tags of {}", name) })
+    }
+
+    /// Take `tag` off the package
+    fn remove_dist_tag(&self, name: &PackageName, _tag: &str) -> Result<()> {
+        Err(NaryError::Unsupported { what: format!("Changing the dist-tags of {}", name) })
+    }
+
+    /// A page of the packages matching a search
+    fn search(&self, query: &str, _options: &SearchOptions) -> Result<SearchResults> {
+        Err(NaryError::Unsupported { what: format!("Searching for {}", query) })
+    }
+
+    /// Deprecate versions of the package with `message`, or take their deprecation off with an empty one
+    fn deprecate(&self, name: &PackageName, _versions: &[String], _message: &str) -> Result<()> {
+        Err(NaryError::Unsupported { what: format!("Deprecating {}", name) })
+    }
+
+    /// Take one version of the package off the registry, or the whole package when None. `unpublish::unpublish`
+    /// is the way to call it, so the caller confirms what goes.
+    fn unpublish(&self, name: &PackageName, _version: Option<&str>) -> Result<()> {
+        Err(NaryError::Unsupported { what: format!("Unpublishing {}", name) })
+    }
+
+    /// Security advisories against any of the given versions of each package, by package. None when the registry
+    /// has no advisory database.
+    fn advisories(&self, _versions: &BTreeMap<String, Vec<String>>) -> Result<BTreeMap<String, Vec<Advisory>>> {
+        Ok(BTreeMap::new())
+    }
+
+    /// How the signatures of the package's versions are checked, not at all by default
+    fn verify_policy(&self, _name: &PackageName) -> VerifyPolicy {
+        VerifyPolicy::default()
+    }
+
+    /// The keys the package's registry signs versions with
+    fn signing_keys(&self, _name: &PackageName) -> Result<Vec<SigningKey>> {
+        Ok(Vec::new())
+    }
+
+    /// Drop a cached tarball that turned out to be corrupt, so it's downloaded again. False when it wasn't cached, as
+    /// nothing is without a cache.
+    fn evict_tarball(&self, _name: &PackageName, _version: &str, _tarball_url: &Url) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Set aside a cached tarball that was corrupt even downloaded again, with why. Returns where its diagnostics
+    /// are, None when it wasn't cached.
+    fn quarantine_tarball(
+        &self,
+        _name: &PackageName,
+        _version: &str,
+        _tarball_url: &Url,
+        _reason: &str,
+    ) -> Result<Option<PathBuf>> {
+        Ok(None)
+    }
+}
+
+/// An npm compatible registry over HTTP(S), backed by the cache, whose tarballs are on `options.fs`
+#[derive(Clone, Debug, Default)]
+pub struct HttpRegistry {
+    pub config: RegistryConfig,
+    pub options: InstallOptions,
+}
+
+impl HttpRegistry {
+    pub fn new(config: RegistryConfig, options: InstallOptions) -> HttpRegistry {
+        HttpRegistry { config, options }
+    }
+
+    /// A tarball from the cache or the first of its registries that has it
+    fn cached_tarball<'a>(
+        &'a self,
+        name: &PackageName,
+        version: &str,
+        tarball_url: &Url,
+        identity: Option<&str>,
+        reporter: &'a dyn InstallReporter,
+    ) -> Result<Box<dyn Read + 'a>> {
+        let (fs, key) = (&*self.options.fs, name.to_string());
+        let mut urls = self.config.tarball_urls(name, tarball_url);
+        // One that's cached already goes first, whichever registry it came from
+        if let Some(cached) = urls
+            .iter()
+            .position(|url| matches!(cache::read_index_with(fs, &cache::tarball_key(&key, version, url)), Ok(Some(_))))
+        {
+            let url = urls.remove(cached);
+            urls.insert(0, url);
+        }
+
+        let fetch = |url| cache::cache_reader_for(&key, version, url, identity, &self.config, &self.options, reporter);
+        let mut urls = urls.iter();
+        let mut result = fetch(urls.next().unwrap_or(tarball_url));
+        for url in urls {
+            if result.is_ok() {
+                break;
+            }
+            result = fetch(url);
+        }
+        result
+    }
+}
+
+impl RegistryClient for HttpRegistry {
+    fn packument(&self, name: &PackageName) -> Result<Packument> {
+        fetch_package_root_metadata(&dependency(name), &self.config, &self.options)
+    }
+
+    fn dist_tags(&self, name: &PackageName) -> Result<IndexMap<String, String>> {
+        fetch_dist_tags(&dependency(name), &self.config, &self.options)
+    }
+
+    fn version_metadata(&self, name: &PackageName, version: &str) -> Result<Value> {
+        fetch_package_version_metadata(&dependency(name), version, &self.config, &self.options)
+    }
+
+    fn full_packument(&self, name: &PackageName) -> Result<Packument> {
+        if self.options.offline {
+            return Err(NaryError::NotCached { what: format!("The full document of {}", name) });
+        }
+        let url = format!("{}/{}", self.config.registry_for(name), name.registry_path());
+        let document = read_document(&url, &self.config)?;
+        serde_json::from_value(document).map_err(|err| NaryError::json(&url, err))
+    }
+
+    fn publish(&self, name: &PackageName, payload: &Value) -> Result<()> {
+        let url = format!("{}/{}", self.config.registry_for(name), name.registry_path());
+        let body = serde_json::to_string(payload).map_err(|err| NaryError::json(&url, err))?;
+        self.config.put_json(&url, &body)?;
+        Ok(())
+    }
+
+    fn set_dist_tag(&self, name: &PackageName, tag: &str, version: &str) -> Result<()> {
+        let url = dist_tag_url(&self.config, name, tag);
+        let body = serde_json::to_string(version).map_err(|err| NaryError::json(&url, err))?;
+        self.config.put_json(&url, &body)?;
+        Ok(())
+    }
+
+    fn remove_dist_tag(&self, name: &PackageName, tag: &str) -> Result<()> {
+        self.config.delete(&dist_tag_url(&self.config, name, tag))?;
+        Ok(())
+    }
+
+    fn search(&self, query: &str, options: &SearchOptions) -> Result<SearchResults> {
+        fetch_search(query, options, &self.config, &self.options)
+    }
+
+    fn deprecate(&self, name: &PackageName, versions: &[String], message: &str) -> Result<()> {
+        put_deprecation(name, versions, message, &self.config)
+    }
+
+    fn unpublish(&self, name: &PackageName, version: Option<&str>) -> Result<()> {
+        delete_versions(name, version, &self.config)
+    }
+
+    fn advisories(&self, versions: &BTreeMap<String, Vec<String>>) -> Result<BTreeMap<String, Vec<Advisory>>> {
+        fetch_advisories(versions, &self.config, &self.options)
+    }
+
+    fn verify_policy(&self, name: &PackageName) -> VerifyPolicy {
+        self.config.verify_policy_for(name)
+    }
+
+    fn signing_keys(&self, name: &PackageName) -> Result<Vec<SigningKey>> {
+        fetch_signing_keys(name, &self.config, &self.options)
+    }
+
+    fn tarball(
+        &self,
+        name: &PackageName,
+        version: &str,
+        tarball_url: &Url,
+        reporter: &dyn InstallReporter,
+    ) -> Result<Vec<u8>> {
+        let mut tarball = Vec::new();
+        self.tarball_reader(name, version, tarball_url, reporter)?
+            .read_to_end(&mut tarball)
+            .map_err(|err| NaryError::network(tarball_url.as_str(), err))?;
+        Ok(tarball)
+    }
+
+    fn tarball_reader<'a>(
+        &'a self,
+        name: &PackageName,
+        version: &str,
+        tarball_url: &Url,
+        reporter: &'a dyn InstallReporter,
+    ) -> Result<Box<dyn Read + 'a>> {
+        self.cached_tarball(name, version, tarball_url, None, reporter)
+    }
+
+    fn dist_tarball_reader<'a>(
+        &'a self,
+        name: &PackageName,
+        version: &str,
+        dist: &Dist,
+        reporter: &'a dyn InstallReporter,
+    ) -> Result<Box<dyn Read + 'a>> {
+        self.cached_tarball(name, version, &parse_url(&dist.tarball)?, Some(&dist.identity()), reporter)
+    }
+
+    fn evict_tarball(&self, name: &PackageName, version: &str, tarball_url: &Url) -> Result<bool> {
+        let mut evicted = false;
+        for url in self.config.tarball_urls(name, tarball_url) {
+            let key = cache::tarball_key(&name.to_string(), version, &url);
+            evicted |= cache::evict_with(&*self.options.fs, &key)?.is_some();
+        }
+        Ok(evicted)
+    }
+
+    fn quarantine_tarball(
+        &self,
+        name: &PackageName,
+        version: &str,
+        tarball_url: &Url,
+        reason: &str,
+    ) -> Result<Option<PathBuf>> {
+        let mut quarantined = None;
+        for url in self.config.tarball_urls(name, tarball_url) {
+            let key = cache::tarball_key(&name.to_string(), version, &url);
+            quarantined = quarantined.or(cache::quarantine_with(&*self.options.fs, &key, reason)?);
+        }
+        Ok(quarantined)
+    }
+}
+
+fn dependency(name: &PackageName) -> Dependency {
+    Dependency::new(name.to_string(), "")
+}
+
+/// A registry held in memory, for tests and embedders that already have the packages
+#[derive(Debug, Default)]
+pub struct MemoryRegistry {
+    packuments: RwLock<HashMap<String, Packument>>,
+    tarballs: RwLock<HashMap<String, Vec<u8>>>,
+    advisories: RwLock<HashMap<String, Vec<Advisory>>>,
+    verify_policy: RwLock<VerifyPolicy>,
+    signing_keys: RwLock<Vec<SigningKey>>,
+}
+
+impl MemoryRegistry {
+    pub fn new() -> MemoryRegistry {
+        MemoryRegistry::default()
+    }
+
+    /// The made up tarball URL of an added package version
+    pub fn tarball_url(name: &str, version: &str) -> String {
+        let file_name = name.rsplit('/').next().unwrap_or(name);
+        format!("memory://registry/{}/-/{}-{}.tgz", name, file_name, version)
+    }
+
+    /// Add a version from its package.json, serving `tarball` for it.
+    /// The version becomes the `latest` dist-tag.
+    pub fn add_manifest(&self, manifest: &Value, tarball: Vec<u8>) -> Result<()> {
+        let name = manifest["name"]
+            .as_str()
+            .ok_or_else(|| NaryError::InvalidPackageName(manifest["name"].to_string()))?;
+        let version = manifest["version"].as_str().unwrap_or_default();
+
+        let metadata: PackumentVersion =
+            serde_json::from_value(with_dist(manifest, name, version)).map_err(|err| NaryError::json(name, err))?;
+
+        self.add_version(metadata, tarball);
+        Ok(())
+    }
+
+    pub fn add_version(&self, metadata: PackumentVersion, tarball: Vec<u8>) {
+        let mut packuments = self.packuments.write().unwrap();
+        let packument = packuments.entry(metadata.name.clone()).or_insert_with(|| Packument {
+            name: metadata.name.clone(),
+            ..Packument::default()
+        });
+
+        packument
+            .dist_tags
+            .insert("latest".to_string(), metadata.version.clone());
+        self.tarballs
+            .write()
+            .unwrap()
+            .insert(metadata.dist.tarball.clone(), tarball);
+        packument.versions.insert(metadata.version.clone(), metadata);
+    }
+
+    /// Serve `tarball` at a URL that isn't tied to a packument, like a tarball URL dependency
+    pub fn add_tarball(&self, tarball_url: &str, tarball: Vec<u8>) {
+        self.tarballs.write().unwrap().insert(tarball_url.to_string(), tarball);
+    }
+
+    /// Point `tag` at `version` of an added package
+    pub fn add_dist_tag(&self, name: &str, tag: &str, version: &str) {
+        if let Some(packument) = self.packuments.write().unwrap().get_mut(name) {
+            packument.dist_tags.insert(tag.to_string(), version.to_string());
+        }
+    }
+
+    /// Record when a version was published, like `2024-05-01T12:30:00.000Z`
+    pub fn add_publish_time(&self, name: &str, version: &str, time: &str) {
+        if let Some(packument) = self.packuments.write().unwrap().get_mut(name) {
+            packument.time.insert(version.to_string(), time.to_string());
+        }
+    }
+
+    /// Report `advisory` against the versions of `name` it names
+    pub fn add_advisory(&self, name: &str, advisory: Advisory) {
+        self.advisories.write().unwrap().entry(name.to_string()).or_default().push(advisory);
+    }
+
+    /// Check the versions of every package as `policy` says
+    pub fn set_verify_policy(&self, policy: VerifyPolicy) {
+        *self.verify_policy.write().unwrap() = policy;
+    }
+
+    pub fn add_signing_key(&self, key: SigningKey) {
+        self.signing_keys.write().unwrap().push(key);
+    }
+
+    pub fn add_packument(&self, packument: Packument) {
+        self.packuments
+            .write()
+            .unwrap()
+            .insert(packument.name.clone(), packument);
+    }
+}
+
+impl RegistryClient for MemoryRegistry {
+    fn packument(&self, name: &PackageName) -> Result<Packument> {
+        self.packuments
+            .read()
+            .unwrap()
+            .get(&name.to_string())
+            .cloned()
+            .ok_or_else(|| not_found(&name.to_string()))
+    }
+
+    fn version_metadata(&self, name: &PackageName, version: &str) -> Result<Value> {
+        let packument = self.packument(name)?;
+        let metadata = packument
+            .versions
+            .get(version)
+            .ok_or_else(|| not_found(&format!("{}/{}", name, version)))?;
+
+        serde_json::to_value(metadata).map_err(|err| NaryError::json(name, err))
+    }
+
+    fn tarball(
+        &self,
+        name: &PackageName,
+        version: &str,
+        tarball_url: &Url,
+        reporter: &dyn InstallReporter,
+    ) -> Result<Vec<u8>> {
+        let tarball = self
+            .tarballs
+            .read()
+            .unwrap()
+            .get(tarball_url.as_str())
+            .cloned()
+            .ok_or_else(|| not_found(tarball_url.as_str()))?;
+
+        let size = tarball.len() as u64;
+        reporter.on_download_progress(&name.to_string(), version, size, Some(size));
+        reporter.on_download_end(&name.to_string(), version, size);
+
+        Ok(tarball)
+    }
+
+    /// Refuses versions that are there already, like registries do
+    fn publish(&self, name: &PackageName, payload: &Value) -> Result<()> {
+        let key = name.to_string();
+        let attachments = payload["_attachments"].as_object().cloned().unwrap_or_default();
+        let versions = payload["versions"].as_object().cloned().unwrap_or_default();
+        for (version, manifest) in &versions {
+            let packuments = self.packuments.read().unwrap();
+            if packuments.get(&key).is_some_and(|packument| packument.versions.contains_key(version)) {
+                return Err(NaryError::RegistryError {
+                    url: format!("{}/{}", key, version),
+                    status: 403,
+                });
+            }
+            let attachment = format!("{}-{}.tgz", key, version);
+            let data = attachments.get(&attachment).and_then(|attachment| attachment["data"].as_str());
+            let tarball = base64::decode(data.unwrap_or_default()).map_err(|_| NaryError::RegistryError {
+                url: attachment.clone(),
+                status: 400,
+            })?;
+
+            let latest = packuments.get(&key).and_then(|packument| packument.dist_tags.get("latest").cloned());
+            drop(packuments);
+            let mut manifest = manifest.clone();
+            manifest["dist"]["tarball"] = Value::from(MemoryRegistry::tarball_url(&key, version));
+            self.add_manifest(&manifest, tarball)?;
+            // Adding made it the latest, which only the payload's dist-tags decide
+            if let Some(latest) = latest {
+                self.add_dist_tag(&key, "latest", &latest);
+            }
+        }
+        for (tag, version) in payload["dist-tags"].as_object().into_iter().flatten() {
+            self.add_dist_tag(&key, tag, version.as_str().unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    fn set_dist_tag(&self, name: &PackageName, tag: &str, version: &str) -> Result<()> {
+        let mut packuments = self.packuments.write().unwrap();
+        let key = name.to_string();
+        let packument = packuments.get_mut(&key).ok_or_else(|| not_found(&key))?;
+        packument.dist_tags.insert(tag.to_string(), version.to_string());
+        Ok(())
+    }
+
+    fn remove_dist_tag(&self, name: &PackageName, tag: &str) -> Result<()> {
+        let mut packuments = self.packuments.write().unwrap();
+        let key = name.to_string();
+        let packument = packuments.get_mut(&key).ok_or_else(|| not_found(&key))?;
+        packument.dist_tags.shift_remove(tag);
+        Ok(())
+    }
+
+    /// Matches the names with every word of the query in them, an exact match first
+    fn search(&self, query: &str, options: &SearchOptions) -> Result<SearchResults> {
+        let words: Vec<String> = query.to_lowercase().split_whitespace().map(str::to_string).collect();
+        let packuments = self.packuments.read().unwrap();
+        let mut results: Vec<SearchResult> = packuments
+            .values()
+            .filter(|packument| words.iter().all(|word| packument.name.to_lowercase().contains(word)))
+            .map(|packument| SearchResult {
+                name: packument.name.clone(),
+                version: packument.dist_tags.get("latest").cloned().unwrap_or_default(),
+                score: SearchScore {
+                    overall: if packument.name == query { 1.0 } else { 0.5 },
+                    ..SearchScore::default()
+                },
+                ..SearchResult::default()
+            })
+            .collect();
+        results.sort_by(|a, b| {
+            b.score.overall.partial_cmp(&a.score.overall).unwrap_or(Ordering::Equal).then_with(|| a.name.cmp(&b.name))
+        });
+        Ok(SearchResults {
+            total: results.len() as u64,
+            results: results.into_iter().skip(options.from).take(options.size).collect(),
+        })
+    }
+
+    fn deprecate(&self, name: &PackageName, versions: &[String], message: &str) -> Result<()> {
+        let mut packuments = self.packuments.write().unwrap();
+        let key = name.to_string();
+        let packument = packuments.get_mut(&key).ok_or_else(|| not_found(&key))?;
+        for version in versions {
+            if let Some(metadata) = packument.versions.get_mut(version) {
+                metadata.deprecated = Some(message).filter(|message| !message.is_empty()).map(Value::from);
+            }
+        }
+        Ok(())
+    }
+
+    /// Like registries, the whole package goes with its last version
+    fn unpublish(&self, name: &PackageName, version: Option<&str>) -> Result<()> {
+        let mut packuments = self.packuments.write().unwrap();
+        let key = name.to_string();
+        let packument = packuments.get_mut(&key).ok_or_else(|| not_found(&key))?;
+        if let Some(version) = version.filter(|version| !packument.versions.contains_key(*version)) {
+            return Err(not_found(&format!("{}/{}", key, version)));
+        }
+        if let Some(version) = version.filter(|_| packument.versions.len() > 1) {
+            packument.versions.shift_remove(version);
+            packument.time.shift_remove(version);
+            packument.dist_tags.retain(|_, tagged| tagged != version);
+            if !packument.dist_tags.contains_key("latest") {
+                if let Some(latest) = next_latest(packument.versions.keys()) {
+                    packument.dist_tags.insert("latest".to_string(), latest);
+                }
+            }
+        } else {
+            packuments.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn advisories(&self, versions: &BTreeMap<String, Vec<String>>) -> Result<BTreeMap<String, Vec<Advisory>>> {
+        let advisories = self.advisories.read().unwrap();
+        Ok(versions
+            .keys()
+            .filter_map(|name| Some((name.clone(), advisories.get(name)?.clone())))
+            .collect())
+    }
+
+    fn verify_policy(&self, _name: &PackageName) -> VerifyPolicy {
+        *self.verify_policy.read().unwrap()
+    }
+
+    fn signing_keys(&self, _name: &PackageName) -> Result<Vec<SigningKey>> {
+        Ok(self.signing_keys.read().unwrap().clone())
+    }
+}
+
+/// `/-/package/<name>/dist-tags/<tag>` of the package's registry
+fn dist_tag_url(config: &RegistryConfig, name: &PackageName, tag: &str) -> String {
+    format!(
+        "{}/-/package/{}/dist-tags/{}",
+        config.registry_for(name),
+        name.registry_path(),
+        utf8_percent_encode(tag, NON_ALPHANUMERIC)
+    )
+}
+
+fn not_found(url: &str) -> NaryError {
+    NaryError::RegistryError {
+        url: url.to_string(),
+        status: 404,
+    }
+}
+
+fn with_dist(manifest: &Value, name: &str, version: &str) -> Value {
+    let mut manifest = manifest.clone();
+    // package.json dependency maps may hold non-string junk, and only the strings matter here
+    for key in &["dependencies", "optionalDependencies", "peerDependencies"] {
+        manifest[*key] = serde_json::to_value(string_map(&manifest[*key])).unwrap_or_default();
+    }
+    if manifest.get("dist").is_none() {
+        manifest["dist"] = serde_json::to_value(Dist {
+            tarball: MemoryRegistry::tarball_url(name, version),
+            ..Dist::default()
+        })
+        .unwrap_or_default();
+    }
+    manifest
+}
+
+fn string_map(value: &Value) -> IndexMap<String, String> {
+    value
+        .as_object()
+        .map(|map| {
+            map.iter()
+                .filter_map(|(key, value)| value.as_str().map(|value| (key.clone(), value.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,103 @@
+use std::{path::Path, time::Duration};
+
+use crate::{Dependency, InstallStats};
+
+/// Receives progress events during resolution and installation, so callers can render their own UI.
+/// Every method defaults to doing nothing.
+pub trait InstallReporter: Send + Sync {
+    fn on_resolve_start(&self, _root: &Dependency, _direct_dependencies: usize) {}
+
+    /// `dependency` resolved to the exact `version`
+    fn on_package_resolved(&self, _dependency: &Dependency, _version: &str) {}
+
+    /// Whether a tarball was in the cache, or has to be downloaded
+    fn on_cache_lookup(&self, _name: &str, _version: &str, _hit: bool) {}
+
+    /// `total` is the Content-Length, when the registry sent one
+    fn on_download_progress(&self, _name: &str, _version: &str, _downloaded: u64, _total: Option<u64>) {}
+
+    /// A download ended, whole or cut off, having `received` bytes from the registry this run. One that resumed an
+    /// earlier download only counts the rest.
+    fn on_download_end(&self, _name: &str, _version: &str, _received: u64) {}
+
+    fn on_unpack(&self, _name: &str, _version: &str, _path: &Path) {}
+
+    /// The package was already installed at this version and integrity, so it was left alone
+    fn on_up_to_date(&self, _name: &str, _version: &str, _path: &Path) {}
+
+    fn on_warning(&self, _message: &str) {}
+
+    /// A package.json script is about to run `command` as the `script` stage, like `pretest`
+    fn on_script_start(&self, _script: &str, _command: &str) {}
+
+    /// A line the running script wrote, to stderr or stdout
+    fn on_script_output(&self, _script: &str, _line: &str, _stderr: bool) {}
+
+    /// A phase of the install, like `resolve` or `extract`, is done
+    fn on_phase(&self, _phase: &str, _elapsed: Duration) {}
+
+    /// What the install did, once it's done
+    fn on_install_stats(&self, _stats: &InstallStats) {}
+}
+
+/// Reports nothing
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SilentReporter;
+
+impl InstallReporter for SilentReporter {}
+
+/// Prints a line per event to stderr
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TerminalReporter;
+
+impl InstallReporter for TerminalReporter {
+    fn on_resolve_start(&self, root: &Dependency, direct_dependencies: usize) {
+        eprintln!("Resolving {}@{} ({} direct dependencies)", root.name, root.version, direct_dependencies);
+    }
+
+    fn on_package_resolved(&self, dependency: &Dependency, version: &str) {
+        eprintln!("Resolved {}@{} to {}", dependency.name, dependency.version, version);
+    }
+
+    fn on_download_progress(&self, name: &str, version: &str, downloaded: u64, total: Option<u64>) {
+        if Some(downloaded) == total {
+            eprintln!("Downloaded {}@{} ({} bytes)", name, version, downloaded);
+        }
+    }
+
+    fn on_unpack(&self, name: &str, version: &str, path: &Path) {
+        eprintln!("Unpacked {}@{} into {}", name, version, path.display());
+    }
+
+    fn on_up_to_date(&self, name: &str, version: &str, path: &Path) {
+        eprintln!("{}@{} is up to date in {}", name, version, path.display());
+    }
+
+    fn on_warning(&self, message: &str) {
+        eprintln!("Warning: {}", message);
+    }
+
+    fn on_script_start(&self, script: &str, command: &str) {
+        eprintln!("> {}\n> {}", script, command);
+    }
+
+    fn on_script_output(&self, _script: &str, line: &str, stderr: bool) {
+        if stderr {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    fn on_install_stats(&self, stats: &InstallStats) {
+        eprintln!(
+            "Installed {} of {} packages ({} up to date) in {:.2?}, downloading {} bytes ({} from the cache)",
+            stats.packages_installed,
+            stats.packages_resolved,
+            stats.packages_up_to_date,
+            stats.total_time(),
+            stats.bytes_downloaded,
+            stats.cache_hits
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use serde_json::Value;
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+};
+
+use crate::{InstallHooks, InstallReporter, Manifest, NaryError, Result};
+
+/// Run the script called `script_name` from the `scripts` of the package.json in `path`, with `extra_args` after
+/// it, between its `pre` and `post` scripts when there are any. Scripts run in a shell in `path`, with the
+/// node_modules/.bin of it and every directory above it first on the PATH, and npm's `npm_lifecycle_event`,
+/// `npm_lifecycle_script` and `npm_package_*` variables set. `hooks` can refuse each one before it runs, and their
+/// output goes to the reporter line by line.
+pub fn run(
+    path: &Path,
+    script_name: &str,
+    extra_args: &[String],
+    hooks: &dyn InstallHooks,
+    reporter: &dyn InstallReporter,
+) -> Result<()> {
+    let manifest_path = path.join("package.json");
+    let manifest = fs::read_to_string(&manifest_path).map_err(|err| NaryError::io(&manifest_path, err))?;
+    let manifest: Value =
+        serde_json::from_str(&manifest).map_err(|err| NaryError::json(manifest_path.display(), err))?;
+    let scripts = Manifest::from_value(&manifest).scripts;
+    if !scripts.contains_key(script_name) {
+        return Err(NaryError::MissingScript { name: script_name.to_string() });
+    }
+
+    let stages = [
+        (format!("pre{}", script_name), &[][..]),
+        (script_name.to_string(), extra_args),
+        (format!("post{}", script_name), &[][..]),
+    ];
+    for (stage, args) in &stages {
+        if let Some(script) = scripts.get(stage) {
+            let mut command = script.to_string();
+            for arg in args.iter() {
+                command.push(' ');
+                command.push_str(&shell_quote(arg));
+            }
+            run_stage(path, &manifest, stage, &command, hooks, reporter)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn run_stage(
+    path: &Path,
+    manifest: &Value,
+    stage: &str,
+    command: &str,
+    hooks: &dyn InstallHooks,
+    reporter: &dyn InstallReporter,
+) -> Result<()> {
+    hooks.before_script(path, stage, command)?;
+    reporter.on_script_start(stage, command);
+    let mut child = shell(command)
+        .current_dir(path)
+        .env("PATH", script_path(path)?)
+        .env("npm_lifecycle_event", stage)
+        .env("npm_lifecycle_script", command)
+        .env("npm_package_json", path.join("package.json"))
+        .envs(package_vars(manifest))
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| NaryError::io(path, err))?;
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    thread::scope(|scope| {
+        scope.spawn(|| forward_lines(stderr, |line| reporter.on_script_output(stage, line, true)));
+        forward_lines(stdout, |line| reporter.on_script_output(stage, line, false));
+    });
+
+    let status = child.wait().map_err(|err| NaryError::io(path, err))?;
+    if !status.success() {
+        return Err(NaryError::ScriptFailed {
+            name: stage.to_string(),
+            code: status.code(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+
+    let mut shell = Command::new("cmd");
+    shell.args(["/d", "/s", "/c"]).raw_arg(format!("\"{}\"", command));
+    shell
+}
+
+/// An argument as the shell passes it on unchanged
+#[cfg(not(windows))]
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c)) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+#[cfg(windows)]
+fn shell_quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('"', "\"\""))
+}
+
+/// The node_modules/.bin of `path` and each directory above it, nearest first, ahead of the PATH
+fn script_path(path: &Path) -> Result<OsString> {
+    let path = path.canonicalize().map_err(|err| NaryError::io(path, err))?;
+    let mut dirs: Vec<PathBuf> = path.ancestors().map(|dir| dir.join("node_modules").join(".bin")).collect();
+    if let Some(existing) = env::var_os("PATH") {
+        dirs.extend(env::split_paths(&existing));
+    }
+    env::join_paths(dirs).map_err(|err| NaryError::io(&path, std::io::Error::other(err)))
+}
+
+/// `npm_package_<field>` for every string, number and boolean in package.json, nested fields joined with `_` and
+/// other characters than letters and digits replaced by `_`, as npm used to set them
+pub fn package_vars(manifest: &Value) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    flatten("npm_package", manifest, &mut vars);
+    vars
+}
+
+fn flatten(prefix: &str, value: &Value, vars: &mut Vec<(String, String)>) {
+    match value {
+        Value::String(value) => vars.push((prefix.to_string(), value.clone())),
+        Value::Number(value) => vars.push((prefix.to_string(), value.to_string())),
+        Value::Bool(value) => vars.push((prefix.to_string(), value.to_string())),
+        Value::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                flatten(&format!("{}_{}", prefix, i), value, vars);
+            }
+        }
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                // The readme can be the size of a book, and `_` fields are the registry's
+                if prefix == "npm_package" && (key == "readme" || key.starts_with('_')) {
+                    continue;
+                }
+                let key: String = key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+                flatten(&format!("{}_{}", prefix, key), value, vars);
+            }
+        }
+        Value::Null => {}
+    }
+}
+
+/// Output that isn't UTF-8 is passed on lossily, rather than left in a pipe that fills up
+fn forward_lines<R: Read, F: Fn(&str)>(output: R, forward: F) {
+    let mut output = BufReader::new(output);
+    let mut line = Vec::new();
+    while output.read_until(b'\n', &mut line).is_ok_and(|read| read > 0) {
+        let text = String::from_utf8_lossy(&line);
+        forward(text.trim_end_matches(['\n', '\r']));
+        line.clear();
+    }
+}
@@ -0,0 +1,184 @@
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_derive::{Deserialize, Serialize};
+use std::io::Read;
+
+use crate::{InstallOptions, NaryError, RegistryClient, RegistryConfig, Result};
+
+/// Which page of results to ask for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SearchOptions {
+    /// Results per page, which registries cap at 250
+    pub size: usize,
+    /// How many results to skip
+    pub from: usize,
+}
+
+impl Default for SearchOptions {
+    fn default() -> SearchOptions {
+        SearchOptions { size: 20, from: 0 }
+    }
+}
+
+/// One page of the packages matching a search
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SearchResults {
+    pub results: Vec<SearchResult>,
+    /// Matches on every page
+    pub total: u64,
+}
+
+impl SearchResults {
+    /// The options for the page after this one, None when this is the last
+    pub fn next_page(&self, options: &SearchOptions) -> Option<SearchOptions> {
+        let from = options.from + self.results.len();
+        if self.results.is_empty() || from as u64 >= self.total {
+            return None;
+        }
+        Some(SearchOptions { from, ..*options })
+    }
+}
+
+/// A package matching a search, at its latest version
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub name: String,
+    pub version: String,
+    pub description: Option<String>,
+    pub keywords: Vec<String>,
+    /// When the version was published
+    pub date: Option<String>,
+    pub maintainers: Vec<Maintainer>,
+    pub score: SearchScore,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Maintainer {
+    pub username: String,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+/// How well a package matches, each between 0 and 1
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct SearchScore {
+    /// The other three combined, which results are ordered by
+    pub overall: f64,
+    pub quality: f64,
+    pub popularity: f64,
+    pub maintenance: f64,
+}
+
+/// A page of `/-/v1/search` as registries answer it
+#[derive(Deserialize)]
+struct SearchResponse {
+    #[serde(default)]
+    objects: Vec<SearchObject>,
+    #[serde(default)]
+    total: u64,
+}
+
+#[derive(Deserialize)]
+struct SearchObject {
+    package: SearchPackage,
+    #[serde(default)]
+    score: Score,
+}
+
+#[derive(Deserialize)]
+struct SearchPackage {
+    name: String,
+    #[serde(default)]
+    version: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    #[serde(default)]
+    date: Option<String>,
+    #[serde(default)]
+    maintainers: Vec<Maintainer>,
+}
+
+#[derive(Default, Deserialize)]
+struct Score {
+    #[serde(rename = "final", default)]
+    overall: f64,
+    #[serde(default)]
+    detail: ScoreDetail,
+}
+
+#[derive(Default, Deserialize)]
+struct ScoreDetail {
+    #[serde(default)]
+    quality: f64,
+    #[serde(default)]
+    popularity: f64,
+    #[serde(default)]
+    maintenance: f64,
+}
+
+impl From<SearchResponse> for SearchResults {
+    fn from(response: SearchResponse) -> SearchResults {
+        let results = response
+            .objects
+            .into_iter()
+            .map(|object| SearchResult {
+                name: object.package.name,
+                version: object.package.version,
+                description: object.package.description,
+                keywords: object.package.keywords,
+                date: object.package.date,
+                maintainers: object.package.maintainers,
+                score: SearchScore {
+                    overall: object.score.overall,
+                    quality: object.score.detail.quality,
+                    popularity: object.score.detail.popularity,
+                    maintenance: object.score.detail.maintenance,
+                },
+            })
+            .collect();
+        SearchResults {
+            results,
+            total: response.total,
+        }
+    }
+}
+
+/// Search the registry for packages matching `query`, which can use the registry's qualifiers like
+/// `keywords:` and `author:`, one page at a time
+pub fn search(query: &str, options: &SearchOptions, registry: &dyn RegistryClient) -> Result<SearchResults> {
+    if query.trim().is_empty() {
+        return Ok(SearchResults::default());
+    }
+    registry.search(query.trim(), options)
+}
+
+/// Ask the default registry's `/-/v1/search` endpoint for a page of results
+pub fn fetch_search(
+    query: &str,
+    search: &SearchOptions,
+    config: &RegistryConfig,
+    options: &InstallOptions,
+) -> Result<SearchResults> {
+    if options.offline {
+        return Err(NaryError::NotCached { what: format!("Search results for {}", query) });
+    }
+
+    let url = format!(
+        "{}/-/v1/search?text={}&size={}&from={}",
+        config.registry.trim_end_matches('/'),
+        utf8_percent_encode(query, NON_ALPHANUMERIC),
+        search.size,
+        search.from
+    );
+    let mut body = String::new();
+    config.fetch(&url, |request| request)?
+        .read_to_string(&mut body)
+        .map_err(|err| NaryError::network(&url, err))?;
+    parse_search(&body).map_err(|err| NaryError::json(&url, err))
+}
+
+/// A page of results from the body of a `/-/v1/search` response
+pub fn parse_search(body: &str) -> serde_json::Result<SearchResults> {
+    serde_json::from_str::<SearchResponse>(body).map(SearchResults::from)
+}
@@ -0,0 +1,67 @@
+use semver_rs::Range;
+use std::path::PathBuf;
+
+use crate::{workspace_range, Dependency, GitSpec};
+
+/// What the version of a dependency asks for, told apart once so that nothing else has to look at prefixes
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Specifier {
+    /// A range or exact version from the registry. An empty range means any version.
+    SemverRange(String),
+    /// A dist-tag like `latest`
+    Tag(String),
+    Git(GitSpec),
+    /// A local directory, from `file:`
+    File(PathBuf),
+    /// A tarball at an http(s) URL
+    Url(String),
+    /// Another registry package installed under this name, from `npm:<name>@<version>`
+    Alias(Box<Dependency>),
+    /// The range of a workspace package, from `workspace:`
+    Workspace(String),
+}
+
+impl Specifier {
+    pub fn parse(version: &str) -> Specifier {
+        if let Some(local) = version.strip_prefix("file:") {
+            Specifier::File(PathBuf::from(local))
+        } else if let Some(spec) = GitSpec::parse(version) {
+            Specifier::Git(spec)
+        } else if version.starts_with("https://") || version.starts_with("http://") {
+            Specifier::Url(version.to_string())
+        } else if let Some(target) = version.strip_prefix("npm:") {
+            Specifier::Alias(Box::new(alias_target(target)))
+        } else if let Some(range) = workspace_range(version) {
+            Specifier::Workspace(range.to_string())
+        } else if version.trim().is_empty() || Range::new(version).parse().is_ok() {
+            Specifier::SemverRange(version.to_string())
+        } else {
+            Specifier::Tag(version.to_string())
+        }
+    }
+
+    /// Whether the registry has what it asks for: a range, a tag or an alias
+    pub fn is_registry(&self) -> bool {
+        matches!(self, Specifier::SemverRange(_) | Specifier::Tag(_) | Specifier::Alias(_))
+    }
+
+    /// The semver range to match registry versions against, an alias's target's included
+    pub fn registry_range(&self) -> Option<&str> {
+        match self {
+            Specifier::SemverRange(range) => Some(range),
+            Specifier::Alias(target) => target.specifier().registry_range(),
+            _ => None,
+        }
+    }
+}
+
+/// The package and range after `npm:`, any version when there's none
+fn alias_target(target: &str) -> Dependency {
+    // Skip the @ of a scope
+    match target.char_indices().skip(1).find(|(_, c)| *c == '@') {
+        Some((index, _)) if index + 1 < target.len() => Dependency::new(&target[..index], &target[index + 1..]),
+        Some((index, _)) => Dependency::new(&target[..index], "*"),
+        None => Dependency::new(target, "*"),
+    }
+}
+
@@ -0,0 +1,181 @@
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+use crate::{InstallFs, InstallReporter, NaryError, Result};
+
+/// Below node_modules, where an install unpacks packages and keeps what they replace until it's done
+pub const STAGING_DIR: &str = ".nary-staging";
+
+/// Lists the packages being moved into place, so an install that stopped halfway can be undone
+const JOURNAL_FILE: &str = "journal.json";
+
+/// An install in progress. Packages are unpacked into directories of their own here and only moved into
+/// node_modules once every one of them is, with what they replace kept aside until the install is committed.
+pub(crate) struct Staging<'a> {
+    fs: &'a dyn InstallFs,
+    node_modules: PathBuf,
+    dir: PathBuf,
+    swaps: Vec<Swap>,
+}
+
+/// A directory in node_modules being replaced by one from the staging directory, or removed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Swap {
+    /// The name of the staged directory, and with `.old` of what it replaces
+    name: String,
+    /// Relative to node_modules
+    package: PathBuf,
+    /// Whether there's a staged directory to move in, rather than only something to remove
+    staged: bool,
+    /// Whether something was there to move aside
+    replaces: bool,
+}
+
+/// Run an install in `node_modules` through a `Staging`, committing it when `install` succeeds and rolling back
+/// whatever it moved into place when it fails. One an earlier run left behind is rolled back first.
+pub(crate) fn staged<T>(
+    node_modules: &Path,
+    fs: &dyn InstallFs,
+    reporter: &dyn InstallReporter,
+    install: impl FnOnce(&mut Staging) -> Result<T>,
+) -> Result<T> {
+    let mut staging = Staging::begin(node_modules, fs, reporter)?;
+    match install(&mut staging) {
+        Ok(installed) => {
+            staging.commit()?;
+            Ok(installed)
+        }
+        Err(err) => {
+            if let Err(rollback) = staging.rollback() {
+                reporter.on_warning(&format!("Couldn't roll back the install: {}", rollback));
+            }
+            Err(err)
+        }
+    }
+}
+
+impl<'a> Staging<'a> {
+    fn begin(node_modules: &Path, fs: &'a dyn InstallFs, reporter: &dyn InstallReporter) -> Result<Staging<'a>> {
+        let mut staging = Staging {
+            fs,
+            node_modules: node_modules.to_path_buf(),
+            dir: node_modules.join(STAGING_DIR),
+            swaps: Vec::new(),
+        };
+
+        let journal = staging.dir.join(JOURNAL_FILE);
+        if let Ok(contents) = staging.fs.read(&journal) {
+            let swaps = serde_json::from_slice(&contents).map_err(|err| NaryError::json(journal.display(), err))?;
+            staging.swaps = swaps;
+            staging.rollback()?;
+            reporter.on_warning(&format!(
+                "An earlier install into {} stopped halfway, and was rolled back",
+                node_modules.display()
+            ));
+        }
+        let dir = &staging.dir;
+        staging.fs.remove(dir).map_err(|err| NaryError::io(dir, err))?;
+        staging.fs.create_dir_all(dir).map_err(|err| NaryError::io(dir, err))?;
+        Ok(staging)
+    }
+
+    /// Where to unpack a package that's `name`d in a later swap
+    pub(crate) fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Move each staged directory by name into place at its package directory, moving aside what's there
+    pub(crate) fn swap(&mut self, staged: impl IntoIterator<Item = (String, PathBuf)>) -> Result<()> {
+        let swaps = staged.into_iter().map(|(name, package)| (name, package, true)).collect();
+        self.apply(swaps)
+    }
+
+    /// Move the packages aside, to be deleted when the install is committed
+    pub(crate) fn remove(&mut self, packages: &[PathBuf]) -> Result<()> {
+        let first = self.swaps.len();
+        let swaps = packages.iter().enumerate().map(|(index, package)| {
+            (format!("removed-{}", first + index), package.clone(), false)
+        });
+        self.apply(swaps.collect())
+    }
+
+    fn apply(&mut self, swaps: Vec<(String, PathBuf, bool)>) -> Result<()> {
+        let first = self.swaps.len();
+        for (name, package, staged) in swaps {
+            let relative = package.strip_prefix(&self.node_modules).unwrap_or(&package).to_path_buf();
+            self.swaps.push(Swap {
+                name,
+                replaces: self.exists(&package),
+                package: relative,
+                staged,
+            });
+        }
+        // Written before anything moves, so a crash midway is found and undone by the next install
+        self.write_journal()?;
+
+        let fs = self.fs;
+        for swap in &self.swaps[first..] {
+            let (package, staged, backup) = self.paths_of(swap);
+            if swap.replaces {
+                fs.rename(&package, &backup).map_err(|err| NaryError::io(&package, err))?;
+            } else {
+                // A dangling link can be in the way
+                fs.remove(&package).map_err(|err| NaryError::io(&package, err))?;
+            }
+            if swap.staged {
+                if let Some(parent) = package.parent() {
+                    fs.create_dir_all(parent).map_err(|err| NaryError::io(parent, err))?;
+                }
+                fs.rename(&staged, &package).map_err(|err| NaryError::io(&package, err))?;
+            }
+        }
+        debug!(moved = self.swaps.len() - first, "swapped into place");
+        Ok(())
+    }
+
+    /// Put back what was there before, latest swap first
+    fn rollback(&self) -> Result<()> {
+        for swap in self.swaps.iter().rev() {
+            let (package, staged, backup) = self.paths_of(swap);
+            if swap.staged && !self.exists(&staged) {
+                self.fs.remove(&package).map_err(|err| NaryError::io(&package, err))?;
+            }
+            if self.exists(&backup) {
+                self.fs.remove(&package).map_err(|err| NaryError::io(&package, err))?;
+                self.fs.rename(&backup, &package).map_err(|err| NaryError::io(&package, err))?;
+            }
+        }
+        let dir = &self.dir;
+        self.fs.remove(dir).map_err(|err| NaryError::io(dir, err))
+    }
+
+    /// Delete what was replaced. The journal goes first, so what's left can't be mistaken for an install to undo.
+    fn commit(self) -> Result<()> {
+        let journal = self.dir.join(JOURNAL_FILE);
+        self.fs.remove(&journal).map_err(|err| NaryError::io(&journal, err))?;
+        self.fs.remove(&self.dir).map_err(|err| NaryError::io(&self.dir, err))
+    }
+
+    /// Written next to it first and renamed over it, so it's never read half written
+    fn write_journal(&self) -> Result<()> {
+        let journal = self.dir.join(JOURNAL_FILE);
+        let temp = journal.with_extension("tmp");
+        let contents = serde_json::to_vec(&self.swaps).map_err(|err| NaryError::json(journal.display(), err))?;
+        self.fs.write_file(&temp, &mut contents.as_slice(), false).map_err(|err| NaryError::io(&temp, err))?;
+        self.fs.rename(&temp, &journal).map_err(|err| NaryError::io(&journal, err))
+    }
+
+    /// The package directory, its staged replacement, and where what it replaces is kept
+    fn paths_of(&self, swap: &Swap) -> (PathBuf, PathBuf, PathBuf) {
+        (
+            self.node_modules.join(&swap.package),
+            self.dir.join(&swap.name),
+            self.dir.join(format!("{}.old", swap.name)),
+        )
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.fs.is_dir(path) || self.fs.is_file(path)
+    }
+}
@@ -0,0 +1,143 @@
+use serde_derive::Serialize;
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{Dependency, InstallReporter, ResolvedGraph};
+
+/// What an install did, so CI can keep track of how big the dependencies get
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct InstallStats {
+    /// Packages in the graph, not counting the root
+    pub packages_resolved: usize,
+    /// Packages unpacked, checked out or linked into node_modules
+    pub packages_installed: usize,
+    /// Packages left alone because they were installed at the same version and integrity already
+    pub packages_up_to_date: usize,
+    /// Tarballs read from the cache
+    pub cache_hits: usize,
+    /// Tarballs that had to be downloaded
+    pub cache_misses: usize,
+    /// Bytes received from registries, not counting what resumed downloads had from an earlier run
+    pub bytes_downloaded: u64,
+    /// How long each phase took, in the order they ran
+    pub phases: Vec<Phase>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Phase {
+    /// `resolve`, `remove`, `extract` or `link`
+    pub name: String,
+    pub elapsed: Duration,
+}
+
+impl InstallStats {
+    /// How long a phase took, None when it didn't run
+    pub fn phase(&self, name: &str) -> Option<Duration> {
+        self.phases.iter().find(|phase| phase.name == name).map(|phase| phase.elapsed)
+    }
+
+    pub fn total_time(&self) -> Duration {
+        self.phases.iter().map(|phase| phase.elapsed).sum()
+    }
+}
+
+/// Passes events through to a reporter, counting what the stats want along the way
+pub(crate) struct StatsRecorder<'a> {
+    reporter: &'a dyn InstallReporter,
+    stats: Mutex<InstallStats>,
+}
+
+impl<'a> StatsRecorder<'a> {
+    pub(crate) fn new(graph: &ResolvedGraph, reporter: &'a dyn InstallReporter) -> StatsRecorder<'a> {
+        let stats = InstallStats {
+            packages_resolved: graph.len().saturating_sub(1),
+            ..InstallStats::default()
+        };
+        StatsRecorder {
+            reporter,
+            stats: Mutex::new(stats),
+        }
+    }
+
+    /// Run a phase, recording how long it took
+    pub(crate) fn time<T>(&self, phase: &str, run: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let ran = run();
+        self.on_phase(phase, started.elapsed());
+        ran
+    }
+
+    /// The stats, which the reporter gets too
+    pub(crate) fn finish(self) -> InstallStats {
+        let stats = self.stats.into_inner().unwrap();
+        self.reporter.on_install_stats(&stats);
+        stats
+    }
+}
+
+impl InstallReporter for StatsRecorder<'_> {
+    fn on_resolve_start(&self, root: &Dependency, direct_dependencies: usize) {
+        self.reporter.on_resolve_start(root, direct_dependencies);
+    }
+
+    fn on_package_resolved(&self, dependency: &Dependency, version: &str) {
+        self.reporter.on_package_resolved(dependency, version);
+    }
+
+    fn on_cache_lookup(&self, name: &str, version: &str, hit: bool) {
+        let mut stats = self.stats.lock().unwrap();
+        if hit {
+            stats.cache_hits += 1;
+        } else {
+            stats.cache_misses += 1;
+        }
+        drop(stats);
+        self.reporter.on_cache_lookup(name, version, hit);
+    }
+
+    fn on_download_progress(&self, name: &str, version: &str, downloaded: u64, total: Option<u64>) {
+        self.reporter.on_download_progress(name, version, downloaded, total);
+    }
+
+    fn on_download_end(&self, name: &str, version: &str, received: u64) {
+        self.stats.lock().unwrap().bytes_downloaded += received;
+        self.reporter.on_download_end(name, version, received);
+    }
+
+    fn on_unpack(&self, name: &str, version: &str, path: &Path) {
+        self.stats.lock().unwrap().packages_installed += 1;
+        self.reporter.on_unpack(name, version, path);
+    }
+
+    fn on_up_to_date(&self, name: &str, version: &str, path: &Path) {
+        self.stats.lock().unwrap().packages_up_to_date += 1;
+        self.reporter.on_up_to_date(name, version, path);
+    }
+
+    fn on_warning(&self, message: &str) {
+        self.reporter.on_warning(message);
+    }
+
+    fn on_script_start(&self, script: &str, command: &str) {
+        self.reporter.on_script_start(script, command);
+    }
+
+    fn on_script_output(&self, script: &str, line: &str, stderr: bool) {
+        self.reporter.on_script_output(script, line, stderr);
+    }
+
+    fn on_phase(&self, phase: &str, elapsed: Duration) {
+        self.stats.lock().unwrap().phases.push(Phase {
+            name: phase.to_string(),
+            elapsed,
+        });
+        self.reporter.on_phase(phase, elapsed);
+    }
+
+    fn on_install_stats(&self, stats: &InstallStats) {
+        self.reporter.on_install_stats(stats);
+    }
+}
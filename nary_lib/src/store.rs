@@ -0,0 +1,111 @@
+use hyper::Url;
+use reflink_copy::reflink_or_copy;
+use sha2::{Digest, Sha512};
+use std::{
+    fs::{self, create_dir_all},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cache::{self, get_cache_dir, integrity_of},
+    pack::extract_stream,
+    InstallReporter, InstallStrategy, NaryError, RealFs, Result,
+};
+
+/// Packages extracted once, shared by every project installed with a linking strategy
+const STORE_DIR: &str = "store-v1";
+
+/// The store directory of a tarball's contents
+pub fn store_path(tarball: &[u8]) -> Result<PathBuf> {
+    store_path_of(&integrity_of(tarball))
+}
+
+fn store_path_of(integrity: &str) -> Result<PathBuf> {
+    let mut path = get_cache_dir()?;
+    path.push(STORE_DIR);
+    path.push(integrity.trim_start_matches("sha512-").replace('/', "_"));
+    Ok(path)
+}
+
+/// Extract a gzipped package tarball into the store, unless it's there already, returning its directory
+pub fn add_to_store(tarball: impl Read, tarball_url: &Url, reporter: &dyn InstallReporter) -> Result<PathBuf> {
+    // Which directory is only known once the whole tarball has been hashed, so it's extracted elsewhere first.
+    // That also means a directory in the store is always complete.
+    let temp = cache::temp_path(&RealFs)?;
+    let mut tarball = Hashing {
+        inner: tarball,
+        hasher: Sha512::new(),
+    };
+    extract_stream(&mut tarball, &temp, tarball_url, &RealFs, reporter)?;
+    let path = store_path_of(&format!("sha512-{}", base64::encode(tarball.hasher.finalize())))?;
+
+    let _lock = cache::lock(&format!("store {}", path.display()))?;
+    if path.is_dir() {
+        fs::remove_dir_all(&temp).map_err(|err| NaryError::io(&temp, err))?;
+        return Ok(path);
+    }
+
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).map_err(|err| NaryError::io(parent, err))?;
+    }
+    fs::rename(&temp, &path).map_err(|err| {
+        let _ = fs::remove_dir_all(&temp);
+        NaryError::io(&path, err)
+    })?;
+
+    Ok(path)
+}
+
+/// Hashes what's read through it
+struct Hashing<R> {
+    inner: R,
+    hasher: Sha512,
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        self.hasher.update(&buffer[..read]);
+        Ok(read)
+    }
+}
+
+/// Recreate a store directory at `destination`, replacing whatever was there. Files are hard-linked or reflinked
+/// as the strategy asks, and copied when the filesystem can't do that.
+pub fn link_from_store(store: &Path, destination: &Path, strategy: InstallStrategy) -> Result<()> {
+    if let Ok(metadata) = fs::symlink_metadata(destination) {
+        let removed = if metadata.is_dir() {
+            fs::remove_dir_all(destination)
+        } else {
+            fs::remove_file(destination)
+        };
+        removed.map_err(|err| NaryError::io(destination, err))?;
+    }
+
+    let mut pending = vec![(store.to_path_buf(), destination.to_path_buf())];
+    while let Some((from, to)) = pending.pop() {
+        create_dir_all(&to).map_err(|err| NaryError::io(&to, err))?;
+
+        for entry in fs::read_dir(&from).map_err(|err| NaryError::io(&from, err))? {
+            let entry = entry.map_err(|err| NaryError::io(&from, err))?;
+            let (source, target) = (entry.path(), to.join(entry.file_name()));
+
+            if source.is_dir() {
+                pending.push((source, target));
+                continue;
+            }
+
+            let linked = match strategy {
+                InstallStrategy::HardLink => {
+                    fs::hard_link(&source, &target).or_else(|_| fs::copy(&source, &target).map(|_| ()))
+                }
+                InstallStrategy::Reflink => reflink_or_copy(&source, &target).map(|_| ()),
+                InstallStrategy::Extract => fs::copy(&source, &target).map(|_| ()),
+            };
+            linked.map_err(|err| NaryError::io(&target, err))?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{InstallFs, NaryError, RealFs, Result};
+
+/// A package as it is on disk, with the packages in its own node_modules
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InstalledTree {
+    /// The name it's installed under, which is the alias for `npm:` aliases
+    pub name: String,
+    /// From its package.json, when it has one
+    pub version: Option<String>,
+    /// Where it was found
+    pub path: PathBuf,
+    /// Where it really is, with links resolved
+    pub realpath: PathBuf,
+    /// What a symlink or junction points at, as stored in the link
+    pub link_target: Option<PathBuf>,
+    /// The packages in `<realpath>/node_modules`, by name
+    pub dependencies: BTreeMap<String, InstalledTree>,
+}
+
+impl InstalledTree {
+    pub fn is_link(&self) -> bool {
+        self.link_target.is_some()
+    }
+
+    /// A package directly in this one's node_modules
+    pub fn get(&self, name: &str) -> Option<&InstalledTree> {
+        self.dependencies.get(name)
+    }
+
+    /// Every package below this one, parents before their children
+    pub fn descendants(&self) -> Vec<&InstalledTree> {
+        let mut descendants = Vec::new();
+        let mut pending: Vec<&InstalledTree> = self.dependencies.values().rev().collect();
+        while let Some(tree) = pending.pop() {
+            descendants.push(tree);
+            pending.extend(tree.dependencies.values().rev());
+        }
+        descendants
+    }
+}
+
+/// Read a project, or any package directory, and everything installed below it. Links are followed, except
+/// back into a package that's already being read.
+pub fn read_tree(package_dir: &Path) -> Result<InstalledTree> {
+    let name = package_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    read_package(name, package_dir.to_path_buf(), &mut Vec::new())
+}
+
+fn read_package(name: String, path: PathBuf, ancestors: &mut Vec<PathBuf>) -> Result<InstalledTree> {
+    let metadata = fs::symlink_metadata(&path).map_err(|err| NaryError::io(&path, err))?;
+    let link_target = if metadata.file_type().is_symlink() {
+        Some(fs::read_link(&path).map_err(|err| NaryError::io(&path, err))?)
+    } else {
+        None
+    };
+    // A dangling link has nothing behind it
+    let realpath = path.canonicalize().unwrap_or_else(|_| path.clone());
+
+    let mut tree = InstalledTree {
+        name,
+        version: read_version(&RealFs, &realpath),
+        path,
+        realpath,
+        link_target,
+        dependencies: BTreeMap::new(),
+    };
+    if ancestors.contains(&tree.realpath) {
+        return Ok(tree);
+    }
+
+    ancestors.push(tree.realpath.clone());
+    let node_modules = tree.realpath.join("node_modules");
+    for (name, path) in packages_in(&RealFs, &node_modules)? {
+        let dependency = read_package(name.clone(), path, ancestors)?;
+        tree.dependencies.insert(name, dependency);
+    }
+    ancestors.pop();
+
+    Ok(tree)
+}
+
+pub(crate) fn read_version(fs: &dyn InstallFs, package_dir: &Path) -> Option<String> {
+    let manifest = fs.read(&package_dir.join("package.json")).ok()?;
+    let manifest: Value = serde_json::from_slice(&manifest).ok()?;
+    manifest["version"].as_str().map(str::to_string)
+}
+
+/// Names and paths of the packages in a node_modules directory, leaving out `.bin`, the virtual store and other
+/// dot entries
+pub(crate) fn packages_in(fs: &dyn InstallFs, node_modules: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut packages = Vec::new();
+    for (name, path) in entries(fs, node_modules)? {
+        if name.starts_with('.') {
+            continue;
+        }
+        if !name.starts_with('@') {
+            packages.push((name, path));
+            continue;
+        }
+        for (scoped, path) in entries(fs, &path)? {
+            packages.push((format!("{}/{}", name, scoped), path));
+        }
+    }
+    Ok(packages)
+}
+
+/// Names and paths in a directory, sorted, and none when it doesn't exist
+pub(crate) fn entries(fs: &dyn InstallFs, dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let read = match fs.read_dir(dir) {
+        Ok(read) => read,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(NaryError::io(dir, err)),
+    };
+
+    let mut entries: Vec<(String, PathBuf)> = read
+        .into_iter()
+        .filter_map(|path| Some((path.file_name()?.to_string_lossy().into_owned(), path)))
+        .collect();
+    entries.sort();
+    Ok(entries)
+}
@@ -0,0 +1,72 @@
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{
+    calculate_depends, execute_plan,
+    lockfile::{read_or_import, write_lockfile, Lockfile},
+    path_to_root_dependency, plan_install, remove_dependency,
+    workspace::project_dependencies,
+    InstallOptions, InstallReporter, InstallStats, InstalledPackage, RegistryClient, ResolutionOptions, ResolvedGraph,
+    Result,
+};
+
+/// What `uninstall` took out of the project
+#[derive(Debug)]
+pub struct Uninstall {
+    /// The names that were dependencies in package.json, and aren't any more
+    pub removed: Vec<String>,
+    /// What went from node_modules: the packages named, and those nothing else depends on any more
+    pub pruned: Vec<InstalledPackage>,
+    pub graph: ResolvedGraph,
+    pub lockfile: Lockfile,
+    pub stats: InstallStats,
+}
+
+/// Remove the dependencies on `names` from package.json, then resolve the project again without them and bring
+/// node_modules and the lockfile in line. Everything else keeps the version it's locked at, so what goes from
+/// node_modules is the named packages with the dependencies only they needed, and the bins linked to any of them.
+/// Names that aren't dependencies are left out of `removed`.
+pub fn uninstall(
+    project_dir: &Path,
+    names: &[String],
+    registry: &dyn RegistryClient,
+    options: &InstallOptions,
+    resolution: &ResolutionOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<Uninstall> {
+    let mut removed = Vec::new();
+    for name in names {
+        if remove_dependency(project_dir, name)? {
+            removed.push(name.clone());
+        }
+    }
+
+    let mut preferred: BTreeMap<String, Vec<String>> = resolution.preferred.clone();
+    for package in read_or_import(project_dir)?.unwrap_or_default().packages.values() {
+        let name = package.alias_of.as_deref().unwrap_or(&package.name);
+        preferred.entry(name.to_string()).or_default().push(package.version.clone());
+    }
+    let resolution = ResolutionOptions {
+        preferred,
+        ..resolution.clone()
+    };
+    let root = path_to_root_dependency(project_dir)?;
+    let graph = calculate_depends(&root, &project_dependencies(project_dir)?, registry, &resolution, reporter)?;
+
+    // Packages the new graph puts elsewhere are installed there, so node_modules is whole again
+    let node_modules = options.modules_dir(project_dir);
+    let plan = plan_install(&node_modules, &graph, registry, options)?;
+    let stats = execute_plan(&node_modules, &plan, &graph, registry, options, reporter)?;
+
+    let mut lockfile = Lockfile::from_graph(&graph, registry)?;
+    lockfile.record_overrides(&resolution.overrides);
+    lockfile.record_contents(&node_modules)?;
+    write_lockfile(project_dir, &lockfile)?;
+
+    Ok(Uninstall {
+        removed,
+        pruned: plan.remove,
+        graph,
+        lockfile,
+        stats,
+    })
+}
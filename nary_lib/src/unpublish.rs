@@ -0,0 +1,231 @@
+use semver_rs::{Range, Version};
+use serde_json::Value;
+use std::{
+    convert::TryFrom,
+    io::Read,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{parse_spec, semver_order, NaryError, PackageName, RegistryClient, RegistryConfig, Result};
+
+/// How long after it's published the registry lets a version be unpublished
+pub const UNPUBLISH_WINDOW: Duration = Duration::from_secs(72 * 60 * 60);
+
+/// What an unpublish would take off the registry, which nothing is until it's confirmed
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[must_use = "nothing is unpublished until it's confirmed"]
+pub struct PendingUnpublish {
+    pub name: String,
+    /// The version asked for, None for the whole package
+    pub version: Option<String>,
+    /// Every version that goes
+    pub versions: Vec<String>,
+}
+
+impl PendingUnpublish {
+    /// Unpublish it, returning the versions that went
+    pub fn confirm(self, registry: &dyn RegistryClient) -> Result<Vec<String>> {
+        registry.unpublish(&PackageName::parse(&self.name)?, self.version.as_deref())?;
+        Ok(self.versions)
+    }
+}
+
+/// Look up what unpublishing `name@version`, or all of `name`, would take off the registry, as its full document
+/// has it now. A version published longer ago than `UNPUBLISH_WINDOW` is refused here when the registry says when it
+/// was; for the whole package the registry alone decides.
+pub fn unpublish(spec: &str, registry: &dyn RegistryClient) -> Result<PendingUnpublish> {
+    let spec = parse_spec(spec);
+    let packument = registry.full_packument(&PackageName::parse(&spec.name)?)?;
+    if spec.version.is_empty() {
+        return Ok(PendingUnpublish {
+            name: spec.name,
+            version: None,
+            versions: packument.versions.keys().cloned().collect(),
+        });
+    }
+
+    if !packument.versions.contains_key(&spec.version) {
+        return Err(NaryError::NoMatchingVersion {
+            name: spec.name,
+            range: spec.version,
+            available: packument.versions.keys().cloned().collect(),
+        });
+    }
+    check_window(&spec.name, &spec.version, packument.time.get(&spec.version).map(String::as_str))?;
+    Ok(PendingUnpublish {
+        versions: vec![spec.version.clone()],
+        version: Some(spec.version),
+        name: spec.name,
+    })
+}
+
+/// Deprecate the versions of the package in the spec's range, or all of them without one, with `message`. An empty
+/// message takes the deprecation off again. Returns the versions it was set on.
+pub fn deprecate(spec: &str, message: &str, registry: &dyn RegistryClient) -> Result<Vec<String>> {
+    let spec = parse_spec(spec);
+    let name = PackageName::parse(&spec.name)?;
+    let range = if spec.version.is_empty() { "*" } else { spec.version.as_str() };
+    let parsed = Range::new(range)
+        .with_options(semver_rs::Options::builder().include_prerelease(true).build())
+        .parse()
+        .map_err(|source| NaryError::VersionParse {
+            name: spec.name.clone(),
+            version: range.to_string(),
+            source,
+        })?;
+
+    let packument = registry.packument(&name)?;
+    let versions: Vec<String> = packument
+        .versions
+        .keys()
+        .filter(|version| Version::new(version).parse().is_ok_and(|version| parsed.test(&version)))
+        .cloned()
+        .collect();
+    if versions.is_empty() {
+        return Err(NaryError::NoMatchingVersion {
+            name: spec.name,
+            range: range.to_string(),
+            available: packument.versions.keys().cloned().collect(),
+        });
+    }
+    registry.deprecate(&name, &versions, message)?;
+    Ok(versions)
+}
+
+/// Refuse a version published longer than `UNPUBLISH_WINDOW` ago, going by its `time` in the packument
+pub(crate) fn check_window(name: &str, version: &str, published: Option<&str>) -> Result<()> {
+    let published_at = match published.and_then(unix_seconds) {
+        Some(seconds) => UNIX_EPOCH + Duration::from_secs(seconds),
+        None => return Ok(()),
+    };
+    if SystemTime::now().duration_since(published_at).unwrap_or_default() > UNPUBLISH_WINDOW {
+        return Err(NaryError::UnpublishWindow {
+            package: format!("{}@{}", name, version),
+            published: published.unwrap_or_default().to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Seconds since the epoch of an ISO 8601 UTC time like `2024-05-01T12:30:00.000Z`
+pub(crate) fn unix_seconds(time: &str) -> Option<u64> {
+    let field = |range: std::ops::Range<usize>| time.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hours, minutes, seconds) = (field(11..13)?, field(14..16)?, field(17..19)?);
+
+    // Days from the civil calendar, with years starting in March so leap days come last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    u64::try_from(days * 86_400 + hours * 3600 + minutes * 60 + seconds).ok()
+}
+
+/// The version the `latest` tag moves to once the one it pointed at is gone: the highest left
+pub(crate) fn next_latest<'a>(versions: impl Iterator<Item = &'a String>) -> Option<String> {
+    versions
+        .filter_map(|version| Some((Version::new(version).parse().ok()?, version)))
+        .max_by(|(a, _), (b, _)| semver_order(a, b))
+        .map(|(_, version)| version.clone())
+}
+
+/// Set the deprecation of versions in the package's document, as npm does: read it and put it back changed
+pub(crate) fn put_deprecation(
+    name: &PackageName,
+    versions: &[String],
+    message: &str,
+    config: &RegistryConfig,
+) -> Result<()> {
+    let forbidden = "you don't maintain it";
+    let url = format!("{}/{}", config.registry_for(name), name.registry_path());
+    let mut document = read_document(&url, config).map_err(|err| refusal("deprecate", name, forbidden, err))?;
+    for version in versions {
+        if let Some(manifest) = document["versions"].get_mut(version.as_str()) {
+            manifest["deprecated"] = Value::from(message);
+        }
+    }
+    let body = serde_json::to_string(&document).map_err(|err| NaryError::json(&url, err))?;
+    config.put_json(&url, &body).map_err(|err| refusal("deprecate", name, forbidden, err))?;
+    Ok(())
+}
+
+/// Unpublish one version, or the whole package, as npm does. A version goes by putting back the document without
+/// it at its revision and deleting its tarball; the whole package, or its last version, by deleting the document.
+pub(crate) fn delete_versions(name: &PackageName, version: Option<&str>, config: &RegistryConfig) -> Result<()> {
+    let forbidden = "you don't maintain it, or it's past the window versions can be unpublished in";
+    let refused = |err: NaryError| refusal("unpublish", name, forbidden, err);
+    let url = format!("{}/{}", config.registry_for(name), name.registry_path());
+    let mut document = read_document(&url, config).map_err(refused)?;
+    let versions: Vec<String> =
+        document["versions"].as_object().into_iter().flatten().map(|(version, _)| version.clone()).collect();
+    if let Some(version) = version {
+        if !versions.iter().any(|known| known == version) {
+            return Err(NaryError::NoMatchingVersion {
+                name: name.to_string(),
+                range: version.to_string(),
+                available: versions,
+            });
+        }
+        check_window(&name.to_string(), version, document["time"][version].as_str())?;
+    }
+
+    let version = match version {
+        Some(version) if versions.len() > 1 => version,
+        _ => {
+            let url = format!("{}/-rev/{}", url, document["_rev"].as_str().unwrap_or_default());
+            config.delete(&url).map_err(refused)?;
+            return Ok(());
+        }
+    };
+    let removed = document["versions"].as_object_mut().and_then(|versions| versions.remove(version));
+    let tarball = removed.as_ref().and_then(|manifest| manifest["dist"]["tarball"].as_str()).map(str::to_string);
+    if let Some(time) = document["time"].as_object_mut() {
+        time.remove(version);
+    }
+    let latest = document["versions"].as_object().and_then(|versions| next_latest(versions.keys()));
+    if let Some(tags) = document["dist-tags"].as_object_mut() {
+        tags.retain(|_, tagged| tagged != version);
+        if let (Some(latest), false) = (latest, tags.contains_key("latest")) {
+            tags.insert("latest".to_string(), Value::from(latest));
+        }
+    }
+
+    let put = format!("{}/-rev/{}", url, document["_rev"].as_str().unwrap_or_default());
+    let body = serde_json::to_string(&document).map_err(|err| NaryError::json(&put, err))?;
+    config.put_json(&put, &body).map_err(refused)?;
+    if let Some(tarball) = tarball {
+        // The revision changed with the put
+        let document = read_document(&url, config).map_err(refused)?;
+        let delete = format!("{}/-rev/{}", tarball, document["_rev"].as_str().unwrap_or_default());
+        config.delete(&delete).map_err(refused)?;
+    }
+    Ok(())
+}
+
+/// The full document of the package, revision and all, as it's read to change it
+pub(crate) fn read_document(url: &str, config: &RegistryConfig) -> Result<Value> {
+    let url = format!("{}?write=true", url);
+    let mut body = String::new();
+    config.fetch(&url, |request| request)?
+        .read_to_string(&mut body)
+        .map_err(|err| NaryError::network(&url, err))?;
+    serde_json::from_str(&body).map_err(|err| NaryError::json(&url, err))
+}
+
+/// What a refused write means, for the statuses registries refuse with
+fn refusal(action: &str, name: &PackageName, forbidden: &str, err: NaryError) -> NaryError {
+    let reason = match &err {
+        NaryError::RegistryError { status: 401, .. } => "it needs you to log in",
+        NaryError::RegistryError { status: 403, .. } => forbidden,
+        NaryError::RegistryError { status: 404, .. } => "it doesn't have the package",
+        NaryError::RegistryError { status: 409, .. } => "the package changed while it was being written, try again",
+        _ => return err,
+    };
+    NaryError::RegistryRefused {
+        action: action.to_string(),
+        package: name.to_string(),
+        reason: reason.to_string(),
+    }
+}
@@ -0,0 +1,124 @@
+use semver_rs::{Range, Version};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use crate::{
+    add_dependency, calculate_depends,
+    lockfile::{read_or_import, write_lockfile, Lockfile},
+    path_to_root_dependency,
+    workspace::project_dependencies,
+    Dependency, InstallReporter, RegistryClient, ResolutionOptions, ResolvedGraph, Result, Specifier,
+};
+
+/// A package whose versions an update changed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpdatedPackage {
+    pub name: String,
+    /// Its versions in the lockfile before, none when it's new
+    pub from: Vec<String>,
+    /// Its versions now, none when nothing depends on it any more
+    pub to: Vec<String>,
+}
+
+/// What `update` resolved, and how that differs from the lockfile before it
+#[derive(Debug)]
+pub struct Update {
+    pub graph: ResolvedGraph,
+    pub lockfile: Lockfile,
+    pub updated: Vec<UpdatedPackage>,
+}
+
+/// Resolve the packages called `names` again, wherever they're depended on, to the newest versions their ranges
+/// allow, or every package when `names` is empty. Everything else keeps the version it's locked at as long as that's
+/// still in range, so the lockfile only changes below what's updated. With `major`, the root's dependencies among
+/// them whose range doesn't allow the latest version are bumped in package.json to `^<latest>` first.
+///
+/// The lockfile is written with the new graph, for an install to follow it.
+pub fn update(
+    project_dir: &Path,
+    names: &[String],
+    major: bool,
+    registry: &dyn RegistryClient,
+    options: &ResolutionOptions,
+    reporter: &dyn InstallReporter,
+) -> Result<Update> {
+    let selected = |name: &str| names.is_empty() || names.iter().any(|selected| selected == name);
+    if major {
+        for (dependency, kind) in project_dependencies(project_dir)? {
+            if selected(&dependency.name) && !allows_latest(&dependency, registry, options)? {
+                let spec = format!("{}@latest", dependency.name);
+                add_dependency(project_dir, &spec, kind, false, registry, options)?;
+            }
+        }
+    }
+
+    let before = read_or_import(project_dir)?.unwrap_or_default();
+    let mut preferred: BTreeMap<String, Vec<String>> = options.preferred.clone();
+    for package in before.packages.values() {
+        let name = package.alias_of.as_deref().unwrap_or(&package.name);
+        if !selected(&package.name) && !selected(name) {
+            preferred.entry(name.to_string()).or_default().push(package.version.clone());
+        }
+    }
+    let options = ResolutionOptions {
+        preferred,
+        ..options.clone()
+    };
+
+    let root = path_to_root_dependency(project_dir)?;
+    let graph = calculate_depends(&root, &project_dependencies(project_dir)?, registry, &options, reporter)?;
+    let mut lockfile = Lockfile::from_graph(&graph, registry)?;
+    lockfile.record_overrides(&options.overrides);
+    write_lockfile(project_dir, &lockfile)?;
+
+    let updated = changes(&before, &lockfile);
+    Ok(Update { graph, lockfile, updated })
+}
+
+/// Whether a root dependency from the registry can resolve to its latest version. Others have no latest version.
+fn allows_latest(dependency: &Dependency, registry: &dyn RegistryClient, options: &ResolutionOptions) -> Result<bool> {
+    let version = match dependency.specifier() {
+        Specifier::SemverRange(range) | Specifier::Tag(range) => range,
+        _ => return Ok(true),
+    };
+    let packument = registry.packument(&dependency.package_name()?)?;
+    let latest = match packument.dist_tags.get("latest") {
+        Some(latest) => latest,
+        None => return Ok(true),
+    };
+    // Dist-tags are left as they are
+    match (Range::new(version).with_options(options.semver()).parse(), Version::new(latest).parse()) {
+        (Ok(range), Ok(latest)) => Ok(range.test(&latest)),
+        _ => Ok(true),
+    }
+}
+
+fn changes(before: &Lockfile, after: &Lockfile) -> Vec<UpdatedPackage> {
+    let versions = |lockfile: &Lockfile| {
+        let mut versions: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for package in lockfile.packages.values() {
+            versions.entry(package.name.clone()).or_default().insert(package.version.clone());
+        }
+        versions
+    };
+    let (mut before, mut after) = (versions(before), versions(after));
+    let names: BTreeSet<String> = before.keys().chain(after.keys()).cloned().collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let from = before.remove(&name).unwrap_or_default();
+            let to = after.remove(&name).unwrap_or_default();
+            if from == to {
+                return None;
+            }
+            Some(UpdatedPackage {
+                name,
+                from: from.into_iter().collect(),
+                to: to.into_iter().collect(),
+            })
+        })
+        .collect()
+}
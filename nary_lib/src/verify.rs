@@ -0,0 +1,249 @@
+use p256::{
+    ecdsa::{signature::Verifier, Signature, VerifyingKey},
+    pkcs8::DecodePublicKey,
+};
+use serde_derive::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha512};
+use static_init::dynamic;
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    cache::{self, hex},
+    unpublish::unix_seconds,
+    Dist, InstallOptions, InstallReporter, NaryError, PackageName, RegistryClient, RegistryConfig, Result,
+};
+
+/// What to do about a package version whose signature is missing or doesn't check out
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Enforcement {
+    #[default]
+    Ignore,
+    Warn,
+    Require,
+}
+
+impl Enforcement {
+    /// `ignore`, `warn` or `require`
+    pub fn parse(value: &str) -> Option<Enforcement> {
+        match value {
+            "ignore" => Some(Enforcement::Ignore),
+            "warn" => Some(Enforcement::Warn),
+            "require" => Some(Enforcement::Require),
+            _ => None,
+        }
+    }
+}
+
+/// How the versions of one registry's packages are checked before they're installed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VerifyPolicy {
+    /// Versions are signed by a key of the registry, over their name, version and integrity
+    pub signatures: Enforcement,
+}
+
+/// A key the registry signs versions with, as `/-/npm/v1/keys` lists them
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SigningKey {
+    pub keyid: String,
+    /// The base64 DER SubjectPublicKeyInfo of an ECDSA P-256 key
+    pub key: String,
+    /// When the key stopped being used, an ISO 8601 UTC time. Versions published since aren't signed by it.
+    #[serde(default)]
+    pub expires: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SigningKeys {
+    keys: Vec<SigningKey>,
+}
+
+/// Keys fetched this run, by registry
+#[dynamic]
+static SIGNING_KEYS: Mutex<HashMap<String, Vec<SigningKey>>> = Mutex::new(HashMap::new());
+
+/// The keys the package's registry signs with. They're fetched once per run and cached for offline installs.
+pub fn fetch_signing_keys(
+    name: &PackageName,
+    config: &RegistryConfig,
+    options: &InstallOptions,
+) -> Result<Vec<SigningKey>> {
+    let registry = config.registry_for(name).to_string();
+    if let Some(keys) = SIGNING_KEYS.lock().unwrap().get(&registry) {
+        return Ok(keys.clone());
+    }
+
+    let url = format!("{}/-/npm/v1/keys", registry);
+    let body = match cache::read_signing_keys(&registry)? {
+        Some(body) if options.use_cached_metadata() => body,
+        _ if options.offline => return Err(NaryError::NotCached { what: format!("Signing keys of {}", registry) }),
+        _ => {
+            let mut body = String::new();
+            config.fetch(&url, |request| request)?
+                .read_to_string(&mut body)
+                .map_err(|err| NaryError::network(&url, err))?;
+            cache::write_signing_keys(&registry, &body)?;
+            body
+        }
+    };
+    let keys = serde_json::from_str::<SigningKeys>(&body).map_err(|err| NaryError::json(&url, err))?.keys;
+    SIGNING_KEYS.lock().unwrap().insert(registry, keys.clone());
+    Ok(keys)
+}
+
+/// Check the signature of a registry version as the registry's `VerifyPolicy` asks, warning about or refusing one
+/// that doesn't pass. Returns whether the check passed.
+pub fn verify_version(
+    name: &PackageName,
+    version: &str,
+    dist: &Dist,
+    registry: &dyn RegistryClient,
+    reporter: &dyn InstallReporter,
+) -> Result<bool> {
+    let policy = registry.verify_policy(name);
+    let package = format!("{}@{}", name, version);
+
+    if policy.signatures == Enforcement::Ignore {
+        return Ok(false);
+    }
+    // The abbreviated packument has no publish times, so the full document is only read for a key that's expired
+    let published = || {
+        let full = registry.full_packument(name).ok()?;
+        full.time.get(version).and_then(|time| unix_seconds(time))
+    };
+    let checked = registry.signing_keys(name).map(|keys| check_signature(&package, dist, &keys, published));
+    enforce(policy.signatures, &package, checked, reporter)
+}
+
+/// Whether the check passed, warning or failing as the policy says about one that didn't
+fn enforce(
+    policy: Enforcement,
+    package: &str,
+    checked: Result<std::result::Result<(), String>>,
+    reporter: &dyn InstallReporter,
+) -> Result<bool> {
+    let reason = match checked {
+        Ok(Ok(())) => return Ok(true),
+        Ok(Err(reason)) => reason,
+        Err(err) => format!("couldn't be verified: {}", err),
+    };
+    match policy {
+        Enforcement::Require => Err(NaryError::Unverified {
+            package: package.to_string(),
+            reason,
+        }),
+        _ => {
+            reporter.on_warning(&format!("{} {}", package, reason));
+            Ok(false)
+        }
+    }
+}
+
+/// npm signs `<name>@<version>:<integrity>` with ECDSA P-256. A key that expired before the version was published
+/// doesn't count, nor one that has expired at all when the version's publish time isn't known.
+fn check_signature(
+    package: &str,
+    dist: &Dist,
+    keys: &[SigningKey],
+    mut published: impl FnMut() -> Option<u64>,
+) -> std::result::Result<(), String> {
+    let integrity = match dist.integrity.as_deref().filter(|integrity| sha512_of(integrity).is_some()) {
+        Some(integrity) => integrity,
+        None => return Err("has no sha512 integrity to check a signature of".to_string()),
+    };
+    if dist.signatures.is_empty() {
+        return Err("isn't signed by its registry".to_string());
+    }
+
+    let message = format!("{}:{}", package, integrity);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut published_at = None;
+    let mut known_key = false;
+    let mut expired_key = false;
+    for signature in &dist.signatures {
+        let key = match keys.iter().find(|key| key.keyid == signature.keyid) {
+            Some(key) => key,
+            None => continue,
+        };
+        known_key = true;
+        if let Some(expires) = key.expires.as_deref().and_then(unix_seconds).filter(|&expires| expires < now) {
+            if published_at.get_or_insert_with(&mut published).is_none_or(|published| expires < published) {
+                expired_key = true;
+                continue;
+            }
+        }
+        if let (Ok(public_key), Ok(sig)) = (base64::decode(&key.key), base64::decode(&signature.sig)) {
+            if verifies(&public_key, message.as_bytes(), &sig) {
+                return Ok(());
+            }
+        }
+    }
+    if expired_key {
+        Err("is signed with a key that expired before it was published".to_string())
+    } else if known_key {
+        Err("has a registry signature that doesn't match it".to_string())
+    } else {
+        Err("is signed with a key its registry doesn't list".to_string())
+    }
+}
+
+/// Whether `signature`, DER encoded ECDSA over P-256 with SHA-256, is one of `message` by the key whose DER
+/// SubjectPublicKeyInfo is `public_key`
+fn verifies(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    match (VerifyingKey::from_public_key_der(public_key), Signature::from_der(signature)) {
+        (Ok(key), Ok(signature)) => key.verify(message, &signature).is_ok(),
+        _ => false,
+    }
+}
+
+/// The sha512 digest of an integrity string with one
+fn sha512_of(integrity: &str) -> Option<Vec<u8>> {
+    integrity
+        .split_whitespace()
+        .find_map(|hash| hash.strip_prefix("sha512-"))
+        .and_then(|digest| base64::decode(digest).ok())
+}
+
+/// Passes a tarball through, failing at its end when it isn't the one a `Dist::identity` names. An identity with no
+/// sha512 or sha1 hash, like a tarball URL, lets anything through.
+pub(crate) struct CheckedReader<R> {
+    inner: R,
+    sha512: Sha512,
+    sha1: Sha1,
+    identity: String,
+}
+
+impl<R: Read> CheckedReader<R> {
+    pub(crate) fn new(inner: R, identity: &str) -> CheckedReader<R> {
+        CheckedReader {
+            inner,
+            sha512: Sha512::new(),
+            sha1: Sha1::new(),
+            identity: identity.to_string(),
+        }
+    }
+}
+
+impl<R: Read> Read for CheckedReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        self.sha512.update(&buffer[..read]);
+        self.sha1.update(&buffer[..read]);
+        if read == 0 && !buffer.is_empty() {
+            let integrity = format!("sha512-{}", base64::encode(self.sha512.clone().finalize()));
+            let shasum = format!("sha1:{}", hex(&self.sha1.clone().finalize()));
+            if !cache::matches_identity(&self.identity, &integrity, Some(&shasum)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the tarball is {} rather than {}", integrity, self.identity),
+                ));
+            }
+        }
+        Ok(read)
+    }
+}
@@ -0,0 +1,223 @@
+use semver_rs::{Range, Version};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    path_to_dependencies, tree::entries, Dependency, DependencyKind, Manifest, NaryError, RealFs, Result,
+};
+
+/// A package of a workspace, in its own directory below the root
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WorkspaceMember {
+    pub name: String,
+    pub version: String,
+    pub path: PathBuf,
+}
+
+/// The range of a `workspace:` specifier, which only workspace packages can satisfy
+pub fn workspace_range(version: &str) -> Option<&str> {
+    version.strip_prefix("workspace:")
+}
+
+impl WorkspaceMember {
+    /// Whether the member can stand in for a dependency on `range`. The `workspace:` shorthands `^` and `~` match
+    /// whatever version the member has, like `*`.
+    pub fn satisfies(&self, range: &str) -> bool {
+        let range = workspace_range(range).unwrap_or(range);
+        if matches!(range, "" | "*" | "^" | "~") {
+            return true;
+        }
+        match (Range::new(range).parse(), Version::new(&self.version).parse()) {
+            (Ok(range), Ok(version)) => range.test(&version),
+            _ => false,
+        }
+    }
+
+    /// What a `workspace:` range becomes once packed, where there's no workspace: the member's version for `*`, with
+    /// the shorthand in front for `^` and `~`, and the range itself otherwise
+    pub fn published_range(&self, range: &str) -> String {
+        match workspace_range(range).unwrap_or(range) {
+            "" | "*" => self.version.clone(),
+            shorthand @ "^" | shorthand @ "~" => format!("{}{}", shorthand, self.version),
+            range => range.to_string(),
+        }
+    }
+
+    /// The member as a dependency, linked from its directory
+    pub fn dependency(&self) -> Dependency {
+        Dependency::new(self.name.clone(), format!("file:{}", self.path.display()))
+    }
+}
+
+/// A root package.json with `workspaces`, and the members its globs found
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Workspace {
+    pub root: PathBuf,
+    pub members: Vec<WorkspaceMember>,
+}
+
+impl Workspace {
+    pub fn member(&self, name: &str) -> Option<&WorkspaceMember> {
+        self.members.iter().find(|member| member.name == name)
+    }
+
+    /// What the whole workspace is resolved from: the root's own dependencies, then every member, so that one graph
+    /// and one lockfile cover them all
+    pub fn dependencies(&self) -> Result<Vec<(Dependency, DependencyKind)>> {
+        let mut dependencies: Vec<(Dependency, DependencyKind)> = path_to_dependencies(&self.root)?
+            .into_iter()
+            .filter(|(dependency, _)| self.member(&dependency.name).is_none())
+            .collect();
+        dependencies.extend(self.members.iter().map(|member| (member.dependency(), DependencyKind::Normal)));
+        Ok(dependencies)
+    }
+
+    /// A member's package.json as it's packed and published, with each `workspace:` specifier rewritten to the
+    /// version of the member it names
+    pub fn publish_manifest(&self, manifest: &Value) -> Result<Value> {
+        let mut manifest = manifest.clone();
+        for field in &["dependencies", "optionalDependencies", "peerDependencies", "devDependencies"] {
+            let dependencies = match manifest[*field].as_object_mut() {
+                Some(dependencies) => dependencies,
+                None => continue,
+            };
+            for (name, range) in dependencies.iter_mut() {
+                let workspace = match range.as_str().and_then(workspace_range) {
+                    Some(workspace) => workspace,
+                    None => continue,
+                };
+                let member = self.member(name).filter(|member| member.satisfies(workspace));
+                let member = member.ok_or_else(|| NaryError::NotInWorkspace {
+                    name: name.clone(),
+                    range: workspace.to_string(),
+                })?;
+                *range = Value::String(member.published_range(workspace));
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// The workspace rooted at `root_dir`, or None when its package.json has no `workspaces`. They're either a list of
+/// globs or `{"packages": [...]}`; globs starting with `!` leave directories out.
+pub fn find_workspace(root_dir: &Path) -> Result<Option<Workspace>> {
+    let patterns = match Manifest::read(root_dir)?.workspaces {
+        Some(patterns) => patterns,
+        None => return Ok(None),
+    };
+
+    let root = root_dir.canonicalize().map_err(|err| NaryError::io(root_dir, err))?;
+    let mut directories = Vec::new();
+    let mut excluded = Vec::new();
+    for pattern in &patterns {
+        match pattern.strip_prefix('!') {
+            Some(pattern) => excluded.extend(expand(&root, pattern)?),
+            None => directories.extend(expand(&root, pattern)?),
+        }
+    }
+
+    let mut members: Vec<WorkspaceMember> = Vec::new();
+    for path in directories {
+        if excluded.contains(&path) || members.iter().any(|member| member.path == path) {
+            continue;
+        }
+        if !path.join("package.json").is_file() {
+            continue;
+        }
+        let manifest = Manifest::read(&path)?;
+        if let Some(name) = manifest.name {
+            members.push(WorkspaceMember {
+                name,
+                version: manifest.version.unwrap_or_default(),
+                path,
+            });
+        }
+    }
+
+    Ok(Some(Workspace { root, members }))
+}
+
+/// The workspace a package is a member or the root of, found in the nearest directory at or above it with a
+/// package.json whose `workspaces` include it
+pub fn workspace_of(package_dir: &Path) -> Result<Option<Workspace>> {
+    let package_dir = package_dir.canonicalize().map_err(|err| NaryError::io(package_dir, err))?;
+    for dir in package_dir.ancestors() {
+        if !dir.join("package.json").is_file() {
+            continue;
+        }
+        if let Some(workspace) = find_workspace(dir)? {
+            if workspace.root == package_dir || workspace.members.iter().any(|member| member.path == package_dir) {
+                return Ok(Some(workspace));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The dependencies to resolve for a project: a workspace's when it is one, its package.json's otherwise
+pub fn project_dependencies(project_dir: &Path) -> Result<Vec<(Dependency, DependencyKind)>> {
+    match find_workspace(project_dir)? {
+        Some(workspace) => workspace.dependencies(),
+        None => path_to_dependencies(project_dir),
+    }
+}
+
+/// Directories matching a glob relative to `root`: `*` and `?` within a name, `**` for any depth. node_modules and
+/// hidden directories are never searched.
+fn expand(root: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let mut matched = vec![root.to_path_buf()];
+    for segment in pattern.split('/').filter(|segment| !segment.is_empty() && *segment != ".") {
+        let mut next = Vec::new();
+        for dir in &matched {
+            if segment == "**" {
+                let mut pending = vec![dir.clone()];
+                while let Some(dir) = pending.pop() {
+                    pending.extend(subdirectories(&dir)?.into_iter().map(|(_, path)| path));
+                    next.push(dir);
+                }
+            } else if segment.contains(['*', '?']) {
+                for (name, path) in subdirectories(dir)? {
+                    if wildcard_matches(segment, &name) {
+                        next.push(path);
+                    }
+                }
+            } else if dir.join(segment).is_dir() {
+                next.push(dir.join(segment));
+            }
+        }
+        matched = next;
+    }
+    matched.sort();
+    matched.dedup();
+    Ok(matched)
+}
+
+fn subdirectories(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    Ok(entries(&RealFs, dir)?
+        .into_iter()
+        .filter(|(name, path)| !name.starts_with('.') && name != "node_modules" && path.is_dir())
+        .collect())
+}
+
+/// Whether a name matches a pattern with `*` and `?` in it
+pub(crate) fn wildcard_matches(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    // Where the last `*` was, and where in the name it started matching
+    let (mut p, mut n, mut star) = (0, 0, None);
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
@@ -0,0 +1,1113 @@
+use nary_lib::cache::{self, CacheStats, CacheVersion, GcStats, PruneLimit, PruneStats, VerifyStats};
+use nary_lib::{
+    calculate_depends, dist_tags, fetch_package_root_metadata, info, install_dep, unpublish, Dependency,
+    DependencyKind, FetchPolicy, Freshness, HttpRegistry, Info, InstallFs, InstallOptions, InstallReporter, MemoryFs,
+    NaryError, PackageName, RegistryClient, RegistryConfig, ResolutionOptions, SilentReporter,
+};
+
+use flate2::{write::GzEncoder, Compression};
+use hyper::Url;
+use sha1::Digest;
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tempfile::TempDir;
+
+use anyhow::Result;
+
+mod common;
+
+fn files_below(dir: std::path::PathBuf) -> Vec<std::path::PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            if entry.path().is_dir() {
+                pending.push(entry.path());
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+    files
+}
+
+/// Serves `body` to every request on a local port, counting the requests
+fn serve(body: Vec<u8>) -> Result<(Url, Arc<AtomicUsize>)> {
+    serve_after(Vec::new(), body)
+}
+
+/// Like `serve`, but answers the first requests with these bodiless responses, a status line and headers each
+fn serve_after(failures: Vec<&'static str>, body: Vec<u8>) -> Result<(Url, Arc<AtomicUsize>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = Url::parse(&format!("http://{}/ms/-/ms-2.0.0.tgz", listener.local_addr()?))?;
+    let requests = Arc::new(AtomicUsize::new(0));
+
+    let counter = requests.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let request = counter.fetch_add(1, Ordering::SeqCst);
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).map(|read| read > 2).unwrap_or(false) {
+                line.clear();
+            }
+            let mut stream = &stream;
+            if let Some(failure) = failures.get(request) {
+                let _ = write!(stream, "{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", failure);
+                continue;
+            }
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            let _ = stream.write_all(&body);
+        }
+    });
+
+    Ok((url, requests))
+}
+
+/// Serves what `route` answers for the path of each request, given the server's origin too, counting the requests
+fn serve_routes(route: impl Fn(&str, &str) -> Vec<u8> + Send + 'static) -> Result<(String, Arc<AtomicUsize>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let origin = format!("http://{}", listener.local_addr()?);
+    let requests = Arc::new(AtomicUsize::new(0));
+
+    let (counter, served) = (requests.clone(), origin.clone());
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            while reader.read_line(&mut head).map(|read| read > 2).unwrap_or(false) {}
+            let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+            let body = route(&served, &path);
+            let mut stream = &stream;
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            let _ = stream.write_all(&body);
+        }
+    });
+
+    Ok((origin, requests))
+}
+
+/// Serves `body` with an ETag and a Last-Modified date, answering 304 to requests that send them back. Returns the
+/// heads of the requests.
+fn serve_revalidating(body: Vec<u8>) -> Result<(Url, Arc<Mutex<Vec<String>>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = Url::parse(&format!("http://{}/ms", listener.local_addr()?))?;
+    let heads = Arc::new(Mutex::new(Vec::new()));
+
+    let seen = heads.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            while reader.read_line(&mut head).map(|read| read > 2).unwrap_or(false) {}
+            seen.lock().unwrap().push(head.clone());
+            let mut stream = &stream;
+            let headers = "ETag: \"v1\"\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\nConnection: close";
+            let _ = if head.contains("If-None-Match: \"v1\"") {
+                write!(stream, "HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\n{}\r\n\r\n", headers)
+            } else {
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}\r\n\r\n", body.len(), headers)
+                    .and_then(|_| stream.write_all(&body))
+            };
+        }
+    });
+
+    Ok((url, heads))
+}
+
+/// A gzipped package tarball holding only this package.json
+fn tarball_of(manifest: &str) -> Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, "package/package.json", manifest.as_bytes())?;
+    Ok(builder.into_inner()?.finish()?)
+}
+
+fn content_files(dir: &TempDir) -> Vec<std::path::PathBuf> {
+    files_below(dir.path().join("content-v1"))
+}
+
+#[test]
+fn it_will_store_identical_tarballs_once() -> Result<()> {
+    let (_guard, dir) = common::isolated_cache()?;
+    let registry = Url::parse("https://registry.npmjs.org/ms/-/ms-2.0.0.tgz")?;
+    let mirror = Url::parse("https://mirror.example.com/ms/-/ms-2.0.0.tgz")?;
+
+    let integrity = cache::write_content(b"tarball")?;
+    assert_eq!(integrity, cache::integrity_of(b"tarball"));
+    assert!(integrity.starts_with("sha512-"));
+    for url in &[&registry, &mirror] {
+        cache::write_index(&cache::tarball_key("ms", "2.0.0", url), &integrity, 7)?;
+    }
+    assert_eq!(cache::write_content(b"tarball")?, integrity);
+
+    let entry = cache::read_index(&cache::tarball_key("ms", "2.0.0", &mirror))?.unwrap();
+    assert_eq!(entry.integrity, integrity);
+    assert_eq!(entry.size, 7);
+    assert_eq!(cache::read_content(&integrity)?, Some(b"tarball".to_vec()));
+    assert_eq!(cache::read_index(&cache::tarball_key("ms", "2.1.0", &mirror))?, None);
+    assert_eq!(content_files(&dir).len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_verify_and_gc_the_cache() -> Result<()> {
+    let (_guard, dir) = common::isolated_cache()?;
+    let url = Url::parse("https://registry.npmjs.org/ms/-/ms-2.0.0.tgz")?;
+
+    let kept = cache::write_content(b"kept")?;
+    cache::write_index(&cache::tarball_key("ms", "2.0.0", &url), &kept, 4)?;
+    let corrupted = cache::write_content(b"corrupted")?;
+    cache::write_index(&cache::tarball_key("ms", "1.0.0", &url), &corrupted, 9)?;
+    cache::write_content(b"unreferenced")?;
+
+    let corrupted_path = content_files(&dir)
+        .into_iter()
+        .find(|path| fs::read(path).unwrap() == b"corrupted")
+        .unwrap();
+    fs::write(&corrupted_path, b"tampered")?;
+    assert_eq!(cache::read_content(&corrupted)?, None);
+
+    assert_eq!(
+        cache::verify()?,
+        VerifyStats {
+            verified: 2,
+            corrupted: 1,
+            dangling_entries: 1,
+        }
+    );
+    assert_eq!(cache::read_index(&cache::tarball_key("ms", "1.0.0", &url))?, None);
+
+    assert_eq!(
+        cache::gc()?,
+        GcStats {
+            removed: 1,
+            freed_bytes: b"unreferenced".len() as u64,
+        }
+    );
+    assert_eq!(cache::read_content(&kept)?, Some(b"kept".to_vec()));
+    assert_eq!(content_files(&dir).len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_report_prune_and_clear_the_cache() -> Result<()> {
+    let (_guard, dir) = common::isolated_cache()?;
+    let url = Url::parse("https://registry.npmjs.org/ms/-/ms-2.0.0.tgz")?;
+
+    for (version, content) in &[("1.0.0", "old"), ("2.0.0", "newer"), ("3.0.0", "newest")] {
+        let integrity = cache::write_content(content.as_bytes())?;
+        cache::write_index(&cache::tarball_key("ms", version, &url), &integrity, content.len() as u64)?;
+    }
+    fs::write(dir.path().join("packument.json"), "{}")?;
+
+    // Make the entries look like they were cached a day and a minute ago
+    for path in files_below(dir.path().join("index-v1")) {
+        let mut entry: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        let age = match entry["key"].as_str().unwrap() {
+            key if key.contains("@1.0.0") => 24 * 60 * 60,
+            key if key.contains("@2.0.0") => 60,
+            _ => 0,
+        };
+        entry["time"] = serde_json::json!(entry["time"].as_u64().unwrap() - age);
+        fs::write(&path, entry.to_string())?;
+    }
+
+    let stats = cache::stats()?;
+    assert_eq!(stats.index_entries, 3);
+    assert_eq!(stats.content_files, 3);
+    assert_eq!(stats.content_bytes, "oldnewernewest".len() as u64);
+    assert!(stats.total_bytes > stats.content_bytes);
+
+    assert_eq!(
+        cache::prune(PruneLimit::MaxAge(Duration::from_secs(60 * 60)))?,
+        PruneStats {
+            removed_entries: 1,
+            removed_content: 1,
+            freed_bytes: 3,
+        }
+    );
+    assert_eq!(cache::read_index(&cache::tarball_key("ms", "1.0.0", &url))?, None);
+
+    assert_eq!(
+        cache::prune(PruneLimit::MaxSize(6))?,
+        PruneStats {
+            removed_entries: 1,
+            removed_content: 1,
+            freed_bytes: 5,
+        }
+    );
+    assert!(cache::read_index(&cache::tarball_key("ms", "3.0.0", &url))?.is_some());
+
+    cache::clear()?;
+    assert_eq!(cache::stats()?, CacheStats::default());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_serialize_concurrent_writers() -> Result<()> {
+    let (_guard, dir) = common::isolated_cache()?;
+    let holders = AtomicUsize::new(0);
+    let content = vec![7u8; 256 * 1024];
+
+    std::thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| {
+                let _lock = cache::lock("ms@2.0.0").unwrap();
+                assert_eq!(holders.fetch_add(1, Ordering::SeqCst), 0);
+                let integrity = cache::write_content(&content).unwrap();
+                assert_eq!(cache::read_content(&integrity).unwrap().as_ref(), Some(&content));
+                holders.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+
+    assert_eq!(content_files(&dir).len(), 1);
+    assert!(files_below(dir.path().join("tmp")).is_empty());
+    // The lock files go with the locks
+    assert!(files_below(dir.path().join("locks")).is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_cache_tarballs_as_they_stream() -> Result<()> {
+    let (_guard, dir) = common::isolated_cache()?;
+    let body: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let (url, requests) = serve(body.clone())?;
+    let config = RegistryConfig::default();
+    let key = cache::tarball_key("ms", "2.0.0", &url);
+
+    // A download that's abandoned halfway never becomes an entry
+    let mut partial = [0; 1024];
+    cache::cache_reader("ms", "2.0.0", &url, &config, &InstallOptions::default(), &SilentReporter)?
+        .read_exact(&mut partial)?;
+    assert_eq!(cache::read_index(&key)?, None);
+
+    let mut streamed = Vec::new();
+    cache::cache_reader("ms", "2.0.0", &url, &config, &InstallOptions::default(), &SilentReporter)?
+        .read_to_end(&mut streamed)?;
+    assert_eq!(streamed, body);
+    assert_eq!(cache::read_index(&key)?.unwrap().integrity, cache::integrity_of(&body));
+    assert!(files_below(dir.path().join("tmp")).is_empty());
+
+    let offline = InstallOptions {
+        offline: true,
+        ..InstallOptions::default()
+    };
+    assert_eq!(cache::cache("ms", "2.0.0", &url, &config, &offline, &SilentReporter)?, body);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_refuse_tarballs_that_dont_match_their_integrity() -> Result<()> {
+    let (_guard, dir) = common::isolated_cache()?;
+    let body = b"tampered tarball".to_vec();
+    let (url, requests) = serve(body.clone())?;
+    let config = RegistryConfig::default();
+    let key = cache::tarball_key("ms", "2.0.0", &url);
+    let options = InstallOptions::default();
+    let download = |identity: &str| {
+        let mut tarball = Vec::new();
+        cache::cache_reader_for("ms", "2.0.0", &url, Some(identity), &config, &options, &SilentReporter)?
+            .read_to_end(&mut tarball)
+            .map(|_| tarball)
+            .map_err(anyhow::Error::from)
+    };
+
+    let shasum = |content: &[u8]| {
+        let digest = sha1::Sha1::digest(content);
+        format!("sha1:{}", digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+    };
+
+    // Neither a sha512 integrity nor a sha1 shasum of something else lets it into the cache
+    for identity in [cache::integrity_of(b"original tarball"), shasum(b"original tarball")] {
+        let refused = download(&identity).unwrap_err().to_string();
+        assert!(refused.contains(&format!("rather than {}", identity)), "{}", refused);
+        assert_eq!(cache::read_index(&key)?, None);
+        assert!(content_files(&dir).is_empty());
+        assert!(files_below(dir.path().join("partial-v1")).is_empty());
+    }
+
+    assert_eq!(download(&shasum(&body))?, body);
+    assert_eq!(cache::read_index(&key)?.unwrap().integrity, cache::integrity_of(&body));
+
+    // What's cached for the URL isn't used for a different integrity either
+    assert!(download(&cache::integrity_of(b"original tarball")).is_err());
+    assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_download_identical_tarballs_once() -> Result<()> {
+    let (_guard, _dir) = common::isolated_cache()?;
+    let body: Vec<u8> = (0..50_000u32).map(|i| (i % 241) as u8).collect();
+    let (url, requests) = serve(body.clone())?;
+    let config = RegistryConfig::default();
+    let read = |name: &str, url: &Url, identity: Option<&str>, options: &InstallOptions| -> Result<Vec<u8>> {
+        let mut tarball = Vec::new();
+        cache::cache_reader_for(name, "2.0.0", url, identity, &config, options, &SilentReporter)?
+            .read_to_end(&mut tarball)?;
+        Ok(tarball)
+    };
+    assert_eq!(read("ms", &url, None, &InstallOptions::default())?, body);
+
+    // A mirror's copy and a republished one are found by their integrity or shasum, without a download
+    let offline = InstallOptions {
+        offline: true,
+        ..InstallOptions::default()
+    };
+    let mirror = Url::parse("http://127.0.0.1:9/ms/-/ms-2.0.0.tgz")?;
+    assert_eq!(read("ms", &mirror, Some(&cache::integrity_of(&body)), &offline)?, body);
+    let shasum: String = sha1::Sha1::digest(&body).iter().map(|byte| format!("{:02x}", byte)).collect();
+    assert_eq!(read("ms-fork", &mirror, Some(&format!("sha1:{}", shasum)), &offline)?, body);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // Those are entries of their own now, and another hash still has to be downloaded
+    let entry = cache::read_index(&cache::tarball_key("ms-fork", "2.0.0", &mirror))?.unwrap();
+    assert_eq!(entry.integrity, cache::integrity_of(&body));
+    assert!(read("ms-other", &mirror, Some("sha512-AAAA"), &offline).is_err());
+    assert_eq!(cache::content_keys("sha1-AAAA sha512-BBBB"), vec!["content sha1:000000", "content sha512-BBBB"]);
+
+    Ok(())
+}
+
+/// Serves `body` with an ETag, cutting the first response off halfway. Later requests for a range get it when
+/// `ranges` is set, and the whole body otherwise. Returns the heads of the requests.
+fn serve_interrupted(body: Vec<u8>, ranges: bool) -> Result<(Url, Arc<Mutex<Vec<String>>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = Url::parse(&format!("http://{}/ms/-/ms-2.0.0.tgz", listener.local_addr()?))?;
+    let heads = Arc::new(Mutex::new(Vec::new()));
+
+    let seen = heads.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            while reader.read_line(&mut head).map(|read| read > 2).unwrap_or(false) {}
+            let mut heads = seen.lock().unwrap();
+            heads.push(head.clone());
+            let mut stream = &stream;
+            let headers = "ETag: \"v1\"\r\nAccept-Ranges: bytes\r\nConnection: close";
+            let start = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Range: bytes="))
+                .and_then(|range| range.trim_end_matches('-').parse::<usize>().ok())
+                .filter(|_| ranges);
+            let _ = match (heads.len(), start) {
+                (1, _) => write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}\r\n\r\n", body.len(), headers)
+                    .and_then(|_| stream.write_all(&body[..body.len() / 2])),
+                (_, Some(start)) => write!(
+                    stream,
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n{}\r\n\r\n",
+                    start,
+                    body.len() - 1,
+                    body.len(),
+                    body.len() - start,
+                    headers
+                )
+                .and_then(|_| stream.write_all(&body[start..])),
+                (_, None) => write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}\r\n\r\n", body.len(), headers)
+                    .and_then(|_| stream.write_all(&body)),
+            };
+        }
+    });
+
+    Ok((url, heads))
+}
+
+/// Adds up the bytes each download received
+#[derive(Default)]
+struct ReceivedReporter(AtomicUsize);
+
+impl InstallReporter for ReceivedReporter {
+    fn on_download_end(&self, _name: &str, _version: &str, received: u64) {
+        self.0.fetch_add(received as usize, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn it_will_resume_interrupted_downloads() -> Result<()> {
+    let (_guard, dir) = common::isolated_cache()?;
+    let body: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let config = RegistryConfig::default();
+    let options = InstallOptions::default();
+
+    let (url, heads) = serve_interrupted(body.clone(), true)?;
+    let key = cache::tarball_key("ms", "2.0.0", &url);
+    let cut_off = ReceivedReporter::default();
+    assert!(cache::cache("ms", "2.0.0", &url, &config, &options, &cut_off).is_err());
+    assert_eq!(cache::read_index(&key)?, None);
+    assert_eq!(files_below(dir.path().join("partial-v1")).len(), 2);
+
+    // Only the rest is asked for, and counted, and the whole is checked before it's an entry
+    let resumed = ReceivedReporter::default();
+    assert_eq!(cache::cache("ms", "2.0.0", &url, &config, &options, &resumed)?, body);
+    let received = (cut_off.0.load(Ordering::SeqCst), resumed.0.load(Ordering::SeqCst));
+    assert_eq!(received, (body.len() / 2, body.len() - body.len() / 2));
+    let resumed = heads.lock().unwrap()[1].clone();
+    let received = resumed.lines().find_map(|line| line.strip_prefix("Range: bytes=")).unwrap();
+    assert!(received.ends_with('-') && received != "0-");
+    assert!(resumed.contains("If-Range: \"v1\""));
+    assert_eq!(cache::read_index(&key)?.unwrap().integrity, cache::integrity_of(&body));
+    assert!(files_below(dir.path().join("partial-v1")).is_empty());
+
+    // A server that sends all of it again starts the download over
+    let (url, heads) = serve_interrupted(body.clone(), false)?;
+    assert!(cache::cache("ms", "2.0.0", &url, &config, &options, &SilentReporter).is_err());
+    assert_eq!(cache::cache("ms", "2.0.0", &url, &config, &options, &SilentReporter)?, body);
+    assert!(heads.lock().unwrap()[1].contains("Range: bytes="));
+    assert!(files_below(dir.path().join("partial-v1")).is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_cache_tarballs_on_the_install_fs() -> Result<()> {
+    let (_guard, dir) = common::isolated_cache()?;
+    let body: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let config = RegistryConfig::default();
+    let memory = Arc::new(MemoryFs::new());
+    let options = InstallOptions {
+        fs: memory.clone(),
+        ..InstallOptions::default()
+    };
+
+    // Downloads are resumed from memory, and committed there
+    let (url, heads) = serve_interrupted(body.clone(), true)?;
+    assert!(cache::cache("ms", "2.0.0", &url, &config, &options, &SilentReporter).is_err());
+    assert_eq!(cache::cache("ms", "2.0.0", &url, &config, &options, &SilentReporter)?, body);
+    assert!(heads.lock().unwrap()[1].contains("If-Range: \"v1\""));
+    let content = memory.paths().into_iter().filter(|path| path.starts_with(dir.path().join("content-v1")));
+    assert_eq!(content.filter(|path| memory.is_file(path)).count(), 1);
+    assert!(content_files(&dir).is_empty());
+    assert!(files_below(dir.path().join("partial-v1")).is_empty());
+    assert!(files_below(dir.path().join("index-v1")).is_empty());
+    // Even the cache's version file and locks are only in memory
+    assert!(files_below(dir.path().to_path_buf()).is_empty());
+    assert!(memory.is_file(&dir.path().join("cache-version")));
+
+    // and so is what looks after the cache
+    assert_eq!(cache::verify_with(&*memory)?.verified, 1);
+    assert_eq!(cache::gc_with(&*memory)?.removed, 0);
+    assert_eq!(cache::stats_with(&*memory)?.content_files, 1);
+    assert!(files_below(dir.path().to_path_buf()).is_empty());
+
+    // It's read back from there, and isn't in the cache on disk
+    let offline = InstallOptions {
+        offline: true,
+        ..options.clone()
+    };
+    assert_eq!(cache::cache("ms", "2.0.0", &url, &config, &offline, &SilentReporter)?, body);
+    assert_eq!(heads.lock().unwrap().len(), 2);
+    let on_disk = InstallOptions {
+        offline: true,
+        ..InstallOptions::default()
+    };
+    let missing = cache::cache("ms", "2.0.0", &url, &config, &on_disk, &SilentReporter);
+    assert!(matches!(missing, Err(NaryError::NotCached { .. })));
+
+    cache::clear_with(&*memory)?;
+    let cleared = cache::cache("ms", "2.0.0", &url, &config, &offline, &SilentReporter);
+    assert!(matches!(cleared, Err(NaryError::NotCached { .. })));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_retry_unavailable_registries() -> Result<()> {
+    let (_guard, _dir) = common::isolated_cache()?;
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc("fetch-retries=3\nfetch-retry-mintimeout=1\nfetch-retry-factor=2\nfetch-retry-maxtimeout=50");
+    assert_eq!(
+        config.fetch,
+        FetchPolicy {
+            retries: 3,
+            min_backoff: Duration::from_millis(1),
+            backoff_factor: 2,
+            max_backoff: Duration::from_millis(50),
+            ..FetchPolicy::default()
+        }
+    );
+    assert_eq!(config.fetch.backoff(2), Duration::from_millis(4));
+    assert_eq!(config.fetch.backoff(10), Duration::from_millis(50));
+
+    let failures = vec![
+        "HTTP/1.1 503 Service Unavailable",
+        "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0",
+    ];
+    let (url, requests) = serve_after(failures, b"tarball".to_vec())?;
+    let tarball = cache::cache("ms", "2.0.0", &url, &config, &InstallOptions::default(), &SilentReporter)?;
+    assert_eq!(tarball, b"tarball");
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+    // Once the retries run out the last answer stands
+    config.fetch.retries = 1;
+    let failures = vec!["HTTP/1.1 500 Internal Server Error"; 2];
+    let (url, requests) = serve_after(failures, b"tarball".to_vec())?;
+    assert!(cache::cache("ms", "2.0.0", &url, &config, &InstallOptions::default(), &SilentReporter).is_err());
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    // Writes are sent once, as the registry may have acted on them before failing
+    let (url, requests) = serve_after(vec!["HTTP/1.1 503 Service Unavailable"], Vec::new())?;
+    config.parse_npmrc(&format!("registry={}", url.as_str().trim_end_matches("/ms/-/ms-2.0.0.tgz")));
+    let registry = HttpRegistry::new(config, InstallOptions::default());
+    assert!(registry.set_dist_tag(&PackageName::parse("ms")?, "next", "2.0.0").is_err());
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_go_through_proxies() -> Result<()> {
+    let (_guard, _dir) = common::isolated_cache()?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let proxy = format!("http://nary:s%40fe@{}", listener.local_addr()?);
+    let heads = Arc::new(Mutex::new(Vec::new()));
+
+    let seen = heads.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            while reader.read_line(&mut head).map(|read| read > 2).unwrap_or(false) {}
+            seen.lock().unwrap().push(head);
+            let mut stream = &stream;
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\ntarball");
+        }
+    });
+
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc(&format!("proxy={}\nnoproxy=.internal.example.com, localhost:8080", proxy));
+    assert_eq!(config.proxy_for("https://registry.npmjs.org/koa"), Some(proxy.as_str()));
+    assert_eq!(config.proxy_for("https://npm.internal.example.com/koa"), None);
+    assert_eq!(config.proxy_for("http://localhost:8080/koa"), None);
+    assert_eq!(config.proxy_for("http://localhost:8081/koa"), Some(proxy.as_str()));
+
+    // Plain http is forwarded with the request's full URL
+    let url = Url::parse("http://registry.invalid/ms/-/ms-2.0.0.tgz")?;
+    let tarball = cache::cache("ms", "2.0.0", &url, &config, &InstallOptions::default(), &SilentReporter)?;
+    assert_eq!(tarball, b"tarball");
+
+    let heads = heads.lock().unwrap();
+    assert_eq!(heads.len(), 1);
+    assert!(heads[0].starts_with("GET http://registry.invalid/ms/-/ms-2.0.0.tgz HTTP/1.1\r\n"));
+    // nary:s@fe
+    assert!(heads[0].contains("Proxy-Authorization: Basic bmFyeTpzQGZl\r\n"));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_fall_back_to_other_registries() -> Result<()> {
+    let (_guard, _dir) = common::isolated_cache()?;
+    let (mirror, mirror_requests) = serve_after(vec!["HTTP/1.1 404 Not Found"; 10], Vec::new())?;
+    let mirror = mirror.as_str().trim_end_matches("/ms/-/ms-2.0.0.tgz").to_string();
+    let packument = format!(
+        r#"{{"name": "ms", "dist-tags": {{"latest": "2.0.0"}}, "versions": {{
+            "2.0.0": {{"name": "ms", "version": "2.0.0", "dist": {{"tarball": "{}/ms/-/ms-2.0.0.tgz"}}}}}}}}"#,
+        mirror
+    );
+    let (public, _) = serve(packument.into_bytes())?;
+    let public = public.as_str().trim_end_matches("/ms/-/ms-2.0.0.tgz").to_string();
+
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc(&format!("registry={}/\nfallback-registries={}", mirror, public));
+    config.fetch.retries = 0;
+    let ms = PackageName::parse("ms")?;
+    assert_eq!(config.registries_for(&ms), vec![mirror.as_str(), public.as_str()]);
+    config.parse_npmrc("@private:registry=https://npm.private.example.com");
+    let private = PackageName::parse("@private/ms")?;
+    assert_eq!(config.registries_for(&private), vec!["https://npm.private.example.com"]);
+
+    let registry = HttpRegistry::new(config, InstallOptions::default());
+    let root = Dependency::new("app", "1.0.0");
+    let dependencies = [(Dependency::new("ms", "^2.0.0"), DependencyKind::Normal)];
+    let graph = calculate_depends(&root, &dependencies, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+    let node = graph.find("ms").next().unwrap();
+    assert_eq!(graph.registry_of(node), Some(public.as_str()));
+
+    // The tarball is under the mirror, which doesn't have it either
+    let url = Url::parse(&format!("{}/ms/-/ms-2.0.0.tgz", mirror))?;
+    assert!(!registry.tarball(&ms, "2.0.0", &url, &SilentReporter)?.is_empty());
+    let asked = mirror_requests.load(Ordering::SeqCst);
+
+    // Which registry had it is remembered with the cached metadata
+    let offline = InstallOptions {
+        offline: true,
+        ..InstallOptions::default()
+    };
+    let offline = HttpRegistry::new(registry.config.clone(), offline);
+    assert_eq!(offline.packument(&ms)?.registry, Some(public));
+    assert_eq!(mirror_requests.load(Ordering::SeqCst), asked);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_reuse_fresh_packuments_and_memoized_resolutions() -> Result<()> {
+    let (_guard, _dir) = common::isolated_cache()?;
+    let packument = r#"{"name": "ms", "modified": "2021-01-01T00:00:00.000Z", "dist-tags": {"latest": "2.1.0"},
+        "versions": {
+            "2.0.0": {"name": "ms", "version": "2.0.0", "dist": {"tarball": "http://localhost/ms-2.0.0.tgz"}},
+            "2.1.0": {"name": "ms", "version": "2.1.0", "dist": {"tarball": "http://localhost/ms-2.1.0.tgz"}}}}"#;
+    let (url, requests) = serve(packument.as_bytes().to_vec())?;
+    let registry_url = url.as_str().trim_end_matches("/ms/-/ms-2.0.0.tgz").to_string();
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc(&format!("registry={}/", registry_url));
+
+    let root = Dependency::new("app", "1.0.0");
+    let dependencies = [(Dependency::new("ms", "^2.0.0"), DependencyKind::Normal)];
+    let resolve = |options: InstallOptions| {
+        let registry = HttpRegistry::new(config.clone(), options);
+        calculate_depends(&root, &dependencies, &registry, &ResolutionOptions::default(), &SilentReporter)
+    };
+
+    let graph = resolve(InstallOptions::default())?;
+    assert_eq!(graph.node(graph.find("ms").next().unwrap()).unwrap().version, "2.1.0");
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    let memo = cache::read_resolution_memo()?;
+    let key = cache::ResolutionMemo::key(&registry_url, "ms", "^2.0.0", false);
+    assert_eq!(memo.get(&key, "2021-01-01T00:00:00.000Z"), Some("2.1.0"));
+    assert_eq!(memo.get(&key, "2022-01-01T00:00:00.000Z"), None);
+
+    // Nothing has to be asked again while the packument is fresh
+    let again = resolve(InstallOptions::default())?;
+    assert_eq!(again.edges(), graph.edges());
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    let force_refresh = InstallOptions {
+        force_refresh: true,
+        ..InstallOptions::default()
+    };
+    resolve(force_refresh)?;
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_revalidate_cached_packuments() -> Result<()> {
+    let (_guard, _dir) = common::isolated_cache()?;
+    let packument = r#"{"name": "ms", "dist-tags": {"latest": "2.0.0"},
+        "versions": {"2.0.0": {"name": "ms", "version": "2.0.0", "dist": {"tarball": "http://localhost/ms.tgz"}}}}"#;
+    let (url, heads) = serve_revalidating(packument.as_bytes().to_vec())?;
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc(&format!("registry={}/", url.origin().ascii_serialization()));
+    let ms = Dependency::new("ms", "^2.0.0");
+    let options = InstallOptions {
+        freshness: Freshness::PreferOnline,
+        ..InstallOptions::default()
+    };
+
+    let fetched = fetch_package_root_metadata(&ms, &config, &options)?;
+    assert!(!heads.lock().unwrap()[0].contains("If-None-Match"));
+
+    // The validators it came with are sent back, and a 304 reuses what's cached
+    let revalidated = fetch_package_root_metadata(&ms, &config, &options)?;
+    let heads = heads.lock().unwrap().clone();
+    assert_eq!(heads.len(), 2);
+    assert!(heads[1].contains("If-None-Match: \"v1\"\r\n"), "{}", heads[1]);
+    assert!(heads[1].contains("If-Modified-Since: Wed, 21 Oct 2015 07:28:00 GMT\r\n"), "{}", heads[1]);
+    assert_eq!(revalidated.versions.keys().collect::<Vec<_>>(), vec!["2.0.0"]);
+    assert_eq!(revalidated.dist_tags, fetched.dist_tags);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_install_offline_from_the_cache() -> Result<()> {
+    let (_guard, _dir) = common::isolated_cache()?;
+    let manifest = r#"{"name": "ms", "version": "2.0.0"}"#;
+    let tarball = tarball_of(manifest)?;
+    let (origin, requests) = serve_routes(move |origin, path| {
+        if path.ends_with(".tgz") {
+            return tarball.clone();
+        }
+        if path == "/ms/2.0.0" {
+            return br#"{"name": "ms", "version": "2.0.0", "description": "Tiny ms conversion utility"}"#.to_vec();
+        }
+        format!(
+            r#"{{"name": "ms", "dist-tags": {{"latest": "2.0.0"}}, "versions": {{"2.0.0": {{"name": "ms",
+                "version": "2.0.0", "dist": {{"tarball": "{}/ms/-/ms-2.0.0.tgz"}}}}}}}}"#,
+            origin
+        )
+        .into_bytes()
+    })?;
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc(&format!("registry={}/", origin));
+
+    let install = |name: &str, options: InstallOptions| -> Result<String> {
+        let dependency = Dependency::new(name.to_string(), "^2.0.0".to_string());
+        let registry = HttpRegistry::new(config.clone(), options.clone());
+        let node_modules = tempfile::tempdir()?;
+        install_dep(node_modules.path(), &dependency, DependencyKind::Normal, &registry, &options, &SilentReporter)?;
+        Ok(fs::read_to_string(node_modules.path().join(name).join("package.json"))?)
+    };
+    assert_eq!(install("ms", InstallOptions::default())?, manifest);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    // Both the packument and the tarball come from the cache
+    let offline = InstallOptions {
+        offline: true,
+        ..InstallOptions::default()
+    };
+    let prefer_offline = InstallOptions {
+        prefer_offline: true,
+        ..InstallOptions::default()
+    };
+    assert_eq!(install("ms", offline.clone())?, manifest);
+    assert_eq!(install("ms", prefer_offline)?, manifest);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    // Offline, what isn't cached fails instead of going to the registry
+    let missing = install("debug", offline.clone()).unwrap_err();
+    assert!(matches!(missing.downcast_ref(), Some(NaryError::NotCached { .. })), "{}", missing);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    // The full metadata of a version isn't in the abbreviated packument, so it's only there once it was fetched
+    let ms = PackageName::parse("ms")?;
+    let metadata = |options: InstallOptions| HttpRegistry::new(config.clone(), options).version_metadata(&ms, "2.0.0");
+    assert!(matches!(metadata(offline.clone()), Err(NaryError::NotCached { .. })));
+    assert_eq!(metadata(InstallOptions::default())?["description"], "Tiny ms conversion utility");
+    assert_eq!(metadata(offline)?["description"], "Tiny ms conversion utility");
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_trust_cached_packuments_as_the_freshness_policy_says() -> Result<()> {
+    let (_guard, _dir) = common::isolated_cache()?;
+    let packument = r#"{"name": "ms", "dist-tags": {"latest": "2.0.0"},
+        "versions": {"2.0.0": {"name": "ms", "version": "2.0.0", "dist": {"tarball": "http://localhost/ms.tgz"}}}}"#;
+    let (url, requests) = serve(packument.as_bytes().to_vec())?;
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc(&format!("registry={}/\nmetadata-freshness=max-age=1h", url.origin().ascii_serialization()));
+    assert_eq!(config.freshness, Some(Freshness::MaxAge(Duration::from_secs(60 * 60))));
+
+    let ms = PackageName::parse("ms")?;
+    let fetch = |freshness: Freshness| {
+        let options = InstallOptions {
+            freshness,
+            ..InstallOptions::default()
+        };
+        HttpRegistry::new(config.clone(), options).packument(&ms).map(|_| requests.load(Ordering::SeqCst))
+    };
+
+    assert_eq!(fetch(Freshness::default())?, 1);
+    assert_eq!(fetch(Freshness::MaxAge(Duration::from_secs(60)))?, 1);
+    assert_eq!(fetch(Freshness::PreferOffline)?, 1);
+    assert_eq!(fetch(Freshness::MaxAge(Duration::from_secs(0)))?, 2);
+    assert_eq!(fetch(Freshness::PreferOnline)?, 3);
+
+    // The older flags still overrule it
+    let prefer_offline = InstallOptions {
+        prefer_offline: true,
+        freshness: Freshness::PreferOnline,
+        ..InstallOptions::default()
+    };
+    assert_eq!(prefer_offline.metadata_freshness(), Freshness::PreferOffline);
+
+    assert_eq!(Freshness::parse("prefer-online"), Some(Freshness::PreferOnline));
+    assert_eq!(Freshness::parse("max-age=90"), Some(Freshness::MaxAge(Duration::from_secs(90))));
+    assert_eq!(Freshness::parse("max-age=2d"), Some(Freshness::MaxAge(Duration::from_secs(2 * 24 * 60 * 60))));
+    assert_eq!(Freshness::parse("max-age=soon"), None);
+    assert_eq!(Freshness::parse("max-age=5w"), None);
+    assert_eq!(Freshness::parse("online"), None);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_migrate_the_flat_cache_layout() -> Result<()> {
+    let (_guard, dir) = common::isolated_cache()?;
+    for (name, body) in &[("debug", "debug packument"), ("@types/node", "node packument")] {
+        let package_dir = dir.path().join(name);
+        fs::create_dir_all(&package_dir)?;
+        fs::write(package_dir.join("packument.json"), body)?;
+        fs::write(package_dir.join("packument.validators.json"), r#"{"etag": "\"abc\""}"#)?;
+    }
+    fs::create_dir_all(dir.path().join("@types/not-a-package"))?;
+    assert_eq!(cache::cache_version(dir.path())?, CacheVersion::FLAT);
+
+    let node = PackageName::parse("@types/node")?;
+    assert_eq!(cache::read_cached_packument(&node)?.as_deref(), Some("node packument"));
+    assert_eq!(cache::read_packument_validators(&node)?.etag.as_deref(), Some("\"abc\""));
+    let debug = PackageName::parse("debug")?;
+    assert_eq!(cache::read_cached_packument(&debug)?.as_deref(), Some("debug packument"));
+    assert_eq!(cache::cache_version(dir.path())?, CacheVersion::CURRENT);
+    assert!(!dir.path().join("debug").exists());
+    assert!(!dir.path().join("@types/node").exists());
+    assert!(dir.path().join("@types/not-a-package").exists());
+    assert!(dir.path().join("packuments-v1/%40types%2fnode/packument.json").is_file());
+
+    assert_eq!(cache::entry_name("JSONStream"), "!j!s!o!n!stream");
+    assert_eq!(cache::entry_name("nul"), "nu%6c");
+    assert_eq!(cache::entry_name(".bin"), "%2ebin");
+    let long = format!("@scope/{}", "a".repeat(300));
+    assert!(cache::entry_name(&long).len() <= 100);
+    assert_ne!(cache::entry_name(&long), cache::entry_name(&format!("{}b", long)));
+
+    // A cache a newer nary wrote is left alone
+    let newer = tempfile::tempdir()?;
+    fs::write(newer.path().join("cache-version"), "3\n")?;
+    std::env::set_var(cache::CACHE_DIR_VAR, newer.path());
+    assert!(cache::get_cache_dir().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_reuse_connections() -> Result<()> {
+    let (_guard, _dir) = common::isolated_cache()?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let base = format!("http://{}", listener.local_addr()?);
+    let connections = Arc::new(AtomicUsize::new(0));
+    let requests = Arc::new(AtomicUsize::new(0));
+
+    let (connected, requested) = (connections.clone(), requests.clone());
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            connected.fetch_add(1, Ordering::SeqCst);
+            let requested = requested.clone();
+            // Answers every request on the connection, keeping it open
+            std::thread::spawn(move || {
+                let mut reader = BufReader::new(&stream);
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        return;
+                    }
+                    while reader.read_line(&mut line).map(|read| read > 2).unwrap_or(false) {}
+                    requested.fetch_add(1, Ordering::SeqCst);
+                    let mut stream = &stream;
+                    let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\ntarball");
+                }
+            });
+        }
+    });
+
+    let mut config = RegistryConfig::default();
+    for version in &["1.0.0", "1.1.0", "1.2.0"] {
+        let url = Url::parse(&format!("{}/ms/-/ms-{}.tgz", base, version))?;
+        cache::cache("ms", version, &url, &config, &InstallOptions::default(), &SilentReporter)?;
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    // Without idle connections each request gets its own
+    config.parse_npmrc("maxsockets=0");
+    assert_eq!(config.fetch.max_idle_connections, 15);
+    config.parse_npmrc("max-idle-connections=0");
+    assert_eq!(config.fetch.max_idle_connections, 0);
+    for version in &["2.0.0", "2.1.0"] {
+        let url = Url::parse(&format!("{}/ms/-/ms-{}.tgz", base, version))?;
+        cache::cache("ms", version, &url, &config, &InstallOptions::default(), &SilentReporter)?;
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 5);
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_change_dist_tags_by_what_the_registry_has_now() -> Result<()> {
+    let (_guard, _dir) = common::isolated_cache()?;
+    let cached = r#"{"name": "ms", "dist-tags": {"latest": "2.0.0"},
+        "versions": {"2.0.0": {"name": "ms", "version": "2.0.0", "dist": {"tarball": "http://x/ms-2.0.0.tgz"}}}}"#;
+    let published = r#"{"name": "ms", "dist-tags": {"latest": "2.0.0", "next": "2.1.0"},
+        "versions": {"2.0.0": {"name": "ms", "version": "2.0.0", "dist": {"tarball": "http://x/ms-2.0.0.tgz"}},
+            "2.1.0": {"name": "ms", "version": "2.1.0", "dist": {"tarball": "http://x/ms-2.1.0.tgz"}}}}"#;
+    // Only the document read for writing has what was published since the packument was cached
+    let (origin, requests) = serve_routes(move |_, path| {
+        if path.ends_with("?write=true") { published } else { cached }.as_bytes().to_vec()
+    })?;
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc(&format!("registry={}/\nmetadata-freshness=max-age=1h", origin));
+    let registry = HttpRegistry::new(config, InstallOptions::default());
+    let ms = PackageName::parse("ms")?;
+    assert!(!registry.packument(&ms)?.versions.contains_key("2.1.0"));
+
+    dist_tags::add("ms", "2.1.0", "beta", &registry)?;
+    assert_eq!(dist_tags::rm("ms", "next", &registry)?.as_deref(), Some("2.1.0"));
+    assert_eq!(requests.load(Ordering::SeqCst), 5);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_read_publish_times_from_the_full_document() -> Result<()> {
+    let (_guard, _dir) = common::isolated_cache()?;
+    let versions = r#""1.0.0": {"name": "ms", "version": "1.0.0", "dist": {"tarball": "http://x/ms-1.0.0.tgz"}},
+        "2.0.0": {"name": "ms", "version": "2.0.0", "dist": {"tarball": "http://x/ms-2.0.0.tgz"}}"#;
+    let corgi = format!(r#"{{"name": "ms", "dist-tags": {{"latest": "2.0.0"}}, "versions": {{{}}}}}"#, versions);
+    let full = format!(
+        r#"{{"name": "ms", "dist-tags": {{"latest": "2.0.0"}}, "versions": {{{}}},
+            "time": {{"1.0.0": "2016-05-01T00:00:00.000Z", "2.0.0": "2017-05-16T00:00:00.000Z"}}}}"#,
+        versions
+    );
+    // Registries only say when versions were published in the full document
+    let (origin, _) = serve_routes(move |_, path| match path {
+        "/ms?write=true" => full.clone().into_bytes(),
+        "/ms/2.0.0" => br#"{"name": "ms", "version": "2.0.0"}"#.to_vec(),
+        _ => corgi.clone().into_bytes(),
+    })?;
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc(&format!("registry={}/", origin));
+    let registry = HttpRegistry::new(config, InstallOptions::default());
+    assert!(registry.packument(&PackageName::parse("ms")?)?.time.is_empty());
+
+    // So an unpublish out of the window is refused before it's confirmed
+    assert!(matches!(unpublish("ms@1.0.0", &registry), Err(NaryError::UnpublishWindow { .. })));
+    match info("ms", Some("time"), &registry, &ResolutionOptions::default())? {
+        Info::Field(time) => assert_eq!(time.unwrap()["1.0.0"], "2016-05-01T00:00:00.000Z"),
+        other => panic!("Expected a field, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn it_will_explain_refused_registry_writes() -> Result<()> {
+    let document = r#"{"_rev": "3-abc", "name": "ms", "dist-tags": {"latest": "2.0.0"},
+        "time": {"1.0.0": "2016-05-01T00:00:00.000Z", "2.0.0": "2017-05-16T00:00:00.000Z"},
+        "versions": {"1.0.0": {"name": "ms", "version": "1.0.0", "dist": {"tarball": "http://x/ms-1.0.0.tgz"}},
+            "2.0.0": {"name": "ms", "version": "2.0.0", "dist": {"tarball": "http://x/ms-2.0.0.tgz"}}}}"#;
+    let registry_for = |url: Url| {
+        let mut config = RegistryConfig::default();
+        config.parse_npmrc(&format!("registry={}", url.as_str().trim_end_matches("/ms/-/ms-2.0.0.tgz")));
+        config.fetch.retries = 0;
+        HttpRegistry::new(config, InstallOptions::default())
+    };
+    let ms = PackageName::parse("ms")?;
+
+    // The document is read and put back with the deprecation
+    let (url, requests) = serve(document.as_bytes().to_vec())?;
+    registry_for(url).deprecate(&ms, &["1.0.0".to_string()], "too old")?;
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    let (url, _) = serve_after(vec!["HTTP/1.1 401 Unauthorized"], document.as_bytes().to_vec())?;
+    match registry_for(url).deprecate(&ms, &["1.0.0".to_string()], "too old") {
+        Err(NaryError::RegistryRefused { action, reason, .. }) => {
+            assert_eq!(action, "deprecate");
+            assert!(reason.contains("log in"));
+        }
+        other => panic!("Expected RegistryRefused, got {:?}", other),
+    }
+
+    let (url, _) = serve_after(vec!["HTTP/1.1 403 Forbidden"], document.as_bytes().to_vec())?;
+    match registry_for(url).unpublish(&ms, None) {
+        Err(NaryError::RegistryRefused { action, reason, .. }) => {
+            assert_eq!(action, "unpublish");
+            assert!(reason.contains("maintain"));
+        }
+        other => panic!("Expected RegistryRefused, got {:?}", other),
+    }
+
+    // The full document says when the version was published, so it's refused before anything is written
+    let (url, requests) = serve(document.as_bytes().to_vec())?;
+    assert!(matches!(registry_for(url).unpublish(&ms, Some("2.0.0")), Err(NaryError::UnpublishWindow { .. })));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+/// Records the warnings
+#[derive(Default)]
+struct WarningReporter(Mutex<Vec<String>>);
+
+impl InstallReporter for WarningReporter {
+    fn on_warning(&self, message: &str) {
+        self.0.lock().unwrap().push(message.to_string());
+    }
+}
+
+#[test]
+fn it_will_recover_from_corrupt_cached_tarballs() -> Result<()> {
+    let (_guard, dir) = common::isolated_cache()?;
+    let manifest = r#"{"name": "ms", "version": "2.0.0"}"#;
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, "package/package.json", manifest.as_bytes())?;
+    let tarball = builder.into_inner()?.finish()?;
+
+    // What's cached doesn't unpack, so it's dropped and downloaded again
+    let (url, requests) = serve(tarball.clone())?;
+    let ms = Dependency::new("ms", url.to_string());
+    let key = cache::tarball_key("ms", url.as_str(), &url);
+    cache::write_index(&key, &cache::write_content(b"truncated")?, 9)?;
+    let registry = HttpRegistry::new(RegistryConfig::default(), InstallOptions::default());
+    let node_modules = tempfile::tempdir()?;
+    let reporter = WarningReporter::default();
+    install_dep(node_modules.path(), &ms, DependencyKind::Normal, &registry, &InstallOptions::default(), &reporter)?;
+    assert_eq!(fs::read_to_string(node_modules.path().join("ms/package.json"))?, manifest);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    assert_eq!(cache::read_index(&key)?.unwrap().integrity, cache::integrity_of(&tarball));
+    let warnings = reporter.0.lock().unwrap().clone();
+    assert!(warnings[0].starts_with("The cached tarball of ms@http://"), "{:?}", warnings);
+
+    // One cut off between entries fails too, and what it did unpack is gone once it's downloaded again
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, contents) in &[("package/stale.js", ""), ("package/package.json", manifest)] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, contents.as_bytes())?;
+    }
+    let mut cut = builder.into_inner()?;
+    cut.truncate(512 + 100);
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&cut)?;
+    let (url, requests) = serve(tarball.clone())?;
+    let ms = Dependency::new("ms", url.to_string());
+    let key = cache::tarball_key("ms", url.as_str(), &url);
+    cache::write_index(&key, &cache::write_content(&encoder.finish()?)?, 0)?;
+    let node_modules = tempfile::tempdir()?;
+    install_dep(node_modules.path(), &ms, DependencyKind::Normal, &registry, &InstallOptions::default(), &reporter)?;
+    assert_eq!(fs::read_to_string(node_modules.path().join("ms/package.json"))?, manifest);
+    assert!(!node_modules.path().join("ms/stale.js").exists());
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // One that's corrupt downloaded again too is quarantined with why
+    let (url, requests) = serve(b"truncated".to_vec())?;
+    let ms = Dependency::new("ms", url.to_string());
+    let key = cache::tarball_key("ms", url.as_str(), &url);
+    cache::write_index(&key, &cache::write_content(b"truncated")?, 9)?;
+    let options = InstallOptions::default();
+    let installed = install_dep(node_modules.path(), &ms, DependencyKind::Normal, &registry, &options, &reporter);
+    let quarantined = match installed {
+        Err(NaryError::CorruptTarball { quarantined, .. }) => quarantined,
+        other => panic!("Expected CorruptTarball, got {:?}", other),
+    };
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    assert!(quarantined.starts_with(dir.path().join("quarantine-v1")));
+    assert_eq!(fs::read(quarantined.with_extension(""))?, b"truncated");
+    let diagnostics: cache::Quarantined = serde_json::from_slice(&fs::read(&quarantined)?)?;
+    assert_eq!((diagnostics.key, diagnostics.reason.as_str()), (key.clone(), "isn't gzipped"));
+    assert_eq!(cache::read_index(&key)?, None);
+
+    Ok(())
+}
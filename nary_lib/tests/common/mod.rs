@@ -0,0 +1,17 @@
+use nary_lib::cache;
+
+use std::sync::{Mutex, MutexGuard};
+use tempfile::TempDir;
+
+use anyhow::Result;
+
+static CACHE_DIR: Mutex<()> = Mutex::new(());
+
+/// Points the cache at a fresh directory for as long as the guard is held. Every test that can reach the cache takes
+/// one first, so none writes into the real one or changes where another's goes while it runs.
+pub fn isolated_cache() -> Result<(MutexGuard<'static, ()>, TempDir)> {
+    let guard = CACHE_DIR.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let dir = tempfile::tempdir()?;
+    std::env::set_var(cache::CACHE_DIR_VAR, dir.path());
+    Ok((guard, dir))
+}
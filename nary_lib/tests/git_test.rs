@@ -0,0 +1,133 @@
+use nary_lib::{
+    calculate_depends, install_dep, Dependency, DependencyKind, GitReference, GitSpec, InstallOptions, MemoryRegistry, ResolutionOptions,
+    SilentReporter,
+};
+
+use git2::{Repository, Signature};
+use std::{fs, path::Path};
+
+use anyhow::Result;
+
+mod common;
+
+/// Commits `package.json` at `version` and tags it `v<version>`
+fn commit_version(repo: &Repository, version: &str) -> Result<()> {
+    let workdir = repo.workdir().unwrap();
+    fs::write(
+        workdir.join("package.json"),
+        format!(
+            r#"{{"name": "widget", "version": "{}", "dependencies": {{"ms": "2.0.0"}}}}"#,
+            version
+        ),
+    )?;
+
+    let mut index = repo.index()?;
+    index.add_path(Path::new("package.json"))?;
+    index.write()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+
+    let signature = Signature::now("nary", "nary@example.com")?;
+    let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    let parents: Vec<&git2::Commit> = parent.iter().collect();
+    let commit = repo.commit(Some("HEAD"), &signature, &signature, version, &tree, &parents)?;
+
+    repo.tag_lightweight(&format!("v{}", version), &repo.find_object(commit, None)?, false)?;
+    Ok(())
+}
+
+#[test]
+fn it_will_parse_git_specifiers() {
+    let (_guard, _cache) = common::isolated_cache().unwrap();
+    let parse = |spec| GitSpec::parse(spec).unwrap();
+
+    assert_eq!(parse("expressjs/express").url, "https://github.com/expressjs/express.git");
+    assert_eq!(parse("expressjs/express").reference, GitReference::Head);
+
+    let tagged = parse("github:expressjs/express#4.17.1");
+    assert_eq!(tagged.url, "https://github.com/expressjs/express.git");
+    assert_eq!(tagged.reference, GitReference::Committish("4.17.1".to_string()));
+
+    assert_eq!(
+        parse("gitlab:user/repo#semver:^1.2.0").reference,
+        GitReference::Semver("^1.2.0".to_string())
+    );
+    assert_eq!(parse("git+https://example.com/repo.git").url, "https://example.com/repo.git");
+    assert_eq!(parse("git+ssh://git@github.com/user/repo.git").url, "ssh://git@github.com/user/repo.git");
+    assert_eq!(parse("git+ssh://git@github.com:user/repo.git").url, "git@github.com:user/repo.git");
+    assert_eq!(parse("git://github.com/user/repo.git#abc123").url, "git://github.com/user/repo.git");
+
+    assert!(GitSpec::parse("^1.2.0").is_none());
+    assert!(GitSpec::parse("@babel/core").is_none());
+    assert!(GitSpec::parse("file:../sibling").is_none());
+    assert!(GitSpec::parse("https://example.com/widget.tgz").is_none());
+}
+
+#[test]
+fn it_will_check_out_git_tags_and_ranges() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let origin = tempfile::tempdir()?;
+    let repo = Repository::init(origin.path())?;
+    for version in &["1.0.0", "1.1.0", "2.0.0"] {
+        commit_version(&repo, version)?;
+    }
+
+    let node_modules = tempfile::tempdir()?;
+    let url = format!("git+file://{}", origin.path().display());
+    let installed_version = |dependency: &str| -> Result<String> {
+        let manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(node_modules.path().join(dependency).join("package.json"))?)?;
+        Ok(manifest["version"].as_str().unwrap().to_string())
+    };
+
+    for (dependency, fragment, expected) in &[
+        ("head", "", "2.0.0"),
+        ("tag", "#v1.0.0", "1.0.0"),
+        ("range", "#semver:^1.0.0", "1.1.0"),
+    ] {
+        let dep = Dependency::new(dependency.to_string(), format!("{}{}", url, fragment));
+        install_dep(
+            node_modules.path(),
+            &dep,
+            DependencyKind::Normal,
+            &MemoryRegistry::new(),
+            &InstallOptions::default(),
+            &SilentReporter,
+        )?;
+        assert_eq!(installed_version(dependency)?, *expected);
+    }
+
+    // Installing again replaces the previous checkout
+    install_dep(
+        node_modules.path(),
+        &Dependency::new("tag", format!("{}#v2.0.0", url)),
+        DependencyKind::Normal,
+        &MemoryRegistry::new(),
+        &InstallOptions::default(),
+        &SilentReporter,
+    )?;
+    assert_eq!(installed_version("tag")?, "2.0.0");
+    assert!(!node_modules.path().join("tag").join(".git").exists());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_resolve_git_dependencies_of_git_packages() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let origin = tempfile::tempdir()?;
+    let repo = Repository::init(origin.path())?;
+    commit_version(&repo, "1.0.0")?;
+
+    let registry = MemoryRegistry::new();
+    registry.add_manifest(&serde_json::from_str(r#"{"name": "ms", "version": "2.0.0"}"#)?, Vec::new())?;
+
+    let root = Dependency::new("app", "1.0.0");
+    let url = format!("git+file://{}#semver:^1.0.0", origin.path().display());
+    let widget = (Dependency::new("widget", url), DependencyKind::Normal);
+    let graph = calculate_depends(&root, &[widget], &registry, &ResolutionOptions::default(), &SilentReporter)?;
+
+    let installed: Vec<&str> = graph.install_order().map(|node| node.name.as_str()).collect();
+    assert_eq!(installed, vec!["ms", "widget"]);
+
+    Ok(())
+}
@@ -1,12 +1,29 @@
 use nary_lib::deps::*;
+use nary_lib::graph::export;
+use nary_lib::manifest::{init, InitOptions};
+use nary_lib::{
+    add, add_dependency, audit, check_peers, deprecate, dist_tags, fetch_matching_version_metadata, find_workspace,
+    info, install_dep, outdated, parse_search, project_dependencies, read_lockfile, read_overrides, remove_dependency,
+    search, unpublish, Advisory, Credentials, Dedupe, Engines, GitSpec, Info, InstallOptions, InstallReporter, Lockfile,
+    Manifest, MemoryRegistry, MergedVersion, NaryError, OutdatedDependency, PackageName, Packument, Platform,
+    RegistryClient, RegistryConfig, ResolutionOptions, ResolutionStrategy, SearchOptions, Severity, SilentReporter,
+    Specifier, UpdatedPackage,
+};
 
 use indoc::indoc;
-use std::io::{Cursor};
+use std::{
+    fs,
+    io::Cursor,
+    sync::Mutex,
+};
 
 use anyhow::{Result};
 
+mod common;
+
 #[test]
 fn it_will_get_dependency_version() {
+    let (_guard, _cache) = common::isolated_cache().unwrap();
     let package_json = indoc! {r###"
         {
             "private": true,
@@ -29,7 +46,7 @@ fn it_will_get_dependency_version() {
     let dependencies = json_to_dependencies(cursor);
 
     let dependencies = dependencies.unwrap();
-    let dep = dependencies.get(0).unwrap();
+    let (dep, _) = dependencies.first().unwrap();
 
     assert_eq!(dep.version, "^4.1.0");
 
@@ -37,38 +54,1684 @@ fn it_will_get_dependency_version() {
 
 #[test]
 fn it_will_gather_dependencies() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
     let koa_ejs = include_str!("repository/koa-ejs.json");
     let cursor = Cursor::new(koa_ejs);
     let dependencies = json_to_dependencies(cursor);
 
     let dependencies = dependencies?;
-    assert_eq!(dependencies.get(0).unwrap().name, "debug");
-    assert_eq!(dependencies.get(1).unwrap().name, "ejs");
-    assert_eq!(dependencies.get(2).unwrap().name, "mz");
+    assert_eq!(dependencies.first().unwrap().0.name, "debug");
+    assert_eq!(dependencies.get(1).unwrap().0.name, "ejs");
+    assert_eq!(dependencies.get(2).unwrap().0.name, "mz");
 
     Ok(())
 }
 
 #[test]
 fn it_will_build_dependency_map() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
     let koa_ejs = Cursor::new(include_str!("repository/koa-ejs.json"));
     let dependencies = json_to_dependencies(koa_ejs);
 
     let dependencies = dependencies?;
-    assert_eq!(dependencies.get(0).unwrap().name, "debug");
-    assert_eq!(dependencies.get(1).unwrap().name, "ejs");
-    assert_eq!(dependencies.get(2).unwrap().name, "mz");
+    assert_eq!(dependencies.first().unwrap().0.name, "debug");
+    assert_eq!(dependencies.get(1).unwrap().0.name, "ejs");
+    assert_eq!(dependencies.get(2).unwrap().0.name, "mz");
+
+    let root = Dependency::new("koa_ejs", "1");
+
+    let registry = fixture_registry()?;
+    let calculated = calculate_depends(&root, &dependencies, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+
+    let resolved: Vec<&str> = calculated.install_order().map(|node| node.name.as_str()).collect();
+    for expected in &[
+        "ms",
+        "debug",
+        "ejs",
+        "any-promise",
+        "object-assign",
+        "thenify",
+        "thenify-all",
+        "mz",
+    ] {
+        assert!(resolved.contains(expected), "{} missing from {:?}", expected, resolved);
+    }
+
+    let position = |name: &str| resolved.iter().position(|dep| *dep == name).unwrap();
+    assert!(position("ms") < position("debug"));
+    assert!(position("thenify") < position("thenify-all"));
+    assert!(position("thenify-all") < position("mz"));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_order_cyclic_dependencies() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "a", "version": "1.0.0", "dependencies": {"b": "^1.0.0"}}"#,
+        r#"{"name": "b", "version": "1.0.0", "dependencies": {"a": "^1.0.0", "leaf": "^1.0.0"}}"#,
+        r#"{"name": "leaf", "version": "1.0.0"}"#,
+    ] {
+        registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+    }
+
+    let root = Dependency::new("root", "1.0.0");
+    let dependencies = vec![(Dependency::new("a", "^1.0.0"), DependencyKind::Normal)];
+
+    let calculated = calculate_depends(&root, &dependencies, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+    let resolved: Vec<&str> = calculated.install_order().map(|node| node.name.as_str()).collect();
+
+    assert_eq!(resolved.len(), 3);
+    assert_eq!(resolved[0], "leaf");
+    assert_eq!(calculated.root().name, "root");
+
+    Ok(())
+}
+
+#[test]
+fn it_will_query_resolved_graph() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let koa_ejs = Cursor::new(include_str!("repository/koa-ejs.json"));
+    let dependencies = json_to_dependencies(koa_ejs)?;
+    let root = Dependency::new("koa-ejs", "4.3.0");
+
+    let graph = calculate_depends(&root, &dependencies, &fixture_registry()?, &ResolutionOptions::default(), &SilentReporter)?;
+
+    assert_eq!(graph.versions_of("debug"), vec!["2.6.9"]);
+    // mz and thenify both ask for any-promise, under the same range
+    assert_eq!(graph.versions_of("any-promise"), vec!["1.3.0"]);
+
+    let dependents: Vec<&str> = graph
+        .direct_dependents("any-promise")
+        .iter()
+        .map(|node| node.name.as_str())
+        .collect();
+    assert_eq!(dependents, vec!["mz", "thenify"]);
+
+    let thenify = graph.find("thenify").next().unwrap();
+    let ranges: Vec<&str> = graph.dependents(thenify).map(|edge| edge.range.as_str()).collect();
+    assert_eq!(ranges, vec![">= 3.1.0 < 4"]);
+
+    let paths = graph.paths_to(thenify);
+    assert_eq!(paths.len(), 1);
+    let names: Vec<&str> = paths[0]
+        .iter()
+        .map(|id| graph.node(*id).unwrap().name.as_str())
+        .collect();
+    assert_eq!(names, vec!["koa-ejs", "mz", "thenify-all", "thenify"]);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_explain_why_a_package_is_installed() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let koa_ejs = Cursor::new(include_str!("repository/koa-ejs.json"));
+    let dependencies = json_to_dependencies(koa_ejs)?;
+    let root = Dependency::new("koa-ejs", "4.3.0");
+
+    let graph = calculate_depends(&root, &dependencies, &fixture_registry()?, &ResolutionOptions::default(), &SilentReporter)?;
+
+    let chains: Vec<String> = graph.why("any-promise").iter().map(|chain| chain.to_string()).collect();
+    assert_eq!(chains.len(), 2);
+    assert!(chains.contains(&"mz@2.7.0 (^2.6.0) > any-promise@1.3.0 (^1.0.0)".to_string()));
+    assert!(chains.contains(
+        &"mz@2.7.0 (^2.6.0) > thenify-all@1.6.0 (^1.0.0) > thenify@3.3.1 (>= 3.1.0 < 4) > any-promise@1.3.0 (^1.0.0)"
+            .to_string()
+    ));
+
+    assert_eq!(graph.why("debug")[0].links.len(), 1);
+    assert!(graph.why("left-pad").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_export_resolved_graph() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let koa_ejs = Cursor::new(include_str!("repository/koa-ejs.json"));
+    let dependencies = json_to_dependencies(koa_ejs)?;
+    let root = Dependency::new("koa-ejs", "4.3.0");
+
+    let graph = calculate_depends(&root, &dependencies, &fixture_registry()?, &ResolutionOptions::default(), &SilentReporter)?;
+
+    let dot = export::to_dot(&graph);
+    assert!(dot.starts_with("digraph dependencies {"));
+    assert!(dot.contains("[label=\"thenify@3.3.1\"]"));
+    assert!(dot.contains("[label=\">= 3.1.0 < 4\"]"));
+    assert_eq!(dot.matches(" -> ").count(), graph.edges().len());
+
+    let tree = export::to_json(&graph);
+    assert_eq!(tree["name"], "koa-ejs");
+    assert_eq!(tree["dependencies"]["debug"]["version"], "2.6.9");
+    assert_eq!(tree["dependencies"]["debug"]["dependencies"]["ms"]["version"], "2.0.0");
+
+    let mz = &tree["dependencies"]["mz"]["dependencies"];
+    let thenify = &mz["thenify-all"]["dependencies"]["thenify"]["dependencies"];
+    // any-promise shows up under both mz and thenify, but is only expanded once
+    assert_eq!(
+        [&mz["any-promise"]["deduped"], &thenify["any-promise"]["deduped"]]
+            .iter()
+            .filter(|deduped| deduped.as_bool() == Some(true))
+            .count(),
+        1
+    );
+
+    Ok(())
+}
+
+#[test]
+fn it_will_resolve_and_link_file_dependencies() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let workspace = tempfile::tempdir()?;
+    let project = workspace.path().join("project");
+    let sibling = workspace.path().join("sibling");
+    fs::create_dir_all(&project)?;
+    fs::create_dir_all(&sibling)?;
+    fs::write(
+        project.join("package.json"),
+        r#"{"name": "project", "version": "1.0.0", "dependencies": {"sibling": "file:../sibling"}}"#,
+    )?;
+    fs::write(
+        sibling.join("package.json"),
+        r#"{"name": "sibling", "version": "0.1.0", "dependencies": {"ms": "2.0.0"}}"#,
+    )?;
+
+    let root = path_to_root_dependency(&project)?;
+    let dependencies = path_to_dependencies(&project)?;
+    let graph = calculate_depends(&root, &dependencies, &fixture_registry()?, &ResolutionOptions::default(), &SilentReporter)?;
+
+    let installed: Vec<&str> = graph.install_order().map(|node| node.name.as_str()).collect();
+    assert_eq!(installed, vec!["ms", "sibling"]);
+
+    let node = graph.install_order().find(|node| node.name == "sibling").unwrap();
+    let node_modules = project.join("node_modules");
+    let registry = fixture_registry()?;
+    install_dep(
+        &node_modules,
+        &node.dependency(),
+        DependencyKind::Normal,
+        &registry,
+        &InstallOptions::default(),
+        &SilentReporter,
+    )?;
+
+    let link = node_modules.join("sibling");
+    assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+    assert_eq!(fs::canonicalize(&link)?, fs::canonicalize(&sibling)?);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_resolve_workspaces() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+    fs::write(
+        root.join("package.json"),
+        r#"{"name": "mono", "version": "1.0.0", "workspaces": ["packages/*", "!packages/scratch"]}"#,
+    )?;
+    for (name, manifest) in &[
+        ("a", r#"{"name": "a", "version": "1.0.0", "dependencies": {"b": "^0.2.0", "ms": "2.0.0"}}"#),
+        ("b", r#"{"name": "b", "version": "0.2.1"}"#),
+        ("scratch", r#"{"name": "scratch", "version": "0.0.0"}"#),
+    ] {
+        fs::create_dir_all(root.join("packages").join(name))?;
+        fs::write(root.join("packages").join(name).join("package.json"), manifest)?;
+    }
+
+    let workspace = find_workspace(root)?.unwrap();
+    let members: Vec<&str> = workspace.members.iter().map(|member| member.name.as_str()).collect();
+    assert_eq!(members, vec!["a", "b"]);
+    assert_eq!(find_workspace(&root.join("packages/a"))?, None);
+
+    let options = ResolutionOptions {
+        workspace: workspace.members.clone(),
+        ..ResolutionOptions::default()
+    };
+    let root_dependency = path_to_root_dependency(root)?;
+    let dependencies = project_dependencies(root)?;
+    let graph = calculate_depends(&root_dependency, &dependencies, &fixture_registry()?, &options, &SilentReporter)?;
+
+    let b = graph.install_order().find(|node| node.name == "b").unwrap();
+    assert_eq!(b.version, workspace.member("b").unwrap().dependency().version);
+    assert_eq!(graph.install_order().filter(|node| node.name == "b").count(), 1);
+    assert!(graph.install_order().any(|node| node.name == "ms" && node.version == "2.0.0"));
+
+    // Out of range, the registry is asked instead
+    assert!(!workspace.member("b").unwrap().satisfies("^1.0.0"));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_resolve_and_publish_workspace_specifiers() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+    fs::write(root.join("package.json"), r#"{"name": "mono", "workspaces": ["packages/*"]}"#)?;
+    let a = r#"{
+        "name": "a",
+        "version": "1.0.0",
+        "dependencies": {"b": "workspace:^", "c": "workspace:*", "ms": "2.0.0"},
+        "peerDependencies": {"c": "workspace:~"},
+        "optionalDependencies": {"b": "workspace:>=0.2.0"}
+    }"#;
+    for (name, manifest) in &[
+        ("a", a),
+        ("b", r#"{"name": "b", "version": "0.2.1"}"#),
+        ("c", r#"{"name": "c", "version": "3.0.0", "dependencies": {"b": "workspace:0.2.x"}}"#),
+    ] {
+        fs::create_dir_all(root.join("packages").join(name))?;
+        fs::write(root.join("packages").join(name).join("package.json"), manifest)?;
+    }
+
+    let workspace = find_workspace(root)?.unwrap();
+    let options = ResolutionOptions {
+        workspace: workspace.members.clone(),
+        ..ResolutionOptions::default()
+    };
+    let root_dependency = path_to_root_dependency(root)?;
+    let graph = calculate_depends(&root_dependency, &project_dependencies(root)?, &fixture_registry()?, &options,
+        &SilentReporter)?;
+    for name in &["b", "c"] {
+        let node = graph.install_order().find(|node| node.name == *name).unwrap();
+        assert_eq!(node.version, workspace.member(name).unwrap().dependency().version);
+    }
+
+    let published = workspace.publish_manifest(&serde_json::from_str(a)?)?;
+    assert_eq!(published["dependencies"]["b"], "^0.2.1");
+    assert_eq!(published["dependencies"]["c"], "3.0.0");
+    assert_eq!(published["dependencies"]["ms"], "2.0.0");
+    assert_eq!(published["peerDependencies"]["c"], "~3.0.0");
+    assert_eq!(published["optionalDependencies"]["b"], ">=0.2.0");
+
+    // Nothing but a workspace package will do
+    let missing = serde_json::json!({"dependencies": {"d": "workspace:*"}});
+    assert!(matches!(workspace.publish_manifest(&missing), Err(NaryError::NotInWorkspace { .. })));
+    let outside = vec![(Dependency::new("b", "workspace:^"), DependencyKind::Normal)];
+    let result = calculate_depends(&root_dependency, &outside, &fixture_registry()?, &ResolutionOptions::default(),
+        &SilentReporter);
+    assert!(matches!(result, Err(NaryError::NotInWorkspace { .. })));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_apply_overrides() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "a", "version": "1.0.0", "dependencies": {"leaf": "^1.0.0"}}"#,
+        r#"{"name": "b", "version": "1.0.0", "dependencies": {"leaf": "^1.0.0", "a": "1.0.0"}}"#,
+        r#"{"name": "leaf", "version": "1.0.0"}"#,
+        r#"{"name": "leaf", "version": "1.1.0"}"#,
+        r#"{"name": "leaf", "version": "2.0.0"}"#,
+    ] {
+        registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+    }
+
+    let dir = tempfile::tempdir()?;
+    fs::write(
+        dir.path().join("package.json"),
+        r#"{
+            "name": "root",
+            "version": "1.0.0",
+            "dependencies": {"a": "^1.0.0", "b": "^1.0.0"},
+            "overrides": {"leaf@^1": "1.0.0", "b": {".": "1.0.0", "leaf": "2.0.0"}}
+        }"#,
+    )?;
+    let overrides = read_overrides(dir.path())?;
+    let keys: Vec<String> = overrides.iter().map(|each| each.to_string()).collect();
+    assert_eq!(keys, vec!["b", "b>leaf", "leaf@^1"]);
+
+    let options = ResolutionOptions {
+        overrides,
+        ..ResolutionOptions::default()
+    };
+    let root = path_to_root_dependency(dir.path())?;
+    let graph = calculate_depends(&root, &path_to_dependencies(dir.path())?, &registry, &options, &SilentReporter)?;
+    let tree = export::to_json(&graph);
+    assert_eq!(tree["dependencies"]["a"]["dependencies"]["leaf"]["version"], "1.0.0");
+    assert_eq!(tree["dependencies"]["b"]["dependencies"]["leaf"]["version"], "2.0.0");
+    // The edge keeps the range that was asked for
+    let leaf = graph.find("leaf").find(|id| graph.node(*id).unwrap().version == "2.0.0").unwrap();
+    assert!(graph.dependents(leaf).all(|edge| edge.range == "^1.0.0"));
+
+    let mut lockfile = Lockfile::from_graph(&graph, &registry)?;
+    lockfile.record_overrides(&options.overrides);
+    assert_eq!(lockfile.overrides["b>leaf"], "2.0.0");
+    assert!(lockfile.check_overrides(&options.overrides).is_ok());
+    assert!(matches!(lockfile.check_overrides(&[]), Err(NaryError::LockfileOutOfSync { .. })));
+
+    // yarn's resolutions are paths of names
+    fs::write(
+        dir.path().join("package.json"),
+        r#"{"resolutions": {"**/leaf": "1.1.0", "@scope/parent/**/@scope/child": "3.0.0", "bad": 1}}"#,
+    )?;
+    assert!(matches!(read_overrides(dir.path()), Err(NaryError::InvalidOverride { .. })));
+    fs::write(
+        dir.path().join("package.json"),
+        r#"{"resolutions": {"**/leaf": "1.1.0", "@scope/parent/**/@scope/child": "3.0.0"}}"#,
+    )?;
+    let overrides = read_overrides(dir.path())?;
+    assert_eq!(overrides[0].to_string(), "leaf");
+    assert_eq!(overrides[1].to_string(), "@scope/parent>@scope/child");
+
+    Ok(())
+}
+
+#[test]
+fn it_will_add_and_remove_dependencies() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "left-pad", "version": "1.2.0"}"#,
+        r#"{"name": "left-pad", "version": "1.3.0"}"#,
+        r#"{"name": "zod", "version": "3.0.0"}"#,
+        r#"{"name": "@types/node", "version": "20.1.0"}"#,
+        r#"{"name": "debug", "version": "2.6.9"}"#,
+    ] {
+        registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+    }
+    registry.add_dist_tag("left-pad", "legacy", "1.2.0");
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("package.json");
+    fs::write(
+        &path,
+        indoc! {r#"
+            {
+                "version": "1.0.0",
+                "name": "app",
+                "dependencies": {
+                    "debug": "^2.6.0",
+                    "ms": "2.0.0"
+                },
+                "optionalDependencies": {
+                    "zod": "^2.0.0"
+                }
+            }
+        "#},
+    )?;
+
+    let options = ResolutionOptions::default();
+    let add = |spec: &str, kind: DependencyKind, exact: bool| {
+        add_dependency(dir.path(), spec, kind, exact, &registry, &options)
+    };
+    assert_eq!(add("left-pad", DependencyKind::Normal, false)?.version, "^1.3.0");
+    assert!(add("@types/node@legacy", DependencyKind::Normal, false).is_err());
+    assert_eq!(add("@types/node@^20", DependencyKind::Normal, true)?.version, "20.1.0");
+    assert_eq!(add("zod@latest", DependencyKind::Normal, false)?.version, "^3.0.0");
+    add("debug@^2.6.9", DependencyKind::Normal, false)?;
+    assert_eq!(
+        fs::read_to_string(&path)?,
+        indoc! {r#"
+            {
+                "version": "1.0.0",
+                "name": "app",
+                "dependencies": {
+                    "@types/node": "20.1.0",
+                    "debug": "^2.6.9",
+                    "left-pad": "^1.3.0",
+                    "ms": "2.0.0",
+                    "zod": "^3.0.0"
+                },
+                "optionalDependencies": {}
+            }
+        "#}
+    );
+
+    assert!(remove_dependency(dir.path(), "zod")?);
+    assert!(remove_dependency(&path, "@types/node")?);
+    assert!(!remove_dependency(dir.path(), "zod")?);
+    add("left-pad@legacy", DependencyKind::Optional, true)?;
+    add("fsevents@file:../fsevents", DependencyKind::Optional, false)?;
+    assert_eq!(
+        fs::read_to_string(&path)?,
+        indoc! {r#"
+            {
+                "version": "1.0.0",
+                "name": "app",
+                "dependencies": {
+                    "debug": "^2.6.9",
+                    "ms": "2.0.0"
+                },
+                "optionalDependencies": {
+                    "fsevents": "file:../fsevents",
+                    "left-pad": "1.2.0"
+                }
+            }
+        "#}
+    );
+
+    // A section that isn't there yet is added, formatted like the rest
+    fs::write(&path, "{\"name\":\"app\"}")?;
+    add("zod", DependencyKind::Normal, false)?;
+    assert_eq!(fs::read_to_string(&path)?, r#"{"name":"app","dependencies": {"zod": "^3.0.0"}}"#);
+    fs::write(&path, "{\n  \"name\": \"app\"\n}\n")?;
+    add("zod", DependencyKind::Normal, false)?;
+    assert_eq!(
+        fs::read_to_string(&path)?,
+        "{\n  \"name\": \"app\",\n  \"dependencies\": {\n    \"zod\": \"^3.0.0\"\n  }\n}\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn it_will_init_a_package_json() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let root = tempfile::tempdir()?;
+    let dir = root.path().join("My Project");
+    let manifest = init(&dir, &InitOptions::default())?;
+    assert_eq!(manifest.name.as_deref(), Some("my-project"));
+    assert_eq!(manifest.version.as_deref(), Some("1.0.0"));
+    assert_eq!(manifest.main.as_deref(), Some("index.js"));
+    let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.join("package.json"))?)?;
+    assert_eq!(written["license"], "ISC");
+    assert_eq!(written["scripts"]["test"], "echo \"Error: no test specified\" && exit 1");
+    assert_eq!(Manifest::read(&dir)?, manifest);
 
-    let root = Dependency {
-        name: "koa_ejs".to_string(),
-        version: "1".to_string(),
+    // An empty answer keeps the default
+    let options = InitOptions::default()
+        .with_name("")
+        .with_version(" 0.1.0\n")
+        .with_license("MIT")
+        .with_workspaces("packages/*, apps/web\n");
+    assert_eq!(options.name_for(&dir), "my-project");
+    assert_eq!(options.version, "0.1.0");
+    assert!(matches!(init(&dir, &options), Err(NaryError::ManifestExists { .. })));
+
+    let options = InitOptions {
+        force: true,
+        ..options.with_name("@acme/monorepo")
+    };
+    let manifest = init(&dir, &options)?;
+    assert_eq!(manifest.name.as_deref(), Some("@acme/monorepo"));
+    assert!(manifest.private);
+    assert_eq!(manifest.workspaces, Some(vec!["packages/*".to_string(), "apps/web".to_string()]));
+    assert!(dir.join("packages").is_dir());
+    assert!(dir.join("apps/web").is_dir());
+    assert!(fs::read_to_string(dir.join("package.json"))?.starts_with("{\n  \"name\": \"@acme/monorepo\",\n"));
+
+    let invalid = |options: InitOptions| matches!(init(&dir, &options), Err(NaryError::InvalidManifest { .. }));
+    assert!(invalid(options.clone().with_version("soon")));
+    assert!(invalid(options.with_name(".hidden")));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_add_several_specs_at_once() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "left-pad", "version": "1.3.0"}"#,
+        r#"{"name": "ms", "version": "2.0.0"}"#,
+        r#"{"name": "ms", "version": "2.1.3"}"#,
+        r#"{"name": "debug", "version": "2.6.9", "dependencies": {"ms": "2.0.0"}}"#,
+        r#"{"name": "fsevents", "version": "2.3.0"}"#,
+    ] {
+        registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+    }
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("package.json");
+    let original = "{\n  \"name\": \"app\",\n  \"dependencies\": {\n    \"debug\": \"^2.6.0\"\n  }\n}\n";
+    fs::write(&path, original)?;
+    fs::create_dir(dir.path().join("local"))?;
+    fs::write(dir.path().join("local/package.json"), r#"{"name": "local-lib", "version": "0.1.0"}"#)?;
+
+    let options = ResolutionOptions::default();
+    let specs = |specs: &[&str]| specs.iter().map(|spec| spec.to_string()).collect::<Vec<_>>();
+    let add = |specs: &[String], kind: DependencyKind| {
+        add(dir.path(), specs, kind, false, &registry, &options, &SilentReporter)
     };
 
-    let calculated = calculate_depends(&root, &dependencies)?;
+    // Nothing is saved when one of them doesn't resolve
+    assert!(add(&specs(&["left-pad", "missing"]), DependencyKind::Normal).is_err());
+    assert_eq!(fs::read_to_string(&path)?, original);
+    assert!(read_lockfile(dir.path())?.is_none());
+
+    let added = add(&specs(&["left-pad", "ms@^2", "file:local"]), DependencyKind::Normal)?;
+    let saved: Vec<(&str, &str)> =
+        added.dependencies.iter().map(|dependency| (dependency.name.as_str(), dependency.version.as_str())).collect();
+    assert_eq!(saved, [("left-pad", "^1.3.0"), ("ms", "^2"), ("local-lib", "file:local")]);
+    let mut versions = added.graph.versions_of("ms");
+    versions.sort();
+    assert_eq!(versions, ["2.0.0", "2.1.3"]);
+    assert_eq!(
+        fs::read_to_string(&path)?,
+        indoc! {r#"
+            {
+              "name": "app",
+              "dependencies": {
+                "debug": "^2.6.0",
+                "left-pad": "^1.3.0",
+                "local-lib": "file:local",
+                "ms": "^2"
+              }
+            }
+        "#}
+    );
+
+    // The lockfile is written once, with all of them, so the install that follows doesn't resolve again
+    let lockfile = read_lockfile(dir.path())?.unwrap();
+    assert_eq!(lockfile, added.lockfile);
+    lockfile.check_sync(&project_dependencies(dir.path())?)?;
+
+    // An optional one is resolved along with the rest, as optional
+    let added = add(&specs(&["fsevents"]), DependencyKind::Optional)?;
+    let fsevents = added.graph.install_order().find(|node| node.name == "fsevents").unwrap();
+    assert_eq!(added.graph.kind_of(fsevents), DependencyKind::Optional);
+    assert!(added.lockfile.dependencies["fsevents"].optional);
+    assert_eq!(added.graph.versions_of("left-pad"), ["1.3.0"]);
+
+    Ok(())
+}
 
-    for dep in calculated {
-        println!("{:?}", dep);
+#[test]
+fn it_will_report_outdated_dependencies() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "a", "version": "1.0.0"}"#,
+        r#"{"name": "a", "version": "1.1.0"}"#,
+        r#"{"name": "a", "version": "2.0.0"}"#,
+        r#"{"name": "b", "version": "1.0.0"}"#,
+        r#"{"name": "leaf", "version": "1.0.0"}"#,
+        r#"{"name": "leaf", "version": "1.2.0"}"#,
+    ] {
+        registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+    }
+
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+    fs::write(
+        root.join("package.json"),
+        r#"{"name": "app", "dependencies": {"a": "^1.0.0", "b": "1.0.0", "c": "file:../c", "missing": "^1.0.0"}}"#,
+    )?;
+    for (name, manifest) in &[
+        ("a", r#"{"name": "a", "version": "1.0.0", "dependencies": {"leaf": "^1.0.0"}}"#),
+        ("b", r#"{"name": "b", "version": "1.0.0", "dependencies": {"leaf": "1.0.0"}}"#),
+        ("leaf", r#"{"name": "leaf", "version": "1.0.0"}"#),
+    ] {
+        fs::create_dir_all(root.join("node_modules").join(name))?;
+        fs::write(root.join("node_modules").join(name).join("package.json"), manifest)?;
     }
+    registry.add_manifest(&serde_json::json!({"name": "missing", "version": "1.0.0"}), Vec::new())?;
+
+    let options = ResolutionOptions::default();
+    let direct = outdated(root, &registry, &options, false)?;
+    let summary = |found: &[OutdatedDependency]| -> Vec<String> {
+        found
+            .iter()
+            .map(|each| {
+                let version = |version: &Option<String>| version.clone().unwrap_or_default();
+                format!(
+                    "{} {} {} {} {}",
+                    each.dependent.as_deref().unwrap_or("root"),
+                    each.name,
+                    version(&each.current),
+                    version(&each.wanted),
+                    version(&each.latest)
+                )
+            })
+            .collect()
+    };
+    assert_eq!(summary(&direct), vec!["root a 1.0.0 1.1.0 2.0.0", "root missing  1.0.0 1.0.0"]);
+
+    let all = outdated(root, &registry, &options, true)?;
+    assert_eq!(
+        summary(&all),
+        vec![
+            "root a 1.0.0 1.1.0 2.0.0",
+            "root missing  1.0.0 1.0.0",
+            "a leaf 1.0.0 1.2.0 1.2.0",
+            "b leaf 1.0.0 1.0.0 1.2.0",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn it_will_update_dependencies() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    let publish = |manifests: &[&str]| -> Result<()> {
+        for manifest in manifests {
+            registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+        }
+        Ok(())
+    };
+    publish(&[
+        r#"{"name": "a", "version": "1.1.0", "dependencies": {"leaf": "^1.0.0"}}"#,
+        r#"{"name": "b", "version": "1.0.0"}"#,
+        r#"{"name": "c", "version": "1.0.0"}"#,
+        r#"{"name": "leaf", "version": "1.0.0"}"#,
+    ])?;
+    let dir = tempfile::tempdir()?;
+    fs::write(
+        dir.path().join("package.json"),
+        r#"{"name": "app", "dependencies": {"a": "^1.0.0", "b": "^1.0.0", "c": "^1.0.0"}}"#,
+    )?;
+    let options = ResolutionOptions::default();
+    let update = |names: &[&str], major: bool| {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        nary_lib::update(dir.path(), &names, major, &registry, &options, &SilentReporter)
+    };
+    let summary = |updated: &[UpdatedPackage]| -> Vec<String> {
+        updated.iter().map(|package| format!("{} {:?} {:?}", package.name, package.from, package.to)).collect()
+    };
+
+    let first = update(&[], false)?;
+    assert_eq!(first.updated.len(), 4);
+    assert_eq!(read_lockfile(dir.path())?, Some(first.lockfile));
+
+    publish(&[
+        r#"{"name": "a", "version": "1.2.0", "dependencies": {"leaf": "^1.0.0"}}"#,
+        r#"{"name": "b", "version": "1.1.0"}"#,
+        r#"{"name": "c", "version": "2.0.0"}"#,
+        r#"{"name": "leaf", "version": "1.1.0"}"#,
+    ])?;
+    // leaf is in range of a's new version, and stays
+    let only_a = update(&["a"], false)?;
+    assert_eq!(summary(&only_a.updated), vec![r#"a ["1.1.0"] ["1.2.0"]"#]);
+
+    let c = update(&["c"], true)?;
+    assert_eq!(summary(&c.updated), vec![r#"c ["1.0.0"] ["2.0.0"]"#]);
+    assert_eq!(path_to_dependencies(dir.path())?[2].0.version, "^2.0.0");
+
+    let everything = update(&[], true)?;
+    assert_eq!(
+        summary(&everything.updated),
+        vec![r#"b ["1.0.0"] ["1.1.0"]"#, r#"leaf ["1.0.0"] ["1.1.0"]"#]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn it_will_audit_the_graph() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "a", "version": "1.0.0", "dependencies": {"vulnerable": "^1.0.0"}}"#,
+        r#"{"name": "b", "version": "1.0.0", "dependencies": {"vulnerable": "~1.0.0"}}"#,
+        r#"{"name": "vulnerable", "version": "1.0.0"}"#,
+        r#"{"name": "vulnerable", "version": "1.0.5"}"#,
+        r#"{"name": "vulnerable", "version": "1.1.0"}"#,
+        r#"{"name": "vulnerable", "version": "1.2.0"}"#,
+        r#"{"name": "safe", "version": "1.0.0"}"#,
+    ] {
+        registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+    }
+    let advisory = |id, severity, vulnerable_versions: &str| Advisory {
+        id,
+        title: format!("Advisory {}", id),
+        url: format!("https://example.com/advisories/{}", id),
+        severity,
+        vulnerable_versions: vulnerable_versions.to_string(),
+    };
+    registry.add_advisory("vulnerable", advisory(1, Severity::Moderate, "<1.1.0"));
+    registry.add_advisory("vulnerable", advisory(2, Severity::Critical, ">=1.1.0 <1.2.0"));
+
+    let root = Dependency::new("app", "1.0.0");
+    let dependencies: Vec<(Dependency, DependencyKind)> = ["a", "b", "safe"]
+        .iter()
+        .map(|name| (Dependency::new(name.to_string(), "1.0.0".to_string()), DependencyKind::Normal))
+        .collect();
+    let options = ResolutionOptions {
+        preferred: vec![("vulnerable".to_string(), vec!["1.1.0".to_string()])].into_iter().collect(),
+        ..ResolutionOptions::default()
+    };
+    let graph = calculate_depends(&root, &dependencies, &registry, &options, &SilentReporter)?;
+
+    let report = audit(&graph, &registry, &ResolutionOptions::default())?;
+    let summary: Vec<String> = report
+        .vulnerabilities
+        .iter()
+        .map(|found| {
+            let chains: Vec<String> = found
+                .chains
+                .iter()
+                .map(|chain| chain.links.iter().map(|link| link.name.as_str()).collect::<Vec<_>>().join(" > "))
+                .collect();
+            let fix = found.fix.as_deref().unwrap_or("none");
+            format!(
+                "{} {}@{} [{}] fix {} {}",
+                found.advisory.severity, found.name, found.version, chains.join(", "), fix, found.fix_in_range
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            "critical vulnerable@1.1.0 [a > vulnerable] fix 1.2.0 true",
+            "moderate vulnerable@1.0.5 [b > vulnerable] fix 1.2.0 false",
+        ]
+    );
+    assert_eq!(report.highest(), Some(Severity::Critical));
+    assert_eq!(report.counts().get(&Severity::Moderate), Some(&1));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_dedupe_the_graph() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "a", "version": "1.0.0", "dependencies": {"shared": "^1.0.0"}}"#,
+        r#"{"name": "b", "version": "1.0.0", "dependencies": {"shared": "1.0.0"}}"#,
+        r#"{"name": "c", "version": "1.0.0", "dependencies": {"shared": ">=1.0.0 <1.2.0"}}"#,
+        r#"{"name": "d", "version": "1.0.0", "dependencies": {"shared": "^1.1.0"}}"#,
+        r#"{"name": "shared", "version": "1.0.0"}"#,
+        r#"{"name": "shared", "version": "1.1.0"}"#,
+        r#"{"name": "shared", "version": "1.2.0", "dependencies": {"only-new": "^1.0.0"}}"#,
+        r#"{"name": "only-new", "version": "1.0.0"}"#,
+    ] {
+        registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+    }
+    let root = Dependency::new("app", "1.0.0");
+    let dependencies: Vec<(Dependency, DependencyKind)> = ["a", "b", "c", "d"]
+        .iter()
+        .map(|name| (Dependency::new(name.to_string(), "1.0.0".to_string()), DependencyKind::Normal))
+        .collect();
+    let mut graph = calculate_depends(&root, &dependencies, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+    assert_eq!(graph.versions_of("shared"), vec!["1.2.0", "1.0.0", "1.1.0"]);
+
+    let deduped = graph.dedupe();
+    // 1.1.0 serves as many as 1.0.0 and is higher, and b still needs 1.0.0 exactly
+    assert_eq!(graph.versions_of("shared"), vec!["1.0.0", "1.1.0"]);
+    assert_eq!(
+        deduped.merged,
+        vec![MergedVersion {
+            name: "shared".to_string(),
+            version: "1.2.0".to_string(),
+            into: "1.1.0".to_string(),
+        }]
+    );
+    let removed: Vec<String> = deduped.removed.iter().map(|node| format!("{}@{}", node.name, node.version)).collect();
+    assert_eq!(removed, vec!["shared@1.2.0", "only-new@1.0.0"]);
+    assert_eq!(graph.find("only-new").count(), 0);
+    assert_eq!(graph.install_order().count(), 6);
+    let chains: Vec<String> = graph.why("shared").iter().map(|chain| chain.to_string()).collect();
+    assert!(chains.contains(&"a@1.0.0 (1.0.0) > shared@1.1.0 (^1.0.0)".to_string()));
+
+    assert_eq!(graph.dedupe(), Dedupe::default());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_resolve_dist_tags() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for version in &["1.0.0", "1.1.0", "2.0.0-beta.1"] {
+        registry.add_manifest(
+            &serde_json::json!({"name": "react", "version": version}),
+            Vec::new(),
+        )?;
+    }
+    registry.add_dist_tag("react", "latest", "1.1.0");
+    registry.add_dist_tag("react", "next", "2.0.0-beta.1");
+
+    let name = PackageName::parse("react")?;
+    assert_eq!(registry.dist_tags(&name)?["next"], "2.0.0-beta.1");
+
+    let packument = registry.packument(&name)?;
+    let resolve = |version: &str| -> Result<String> {
+        let dep = Dependency::new("react", version.to_string());
+        Ok(fetch_matching_version_metadata(&dep, &packument, &ResolutionOptions::default())?.0.clone())
+    };
+
+    assert_eq!(resolve("next")?, "2.0.0-beta.1");
+    assert_eq!(resolve("latest")?, "1.1.0");
+    assert_eq!(resolve("")?, "1.1.0");
+    assert_eq!(resolve("^1.0.0")?, "1.1.0");
+    assert!(resolve("canary").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_pick_versions_in_semver_order() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for version in &["1.10.0", "1.9.0", "2.0.0-rc.1"] {
+        registry.add_manifest(&serde_json::json!({"name": "left-pad", "version": version}), Vec::new())?;
+    }
+    let packument = registry.packument(&PackageName::parse("left-pad")?)?;
+
+    let resolve = |version: &str, include_prerelease: bool| -> Result<String> {
+        let dep = Dependency::new("left-pad", version.to_string());
+        let options = ResolutionOptions {
+            include_prerelease,
+            ..ResolutionOptions::default()
+        };
+        Ok(fetch_matching_version_metadata(&dep, &packument, &options)?.0.clone())
+    };
+
+    assert_eq!(resolve("^1.0.0", false)?, "1.10.0");
+    assert_eq!(resolve(">=1.0.0", false)?, "1.10.0");
+    assert_eq!(resolve(">=1.0.0", true)?, "2.0.0-rc.1");
+    assert_eq!(resolve(">=2.0.0-rc.0", false)?, "2.0.0-rc.1");
+
+    Ok(())
+}
+
+#[test]
+fn it_will_resolve_the_same_graph_whatever_the_order() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let manifests = vec![
+        serde_json::json!({"name": "ms", "version": "2.1.3"}),
+        serde_json::json!({"name": "ms", "version": "2.1.3+build.2"}),
+        serde_json::json!({"name": "ms", "version": "2.1.3+build.1"}),
+        serde_json::json!({"name": "debug", "version": "4.3.4", "dependencies": {"ms": "2.1.3", "supports-color": "1"}}),
+        serde_json::json!({"name": "supports-color", "version": "1.0.0", "dependencies": {"has-flag": "1"}}),
+        serde_json::json!({"name": "has-flag", "version": "1.0.0"}),
+    ];
+    let reordered = vec![
+        serde_json::json!({"name": "has-flag", "version": "1.0.0"}),
+        serde_json::json!({"name": "debug", "version": "4.3.4", "dependencies": {"supports-color": "1", "ms": "2.1.3"}}),
+        serde_json::json!({"name": "ms", "version": "2.1.3+build.1"}),
+        serde_json::json!({"name": "supports-color", "version": "1.0.0", "dependencies": {"has-flag": "1"}}),
+        serde_json::json!({"name": "ms", "version": "2.1.3+build.2"}),
+        serde_json::json!({"name": "ms", "version": "2.1.3"}),
+    ];
+
+    let root = Dependency::new("app", "1.0.0");
+    let dep =
+        |name: &str, version: &str| (Dependency::new(name.to_string(), version.to_string()), DependencyKind::Normal);
+    let resolve = |manifests: &[serde_json::Value], deps: &[_]| -> Result<(String, Vec<String>)> {
+        let registry = MemoryRegistry::new();
+        for manifest in manifests {
+            registry.add_manifest(manifest, Vec::new())?;
+        }
+        let graph = calculate_depends(&root, deps, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+        let order = graph.install_order().map(|node| format!("{}@{}", node.name, node.version)).collect();
+        Ok((serde_json::to_string(&Lockfile::from_graph(&graph, &registry)?)?, order))
+    };
+
+    let (lockfile, order) = resolve(&manifests, &[dep("debug", "4"), dep("ms", "2")])?;
+    let (reordered_lockfile, reordered_order) = resolve(&reordered, &[dep("ms", "2"), dep("debug", "4")])?;
+    assert_eq!(lockfile, reordered_lockfile);
+    assert_eq!(order, reordered_order);
+    // Versions that differ only in build metadata go to the highest version string
+    assert_eq!(order, vec!["ms@2.1.3+build.2", "has-flag@1.0.0", "supports-color@1.0.0", "debug@4.3.4"]);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn it_will_skip_optional_dependencies_for_other_platforms() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        serde_json::json!({
+            "name": "esbuild",
+            "version": "0.19.0",
+            "optionalDependencies": {"@esbuild/linux-x64": "0.19.0", "@esbuild/darwin-arm64": "0.19.0"},
+        }),
+        serde_json::json!({"name": "@esbuild/linux-x64", "version": "0.19.0", "os": ["linux"], "cpu": ["x64"]}),
+        serde_json::json!({"name": "@esbuild/darwin-arm64", "version": "0.19.0", "os": ["darwin"], "cpu": ["arm64"]}),
+        serde_json::json!({"name": "not-windows", "version": "1.0.0", "os": ["!win32"]}),
+        serde_json::json!({"name": "musl-only", "version": "1.0.0", "os": ["linux"], "libc": ["musl"]}),
+    ] {
+        registry.add_manifest(manifest, Vec::new())?;
+    }
+
+    let root = Dependency::new("app", "1.0.0");
+    let dep = |name: &str| (Dependency::new(name.to_string(), "*".to_string()), DependencyKind::Normal);
+    let linux = ResolutionOptions {
+        platform: Platform {
+            os: "linux".to_string(),
+            cpu: "x64".to_string(),
+            libc: Some("glibc".to_string()),
+        },
+        ..ResolutionOptions::default()
+    };
+
+    let graph = calculate_depends(&root, &[dep("esbuild"), dep("not-windows")], &registry, &linux, &SilentReporter)?;
+    let installed: Vec<&str> = graph.install_order().map(|node| node.name.as_str()).collect();
+    assert_eq!(installed, vec!["@esbuild/linux-x64", "esbuild", "not-windows"]);
+    assert!(graph.edges().iter().any(|edge| edge.kind == DependencyKind::Optional));
+
+    match calculate_depends(&root, &[dep("musl-only")], &registry, &linux, &SilentReporter) {
+        Err(NaryError::UnsupportedPlatform { name, platform, .. }) => {
+            assert_eq!(name, "musl-only");
+            assert_eq!(platform, "linux x64 glibc");
+        }
+        other => panic!("expected an unsupported platform error, got {:?}", other.map(|graph| graph.len())),
+    }
+
+    let windows = ResolutionOptions {
+        platform: Platform {
+            os: "win32".to_string(),
+            cpu: "x64".to_string(),
+            libc: None,
+        },
+        ..ResolutionOptions::default()
+    };
+    assert!(calculate_depends(&root, &[dep("not-windows")], &registry, &windows, &SilentReporter).is_err());
+    assert!(calculate_depends(&root, &[dep("musl-only")], &registry, &windows, &SilentReporter).is_err());
+
+    Ok(())
+}
+
+/// Keeps the warnings it is given
+#[derive(Default)]
+struct WarningReporter {
+    warnings: Mutex<Vec<String>>,
+}
+
+impl InstallReporter for WarningReporter {
+    fn on_warning(&self, message: &str) {
+        self.warnings.lock().unwrap().push(message.to_string());
+    }
+}
+
+#[test]
+fn it_will_leave_out_failed_optional_dependencies() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        serde_json::json!({
+            "name": "chokidar",
+            "version": "3.5.0",
+            "dependencies": {"ms": "2.0.0"},
+            "optionalDependencies": {"fsevents": "^2.0.0", "broken": "^1.0.0"},
+        }),
+        serde_json::json!({"name": "ms", "version": "2.0.0"}),
+        serde_json::json!({"name": "fsevents", "version": "2.3.0"}),
+        // Needs a package the registry doesn't have, so all of it has to go
+        serde_json::json!({"name": "broken", "version": "1.0.0", "dependencies": {"helper": "^1.0.0", "missing": "^1.0.0"}}),
+        serde_json::json!({"name": "helper", "version": "1.0.0"}),
+    ] {
+        registry.add_manifest(manifest, Vec::new())?;
+    }
+
+    let root = Dependency::new("app", "1.0.0");
+    let chokidar = (Dependency::new("chokidar", "^3.0.0"), DependencyKind::Normal);
+    let reporter = WarningReporter::default();
+    let graph = calculate_depends(&root, &[chokidar], &registry, &ResolutionOptions::default(), &reporter)?;
+
+    let mut resolved: Vec<&str> = graph.install_order().map(|node| node.name.as_str()).collect();
+    resolved.sort_unstable();
+    assert_eq!(resolved, vec!["chokidar", "fsevents", "ms"]);
+
+    let warnings = reporter.warnings.lock().unwrap().clone();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("broken@^1.0.0"), "{}", warnings[0]);
+
+    let fsevents = graph.install_order().find(|node| node.name == "fsevents").unwrap();
+    let ms = graph.install_order().find(|node| node.name == "ms").unwrap();
+    assert_eq!(graph.kind_of(fsevents), DependencyKind::Optional);
+    assert_eq!(graph.kind_of(ms), DependencyKind::Normal);
+
+    // The project's own optional dependencies are left out the same way, and a lockfile without them is in sync
+    let project = tempfile::tempdir()?;
+    fs::write(
+        project.path().join("package.json"),
+        r#"{"name": "app", "version": "1.0.0", "dependencies": {"ms": "2.0.0"},
+            "optionalDependencies": {"fsevents": "^2.0.0", "broken": "^1.0.0"}}"#,
+    )?;
+    let dependencies = project_dependencies(project.path())?;
+    let graph = calculate_depends(&root, &dependencies, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+    let fsevents = graph.install_order().find(|node| node.name == "fsevents").unwrap();
+    assert_eq!(graph.kind_of(fsevents), DependencyKind::Optional);
+    assert_eq!(graph.find("broken").count(), 0);
+    Lockfile::from_graph(&graph, &registry)?.check_sync(&dependencies)?;
+
+    // fsevents has no tarball in the registry, which is only a warning for an optional dependency
+    let node_modules = tempfile::tempdir()?;
+    let missing = Dependency::new("fsevents", "2.3.0");
+    let reporter = WarningReporter::default();
+    install_dep(
+        node_modules.path(),
+        &missing,
+        DependencyKind::Optional,
+        &registry,
+        &InstallOptions::default(),
+        &reporter,
+    )?;
+    assert_eq!(reporter.warnings.lock().unwrap().len(), 1);
+    let installed = install_dep(
+        node_modules.path(),
+        &missing,
+        DependencyKind::Normal,
+        &registry,
+        &InstallOptions::default(),
+        &reporter,
+    );
+    assert!(installed.is_err());
+
+    Ok(())
+}
+
+/// A registry that counts what's asked of it, taking `delay` over each packument
+#[derive(Default)]
+struct CountingRegistry {
+    registry: MemoryRegistry,
+    delay: std::time::Duration,
+    packuments: Mutex<Vec<String>>,
+    version_metadata: Mutex<usize>,
+    /// Packuments being fetched, and the most there were at once
+    fetching: Mutex<(usize, usize)>,
+}
+
+impl RegistryClient for CountingRegistry {
+    fn packument(&self, name: &PackageName) -> nary_lib::Result<Packument> {
+        self.packuments.lock().unwrap().push(name.to_string());
+        {
+            let mut fetching = self.fetching.lock().unwrap();
+            fetching.0 += 1;
+            fetching.1 = fetching.1.max(fetching.0);
+        }
+        std::thread::sleep(self.delay);
+        self.fetching.lock().unwrap().0 -= 1;
+        self.registry.packument(name)
+    }
+
+    fn version_metadata(&self, name: &PackageName, version: &str) -> nary_lib::Result<serde_json::Value> {
+        *self.version_metadata.lock().unwrap() += 1;
+        self.registry.version_metadata(name, version)
+    }
+
+    fn tarball(
+        &self,
+        name: &PackageName,
+        version: &str,
+        tarball_url: &hyper::Url,
+        reporter: &dyn InstallReporter,
+    ) -> nary_lib::Result<Vec<u8>> {
+        self.registry.tarball(name, version, tarball_url, reporter)
+    }
+
+    fn publish(&self, name: &PackageName, payload: &serde_json::Value) -> nary_lib::Result<()> {
+        self.registry.publish(name, payload)
+    }
+}
+
+#[test]
+fn it_will_resolve_deep_graphs_fetching_each_packument_once() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = CountingRegistry::default();
+    let depth = 2000;
+    for i in 0..depth {
+        let mut manifest = serde_json::json!({"name": format!("chain-{}", i), "version": "1.0.0"});
+        if i + 1 < depth {
+            // Different ranges of the same package, which still needs fetching only once
+            manifest["dependencies"] =
+                serde_json::json!({ format!("chain-{}", i + 1): "^1.0.0", "shared": format!("^1.{}.0", i % 2) });
+        }
+        registry.registry.add_manifest(&manifest, Vec::new())?;
+    }
+    registry.registry.add_manifest(&serde_json::json!({"name": "shared", "version": "1.1.0"}), Vec::new())?;
+
+    let root = Dependency::new("app", "1.0.0");
+    let chain = (Dependency::new("chain-0", "^1.0.0"), DependencyKind::Normal);
+    let graph = calculate_depends(&root, &[chain], &registry, &ResolutionOptions::default(), &SilentReporter)?;
+    assert_eq!(graph.len(), depth + 2);
+    assert_eq!(graph.find("shared").count(), 1);
+
+    let packuments = registry.packuments.lock().unwrap();
+    assert_eq!(packuments.len(), depth + 1);
+    assert_eq!(packuments.iter().filter(|name| *name == "shared").count(), 1);
+    assert_eq!(*registry.version_metadata.lock().unwrap(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_fetch_packuments_in_parallel() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = CountingRegistry {
+        delay: std::time::Duration::from_millis(20),
+        ..CountingRegistry::default()
+    };
+    let names: Vec<String> = (0..12).map(|i| format!("leaf-{}", i)).collect();
+    for name in &names {
+        let manifest = serde_json::json!({"name": name, "version": "1.0.0", "dependencies": {"shared": "^1.0.0"}});
+        registry.registry.add_manifest(&manifest, Vec::new())?;
+    }
+    registry.registry.add_manifest(&serde_json::json!({"name": "shared", "version": "1.0.0"}), Vec::new())?;
+
+    let root = Dependency::new("app", "1.0.0");
+    let deps: Vec<(Dependency, DependencyKind)> = names
+        .iter()
+        .map(|name| (Dependency::new(name.clone(), "^1.0.0".to_string()), DependencyKind::Normal))
+        .collect();
+    let options = ResolutionOptions {
+        parallelism: 4,
+        ..ResolutionOptions::default()
+    };
+    let graph = calculate_depends(&root, &deps, &registry, &options, &SilentReporter)?;
+    let most_at_once = registry.fetching.lock().unwrap().1;
+    assert!(most_at_once > 1 && most_at_once <= 4, "{} at once", most_at_once);
+    assert_eq!(registry.packuments.lock().unwrap().len(), names.len() + 1);
+
+    // The same graph as one fetch at a time gives
+    let sequential = ResolutionOptions {
+        parallelism: 1,
+        ..ResolutionOptions::default()
+    };
+    let expected = calculate_depends(&root, &deps, &registry.registry, &sequential, &SilentReporter)?;
+    assert_eq!(graph.nodes().collect::<Vec<_>>(), expected.nodes().collect::<Vec<_>>());
+    assert_eq!(graph.edges(), expected.edges());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_check_engines() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        serde_json::json!({"name": "modern", "version": "2.0.0", "engines": {"node": ">=18", "npm": ">=9"}}),
+        serde_json::json!({"name": "old", "version": "1.0.0", "engines": {"node": "<12"}}),
+        serde_json::json!({"name": "loose", "version": "1.0.0", "engines": {"node": "not a range"}}),
+        serde_json::json!({"name": "app-extras", "version": "1.0.0", "optionalDependencies": {"old": "1.0.0"}}),
+    ] {
+        registry.add_manifest(manifest, Vec::new())?;
+    }
+
+    let root = Dependency::new("app", "1.0.0");
+    let dep = |name: &str| (Dependency::new(name.to_string(), "*".to_string()), DependencyKind::Normal);
+    let mut options = ResolutionOptions {
+        engines: Engines {
+            node: Some("16.20.0".to_string()),
+            npm: None,
+            strict: false,
+        },
+        ..ResolutionOptions::default()
+    };
+
+    let reporter = WarningReporter::default();
+    let deps = [dep("modern"), dep("old"), dep("loose")];
+    let graph = calculate_depends(&root, &deps, &registry, &options, &reporter)?;
+    assert_eq!(graph.len(), 4);
+    let warnings = vec![
+        "modern@2.0.0 wants node >=18, but it's 16.20.0",
+        "old@1.0.0 wants node <12, but it's 16.20.0",
+    ];
+    assert_eq!(*reporter.warnings.lock().unwrap(), warnings);
+
+    options.engines.strict = true;
+    match calculate_depends(&root, &[dep("modern")], &registry, &options, &SilentReporter) {
+        Err(NaryError::UnsupportedEngine { name, engine, .. }) => {
+            assert_eq!((name.as_str(), engine.as_str()), ("modern", "node"));
+        }
+        other => panic!("expected an unsupported engine error, got {:?}", other.map(|graph| graph.len())),
+    }
+    let graph = calculate_depends(&root, &[dep("app-extras")], &registry, &options, &SilentReporter)?;
+    assert_eq!(graph.find("old").count(), 0);
+
+    // Nothing is checked without a version to check against
+    let graph = calculate_depends(&root, &[dep("old")], &registry, &ResolutionOptions::default(), &SilentReporter)?;
+    assert_eq!(graph.len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_check_and_install_peer_dependencies() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        serde_json::json!({"name": "react", "version": "17.0.2"}),
+        serde_json::json!({"name": "react", "version": "18.2.0"}),
+        serde_json::json!({"name": "scheduler", "version": "1.0.0"}),
+        serde_json::json!({"name": "widgets", "version": "1.0.0", "peerDependencies": {"react": "^17.0.0"}}),
+        serde_json::json!({
+            "name": "hooks",
+            "version": "1.0.0",
+            "peerDependencies": {"react": "^18.0.0", "scheduler": "1", "devtools": "1"},
+            "peerDependenciesMeta": {"devtools": {"optional": true}},
+        }),
+        serde_json::json!({"name": "ui", "version": "1.0.0", "dependencies": {"hooks": "1"}}),
+    ] {
+        registry.add_manifest(manifest, Vec::new())?;
+    }
+
+    let root = Dependency::new("app", "1.0.0");
+    let dep =
+        |name: &str, version: &str| (Dependency::new(name.to_string(), version.to_string()), DependencyKind::Normal);
+    let options = ResolutionOptions::default();
+    let deps = [dep("react", "^18.0.0"), dep("widgets", "1"), dep("ui", "1")];
+    let graph = calculate_depends(&root, &deps, &registry, &options, &SilentReporter)?;
+    let issues = check_peers(&graph, &registry, &options)?;
+    let described: Vec<String> = issues.iter().map(|issue| issue.to_string()).collect();
+    assert_eq!(
+        described,
+        vec![
+            "hooks@1.0.0 wants scheduler@1 as a peer, which isn't installed",
+            "widgets@1.0.0 wants react@^17.0.0 as a peer, but finds 18.2.0",
+        ]
+    );
+    assert_eq!(issues[0].chains[0].to_string(), "ui@1.0.0 (1) > hooks@1.0.0 (1)");
+    assert!(issues[0].is_missing());
+
+    // Installing peers brings in the missing one, but neither the optional one nor a second react
+    let options = ResolutionOptions {
+        install_peers: true,
+        ..ResolutionOptions::default()
+    };
+    let graph = calculate_depends(&root, &deps, &registry, &options, &SilentReporter)?;
+    assert_eq!(graph.versions_of("scheduler"), vec!["1.0.0"]);
+    assert_eq!(graph.versions_of("react"), vec!["18.2.0"]);
+    assert!(graph.find("devtools").next().is_none());
+    let issues = check_peers(&graph, &registry, &options)?;
+    let described: Vec<String> = issues.iter().map(|issue| issue.to_string()).collect();
+    assert_eq!(described, vec!["widgets@1.0.0 wants react@^17.0.0 as a peer, but finds 18.2.0"]);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_backtrack_with_the_strict_strategy() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        serde_json::json!({"name": "a", "version": "1.0.0", "dependencies": {"b": "^1.0.0"}}),
+        serde_json::json!({"name": "a", "version": "1.1.0", "dependencies": {"b": "^2.0.0"}}),
+        serde_json::json!({"name": "b", "version": "1.4.0"}),
+        serde_json::json!({"name": "b", "version": "2.0.0"}),
+        serde_json::json!({"name": "c", "version": "1.0.0", "peerDependencies": {"b": "^1.0.0"}}),
+        serde_json::json!({"name": "react", "version": "17.0.2"}),
+        serde_json::json!({"name": "react", "version": "18.2.0"}),
+        serde_json::json!({"name": "widgets", "version": "1.0.0", "peerDependencies": {"react": "^17.0.0"}}),
+        serde_json::json!({"name": "ui", "version": "1.0.0", "dependencies": {"widgets": "1"}}),
+    ] {
+        registry.add_manifest(manifest, Vec::new())?;
+    }
+
+    let root = Dependency::new("app", "1.0.0");
+    let dep =
+        |name: &str, version: &str| (Dependency::new(name.to_string(), version.to_string()), DependencyKind::Normal);
+    let deps = [dep("a", "^1.0.0"), dep("c", "^1.0.0")];
+    let graph = calculate_depends(&root, &deps, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+    assert_eq!(graph.versions_of("a"), vec!["1.1.0"]);
+
+    // The peer of c rules out the b the newest a wants, so strict goes back to the a before it
+    let strict = ResolutionOptions {
+        strategy: ResolutionStrategy::Strict,
+        ..ResolutionOptions::default()
+    };
+    let graph = calculate_depends(&root, &deps, &registry, &strict, &SilentReporter)?;
+    assert_eq!(graph.versions_of("a"), vec!["1.0.0"]);
+    assert_eq!(graph.versions_of("b"), vec!["1.4.0"]);
+
+    let deps = [dep("react", "^18.0.0"), dep("ui", "1")];
+    let conflict = match calculate_depends(&root, &deps, &registry, &strict, &SilentReporter) {
+        Err(NaryError::ResolutionConflict { conflict }) => conflict,
+        other => panic!("expected a conflict, got {:?}", other.map(|graph| graph.len())),
+    };
+    assert_eq!(conflict.name, "react");
+    assert_eq!(conflict.requirements.len(), 2);
+    assert!(conflict.requirements[1].peer);
+    assert_eq!(
+        conflict.to_string(),
+        indoc! {"
+            No version of react meets every requirement on it:
+              ^18.0.0 from the root
+              ^17.0.0 as a peer of ui@1.0.0 (1) > widgets@1.0.0 (1)"}
+    );
+
+    Ok(())
+}
+
+#[test]
+fn it_will_parse_version_specifiers() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let parse = |version: &str| Specifier::parse(version);
+    assert_eq!(parse("^1.0.0"), Specifier::SemverRange("^1.0.0".to_string()));
+    assert_eq!(parse(""), Specifier::SemverRange("".to_string()));
+    assert_eq!(parse("latest"), Specifier::Tag("latest".to_string()));
+    assert_eq!(parse("github:user/repo#v1"), Specifier::Git(GitSpec::parse("github:user/repo#v1").unwrap()));
+    assert_eq!(parse("file:../x"), Specifier::File("../x".into()));
+    assert_eq!(parse("https://x.test/y.tgz"), Specifier::Url("https://x.test/y.tgz".to_string()));
+    assert_eq!(parse("workspace:^"), Specifier::Workspace("^".to_string()));
+    let target = Dependency::new("pkg", "^2");
+    assert_eq!(parse("npm:pkg@^2"), Specifier::Alias(Box::new(target.clone())));
+    assert_eq!(parse("npm:pkg").registry_range(), Some("*"));
+
+    let alias = Dependency::new("other", "npm:pkg@^2");
+    assert_eq!(alias.registry_target(), target);
+    assert_eq!(alias.specifier().registry_range(), Some("^2"));
+    assert!(alias.specifier().is_registry() && parse("latest").is_registry());
+    assert!(!parse("file:../x").is_registry() && !parse("workspace:*").is_registry());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_read_malformed_manifests_leniently() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let dir = tempfile::tempdir()?;
+    fs::write(
+        dir.path().join("package.json"),
+        r#"{"name": "app", "version": 2, "dependencies": {"ms": "^2.0.0", "broken": {"version": "1"}, "_where": "x"},
+            "optionalDependencies": {"ms": "^2.1.0"}, "bin": {"app": "cli.js", "bad": 1}, "os": "linux",
+            "workspaces": {"packages": ["packages/*", 3]}, "files": "not a list", "private": "yes"}"#,
+    )?;
+
+    let manifest = Manifest::read(dir.path())?;
+    assert_eq!((manifest.name.as_deref(), manifest.version.as_deref()), (Some("app"), None));
+    assert_eq!(manifest.bins(), vec![("app".to_string(), std::path::PathBuf::from("cli.js"))]);
+    assert_eq!(manifest.dependencies.keys().collect::<Vec<_>>(), vec!["_where", "ms"]);
+    assert_eq!((&manifest.os, &manifest.files, manifest.private), (&vec!["linux".to_string()], &None, false));
+    assert_eq!(manifest.workspaces, Some(vec!["packages/*".to_string()]));
+
+    let dependencies = path_to_dependencies(dir.path())?;
+    assert_eq!(dependencies.iter().map(|(dependency, _)| dependency.name.as_str()).collect::<Vec<_>>(), vec!["ms"]);
+    let kinds: Vec<DependencyKind> = manifest.runtime_dependencies().into_iter().map(|(_, kind)| kind).collect();
+    assert_eq!(kinds, vec![DependencyKind::Optional]);
+    assert_eq!(path_to_root_dependency(dir.path())?.version, "");
+    assert_eq!(Manifest::from_value(&serde_json::json!([1, 2])), Manifest::default());
+
+    fs::write(dir.path().join("package.json"), "{")?;
+    assert!(matches!(Manifest::read(dir.path()), Err(NaryError::Json { .. })));
+
+    Ok(())
+}
+
+fn fixture_registry() -> Result<MemoryRegistry> {
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        include_str!("repository/debug.json"),
+        include_str!("repository/ejs.json"),
+        include_str!("repository/ms.json"),
+        include_str!("repository/mz.json"),
+        include_str!("repository/any-promise.json"),
+        include_str!("repository/object-assign.json"),
+        include_str!("repository/thenify-all.json"),
+        include_str!("repository/thenify.json"),
+    ] {
+        registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+    }
+    Ok(registry)
+}
+#[test]
+fn it_will_read_npmrc() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc(indoc! {r###"
+        ; comment
+        registry=https://mirror.example.com/npm/
+        @myorg:registry=https://npm.myorg.example.com
+        //npm.myorg.example.com/:_authToken=secret
+        strict-ssl=false
+    "###});
+
+    assert_eq!(config.registry_for(&PackageName::parse("koa")?), "https://mirror.example.com/npm");
+    assert_eq!(config.registry_for(&PackageName::parse("@myorg/tools")?), "https://npm.myorg.example.com");
+    assert_eq!(config.auth_token_for("https://npm.myorg.example.com/@myorg%2ftools"), Some("secret"));
+    assert_eq!(config.auth_token_for("https://npm.myorg.example.com.evil.com/x"), None);
+    assert!(!config.strict_ssl);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_pick_registry_credentials() {
+    let (_guard, _cache) = common::isolated_cache().unwrap();
+    let mut config = RegistryConfig::default().with_credentials_callback(|url| {
+        if url.starts_with("https://private.example.com/") {
+            Some(Credentials::Bearer("from-callback".to_string()))
+        } else {
+            None
+        }
+    });
+    config.parse_npmrc(indoc! {r###"
+        //npm.myorg.example.com/:_authToken=secret
+        //basic.example.com/:username=grant
+        //basic.example.com/:_password=aHVudGVyMg==
+    "###});
+
+    assert_eq!(
+        config.credentials_for("https://npm.myorg.example.com/@myorg%2ftools"),
+        Some(Credentials::Bearer("secret".to_string()))
+    );
+    assert_eq!(
+        config.credentials_for("https://basic.example.com/koa"),
+        Some(Credentials::Basic { username: "grant".to_string(), password: "hunter2".to_string() })
+    );
+    assert_eq!(
+        config.credentials_for("https://private.example.com/koa"),
+        Some(Credentials::Bearer("from-callback".to_string()))
+    );
+    assert_eq!(config.credentials_for("https://elsewhere.example.com/koa"), None);
+}
+
+#[test]
+fn it_will_parse_abbreviated_packument() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let packument: Packument = serde_json::from_str(indoc! {r###"
+        {
+            "name": "mz",
+            "modified": "2017-09-26T00:00:00.000Z",
+            "dist-tags": { "latest": "2.7.0" },
+            "versions": {
+                "2.6.0": {
+                    "name": "mz",
+                    "version": "2.6.0",
+                    "dependencies": { "any-promise": "^1.0.0" },
+                    "dist": { "tarball": "https://registry.npmjs.org/mz/-/mz-2.6.0.tgz", "shasum": "abc" }
+                },
+                "2.7.0": {
+                    "name": "mz",
+                    "version": "2.7.0",
+                    "dependencies": { "any-promise": "^1.0.0", "thenify-all": "^1.0.0" },
+                    "dist": { "tarball": "https://registry.npmjs.org/mz/-/mz-2.7.0.tgz", "integrity": "sha512-xyz" }
+                }
+            }
+        }
+    "###})?;
+
+    assert_eq!(packument.dist_tags["latest"], "2.7.0");
+
+    let dep = Dependency::new("mz", "^2.6.0");
+    let (version, metadata) = fetch_matching_version_metadata(&dep, &packument, &ResolutionOptions::default())?;
+    assert_eq!(version, "2.7.0");
+    assert_eq!(metadata.dependencies.len(), 2);
+    assert_eq!(metadata.dist.integrity.as_deref(), Some("sha512-xyz"));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_report_no_matching_version() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let packument: Packument = serde_json::from_str(indoc! {r###"
+        {
+            "name": "mz",
+            "versions": {
+                "2.6.0": { "name": "mz", "version": "2.6.0", "dist": { "tarball": "https://registry.npmjs.org/mz/-/mz-2.6.0.tgz" } },
+                "2.7.0": { "name": "mz", "version": "2.7.0", "dist": { "tarball": "https://registry.npmjs.org/mz/-/mz-2.7.0.tgz" } }
+            }
+        }
+    "###})?;
+
+    let dep = Dependency::new("mz", "^3.0.0");
+    match fetch_matching_version_metadata(&dep, &packument, &ResolutionOptions::default()) {
+        Err(NaryError::NoMatchingVersion { name, available, .. }) => {
+            assert_eq!(name, "mz");
+            assert_eq!(available, vec!["2.6.0", "2.7.0"]);
+        }
+        other => panic!("Expected NoMatchingVersion, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn it_will_manage_dist_tags() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "@scope/widget", "version": "1.0.0"}"#,
+        r#"{"name": "@scope/widget", "version": "2.0.0-beta.1"}"#,
+    ] {
+        registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+    }
+    registry.add_dist_tag("@scope/widget", "latest", "1.0.0");
+
+    dist_tags::add("@scope/widget", "2.0.0-beta.1", "next", &registry)?;
+    let tags = dist_tags::list("@scope/widget", &registry)?;
+    assert_eq!(tags["latest"], "1.0.0");
+    assert_eq!(tags["next"], "2.0.0-beta.1");
+
+    match dist_tags::add("@scope/widget", "3.0.0", "next", &registry) {
+        Err(NaryError::NoMatchingVersion { available, .. }) => assert_eq!(available, vec!["1.0.0", "2.0.0-beta.1"]),
+        other => panic!("Expected NoMatchingVersion, got {:?}", other),
+    }
+    assert!(matches!(
+        dist_tags::add("@scope/widget", "1.0.0", "^1", &registry),
+        Err(NaryError::InvalidDistTag { .. })
+    ));
+    assert!(matches!(dist_tags::rm("@scope/widget", "latest", &registry), Err(NaryError::InvalidDistTag { .. })));
+
+    assert_eq!(dist_tags::rm("@scope/widget", "next", &registry)?.as_deref(), Some("2.0.0-beta.1"));
+    assert_eq!(dist_tags::rm("@scope/widget", "next", &registry)?, None);
+    assert!(!dist_tags::list("@scope/widget", &registry)?.contains_key("next"));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_deprecate_and_unpublish_versions() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for version in &["1.0.0", "1.1.0", "2.0.0-beta.1", "2.0.0"] {
+        let manifest = format!(r#"{{"name": "widget", "version": "{}"}}"#, version);
+        registry.add_manifest(&serde_json::from_str(&manifest)?, Vec::new())?;
+    }
+    let widget = PackageName::parse("widget")?;
+
+    assert_eq!(deprecate("widget@<1.2", "use 2.x", &registry)?, vec!["1.0.0", "1.1.0"]);
+    assert_eq!(deprecate("widget@^2.0.0-0", "", &registry)?, vec!["2.0.0-beta.1", "2.0.0"]);
+    let packument = registry.packument(&widget)?;
+    assert_eq!(packument.versions["1.1.0"].deprecated, Some("use 2.x".into()));
+    assert_eq!(packument.versions["2.0.0"].deprecated, None);
+    assert!(matches!(deprecate("widget@^3", "gone", &registry), Err(NaryError::NoMatchingVersion { .. })));
+
+    // Nothing goes until it's confirmed
+    let pending = unpublish("widget@2.0.0", &registry)?;
+    assert_eq!(pending.versions, vec!["2.0.0"]);
+    assert!(registry.packument(&widget)?.versions.contains_key("2.0.0"));
+    assert_eq!(pending.confirm(&registry)?, vec!["2.0.0"]);
+    let packument = registry.packument(&widget)?;
+    assert!(!packument.versions.contains_key("2.0.0"));
+    assert_eq!(packument.dist_tags["latest"], "2.0.0-beta.1");
+
+    registry.add_publish_time("widget", "1.0.0", "2020-02-29T12:00:00.000Z");
+    match unpublish("widget@1.0.0", &registry) {
+        Err(NaryError::UnpublishWindow { package, .. }) => assert_eq!(package, "widget@1.0.0"),
+        other => panic!("Expected UnpublishWindow, got {:?}", other),
+    }
+    assert!(matches!(unpublish("widget@9.9.9", &registry), Err(NaryError::NoMatchingVersion { .. })));
+
+    let pending = unpublish("widget", &registry)?;
+    assert_eq!(pending.versions.len(), 3);
+    pending.confirm(&registry)?;
+    assert!(registry.packument(&widget).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_search_the_registry() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let page = parse_search(indoc! {r#"
+        {
+            "objects": [{
+                "package": {
+                    "name": "left-pad",
+                    "version": "1.3.0",
+                    "description": "String left pad",
+                    "keywords": ["leftpad", "pad"],
+                    "date": "2018-04-09T01:00:00.000Z",
+                    "maintainers": [{"username": "stevemao", "email": "steve@example.com"}]
+                },
+                "score": {"final": 0.72, "detail": {"quality": 0.8, "popularity": 0.5, "maintenance": 0.9}},
+                "searchScore": 100.5
+            }],
+            "total": 41,
+            "time": "Mon Oct 12 2026 10:00:00 GMT+0000"
+        }
+    "#})?;
+    let result = &page.results[0];
+    assert_eq!((result.name.as_str(), result.version.as_str()), ("left-pad", "1.3.0"));
+    assert_eq!(result.description.as_deref(), Some("String left pad"));
+    assert_eq!(result.maintainers[0].username, "stevemao");
+    assert_eq!((result.score.overall, result.score.maintenance), (0.72, 0.9));
+    let options = SearchOptions { size: 1, from: 40 };
+    assert_eq!(page.next_page(&options), None);
+    assert_eq!(page.next_page(&SearchOptions { size: 1, from: 0 }), Some(SearchOptions { size: 1, from: 1 }));
+
+    let registry = MemoryRegistry::new();
+    for name in &["pad", "left-pad", "right-pad", "zod"] {
+        let manifest = format!(r#"{{"name": "{}", "version": "1.0.0"}}"#, name);
+        registry.add_manifest(&serde_json::from_str(&manifest)?, Vec::new())?;
+    }
+    let mut options = SearchOptions { size: 2, from: 0 };
+    let mut names = Vec::new();
+    loop {
+        let page = search("pad", &options, &registry)?;
+        assert_eq!(page.total, 3);
+        names.extend(page.results.iter().map(|result| result.name.clone()));
+        match page.next_page(&options) {
+            Some(next) => options = next,
+            None => break,
+        }
+    }
+    assert_eq!(names, vec!["pad", "left-pad", "right-pad"]);
+    assert!(search("  ", &SearchOptions::default(), &registry)?.results.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_show_package_info() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "debug", "version": "2.6.9", "dependencies": {"ms": "2.0.0"}}"#,
+        r#"{"name": "debug", "version": "3.0.0-rc.1"}"#,
+        r#"{"name": "debug", "version": "2.2.0", "maintainers": [{"name": "tj"}]}"#,
+    ] {
+        registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+    }
+    registry.add_dist_tag("debug", "latest", "2.6.9");
+    registry.add_dist_tag("debug", "next", "3.0.0-rc.1");
+    let options = ResolutionOptions::default();
+
+    let package = match info("debug", None, &registry, &options)? {
+        Info::Package(package) => package,
+        other => panic!("Expected the whole package, got {:?}", other),
+    };
+    assert_eq!(package.version, "2.6.9");
+    assert_eq!(package.metadata.dependencies["ms"], "2.0.0");
+    assert_eq!(package.versions, vec!["2.6.9", "3.0.0-rc.1", "2.2.0"]);
+
+    let field = |spec: &str, path: &str| match info(spec, Some(path), &registry, &options) {
+        Ok(Info::Field(value)) => value,
+        other => panic!("Expected a field, got {:?}", other),
+    };
+    assert_eq!(field("debug@next", "version"), Some("3.0.0-rc.1".into()));
+    assert_eq!(field("debug@~2.2", "dist.tarball"), Some(MemoryRegistry::tarball_url("debug", "2.2.0").into()));
+    assert_eq!(field("debug", "dependencies.ms"), Some("2.0.0".into()));
+    assert_eq!(field("debug", "dist-tags.next"), Some("3.0.0-rc.1".into()));
+    assert_eq!(field("debug", "versions.1"), Some("3.0.0-rc.1".into()));
+    assert_eq!(field("debug", "repository.url"), None);
+    assert!(matches!(info("debug@^4", None, &registry, &options), Err(NaryError::NoMatchingVersion { .. })));
+
+    Ok(())
+}
@@ -0,0 +1,409 @@
+use nary_lib::lockfile::{import_npm, import_pnpm, import_yarn};
+use nary_lib::{read_or_import, Dependency, DependencyKind, NaryError};
+
+use indoc::indoc;
+use std::{fs, path::Path};
+
+use anyhow::Result;
+
+const PACKAGE_LOCK: &str = indoc!(
+    r#"
+    {
+      "name": "app",
+      "version": "1.0.0",
+      "lockfileVersion": 3,
+      "requires": true,
+      "packages": {
+        "": {
+          "name": "app",
+          "version": "1.0.0",
+          "dependencies": {
+            "express": "^4.17.0",
+            "my-lodash": "npm:lodash@^4.17.0",
+            "widget": "file:packages/widget"
+          },
+          "devDependencies": {
+            "mocha": "^10.0.0"
+          }
+        },
+        "node_modules/debug": {
+          "version": "2.6.9",
+          "resolved": "https://registry.npmjs.org/debug/-/debug-2.6.9.tgz",
+          "integrity": "sha512-debug",
+          "dependencies": {
+            "ms": "2.0.0"
+          }
+        },
+        "node_modules/express": {
+          "version": "4.17.1",
+          "resolved": "https://registry.npmjs.org/express/-/express-4.17.1.tgz",
+          "integrity": "sha512-express",
+          "dependencies": {
+            "debug": "2.6.9",
+            "ms": "^2.1.0"
+          },
+          "optionalDependencies": {
+            "fsevents": "^2.0.0"
+          },
+          "peerDependencies": {
+            "react": "*"
+          }
+        },
+        "node_modules/express/node_modules/ms": {
+          "version": "2.1.3",
+          "resolved": "https://registry.npmjs.org/ms/-/ms-2.1.3.tgz",
+          "integrity": "sha512-ms-new"
+        },
+        "node_modules/mocha": {
+          "version": "10.2.0",
+          "dev": true
+        },
+        "node_modules/ms": {
+          "version": "2.0.0",
+          "resolved": "https://registry.npmjs.org/ms/-/ms-2.0.0.tgz",
+          "integrity": "sha512-ms-old"
+        },
+        "node_modules/my-lodash": {
+          "name": "lodash",
+          "version": "4.17.21",
+          "resolved": "https://registry.npmjs.org/lodash/-/lodash-4.17.21.tgz",
+          "integrity": "sha512-lodash"
+        },
+        "node_modules/widget": {
+          "resolved": "packages/widget",
+          "link": true
+        },
+        "packages/widget": {
+          "version": "0.1.0",
+          "dependencies": {
+            "ms": "2.0.0"
+          }
+        }
+      }
+    }
+    "#
+);
+
+#[test]
+fn it_will_import_npm_lockfiles() -> Result<()> {
+    let project = tempfile::tempdir()?;
+    fs::create_dir_all(project.path().join("packages").join("widget"))?;
+    fs::write(project.path().join("package-lock.json"), PACKAGE_LOCK)?;
+
+    let lockfile = import_npm(&project.path().join("package-lock.json"))?;
+    assert_eq!(lockfile.name, "app");
+    let mut keys: Vec<&str> = lockfile.packages.keys().map(String::as_str).collect();
+    let widget = project.path().join("packages").join("widget").canonicalize()?;
+    let widget_key = format!("widget@file:{}", widget.display());
+    keys.retain(|key| *key != widget_key);
+    // Dev dependencies aren't installed by nary, so they aren't imported
+    assert_eq!(
+        keys,
+        vec!["debug@2.6.9", "express@4.17.1", "ms@2.0.0", "ms@2.1.3", "my-lodash@npm:lodash@4.17.21"]
+    );
+
+    let express = &lockfile.packages["express@4.17.1"];
+    assert_eq!(express.integrity.as_deref(), Some("sha512-express"));
+    assert_eq!(express.dependencies["ms"].package, "ms@2.1.3");
+    assert_eq!(express.dependencies["ms"].range, "^2.1.0");
+    assert_eq!(express.dependencies["debug"].package, "debug@2.6.9");
+    assert!(!express.dependencies.contains_key("fsevents"));
+    assert!(!express.dependencies.contains_key("react"));
+    assert_eq!(lockfile.packages["debug@2.6.9"].dependencies["ms"].package, "ms@2.0.0");
+    assert_eq!(lockfile.packages["my-lodash@npm:lodash@4.17.21"].alias_of.as_deref(), Some("lodash"));
+    assert_eq!(lockfile.packages[&widget_key].dependencies["ms"].package, "ms@2.0.0");
+    assert_eq!(lockfile.dependencies["widget"].range, format!("file:{}", widget.display()));
+
+    let graph = lockfile.to_graph()?;
+    assert_eq!(graph.versions_of("ms").len(), 2);
+
+    // It stands in for nary's own lockfile until there is one
+    let dependency =
+        |name: &str, version: &str| (Dependency::new(name.to_string(), version.to_string()), DependencyKind::Normal);
+    let imported = read_or_import(project.path())?.unwrap();
+    imported.check_sync(&[
+        dependency("express", "^4.17.0"),
+        dependency("my-lodash", "npm:lodash@^4.17.0"),
+        dependency("widget", &format!("file:{}", widget.display())),
+    ])?;
+    assert!(matches!(
+        imported.check_sync(&[dependency("express", "^4.18.0")]),
+        Err(NaryError::LockfileOutOfSync { .. })
+    ));
+
+    fs::write(project.path().join("package-lock.json"), r#"{"lockfileVersion": 1, "dependencies": {}}"#)?;
+    assert!(matches!(
+        import_npm(&project.path().join("package-lock.json")),
+        Err(NaryError::InvalidLockfile { .. })
+    ));
+
+    Ok(())
+}
+
+const MANIFEST: &str = r#"{
+    "name": "app",
+    "version": "1.0.0",
+    "dependencies": {"express": "^4.17.0", "my-lodash": "npm:lodash@^4.17.0"}
+}"#;
+
+/// The keys of the imported packages, and what express's dependencies resolved to
+fn imported(lockfile: &nary_lib::Lockfile) -> (Vec<&str>, Vec<&str>) {
+    let keys = lockfile.packages.keys().map(String::as_str).collect();
+    let express = &lockfile.packages["express@4.17.1"];
+    let dependencies = express.dependencies.values().map(|dependency| dependency.package.as_str()).collect();
+    (keys, dependencies)
+}
+
+fn write_project(dir: &Path, lockfile_name: &str, lockfile: &str) -> Result<()> {
+    fs::write(dir.join("package.json"), MANIFEST)?;
+    fs::write(dir.join(lockfile_name), lockfile)?;
+    Ok(())
+}
+
+#[test]
+fn it_will_import_yarn_lockfiles() -> Result<()> {
+    let classic = indoc!(
+        r#"
+        # THIS IS AN AUTOGENERATED FILE. DO NOT EDIT THIS FILE DIRECTLY.
+        # yarn lockfile v1
+
+
+        debug@2.6.9:
+          version "2.6.9"
+          resolved "https://registry.yarnpkg.com/debug/-/debug-2.6.9.tgz#5d128515df134ff327e90a4c93f4e077a536341f"
+          integrity sha512-debug
+          dependencies:
+            ms "2.0.0"
+
+        express@^4.17.0:
+          version "4.17.1"
+          resolved "https://registry.yarnpkg.com/express/-/express-4.17.1.tgz#4491fc38605cf51f8629d39c2b5d026f98a4c134"
+          integrity sha512-express
+          dependencies:
+            debug "2.6.9"
+            ms "^2.1.0"
+          optionalDependencies:
+            fsevents "^2.0.0"
+
+        ms@2.0.0:
+          version "2.0.0"
+          resolved "https://registry.yarnpkg.com/ms/-/ms-2.0.0.tgz#5608aeadfc00be6c2901df5f9861788de0d597c8"
+
+        ms@^2.1.0:
+          version "2.1.3"
+          resolved "https://registry.yarnpkg.com/ms/-/ms-2.1.3.tgz#574c8138ce1d2b5861f0b44579dbadd60c6615b2"
+
+        "my-lodash@npm:lodash@^4.17.0":
+          version "4.17.21"
+          resolved "https://registry.yarnpkg.com/lodash/-/lodash-4.17.21.tgz#679591c564c3bffaae8454cf0b3df370c3d6911c"
+          integrity sha512-lodash
+        "#
+    );
+    let project = tempfile::tempdir()?;
+    write_project(project.path(), "yarn.lock", classic)?;
+    let lockfile = import_yarn(&project.path().join("yarn.lock"))?;
+    let (keys, express) = imported(&lockfile);
+    assert_eq!(keys, vec!["debug@2.6.9", "express@4.17.1", "ms@2.0.0", "ms@2.1.3", "my-lodash@npm:lodash@4.17.21"]);
+    assert_eq!(express, vec!["debug@2.6.9", "ms@2.1.3"]);
+    let debug = &lockfile.packages["debug@2.6.9"];
+    assert_eq!(debug.resolved.as_deref(), Some("https://registry.yarnpkg.com/debug/-/debug-2.6.9.tgz"));
+    assert_eq!(debug.integrity.as_deref(), Some("sha512-debug"));
+    assert_eq!(lockfile.dependencies["express"].range, "^4.17.0");
+
+    let berry = indoc!(
+        r#"
+        # This file is generated by running "yarn install" inside your project.
+
+        __metadata:
+          version: 6
+          cacheKey: 8
+
+        "app@workspace:.":
+          version: 0.0.0-use.local
+          resolution: "app@workspace:."
+          dependencies:
+            express: ^4.17.0
+            my-lodash: "npm:lodash@^4.17.0"
+          languageName: unknown
+          linkType: soft
+
+        "debug@npm:2.6.9":
+          version: 2.6.9
+          resolution: "debug@npm:2.6.9"
+          dependencies:
+            ms: 2.0.0
+          checksum: d2f51589ca
+          languageName: node
+          linkType: hard
+
+        "express@npm:^4.17.0":
+          version: 4.17.1
+          resolution: "express@npm:4.17.1"
+          dependencies:
+            debug: 2.6.9
+            fsevents: ^2.0.0
+            ms: ^2.1.0
+          dependenciesMeta:
+            fsevents:
+              optional: true
+          checksum: d964e9e17a
+          languageName: node
+          linkType: hard
+
+        "ms@npm:2.0.0":
+          version: 2.0.0
+          resolution: "ms@npm:2.0.0"
+          languageName: node
+          linkType: hard
+
+        "ms@npm:^2.1.0":
+          version: 2.1.3
+          resolution: "ms@npm:2.1.3"
+          languageName: node
+          linkType: hard
+
+        "my-lodash@npm:lodash@^4.17.0":
+          version: 4.17.21
+          resolution: "lodash@npm:4.17.21"
+          languageName: node
+          linkType: hard
+        "#
+    );
+    write_project(project.path(), "yarn.lock", berry)?;
+    let lockfile = import_yarn(&project.path().join("yarn.lock"))?;
+    let (keys, express) = imported(&lockfile);
+    assert_eq!(keys, vec!["debug@2.6.9", "express@4.17.1", "ms@2.0.0", "ms@2.1.3", "my-lodash@npm:lodash@4.17.21"]);
+    assert_eq!(express, vec!["debug@2.6.9", "ms@2.1.3"]);
+    assert_eq!(lockfile.packages["my-lodash@npm:lodash@4.17.21"].alias_of.as_deref(), Some("lodash"));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_import_pnpm_lockfiles() -> Result<()> {
+    let v6 = indoc!(
+        r#"
+        lockfileVersion: '6.0'
+
+        dependencies:
+          express:
+            specifier: ^4.17.0
+            version: 4.17.1(react@18.2.0)
+          my-lodash:
+            specifier: npm:lodash@^4.17.0
+            version: /lodash@4.17.21
+
+        packages:
+
+          /debug@2.6.9:
+            resolution: {integrity: sha512-debug}
+            dependencies:
+              ms: 2.0.0
+            dev: false
+
+          /express@4.17.1(react@18.2.0):
+            resolution: {integrity: sha512-express}
+            peerDependencies:
+              react: '*'
+            dependencies:
+              debug: 2.6.9
+              ms: 2.1.3
+              react: 18.2.0
+            optionalDependencies:
+              fsevents: 2.3.3
+            dev: false
+
+          /lodash@4.17.21:
+            resolution: {integrity: sha512-lodash}
+            dev: false
+
+          /ms@2.0.0:
+            resolution: {integrity: sha512-ms-old}
+            dev: false
+
+          /ms@2.1.3:
+            resolution: {integrity: sha512-ms-new}
+            dev: false
+
+          /react@18.2.0:
+            resolution: {integrity: sha512-react}
+            dev: false
+        "#
+    );
+    let project = tempfile::tempdir()?;
+    write_project(project.path(), "pnpm-lock.yaml", v6)?;
+    let lockfile = import_pnpm(&project.path().join("pnpm-lock.yaml"))?;
+    let (keys, express) = imported(&lockfile);
+    assert_eq!(
+        keys,
+        vec!["debug@2.6.9", "express@4.17.1", "ms@2.0.0", "ms@2.1.3", "my-lodash@npm:lodash@4.17.21", "react@18.2.0"]
+    );
+    assert_eq!(express, vec!["debug@2.6.9", "ms@2.1.3", "react@18.2.0"]);
+    assert_eq!(lockfile.packages["express@4.17.1"].integrity.as_deref(), Some("sha512-express"));
+    assert_eq!(lockfile.dependencies["my-lodash"].range, "npm:lodash@^4.17.0");
+
+    let v9 = indoc!(
+        r#"
+        lockfileVersion: '9.0'
+
+        importers:
+
+          .:
+            dependencies:
+              express:
+                specifier: ^4.17.0
+                version: 4.17.1(react@18.2.0)
+              my-lodash:
+                specifier: npm:lodash@^4.17.0
+                version: lodash@4.17.21
+
+        packages:
+
+          debug@2.6.9:
+            resolution: {integrity: sha512-debug}
+
+          express@4.17.1:
+            resolution: {integrity: sha512-express}
+            peerDependencies:
+              react: '*'
+
+          lodash@4.17.21:
+            resolution: {integrity: sha512-lodash}
+
+          ms@2.0.0:
+            resolution: {integrity: sha512-ms-old}
+
+          ms@2.1.3:
+            resolution: {integrity: sha512-ms-new}
+
+          react@18.2.0:
+            resolution: {integrity: sha512-react}
+
+        snapshots:
+
+          debug@2.6.9:
+            dependencies:
+              ms: 2.0.0
+
+          express@4.17.1(react@18.2.0):
+            dependencies:
+              debug: 2.6.9
+              ms: 2.1.3
+              react: 18.2.0
+
+          lodash@4.17.21: {}
+
+          ms@2.0.0: {}
+
+          ms@2.1.3: {}
+
+          react@18.2.0: {}
+        "#
+    );
+    write_project(project.path(), "pnpm-lock.yaml", v9)?;
+    let from_v9 = import_pnpm(&project.path().join("pnpm-lock.yaml"))?;
+    assert_eq!(from_v9, lockfile);
+    assert_eq!(read_or_import(project.path())?, Some(lockfile));
+
+    Ok(())
+}
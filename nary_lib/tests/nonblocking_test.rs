@@ -0,0 +1,108 @@
+#![cfg(feature = "nonblocking")]
+
+use nary_lib::nonblocking::{self, SpawnBlocking, ThreadPerCall};
+use nary_lib::{
+    calculate_depends, Advisory, Dependency, DependencyKind, MemoryRegistry, RegistryClient, ResolutionOptions,
+    Severity, SilentReporter,
+};
+
+use futures_executor::block_on;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+
+mod common;
+
+/// Runs work on a thread per call, counting how much it was given
+#[derive(Default)]
+struct CountingSpawner(AtomicUsize);
+
+impl SpawnBlocking for CountingSpawner {
+    fn spawn_blocking(&self, work: Box<dyn FnOnce() + Send>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        ThreadPerCall.spawn_blocking(work);
+    }
+}
+
+/// Never runs anything, like an executor that's shutting down
+struct DroppingSpawner;
+
+impl SpawnBlocking for DroppingSpawner {
+    fn spawn_blocking(&self, _work: Box<dyn FnOnce() + Send>) {}
+}
+
+#[test]
+fn it_will_resolve_and_audit_without_blocking() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "a", "version": "1.0.0", "dependencies": {"vulnerable": "^1.0.0"}}"#,
+        r#"{"name": "vulnerable", "version": "1.0.0"}"#,
+    ] {
+        registry.add_manifest(&serde_json::from_str(manifest)?, Vec::new())?;
+    }
+    registry.add_advisory(
+        "vulnerable",
+        Advisory {
+            id: 1,
+            title: "Advisory 1".to_string(),
+            url: "https://example.com/advisories/1".to_string(),
+            severity: Severity::High,
+            vulnerable_versions: "<2.0.0".to_string(),
+        },
+    );
+    let registry: Arc<dyn RegistryClient> = Arc::new(registry);
+
+    let spawner = CountingSpawner::default();
+    let root = Dependency::new("app", "1.0.0");
+    let a = (Dependency::new("a", "^1.0.0"), DependencyKind::Normal);
+    let resolving = nonblocking::calculate_depends(
+        &spawner,
+        root.clone(),
+        vec![a.clone()],
+        registry.clone(),
+        ResolutionOptions::default(),
+        Arc::new(SilentReporter),
+    );
+    let graph = block_on(resolving)?;
+    let blocking = calculate_depends(&root, &[a], &*registry, &ResolutionOptions::default(), &SilentReporter)?;
+    assert_eq!(graph.edges(), blocking.edges());
+
+    let auditing = nonblocking::audit(&spawner, Arc::new(graph), registry.clone(), ResolutionOptions::default());
+    let report = block_on(auditing)?;
+    let found: Vec<String> =
+        report.vulnerabilities.iter().map(|found| format!("{}@{}", found.name, found.version)).collect();
+    assert_eq!(found, vec!["vulnerable@1.0.0"]);
+    assert_eq!(spawner.0.load(Ordering::SeqCst), 2);
+
+    // Errors come back through the future like they would from the blocking call
+    let missing = (Dependency::new("missing", "^1.0.0"), DependencyKind::Normal);
+    let resolving = nonblocking::calculate_depends(
+        &ThreadPerCall,
+        root.clone(),
+        vec![missing],
+        registry.clone(),
+        ResolutionOptions::default(),
+        Arc::new(SilentReporter),
+    );
+    assert!(block_on(resolving).is_err());
+
+    // Work the spawner drops panics whoever waits on it, rather than leaving them waiting forever
+    let dropped = nonblocking::calculate_depends(
+        &DroppingSpawner,
+        root,
+        Vec::new(),
+        registry,
+        ResolutionOptions::default(),
+        Arc::new(SilentReporter),
+    );
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| block_on(dropped))).is_err());
+
+    Ok(())
+}
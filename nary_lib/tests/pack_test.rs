@@ -0,0 +1,1829 @@
+use nary_lib::bin::{bins, cmd_shim, ps1_shim};
+use nary_lib::{link, scripts};
+use nary_lib::{
+    cache, calculate_depends, collect_licenses, create_package_tarball, execute_plan, install_dep, install_frozen,
+    install_global, install_graph, list_global, package_files, path_to_dependencies, plan_install, prune, publish,
+    read_lockfile, read_manifest, read_tree, uninstall, uninstall_global, unpack_package, use_link, verify_install,
+    verify_version, write_lockfile,
+    Dependency,
+    DependencyKind, GlobalPrefix, InstallFs, InstallHooks, InstallOptions, InstallReporter, InstallStats, InstallStrategy,
+    Layout, LicensePolicy, NoHooks,
+    Lockfile, MemoryEntry, MemoryFs, MemoryRegistry, Enforcement, MismatchReason, NaryError, PackageName, PackumentVersion, PublishOptions,
+    Packument, RegistryClient, RegistryConfig, ResolutionOptions, ResolvedGraph, SigningKey, SilentReporter, VerifyPolicy,
+    LINKS_DIR_VAR, STAGING_DIR,
+};
+
+use flate2::{write::GzEncoder, Compression};
+use hyper::Url;
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Metadata, Subscriber,
+};
+
+use anyhow::Result;
+
+mod common;
+
+#[derive(Default)]
+struct WarningReporter {
+    warnings: Mutex<Vec<String>>,
+}
+
+impl InstallReporter for WarningReporter {
+    fn on_warning(&self, message: &str) {
+        self.warnings.lock().unwrap().push(message.to_string());
+    }
+}
+
+fn tarball(files: &[(&str, &str)]) -> Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, contents.as_bytes())?;
+    }
+
+    Ok(builder.into_inner()?.finish()?)
+}
+
+#[test]
+fn it_will_parse_scoped_names() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let name = PackageName::parse("@babel/core")?;
+
+    assert_eq!(name.scope.as_deref(), Some("babel"));
+    assert_eq!(name.name, "core");
+    assert_eq!(name.registry_path(), "@babel%2fcore");
+    assert_eq!(name.to_path(), std::path::Path::new("@babel").join("core"));
+    assert_eq!(name.to_string(), "@babel/core");
+
+    assert!(PackageName::parse("@babel").is_err());
+    assert!(PackageName::parse("@babel/../../etc").is_err());
+    assert!(PackageName::parse("..").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_unpack_scoped_tarball() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let node_modules = tempfile::tempdir()?;
+    let url = Url::parse("https://registry.npmjs.org/@babel/core/-/core-7.0.0.tgz")?;
+    let tarball = tarball(&[
+        ("package/package.json", r#"{"name":"@babel/core","version":"7.0.0"}"#),
+        ("package/lib/index.js", "module.exports = {};"),
+    ])?;
+
+    let path = unpack_package(
+        node_modules.path(),
+        &PackageName::parse("@babel/core")?,
+        tarball,
+        &url,
+        &SilentReporter,
+    )?;
+
+    assert_eq!(path, node_modules.path().join("@babel").join("core"));
+    assert!(path.join("package.json").is_file());
+    assert_eq!(fs::read_to_string(path.join("lib").join("index.js"))?, "module.exports = {};");
+
+    Ok(())
+}
+
+#[test]
+fn it_will_install_tarball_url_dependencies() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let url = "https://example.com/downloads/widget-1.2.3.tgz";
+    let tarball = tarball(&[
+        (
+            "package/package.json",
+            r#"{"name": "widget", "version": "1.2.3", "dependencies": {"ms": "^2.0.0"}}"#,
+        ),
+        ("package/index.js", "module.exports = 'widget';"),
+    ])?;
+    assert_eq!(read_manifest(&tarball, &Url::parse(url)?)?["version"], "1.2.3");
+
+    let registry = MemoryRegistry::new();
+    registry.add_tarball(url, tarball);
+    registry.add_manifest(
+        &serde_json::from_str(r#"{"name": "ms", "version": "2.0.0"}"#)?,
+        Vec::new(),
+    )?;
+
+    let root = Dependency::new("app", "1.0.0");
+    let widget = Dependency::new("widget", url.to_string());
+    let graph = calculate_depends(
+        &root,
+        &[(widget.clone(), DependencyKind::Normal)],
+        &registry,
+        &ResolutionOptions::default(),
+        &SilentReporter,
+    )?;
+
+    let installed: Vec<String> = graph
+        .install_order()
+        .map(|node| format!("{}@{}", node.name, node.version))
+        .collect();
+    assert_eq!(installed, vec!["ms@2.0.0".to_string(), format!("widget@{}", url)]);
+
+    let node_modules = tempfile::tempdir()?;
+    install_dep(
+        node_modules.path(),
+        &widget,
+        DependencyKind::Normal,
+        &registry,
+        &InstallOptions::default(),
+        &SilentReporter,
+    )?;
+    assert_eq!(
+        fs::read_to_string(node_modules.path().join("widget").join("index.js"))?,
+        "module.exports = 'widget';"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn it_will_install_npm_aliases_under_the_alias() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    registry.add_manifest(
+        &serde_json::from_str(r#"{"name": "lodash", "version": "4.17.21"}"#)?,
+        tarball(&[
+            ("package/package.json", r#"{"name": "lodash", "version": "4.17.21"}"#),
+            ("package/lodash.js", "module.exports = {};"),
+        ])?,
+    )?;
+
+    let root = Dependency::new("app", "1.0.0");
+    let alias = (Dependency::new("my-lodash", "npm:lodash@^4.17.0"), DependencyKind::Normal);
+    let graph = calculate_depends(&root, &[alias], &registry, &ResolutionOptions::default(), &SilentReporter)?;
+
+    let node = graph.install_order().next().unwrap();
+    assert_eq!(node.name, "my-lodash");
+    assert_eq!(node.version, "4.17.21");
+    assert_eq!(node.package(), "lodash");
+    assert_eq!(node.dependency().version, "npm:lodash@4.17.21");
+    assert_eq!(graph.edges()[0].range, "npm:lodash@^4.17.0");
+
+    let node_modules = tempfile::tempdir()?;
+    install_dep(
+        node_modules.path(),
+        &node.dependency(),
+        DependencyKind::Normal,
+        &registry,
+        &InstallOptions::default(),
+        &SilentReporter,
+    )?;
+    assert!(node_modules.path().join("my-lodash").join("lodash.js").is_file());
+    assert!(!node_modules.path().join("lodash").exists());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_link_packages_from_the_store() -> Result<()> {
+    let (_guard, cache_dir) = common::isolated_cache()?;
+
+    let registry = MemoryRegistry::new();
+    registry.add_manifest(
+        &serde_json::from_str(r#"{"name": "ms", "version": "2.0.0"}"#)?,
+        tarball(&[
+            ("package/package.json", r#"{"name": "ms", "version": "2.0.0"}"#),
+            ("package/lib/index.js", "module.exports = 'ms';"),
+        ])?,
+    )?;
+    let ms = Dependency::new("ms", "2.0.0");
+
+    let mut installed = Vec::new();
+    for strategy in &[InstallStrategy::HardLink, InstallStrategy::HardLink, InstallStrategy::Reflink] {
+        let node_modules = tempfile::tempdir()?;
+        let options = InstallOptions {
+            strategy: *strategy,
+            ..InstallOptions::default()
+        };
+        install_dep(node_modules.path(), &ms, DependencyKind::Normal, &registry, &options, &SilentReporter)?;
+        // Installing again replaces the links
+        install_dep(node_modules.path(), &ms, DependencyKind::Normal, &registry, &options, &SilentReporter)?;
+
+        let index = node_modules.path().join("ms").join("lib").join("index.js");
+        assert_eq!(fs::read_to_string(&index)?, "module.exports = 'ms';");
+        installed.push((node_modules, index));
+    }
+
+    // Extracted into the store once
+    assert_eq!(fs::read_dir(cache_dir.path().join("store-v1"))?.count(), 1);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let inode = |index: &std::path::Path| fs::metadata(index).map(|metadata| metadata.ino());
+        assert_eq!(inode(&installed[0].1)?, inode(&installed[1].1)?);
+        assert_ne!(inode(&installed[0].1)?, inode(&installed[2].1)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn it_will_install_isolated_layouts() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for (manifest, file) in &[
+        (r#"{"name": "express", "version": "4.17.1", "dependencies": {"debug": "2.6.9"}}"#, "express.js"),
+        (r#"{"name": "debug", "version": "2.6.9"}"#, "debug.js"),
+    ] {
+        let manifest: serde_json::Value = serde_json::from_str(manifest)?;
+        let contents = manifest.to_string();
+        registry.add_manifest(
+            &manifest,
+            tarball(&[("package/package.json", &contents), (&format!("package/{}", file), "")])?,
+        )?;
+    }
+
+    let root = Dependency::new("app", "1.0.0");
+    let express = (Dependency::new("express", "^4.17.0"), DependencyKind::Normal);
+    let graph = calculate_depends(&root, &[express], &registry, &ResolutionOptions::default(), &SilentReporter)?;
+
+    let hoisted = tempfile::tempdir()?;
+    install_graph(hoisted.path(), &graph, &registry, &InstallOptions::default(), &SilentReporter)?;
+    assert!(hoisted.path().join("express").join("express.js").is_file());
+    assert!(hoisted.path().join("debug").join("debug.js").is_file());
+
+    let isolated = tempfile::tempdir()?;
+    let options = InstallOptions {
+        layout: Layout::Isolated,
+        ..InstallOptions::default()
+    };
+    install_graph(isolated.path(), &graph, &registry, &options, &SilentReporter)?;
+
+    let express = isolated.path().join("express");
+    assert!(fs::symlink_metadata(&express)?.file_type().is_symlink());
+    assert!(express.join("express.js").is_file());
+    // debug isn't a direct dependency, so only express can see it
+    assert!(!isolated.path().join("debug").exists());
+    let store = isolated.path().join(".nary");
+    assert!(store.join("express@4.17.1").join("node_modules").join("debug").join("debug.js").is_file());
+    assert!(store.join("debug@2.6.9").join("node_modules").join("debug").join("debug.js").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_install_vendored_layouts() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "widget", "version": "2.0.0", "dependencies": {"@scope/icons": "^1.0.0"}, "bin": "cli.js"}"#,
+        r#"{"name": "@scope/icons", "version": "1.0.0"}"#,
+        r#"{"name": "@scope/icons", "version": "1.1.0"}"#,
+    ] {
+        let manifest: serde_json::Value = serde_json::from_str(manifest)?;
+        let contents = manifest.to_string();
+        registry.add_manifest(&manifest, tarball(&[("package/package.json", &contents), ("package/cli.js", "")])?)?;
+    }
+
+    let root = Dependency::new("app", "1.0.0");
+    let dependency =
+        |name: &str, version: &str| (Dependency::new(name.to_string(), version.to_string()), DependencyKind::Normal);
+    let resolution = ResolutionOptions::default();
+    let dependencies = [dependency("widget", "^2.0.0"), dependency("@scope/icons", "1.0.0")];
+    let graph = calculate_depends(&root, &dependencies, &registry, &resolution, &SilentReporter)?;
+
+    let vendor = tempfile::tempdir()?;
+    let options = InstallOptions {
+        layout: Layout::Vendored,
+        ..InstallOptions::default()
+    };
+    install_graph(vendor.path(), &graph, &registry, &options, &SilentReporter)?;
+    // Both versions side by side, whatever depends on them
+    for dir in &["widget@2.0.0", "@scope+icons@1.0.0", "@scope+icons@1.1.0"] {
+        assert!(vendor.path().join(dir).join("package.json").is_file(), "{}", dir);
+    }
+    assert!(!vendor.path().join(".bin").exists());
+    assert!(!vendor.path().join("widget").exists());
+    assert!(plan_install(vendor.path(), &graph, &registry, &options)?.is_empty());
+
+    let graph = calculate_depends(&root, &dependencies[1..], &registry, &resolution, &SilentReporter)?;
+    let removed = prune(vendor.path(), &graph)?;
+    let mut removed: Vec<&str> = removed.iter().map(|package| package.name.as_str()).collect();
+    removed.sort_unstable();
+    assert_eq!(removed, vec!["@scope/icons", "widget"]);
+    assert!(vendor.path().join("@scope+icons@1.0.0").is_dir());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_plan_installs_before_running_them() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    let express = format!(
+        r#"{{"name": "express", "version": "4.17.1", "dependencies": {{"debug": "2.6.9"}}, "hasInstallScript": true,
+            "dist": {{"tarball": "{}", "unpackedSize": 1234}}}}"#,
+        MemoryRegistry::tarball_url("express", "4.17.1")
+    );
+    for manifest in &[express.as_str(), r#"{"name": "debug", "version": "2.6.9"}"#] {
+        let manifest: serde_json::Value = serde_json::from_str(manifest)?;
+        let contents = manifest.to_string();
+        registry.add_manifest(&manifest, tarball(&[("package/package.json", &contents)])?)?;
+    }
+
+    let root = Dependency::new("app", "1.0.0");
+    let express = (Dependency::new("express", "^4.17.0"), DependencyKind::Normal);
+    let graph = calculate_depends(&root, &[express], &registry, &ResolutionOptions::default(), &SilentReporter)?;
+
+    let node_modules = tempfile::tempdir()?;
+    for (dir, version) in &[("debug", "2.0.0"), ("left-pad", "1.3.0"), ("@old/pkg", "0.1.0")] {
+        let dir = node_modules.path().join(dir);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("package.json"), format!(r#"{{"version": "{}"}}"#, version))?;
+    }
+
+    let options = InstallOptions::default();
+    let plan = plan_install(node_modules.path(), &graph, &registry, &options)?;
+    let names = |packages: Vec<&str>| packages.into_iter().map(str::to_string).collect::<Vec<_>>();
+    assert_eq!(plan.add.iter().map(|package| package.name.clone()).collect::<Vec<_>>(), names(vec!["express"]));
+    assert_eq!(plan.update.len(), 1);
+    assert_eq!(plan.update[0].installed.as_deref(), Some("2.0.0"));
+    assert_eq!(plan.update[0].version, "2.6.9");
+    let removed: Vec<String> = plan.remove.iter().map(|package| package.name.clone()).collect();
+    assert_eq!(removed, names(vec!["@old/pkg", "left-pad"]));
+    assert_eq!(plan.download_size(), 1234);
+    assert_eq!(plan.scripts().map(|package| package.name.as_str()).collect::<Vec<_>>(), vec!["express"]);
+    // Planning alone changes nothing
+    assert!(node_modules.path().join("left-pad").is_dir());
+    assert!(!node_modules.path().join("express").exists());
+
+    execute_plan(node_modules.path(), &plan, &graph, &registry, &options, &SilentReporter)?;
+    assert!(node_modules.path().join("express").join("package.json").is_file());
+    assert!(!node_modules.path().join("left-pad").exists());
+    assert!(!node_modules.path().join("@old").join("pkg").exists());
+    assert!(plan_install(node_modules.path(), &graph, &registry, &options)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_skip_packages_that_are_up_to_date() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    #[derive(Default)]
+    struct CountingReporter {
+        unpacked: Mutex<usize>,
+        up_to_date: Mutex<usize>,
+    }
+    impl InstallReporter for CountingReporter {
+        fn on_unpack(&self, _name: &str, _version: &str, _path: &std::path::Path) {
+            *self.unpacked.lock().unwrap() += 1;
+        }
+        fn on_up_to_date(&self, _name: &str, _version: &str, _path: &std::path::Path) {
+            *self.up_to_date.lock().unwrap() += 1;
+        }
+    }
+
+    let registry = MemoryRegistry::new();
+    let package = serde_json::json!({"name": "debug", "version": "2.6.9"});
+    let publish = |index: &str| -> Result<()> {
+        let tarball = tarball(&[("package/package.json", &package.to_string()), ("package/index.js", index)])?;
+        let mut manifest = package.clone();
+        manifest["dist"] = serde_json::json!({
+            "tarball": MemoryRegistry::tarball_url("debug", "2.6.9"),
+            "integrity": cache::integrity_of(&tarball),
+        });
+        Ok(registry.add_manifest(&manifest, tarball)?)
+    };
+    publish("")?;
+
+    let node_modules = tempfile::tempdir()?;
+    let debug = Dependency::new("debug", "2.6.9");
+    let reporter = CountingReporter::default();
+    let install = |reporter: &CountingReporter| {
+        let options = InstallOptions::default();
+        install_dep(node_modules.path(), &debug, DependencyKind::Normal, &registry, &options, reporter)
+    };
+
+    install(&reporter)?;
+    let index = node_modules.path().join("debug").join("index.js");
+    fs::remove_file(&index)?;
+    install(&reporter)?;
+    assert_eq!((*reporter.unpacked.lock().unwrap(), *reporter.up_to_date.lock().unwrap()), (1, 1));
+    assert!(!index.exists());
+
+    // Another tarball published under the same version is unpacked again
+    publish("// republished")?;
+    install(&reporter)?;
+    assert_eq!((*reporter.unpacked.lock().unwrap(), *reporter.up_to_date.lock().unwrap()), (2, 1));
+    assert!(index.is_file());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_prune_extraneous_packages() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "express", "version": "4.17.1", "dependencies": {"debug": "2.6.9"}, "bin": "cli.js"}"#,
+        r#"{"name": "@types/node", "version": "14.0.0"}"#,
+        r#"{"name": "debug", "version": "2.6.9"}"#,
+    ] {
+        let manifest: serde_json::Value = serde_json::from_str(manifest)?;
+        let contents = manifest.to_string();
+        registry.add_manifest(&manifest, tarball(&[("package/package.json", &contents), ("package/cli.js", "")])?)?;
+    }
+
+    let root = Dependency::new("app", "1.0.0");
+    let dependency =
+        |name: &str, version: &str| (Dependency::new(name.to_string(), version.to_string()), DependencyKind::Normal);
+    let before = [dependency("express", "^4.17.0"), dependency("@types/node", "^14.0.0")];
+    let after = [dependency("debug", "^2.6.0")];
+    let options = ResolutionOptions::default();
+    let before = calculate_depends(&root, &before, &registry, &options, &SilentReporter)?;
+    let after = calculate_depends(&root, &after, &registry, &options, &SilentReporter)?;
+
+    let node_modules = tempfile::tempdir()?;
+    install_graph(node_modules.path(), &before, &registry, &InstallOptions::default(), &SilentReporter)?;
+    assert!(node_modules.path().join("@types").join("node").is_dir());
+    #[cfg(unix)]
+    assert!(node_modules.path().join(".bin").join("express").is_file());
+
+    let removed = prune(node_modules.path(), &after)?;
+    let mut removed: Vec<&str> = removed.iter().map(|package| package.name.as_str()).collect();
+    removed.sort_unstable();
+    assert_eq!(removed, vec!["@types/node", "express"]);
+    assert!(!node_modules.path().join("express").exists());
+    assert!(!node_modules.path().join("@types").exists());
+    assert!(node_modules.path().join("debug").join("package.json").is_file());
+    #[cfg(unix)]
+    assert!(fs::symlink_metadata(node_modules.path().join(".bin").join("express")).is_err());
+
+    assert!(prune(node_modules.path(), &after)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_uninstall_packages_with_what_only_they_needed() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    let add = |manifest: &str| -> Result<()> {
+        let manifest: serde_json::Value = serde_json::from_str(manifest)?;
+        let contents = manifest.to_string();
+        registry.add_manifest(&manifest, tarball(&[("package/package.json", &contents), ("package/cli.js", "")])?)?;
+        Ok(())
+    };
+    add(r#"{"name": "express", "version": "4.17.1", "dependencies": {"debug": "2.6.9", "shared": "^1.0.0"},
+            "bin": "cli.js"}"#)?;
+    add(r#"{"name": "other", "version": "1.0.0", "dependencies": {"shared": "^1.0.0"}}"#)?;
+    add(r#"{"name": "debug", "version": "2.6.9"}"#)?;
+    add(r#"{"name": "shared", "version": "1.0.0"}"#)?;
+
+    let project = tempfile::tempdir()?;
+    fs::write(
+        project.path().join("package.json"),
+        r#"{"name": "app", "version": "1.0.0", "dependencies": {"express": "^4.17.0", "other": "^1.0.0"}}"#,
+    )?;
+    let (options, resolution) = (InstallOptions::default(), ResolutionOptions::default());
+    let root = Dependency::new("app", "1.0.0");
+    let dependencies = path_to_dependencies(project.path())?;
+    let graph = calculate_depends(&root, &dependencies, &registry, &resolution, &SilentReporter)?;
+    let node_modules = project.path().join("node_modules");
+    install_graph(&node_modules, &graph, &registry, &options, &SilentReporter)?;
+    write_lockfile(project.path(), &Lockfile::from_graph(&graph, &registry)?)?;
+    #[cfg(unix)]
+    assert!(node_modules.join(".bin").join("express").is_file());
+
+    // What's still depended on stays at the version it's locked at
+    add(r#"{"name": "shared", "version": "1.1.0"}"#)?;
+    let names = ["express".to_string(), "missing".to_string()];
+    let uninstalled = uninstall(project.path(), &names, &registry, &options, &resolution, &SilentReporter)?;
+    assert_eq!(uninstalled.removed, vec!["express".to_string()]);
+    let mut pruned: Vec<&str> = uninstalled.pruned.iter().map(|package| package.name.as_str()).collect();
+    pruned.sort_unstable();
+    assert_eq!(pruned, vec!["debug", "express"]);
+    assert!(!node_modules.join("express").exists());
+    assert!(!node_modules.join("debug").exists());
+    assert!(fs::read_to_string(node_modules.join("shared/package.json"))?.contains("1.0.0"));
+    #[cfg(unix)]
+    assert!(fs::symlink_metadata(node_modules.join(".bin").join("express")).is_err());
+
+    let manifest = fs::read_to_string(project.path().join("package.json"))?;
+    assert!(!manifest.contains("express"));
+    let lockfile = read_lockfile(project.path())?.unwrap();
+    let locked: Vec<&str> = lockfile.packages.values().map(|package| package.name.as_str()).collect();
+    assert!(!locked.contains(&"express") && !locked.contains(&"debug"));
+    lockfile.check_sync(&path_to_dependencies(project.path())?)?;
+
+    Ok(())
+}
+
+#[test]
+fn it_will_verify_installs_against_the_lockfile() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "express", "version": "4.17.1", "dependencies": {"debug": "2.6.9"}}"#,
+        r#"{"name": "debug", "version": "2.6.9"}"#,
+    ] {
+        let manifest: serde_json::Value = serde_json::from_str(manifest)?;
+        let contents = manifest.to_string();
+        registry.add_manifest(&manifest, tarball(&[("package/package.json", &contents), ("package/index.js", "")])?)?;
+    }
+
+    let root = Dependency::new("app", "1.0.0");
+    let dependencies = [(Dependency::new("express", "^4.17.0"), DependencyKind::Normal)];
+    let graph = calculate_depends(&root, &dependencies, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+
+    let project = tempfile::tempdir()?;
+    let node_modules = project.path().join("node_modules");
+    install_graph(&node_modules, &graph, &registry, &InstallOptions::default(), &SilentReporter)?;
+
+    let mut lockfile = Lockfile::from_graph(&graph, &registry)?;
+    lockfile.record_contents(&node_modules)?;
+    write_lockfile(project.path(), &lockfile)?;
+    let lockfile = read_lockfile(project.path())?.unwrap();
+    assert!(lockfile.packages["express@4.17.1"].contents.is_some());
+    assert_eq!(lockfile.dependencies["express"].package, "express@4.17.1");
+    let locked: Vec<String> = lockfile.to_graph()?.install_order().map(|node| node.name.clone()).collect();
+    assert_eq!(locked, vec!["debug", "express"]);
+
+    assert!(verify_install(project.path(), &lockfile)?.is_ok());
+
+    fs::write(node_modules.join("express").join("index.js"), "// patched")?;
+    fs::remove_dir_all(node_modules.join("debug"))?;
+    fs::create_dir_all(node_modules.join("left-pad"))?;
+    let verification = verify_install(project.path(), &lockfile)?;
+    assert_eq!(verification.missing.len(), 1);
+    assert_eq!(verification.missing[0].name, "debug");
+    assert_eq!(verification.mismatched.len(), 1);
+    assert_eq!(verification.mismatched[0].name, "express");
+    assert_eq!(verification.mismatched[0].reason, MismatchReason::Contents);
+    assert_eq!(verification.extraneous.len(), 1);
+    assert_eq!(verification.extraneous[0].name, "left-pad");
+
+    fs::write(node_modules.join("express").join("package.json"), r#"{"version": "4.18.0"}"#)?;
+    let verification = verify_install(project.path(), &lockfile)?;
+    assert_eq!(verification.mismatched[0].reason, MismatchReason::Version);
+    assert_eq!(verification.mismatched[0].installed.as_deref(), Some("4.18.0"));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_install_frozen_lockfiles() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        r#"{"name": "express", "version": "4.17.1", "dependencies": {"debug": "^2.6.0"}}"#,
+        r#"{"name": "debug", "version": "2.6.9"}"#,
+    ] {
+        let manifest: serde_json::Value = serde_json::from_str(manifest)?;
+        let contents = manifest.to_string();
+        registry.add_manifest(&manifest, tarball(&[("package/package.json", &contents)])?)?;
+    }
+
+    let project = tempfile::tempdir()?;
+    let write_manifest = |range: &str| {
+        let manifest = format!(r#"{{"name": "app", "version": "1.0.0", "dependencies": {{"express": "{}"}}}}"#, range);
+        fs::write(project.path().join("package.json"), manifest)
+    };
+    write_manifest("^4.17.0")?;
+    let options = InstallOptions::default();
+    assert!(matches!(
+        install_frozen(project.path(), &registry, &options, &SilentReporter),
+        Err(NaryError::NoLockfile { .. })
+    ));
+
+    let root = Dependency::new("app", "1.0.0");
+    let dependencies = path_to_dependencies(project.path())?;
+    let graph = calculate_depends(&root, &dependencies, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+    let mut lockfile = Lockfile::from_graph(&graph, &registry)?;
+    write_lockfile(project.path(), &lockfile)?;
+
+    // A newer debug in the registry is ignored, and whatever was in node_modules goes
+    let debug: serde_json::Value = serde_json::from_str(r#"{"name": "debug", "version": "2.6.10"}"#)?;
+    registry.add_manifest(&debug, tarball(&[("package/package.json", &debug.to_string())])?)?;
+    let node_modules = project.path().join("node_modules");
+    fs::create_dir_all(node_modules.join("left-pad"))?;
+    install_frozen(project.path(), &registry, &options, &SilentReporter)?;
+    let installed = fs::read_to_string(node_modules.join("debug").join("package.json"))?;
+    assert!(installed.contains("2.6.9"));
+    assert!(node_modules.join("express").is_dir());
+    assert!(!node_modules.join("left-pad").exists());
+
+    write_manifest("^4.18.0")?;
+    assert!(matches!(
+        install_frozen(project.path(), &registry, &options, &SilentReporter),
+        Err(NaryError::LockfileOutOfSync { .. })
+    ));
+    write_manifest("^4.17.0")?;
+
+    lockfile.packages.get_mut("debug@2.6.9").unwrap().integrity = Some("sha512-tampered".to_string());
+    write_lockfile(project.path(), &lockfile)?;
+    let tampered = install_frozen(project.path(), &registry, &options, &SilentReporter);
+    assert!(matches!(tampered, Err(NaryError::IntegrityMismatch { name, .. }) if name == "debug"));
+    assert!(node_modules.join("express").is_dir());
+
+    // The tarball comes from where it was locked, and has to be the one that was locked
+    let debug = tarball(&[("package/package.json", r#"{"name":"debug","version":"2.6.9"}"#)])?;
+    let newer = tarball(&[("package/package.json", r#"{"name":"debug","version":"2.6.10"}"#)])?;
+    let mirror = "memory://mirror/debug-2.6.9.tgz";
+    let integrity = cache::integrity_of(&debug);
+    let republished = serde_json::json!({
+        "name": "debug",
+        "version": "2.6.9",
+        "dist": {"tarball": MemoryRegistry::tarball_url("debug", "2.6.9"), "integrity": integrity},
+    });
+    registry.add_manifest(&republished, newer.clone())?;
+    registry.add_tarball(mirror, debug);
+    let locked = lockfile.packages.get_mut("debug@2.6.9").unwrap();
+    locked.integrity = Some(integrity);
+    locked.resolved = Some(mirror.to_string());
+    write_lockfile(project.path(), &lockfile)?;
+    install_frozen(project.path(), &registry, &options, &SilentReporter)?;
+    let installed = fs::read_to_string(node_modules.join("debug").join("package.json"))?;
+    assert!(installed.contains("2.6.9"));
+
+    // node_modules is replaced on the install's filesystem, and the one on disk is left alone
+    let memory = Arc::new(MemoryFs::new());
+    let in_memory = InstallOptions {
+        fs: memory.clone(),
+        ..InstallOptions::default()
+    };
+    fs::write(node_modules.join("marker"), "")?;
+    install_frozen(project.path(), &registry, &in_memory, &SilentReporter)?;
+    assert_eq!(memory.read(&node_modules.join("debug/package.json"))?, installed.as_bytes());
+    assert!(node_modules.join("marker").is_file());
+
+    registry.add_tarball(mirror, newer);
+    let swapped = install_frozen(project.path(), &registry, &options, &SilentReporter);
+    assert!(matches!(swapped, Err(NaryError::UnpackError { .. })));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_read_installed_trees() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let project = tempfile::tempdir()?;
+    let write_package = |dir: &std::path::Path, version: &str| -> Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("package.json"), format!(r#"{{"version": "{}"}}"#, version))?;
+        Ok(())
+    };
+    write_package(project.path(), "1.0.0")?;
+    let node_modules = project.path().join("node_modules");
+    write_package(&node_modules.join("express"), "4.17.1")?;
+    write_package(&node_modules.join("express").join("node_modules").join("@types").join("node"), "14.0.0")?;
+    fs::create_dir_all(node_modules.join(".bin"))?;
+
+    #[cfg(unix)]
+    {
+        // Links are followed, but not round in circles
+        std::os::unix::fs::symlink(project.path(), node_modules.join("app"))?;
+        std::os::unix::fs::symlink("express", node_modules.join("alias"))?;
+    }
+
+    let tree = read_tree(project.path())?;
+    assert_eq!(tree.version.as_deref(), Some("1.0.0"));
+    let express = tree.get("express").unwrap();
+    assert_eq!(express.version.as_deref(), Some("4.17.1"));
+    assert!(!express.is_link());
+    assert_eq!(express.get("@types/node").unwrap().version.as_deref(), Some("14.0.0"));
+    assert!(tree.get(".bin").is_none());
+
+    #[cfg(unix)]
+    {
+        let alias = tree.get("alias").unwrap();
+        assert_eq!(alias.link_target.as_deref(), Some(std::path::Path::new("express")));
+        assert_eq!(alias.realpath, express.realpath);
+        assert_eq!(alias.get("@types/node").unwrap().version.as_deref(), Some("14.0.0"));
+        let app = tree.get("app").unwrap();
+        assert!(app.is_link());
+        assert!(app.dependencies.is_empty());
+    }
+
+    let names: Vec<&str> = tree.descendants().iter().map(|tree| tree.name.as_str()).collect();
+    #[cfg(unix)]
+    assert_eq!(names, vec!["alias", "@types/node", "app", "express", "@types/node"]);
+    #[cfg(not(unix))]
+    assert_eq!(names, vec!["express", "@types/node"]);
+
+    Ok(())
+}
+
+/// A tarball with a single entry, named however the test likes
+fn raw_tarball(path: &str, entry_type: tar::EntryType, link_name: Option<&str>, mode: u32) -> Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let mut header = tar::Header::new_old();
+    header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+    if let Some(link_name) = link_name {
+        header.as_old_mut().linkname[..link_name.len()].copy_from_slice(link_name.as_bytes());
+    }
+    header.set_entry_type(entry_type);
+    header.set_size(if entry_type.is_file() { 4 } else { 0 });
+    header.set_mode(mode);
+    header.set_cksum();
+    let contents: &[u8] = if entry_type.is_file() { b"evil" } else { b"" };
+    builder.append(&header, contents)?;
+
+    Ok(builder.into_inner()?.finish()?)
+}
+
+#[test]
+fn it_will_refuse_entries_outside_the_package() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let url = Url::parse("https://example.com/evil-1.0.0.tgz")?;
+    let name = PackageName::parse("evil")?;
+    let sandbox = tempfile::tempdir()?;
+    let node_modules = sandbox.path().join("node_modules");
+    let unpack = |tarball| unpack_package(&node_modules, &name, tarball, &url, &SilentReporter);
+
+    for tarball in [
+        raw_tarball("package/../../escaped", tar::EntryType::Regular, None, 0o644)?,
+        raw_tarball("/tmp/escaped", tar::EntryType::Regular, None, 0o644)?,
+        raw_tarball("package/link", tar::EntryType::Symlink, Some("../../escaped"), 0o777)?,
+        raw_tarball("package/link", tar::EntryType::Symlink, Some("/etc/passwd"), 0o777)?,
+        raw_tarball("package/link", tar::EntryType::Link, Some("package/../../escaped"), 0o644)?,
+    ] {
+        assert!(unpack(tarball).is_err());
+    }
+    assert!(!sandbox.path().join("escaped").exists());
+    assert!(!node_modules.join("escaped").exists());
+
+    // Links within the package are fine, and any top-level directory name is dropped
+    let path = unpack(raw_tarball("node/lib/link", tar::EntryType::Symlink, Some("../index.js"), 0o777)?)?;
+    assert_eq!(fs::read_link(path.join("lib").join("link"))?, std::path::Path::new("../index.js"));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let path = unpack(raw_tarball("package/bin", tar::EntryType::Regular, None, 0o4755)?)?;
+        assert_eq!(fs::metadata(path.join("bin"))?.permissions().mode() & 0o7777, 0o755);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn it_will_link_bins_and_notice_case_collisions() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let manifest = serde_json::json!({"name": "@scope/tool", "bin": "./cli.js"});
+    assert_eq!(bins(&manifest), vec![("tool".to_string(), std::path::PathBuf::from("cli.js"))]);
+    let manifest = serde_json::json!({"bin": {"tool": "bin/tool.js", "../evil": "x.js", "escape": "../../x.js"}});
+    assert_eq!(bins(&manifest), vec![("tool".to_string(), std::path::Path::new("bin").join("tool.js"))]);
+
+    let target = std::path::Path::new("..").join("tool").join("cli.js");
+    assert!(cmd_shim(&target).contains(r#""%dp0%\..\tool\cli.js" %*"#));
+    assert!(ps1_shim(&target).contains(r#""$basedir/../tool/cli.js" $args"#));
+
+    let registry = MemoryRegistry::new();
+    registry.add_manifest(
+        &serde_json::from_str(r#"{"name": "tool", "version": "1.0.0"}"#)?,
+        tarball(&[
+            ("package/package.json", r#"{"name": "tool", "version": "1.0.0", "bin": "cli.js"}"#),
+            ("package/cli.js", "#!/usr/bin/env node"),
+            ("package/README", ""),
+            ("package/readme", ""),
+        ])?,
+    )?;
+    let tool = Dependency::new("tool", "1.0.0");
+
+    let node_modules = tempfile::tempdir()?;
+    let reporter = WarningReporter::default();
+    install_dep(node_modules.path(), &tool, DependencyKind::Normal, &registry, &InstallOptions::default(), &reporter)?;
+    let warnings = reporter.warnings.lock().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("README and readme"));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let bin = node_modules.path().join(".bin").join("tool");
+        assert_eq!(fs::read_link(&bin)?, target);
+        assert_eq!(fs::metadata(&bin)?.permissions().mode() & 0o111, 0o111);
+    }
+    #[cfg(windows)]
+    assert!(node_modules.path().join(".bin").join("tool.cmd").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_publish_packages() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+    fs::write(root.join("package.json"), r#"{"name": "mono", "private": true, "workspaces": ["packages/*"]}"#)?;
+    let project = root.join("packages").join("tool");
+    for (path, contents) in &[
+        ("package.json", r#"{"name": "@me/tool", "version": "1.0.0", "dependencies": {"helper": "workspace:^"}}"#),
+        ("index.js", "module.exports = 1"),
+        ("lib/util.js", "module.exports = 2"),
+        ("node_modules/ignored/index.js", ""),
+        ("nary-lock.json", "{}"),
+    ] {
+        let path = project.join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, contents)?;
+    }
+    fs::create_dir_all(root.join("packages").join("helper"))?;
+    fs::write(root.join("packages/helper/package.json"), r#"{"name": "helper", "version": "0.3.0"}"#)?;
+
+    let registry = MemoryRegistry::new();
+    let config = RegistryConfig::default();
+    let options = PublishOptions {
+        tag: "next".to_string(),
+        dry_run: true,
+        ..PublishOptions::default()
+    };
+    let dry_run = publish(&project, &registry, &config, &options)?;
+    let files: Vec<String> = dry_run.files.iter().map(|file| file.to_string_lossy().replace('\\', "/")).collect();
+    assert_eq!(files, vec!["index.js", "lib/util.js", "package.json"]);
+    assert_eq!(dry_run.shasum.len(), 40);
+    assert!(registry.packument(&PackageName::parse("@me/tool")?).is_err());
+
+    let published = publish(&project, &registry, &config, &PublishOptions { dry_run: false, ..options.clone() })?;
+    assert_eq!(published.integrity, dry_run.integrity);
+    let packument = registry.packument(&PackageName::parse("@me/tool")?)?;
+    assert_eq!(packument.dist_tags["next"], "1.0.0");
+    let dist = &packument.versions["1.0.0"].dist;
+    assert_eq!(dist.shasum.as_deref(), Some(published.shasum.as_str()));
+    assert_eq!(dist.integrity.as_deref(), Some(published.integrity.as_str()));
+
+    let url = Url::parse(&dist.tarball)?;
+    let tarball = registry.tarball(&PackageName::parse("@me/tool")?, "1.0.0", &url, &SilentReporter)?;
+    let manifest = read_manifest(&tarball, &url)?;
+    assert_eq!(manifest["dependencies"]["helper"], "^0.3.0");
+
+    // A version goes up once
+    let again = publish(&project, &registry, &config, &PublishOptions { dry_run: false, ..options });
+    assert!(matches!(again, Err(NaryError::RegistryError { status: 403, .. })));
+    let private = publish(root, &registry, &config, &PublishOptions::default());
+    assert!(matches!(private, Err(NaryError::InvalidManifest { .. })));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_pack_projects() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let dir = tempfile::tempdir()?;
+    let project = dir.path();
+    let write = |files: &[(&str, &str)]| -> Result<()> {
+        for (path, contents) in files {
+            let path = project.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, contents)?;
+        }
+        Ok(())
+    };
+    write(&[
+        ("package.json", r#"{"name": "@me/tool", "version": "1.2.0", "main": "build/main.js"}"#),
+        ("README.md", "# tool"),
+        ("LICENSE", "MIT"),
+        ("index.js", ""),
+        ("notes.orig", ""),
+        (".npmrc", "//registry.example.com/:_authToken=secret"),
+        ("build/main.js", ""),
+        ("build/cache.json", ""),
+        ("lib/a.js", ""),
+        ("lib/a.test.js", ""),
+        ("lib/fixtures/big.bin", ""),
+        ("lib/.npmignore", "fixtures/\n"),
+        ("node_modules/dep/index.js", ""),
+        (".gitignore", "build\n*.log\n"),
+        ("debug.log", ""),
+    ])?;
+    let listed = |files: Vec<std::path::PathBuf>| -> Vec<String> {
+        files.iter().map(|file| file.to_string_lossy().replace('\\', "/")).collect()
+    };
+
+    // The .gitignore rules without an .npmignore, the main file despite them
+    assert_eq!(
+        listed(package_files(project)?),
+        vec!["LICENSE", "README.md", "build/main.js", "index.js", "lib/a.js", "lib/a.test.js", "package.json"]
+    );
+
+    write(&[(".npmignore", "*.test.js\n")])?;
+    assert_eq!(
+        listed(package_files(project)?),
+        vec![
+            "LICENSE",
+            "README.md",
+            "build/cache.json",
+            "build/main.js",
+            "debug.log",
+            "index.js",
+            "lib/a.js",
+            "package.json",
+        ]
+    );
+
+    write(&[(
+        "package.json",
+        r#"{"name": "@me/tool", "version": "1.2.0", "files": ["lib", "!lib/a.test.js", "index.js"]}"#,
+    )])?;
+    assert_eq!(listed(package_files(project)?), vec!["LICENSE", "README.md", "index.js", "lib/a.js", "package.json"]);
+
+    let tarball_path = create_package_tarball(project)?;
+    assert_eq!(tarball_path, project.join("me-tool-1.2.0.tgz"));
+    let tarball = fs::read(&tarball_path)?;
+    let url = Url::parse("file:///me-tool-1.2.0.tgz")?;
+    assert_eq!(read_manifest(&tarball, &url)?["version"], "1.2.0");
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tarball.as_slice()));
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        entries.push(entry?.path()?.to_string_lossy().into_owned());
+    }
+    assert_eq!(
+        entries,
+        vec!["package/LICENSE", "package/README.md", "package/index.js", "package/lib/a.js", "package/package.json"]
+    );
+    // Packing again gives the same tarball, without the last one in it
+    assert_eq!(fs::read(create_package_tarball(project)?)?, tarball);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_install_global_packages() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc("prefix=/opt/nary");
+    assert_eq!(GlobalPrefix::from_config(&config)?, GlobalPrefix::new("/opt/nary"));
+
+    let registry = MemoryRegistry::new();
+    let tool = r#"{"name": "tool", "version": "1.2.0", "bin": {"tool": "cli.js"},
+                   "dependencies": {"debug": "^2.0.0"}}"#;
+    registry.add_manifest(
+        &serde_json::from_str(tool)?,
+        tarball(&[("package/package.json", tool), ("package/cli.js", "#!/usr/bin/env node")])?,
+    )?;
+    let debug = r#"{"name": "debug", "version": "2.6.9"}"#;
+    registry.add_manifest(&serde_json::from_str(debug)?, tarball(&[("package/package.json", debug)])?)?;
+
+    let prefix = tempfile::tempdir()?;
+    let global = GlobalPrefix::new(prefix.path());
+    let (options, resolution) = (InstallOptions::default(), ResolutionOptions::default());
+    let added = install_global(&global, &["tool".to_string()], &registry, &options, &resolution, &SilentReporter)?;
+    assert_eq!(added[0].version, "^1.2.0");
+    assert!(global.node_modules().join("debug").join("package.json").is_file());
+    assert!(global.lib_dir().join("nary-lock.json").is_file());
+    #[cfg(unix)]
+    assert!(fs::read_link(global.bin_dir().join("tool"))?.ends_with("cli.js"));
+    #[cfg(windows)]
+    assert!(global.bin_dir().join("tool.cmd").is_file());
+
+    let listed = list_global(&global)?;
+    assert_eq!(listed.len(), 1);
+    assert_eq!((listed[0].name.as_str(), listed[0].version.as_deref()), ("tool", Some("1.2.0")));
+    assert_eq!(listed[0].bins, vec!["tool".to_string()]);
+
+    let names = ["tool".to_string(), "missing".to_string()];
+    let removed = uninstall_global(&global, &names, &registry, &options, &resolution, &SilentReporter)?;
+    assert_eq!(removed, vec!["tool".to_string()]);
+    assert!(fs::symlink_metadata(global.bin_dir().join("tool")).is_err());
+    assert!(!global.node_modules().join("tool").exists());
+    assert!(!global.node_modules().join("debug").exists());
+    assert!(list_global(&global)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_link_local_packages_into_projects() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let links = tempfile::tempdir()?;
+    std::env::set_var(LINKS_DIR_VAR, links.path());
+    assert!(matches!(use_link(links.path(), "@scope/lib"), Err(NaryError::NotLinked { .. })));
+
+    let source = tempfile::tempdir()?;
+    fs::write(
+        source.path().join("package.json"),
+        r#"{"name": "@scope/lib", "version": "0.3.0", "bin": {"lib-cli": "cli.js"}}"#,
+    )?;
+    fs::write(source.path().join("cli.js"), "#!/usr/bin/env node")?;
+    let linked = link::link_package(source.path())?;
+    assert_eq!((linked.name.as_str(), linked.version.as_str()), ("@scope/lib", "0.3.0"));
+    assert_eq!(linked.path, links.path().join("@scope").join("lib"));
+
+    let project = tempfile::tempdir()?;
+    let path = use_link(project.path(), "@scope/lib")?;
+    assert_eq!(path, project.path().join("node_modules").join("@scope").join("lib"));
+    assert_eq!(path.canonicalize()?, source.path().canonicalize()?);
+    // Changes to the source show through the link
+    fs::write(source.path().join("index.js"), "module.exports = 1;")?;
+    assert!(path.join("index.js").is_file());
+    #[cfg(unix)]
+    assert!(fs::symlink_metadata(project.path().join("node_modules").join(".bin").join("lib-cli")).is_ok());
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct ScriptReporter {
+    lines: Mutex<Vec<String>>,
+}
+
+impl InstallReporter for ScriptReporter {
+    fn on_script_start(&self, script: &str, _command: &str) {
+        self.lines.lock().unwrap().push(format!("> {}", script));
+    }
+
+    fn on_script_output(&self, _script: &str, line: &str, stderr: bool) {
+        self.lines.lock().unwrap().push(if stderr { format!("! {}", line) } else { line.to_string() });
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn it_will_run_scripts_with_bins_on_the_path() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    use std::os::unix::fs::PermissionsExt;
+
+    let project = tempfile::tempdir()?;
+    fs::write(
+        project.path().join("package.json"),
+        r#"{"name": "app", "version": "1.0.0", "config": {"port": 8080}, "scripts": {
+            "pretest": "echo $npm_lifecycle_event >&2",
+            "test": "hello $npm_package_name $npm_package_config_port",
+            "posttest": "echo $npm_package_scripts_posttest",
+            "fail": "exit 3"
+        }}"#,
+    )?;
+    let bin_dir = project.path().join("node_modules").join(".bin");
+    fs::create_dir_all(&bin_dir)?;
+    fs::write(bin_dir.join("hello"), "#!/bin/sh\necho hello \"$@\"\n")?;
+    fs::set_permissions(bin_dir.join("hello"), fs::Permissions::from_mode(0o755))?;
+
+    let reporter = ScriptReporter::default();
+    let args = ["it's".to_string(), "--watch".to_string()];
+    scripts::run(project.path(), "test", &args, &NoHooks, &reporter)?;
+    let expected = vec![
+        "> pretest",
+        "! pretest",
+        "> test",
+        "hello app 8080 it's --watch",
+        "> posttest",
+        "echo $npm_package_scripts_posttest",
+    ];
+    assert_eq!(*reporter.lines.lock().unwrap(), expected);
+
+    let failed = scripts::run(project.path(), "fail", &[], &NoHooks, &SilentReporter);
+    assert!(matches!(failed, Err(NaryError::ScriptFailed { code: Some(3), .. })));
+    let missing = scripts::run(project.path(), "build", &[], &NoHooks, &SilentReporter);
+    assert!(matches!(missing, Err(NaryError::MissingScript { .. })));
+
+    Ok(())
+}
+
+#[test]
+fn it_will_verify_signatures() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let ms = tarball(&[("package/package.json", r#"{"name": "ms", "version": "2.0.0"}"#)])?;
+    let integrity = cache::integrity_of(&ms);
+    // Signed over `ms@2.0.0:<integrity>` with the key below
+    let signature = "MEUCIFoZydB4DACLB+NRly+Q3AuZloBD8qEi9WyVashjLXYjAiEA/GEPW35ZRPMpJxKDbSO+L2pqxz3lF9r/7/6X4956Psg=";
+    let key = SigningKey {
+        keyid: "SHA256:test".to_string(),
+        key: "MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEMbIm47ARXNixz2sjuqGovLrA3kBMGk43BLzaAe40n/1gZKhOVH1WrH/Iy3tkdowzWG9/\
+              THpaI0PLwBE4tKtNFQ=="
+            .to_string(),
+        expires: None,
+    };
+    let manifest = |version: &str, integrity: &str, signature: &str| -> Result<serde_json::Value> {
+        Ok(serde_json::json!({
+            "name": "ms",
+            "version": version,
+            "dist": {
+                "tarball": MemoryRegistry::tarball_url("ms", version),
+                "integrity": integrity,
+                "signatures": [{"keyid": "SHA256:test", "sig": signature}],
+            },
+        }))
+    };
+    let install = |registry: &MemoryRegistry, version: &str, reporter: &dyn InstallReporter| -> Result<()> {
+        let node_modules = tempfile::tempdir()?;
+        let dependency = Dependency::new("ms", version.to_string());
+        let options = InstallOptions::default();
+        install_dep(node_modules.path(), &dependency, DependencyKind::Normal, registry, &options, reporter)?;
+        Ok(())
+    };
+    let refused_for = |registry: &MemoryRegistry, version: &str| -> Option<String> {
+        match install(registry, version, &SilentReporter).err()?.downcast() {
+            Ok(NaryError::Unverified { reason, .. }) => Some(reason),
+            _ => None,
+        }
+    };
+
+    let registry = MemoryRegistry::new();
+    registry.add_signing_key(key.clone());
+    registry.add_manifest(&manifest("2.0.0", &integrity, signature)?, ms.clone())?;
+    let required = VerifyPolicy {
+        signatures: Enforcement::Require,
+    };
+    registry.set_verify_policy(required);
+    install(&registry, "2.0.0", &SilentReporter)?;
+
+    let name = PackageName::parse("ms")?;
+    let dist = registry.packument(&name)?.versions["2.0.0"].dist.clone();
+    assert!(verify_version(&name, "2.0.0", &dist, &registry, &SilentReporter)?);
+    registry.set_verify_policy(VerifyPolicy::default());
+    assert!(!verify_version(&name, "2.0.0", &dist, &registry, &SilentReporter)?);
+    registry.set_verify_policy(required);
+
+    // The same signature doesn't hold for another version
+    registry.add_manifest(&manifest("2.0.1", &integrity, signature)?, ms.clone())?;
+    assert!(refused_for(&registry, "2.0.1").is_some_and(|reason| reason.contains("signature")));
+
+    let warned = WarningReporter::default();
+    registry.set_verify_policy(VerifyPolicy {
+        signatures: Enforcement::Warn,
+    });
+    install(&registry, "2.0.1", &warned)?;
+    assert_eq!(warned.warnings.lock().unwrap().len(), 1);
+    registry.set_verify_policy(required);
+
+    // A tarball that isn't the signed one is refused once it's read
+    registry.add_tarball(&MemoryRegistry::tarball_url("ms", "2.0.0"), tarball(&[("package/index.js", "")])?);
+    assert!(install(&registry, "2.0.0", &SilentReporter).is_err());
+
+    // A key that expired only counts for versions published before it did
+    let expiring = MemoryRegistry::new();
+    expiring.add_signing_key(SigningKey {
+        expires: Some("2020-01-01T00:00:00.000Z".to_string()),
+        ..key
+    });
+    expiring.add_manifest(&manifest("2.0.0", &integrity, signature)?, ms.clone())?;
+    expiring.set_verify_policy(required);
+    assert!(refused_for(&expiring, "2.0.0").is_some_and(|reason| reason.contains("expired")));
+    expiring.add_publish_time("ms", "2.0.0", "2019-06-01T00:00:00.000Z");
+    install(&expiring, "2.0.0", &SilentReporter)?;
+    expiring.add_publish_time("ms", "2.0.0", "2020-06-01T00:00:00.000Z");
+    assert!(refused_for(&expiring, "2.0.0").is_some_and(|reason| reason.contains("expired")));
+
+    let mut config = RegistryConfig::default();
+    config.parse_npmrc(
+        "signature-policy=warn\n\
+         //other.example/:signature-policy=require",
+    );
+    assert_eq!(config.verify_policy_for(&PackageName::parse("ms")?).signatures, Enforcement::Warn);
+    config.registry = "https://other.example".to_string();
+    assert_eq!(config.verify_policy_for(&PackageName::parse("ms")?).signatures, Enforcement::Require);
+    Ok(())
+}
+
+#[test]
+fn it_will_leave_bundled_dependencies_to_their_tarballs() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    let manifest =
+        r#"{"name": "bundler", "version": "1.0.0", "dependencies": {"dep": "^1.0.0"}, "bundledDependencies": ["dep"]}"#;
+    registry.add_manifest(
+        &serde_json::from_str(manifest)?,
+        tarball(&[
+            ("package/package.json", manifest),
+            ("package/node_modules/dep/package.json", r#"{"name": "dep", "version": "1.0.0"}"#),
+            ("package/node_modules/dep/index.js", "module.exports = 'bundled';"),
+        ])?,
+    )?;
+
+    // dep isn't in the registry, so resolving it would fail
+    let root = Dependency::new("app", "1.0.0");
+    let bundler = (Dependency::new("bundler", "^1.0.0"), DependencyKind::Normal);
+    let graph = calculate_depends(&root, &[bundler], &registry, &ResolutionOptions::default(), &SilentReporter)?;
+    assert_eq!(graph.len(), 2);
+
+    for layout in &[Layout::Hoisted, Layout::Isolated] {
+        let node_modules = tempfile::tempdir()?;
+        let options = InstallOptions {
+            layout: *layout,
+            ..InstallOptions::default()
+        };
+        install_graph(node_modules.path(), &graph, &registry, &options, &SilentReporter)?;
+        let bundled = node_modules.path().join("bundler").join("node_modules").join("dep").join("index.js");
+        assert_eq!(fs::read_to_string(bundled)?, "module.exports = 'bundled';");
+        assert!(!node_modules.path().join("dep").exists());
+    }
+
+    // Packing takes the bundled package and what it needs from node_modules, and nothing else there
+    let project = tempfile::tempdir()?;
+    for (path, contents) in &[
+        (
+            "package.json",
+            r#"{"name": "bundler", "version": "1.0.0", "bundleDependencies": true, "dependencies": {"dep": "1"}}"#,
+        ),
+        ("node_modules/dep/package.json", r#"{"name": "dep", "dependencies": {"ms": "2", "@scope/inner": "1"}}"#),
+        ("node_modules/dep/node_modules/@scope/inner/index.js", ""),
+        ("node_modules/ms/index.js", ""),
+        ("node_modules/other/index.js", ""),
+        ("node_modules/.bin/dep", ""),
+    ] {
+        let path = project.path().join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, contents)?;
+    }
+    let files: Vec<String> = package_files(project.path())?
+        .iter()
+        .map(|file| file.to_string_lossy().replace('\\', "/"))
+        .collect();
+    assert_eq!(
+        files,
+        vec![
+            "node_modules/dep/node_modules/@scope/inner/index.js",
+            "node_modules/dep/package.json",
+            "node_modules/ms/index.js",
+            "package.json",
+        ]
+    );
+
+    Ok(())
+}
+
+/// Denies `evil` and any script, and records the rest along with warnings
+#[derive(Default)]
+struct PolicyHooks {
+    events: Mutex<Vec<String>>,
+}
+
+impl PolicyHooks {
+    fn record(&self, event: String) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl InstallReporter for PolicyHooks {
+    fn on_warning(&self, message: &str) {
+        self.record(format!("warning {}", message));
+    }
+}
+
+impl InstallHooks for PolicyHooks {
+    fn after_resolve(&self, graph: &ResolvedGraph) -> nary_lib::Result<()> {
+        self.record(format!("resolved {}", graph.len() - 1));
+        Ok(())
+    }
+
+    fn before_extract(&self, dependency: &Dependency, metadata: Option<&PackumentVersion>) -> nary_lib::Result<()> {
+        if dependency.name == "evil" {
+            return Err(NaryError::Refused { reason: "evil is denied".to_string() });
+        }
+        let version = metadata.map(|metadata| metadata.version.as_str()).unwrap_or_default();
+        self.record(format!("extract {}@{}", dependency.name, version));
+        Ok(())
+    }
+
+    fn after_extract(&self, name: &str, _version: &str, path: &std::path::Path) -> nary_lib::Result<()> {
+        self.record(format!("extracted {} {}", name, path.join("package.json").is_file()));
+        Ok(())
+    }
+
+    fn before_script(&self, _package_dir: &std::path::Path, script: &str, _command: &str) -> nary_lib::Result<()> {
+        Err(NaryError::Refused { reason: format!("{} isn't allowed", script) })
+    }
+
+    fn on_complete(&self, _node_modules: &std::path::Path, graph: &ResolvedGraph) -> nary_lib::Result<()> {
+        self.record(format!("installed {}", graph.len() - 1));
+        Ok(())
+    }
+}
+
+#[test]
+fn it_will_call_install_hooks() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for name in &["ms", "evil"] {
+        let manifest = format!(r#"{{"name": "{}", "version": "1.0.0"}}"#, name);
+        registry.add_manifest(&serde_json::from_str(&manifest)?, tarball(&[("package/package.json", &manifest)])?)?;
+    }
+    let dependency = |name: &str| Dependency::new(name.to_string(), "1.0.0".to_string());
+
+    let hooks = Arc::new(PolicyHooks::default());
+    let options = InstallOptions {
+        hooks: hooks.clone(),
+        ..InstallOptions::default()
+    };
+    let resolution = ResolutionOptions::default();
+    let graph = calculate_depends(
+        &dependency("app"),
+        &[(dependency("ms"), DependencyKind::Normal)],
+        &registry,
+        &resolution,
+        &SilentReporter,
+    )?;
+    let node_modules = tempfile::tempdir()?;
+    install_graph(node_modules.path(), &graph, &registry, &options, &SilentReporter)?;
+    assert_eq!(
+        *hooks.events.lock().unwrap(),
+        vec!["resolved 1", "extract ms@1.0.0", "extracted ms true", "installed 1"]
+    );
+
+    // A refusal fails a dependency, but only leaves out an optional one
+    let evil = dependency("evil");
+    let denied = install_dep(node_modules.path(), &evil, DependencyKind::Normal, &registry, &options, &*hooks);
+    assert!(matches!(denied, Err(NaryError::Refused { .. })));
+    install_dep(node_modules.path(), &evil, DependencyKind::Optional, &registry, &options, &*hooks)?;
+    assert_eq!(
+        hooks.events.lock().unwrap().last().unwrap(),
+        "warning Skipping optional dependency evil@1.0.0: Refused by an install hook: evil is denied"
+    );
+    assert!(!node_modules.path().join("evil").exists());
+
+    let project = tempfile::tempdir()?;
+    fs::write(project.path().join("package.json"), r#"{"scripts": {"build": "echo built > built"}}"#)?;
+    let refused = scripts::run(project.path(), "build", &[], &*hooks, &SilentReporter);
+    assert!(matches!(refused, Err(NaryError::Refused { .. })));
+    assert!(!project.path().join("built").exists());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_install_into_memory() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    let cli = r#"{"name": "cli", "version": "1.0.0", "bin": "cli.js", "dependencies": {"ms": "1"}}"#;
+    let ms = r#"{"name": "ms", "version": "1.0.0"}"#;
+    let cli_tarball = tarball(&[("package/package.json", cli), ("package/cli.js", "#!/usr/bin/env node")])?;
+    registry.add_manifest(&serde_json::from_str(cli)?, cli_tarball)?;
+    registry.add_manifest(&serde_json::from_str(ms)?, tarball(&[("package/package.json", ms)])?)?;
+    let dependency = |name: &str| Dependency::new(name.to_string(), "1.0.0".to_string());
+    let resolution = ResolutionOptions::default();
+    let graph = calculate_depends(
+        &dependency("app"),
+        &[(dependency("cli"), DependencyKind::Normal)],
+        &registry,
+        &resolution,
+        &SilentReporter,
+    )?;
+
+    let fs = Arc::new(MemoryFs::new());
+    let options = InstallOptions {
+        fs: fs.clone(),
+        ..InstallOptions::default()
+    };
+    let node_modules = std::path::Path::new("/memory/node_modules");
+    let stats = install_graph(node_modules, &graph, &registry, &options, &SilentReporter)?;
+    assert_eq!(stats.packages_installed, 2);
+    assert!(!node_modules.exists());
+    assert_eq!(fs.read(&node_modules.join("ms/package.json"))?, ms.as_bytes());
+    assert_eq!(fs.entry(&node_modules.join(".bin/cli")), Some(MemoryEntry::Symlink("../cli/cli.js".into())));
+    assert!(matches!(fs.entry(&node_modules.join("cli/cli.js")), Some(MemoryEntry::File { executable: true, .. })));
+
+    // Read back through the same filesystem, nothing needs installing again
+    let stats = install_graph(node_modules, &graph, &registry, &options, &SilentReporter)?;
+    assert_eq!((stats.packages_installed, stats.packages_up_to_date), (0, 2));
+    assert!(plan_install(node_modules, &graph, &registry, &options)?.is_empty());
+
+    // Nor does planning and removing what the graph doesn't have
+    fs.create_dir_all(&node_modules.join("@old/pkg"))?;
+    fs.write_file(&node_modules.join("@old/pkg/package.json"), &mut &br#"{"version": "0.1.0"}"#[..], false)?;
+    let plan = plan_install(node_modules, &graph, &registry, &options)?;
+    let removed: Vec<&str> = plan.remove.iter().map(|package| package.name.as_str()).collect();
+    assert_eq!(removed, vec!["@old/pkg"]);
+    execute_plan(node_modules, &plan, &graph, &registry, &options, &SilentReporter)?;
+    assert!(fs.entry(&node_modules.join("@old")).is_none());
+    assert!(fs.is_file(&node_modules.join("ms/package.json")));
+
+    let fs = Arc::new(MemoryFs::new());
+    let options = InstallOptions {
+        layout: Layout::Isolated,
+        fs: fs.clone(),
+        ..InstallOptions::default()
+    };
+    install_graph(node_modules, &graph, &registry, &options, &SilentReporter)?;
+    let linked = node_modules.join(".nary/ms@1.0.0/node_modules/ms");
+    assert_eq!(fs.entry(&node_modules.join(".nary/cli@1.0.0/node_modules/ms")), Some(MemoryEntry::Symlink(linked)));
+    assert_eq!(fs.read(&node_modules.join(".nary/cli@1.0.0/node_modules/ms/package.json"))?, ms.as_bytes());
+    assert!(fs.is_file(&node_modules.join(".bin/cli")));
+    assert!(fs.entry(&node_modules.join("ms")).is_none());
+
+    Ok(())
+}
+
+#[test]
+fn it_will_collect_and_enforce_licenses() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    let packages = [
+        (r#"{"name": "mit", "version": "1.0.0", "license": "MIT"}"#, true),
+        (r#"{"name": "gpl", "version": "1.0.0", "license": {"type": "GPL-3.0-only"}}"#, true),
+        (r#"{"name": "dual", "version": "1.0.0", "license": "(MIT OR GPL-3.0-only)"}"#, false),
+        (r#"{"name": "old", "version": "1.0.0", "licenses": [{"type": "BSD-3-Clause"}, {"type": "MIT"}]}"#, false),
+        (r#"{"name": "none", "version": "1.0.0"}"#, false),
+    ];
+    let mut deps = Vec::new();
+    for (manifest, license_file) in &packages {
+        let parsed: serde_json::Value = serde_json::from_str(manifest)?;
+        let mut files = vec![("package/package.json", *manifest)];
+        if *license_file {
+            files.push(("package/LICENSE.md", "..."));
+        }
+        registry.add_manifest(&parsed, tarball(&files)?)?;
+        let name = parsed["name"].as_str().unwrap().to_string();
+        deps.push((Dependency::new(name, "1.0.0".to_string()), DependencyKind::Normal));
+    }
+    let root = Dependency::new("app", "1.0.0");
+    let graph = calculate_depends(&root, &deps, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+    let node_modules = tempfile::tempdir()?;
+    install_graph(node_modules.path(), &graph, &registry, &InstallOptions::default(), &SilentReporter)?;
+
+    let report = collect_licenses(node_modules.path(), &graph)?;
+    let by_license: Vec<(&str, Vec<&str>)> = report
+        .by_license()
+        .into_iter()
+        .map(|(license, packages)| (license, packages.iter().map(|package| package.name.as_str()).collect()))
+        .collect();
+    assert_eq!(
+        by_license,
+        vec![
+            ("(BSD-3-Clause OR MIT)", vec!["old"]),
+            ("(MIT OR GPL-3.0-only)", vec!["dual"]),
+            ("GPL-3.0-only", vec!["gpl"]),
+            ("MIT", vec!["mit"]),
+            ("UNKNOWN", vec!["none"]),
+        ]
+    );
+    assert_eq!(report.packages.iter().find(|package| package.name == "mit").unwrap().files, vec!["LICENSE.md"]);
+
+    let denying = LicensePolicy {
+        allow: Vec::new(),
+        deny: vec!["gpl-*".to_string()],
+    };
+    match denying.enforce(&report) {
+        Err(NaryError::DisallowedLicenses { packages }) => assert_eq!(packages, vec!["gpl@1.0.0 (GPL-3.0-only)"]),
+        other => panic!("expected disallowed licenses, got {:?}", other),
+    }
+    let allowing = LicensePolicy {
+        allow: vec!["MIT".to_string(), "Apache-2.0".to_string()],
+        deny: Vec::new(),
+    };
+    assert!(allowing.allows(Some("Apache-2.0 AND (MIT OR GPL-2.0+)")));
+    assert!(!allowing.allows(Some("MIT AND GPL-2.0-or-later")));
+    assert!(allowing.allows(Some("MIT WITH Classpath-exception-2.0")));
+    assert!(!allowing.allows(Some("SEE LICENSE IN LICENSE.txt")));
+    match allowing.enforce(&report) {
+        Err(NaryError::DisallowedLicenses { packages }) => {
+            assert_eq!(packages, vec!["gpl@1.0.0 (GPL-3.0-only)", "none@1.0.0 (UNKNOWN)"])
+        }
+        other => panic!("expected disallowed licenses, got {:?}", other),
+    }
+
+    // As a hook, the policy refuses a package as soon as it's installed
+    let node_modules = tempfile::tempdir()?;
+    let options = InstallOptions {
+        hooks: Arc::new(denying),
+        ..InstallOptions::default()
+    };
+    let installed = install_graph(node_modules.path(), &graph, &registry, &options, &SilentReporter);
+    assert!(matches!(installed, Err(NaryError::DisallowedLicenses { .. })));
+
+    Ok(())
+}
+
+/// Collects the spans opened, each as its name and the `name` field it has, if any
+#[derive(Default)]
+struct SpanCollector {
+    spans: Arc<Mutex<Vec<String>>>,
+    next_id: AtomicU64,
+}
+
+struct NameField(Option<String>);
+
+impl Visit for NameField {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "name" {
+            self.0 = Some(format!("{:?}", value).trim_matches('"').to_string());
+        }
+    }
+}
+
+impl Subscriber for SpanCollector {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+        let mut name = NameField(None);
+        span.record(&mut name);
+        let described = match name.0 {
+            Some(name) => format!("{} {}", span.metadata().name(), name),
+            None => span.metadata().name().to_string(),
+        };
+        self.spans.lock().unwrap().push(described);
+        span::Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, _span: &span::Id) {}
+
+    fn exit(&self, _span: &span::Id) {}
+}
+
+#[test]
+fn it_will_trace_resolving_and_extracting() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    let manifest = r#"{"name": "ms", "version": "2.1.3"}"#;
+    registry.add_manifest(&serde_json::from_str(manifest)?, tarball(&[("package/package.json", manifest)])?)?;
+    let dependency = |name: &str, version: &str| Dependency::new(name.to_string(), version.to_string());
+
+    let collector = SpanCollector::default();
+    let spans = Arc::clone(&collector.spans);
+    let node_modules = tempfile::tempdir()?;
+    tracing::subscriber::with_default(collector, || -> Result<()> {
+        let resolution = ResolutionOptions::default();
+        let root = dependency("app", "1.0.0");
+        let graph = calculate_depends(
+            &root,
+            &[(dependency("ms", "2"), DependencyKind::Normal)],
+            &registry,
+            &resolution,
+            &SilentReporter,
+        )?;
+        install_graph(node_modules.path(), &graph, &registry, &InstallOptions::default(), &SilentReporter)?;
+        Ok(())
+    })?;
+    assert_eq!(*spans.lock().unwrap(), vec!["resolve", "resolve_node ms", "install ms", "extract"]);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_count_what_an_install_does() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    #[derive(Default)]
+    struct StatsReporter {
+        phases: Mutex<Vec<String>>,
+        stats: Mutex<Vec<InstallStats>>,
+    }
+    impl InstallReporter for StatsReporter {
+        fn on_phase(&self, phase: &str, _elapsed: std::time::Duration) {
+            self.phases.lock().unwrap().push(phase.to_string());
+        }
+        fn on_install_stats(&self, stats: &InstallStats) {
+            self.stats.lock().unwrap().push(stats.clone());
+        }
+    }
+
+    let registry = MemoryRegistry::new();
+    let mut sizes = 0;
+    for (name, dependencies) in &[("ms", serde_json::json!({})), ("debug", serde_json::json!({"ms": "2"}))] {
+        let manifest = serde_json::json!({"name": name, "version": "2.1.3", "dependencies": dependencies});
+        let tarball = tarball(&[("package/package.json", &manifest.to_string())])?;
+        sizes += tarball.len() as u64;
+        registry.add_manifest(&manifest, tarball)?;
+    }
+    let dependency = |name: &str, version: &str| Dependency::new(name.to_string(), version.to_string());
+
+    let reporter = StatsReporter::default();
+    let resolution = ResolutionOptions::default();
+    let root = dependency("app", "1.0.0");
+    let graph = calculate_depends(
+        &root,
+        &[(dependency("debug", "2"), DependencyKind::Normal)],
+        &registry,
+        &resolution,
+        &reporter,
+    )?;
+    let node_modules = tempfile::tempdir()?;
+    let options = InstallOptions::default();
+    let stats = install_graph(node_modules.path(), &graph, &registry, &options, &reporter)?;
+    assert_eq!(
+        (stats.packages_resolved, stats.packages_installed, stats.packages_up_to_date, stats.bytes_downloaded),
+        (2, 2, 0, sizes)
+    );
+    assert!(stats.phase("extract").is_some() && stats.phase("link").is_none());
+
+    // Installing again finds everything in place, and the reporter hears about both
+    let plan = plan_install(node_modules.path(), &graph, &registry, &options)?;
+    let again = execute_plan(node_modules.path(), &plan, &graph, &registry, &options, &reporter)?;
+    assert_eq!((again.packages_installed, again.bytes_downloaded), (0, 0));
+    assert_eq!(*reporter.stats.lock().unwrap(), vec![stats, again]);
+    assert_eq!(*reporter.phases.lock().unwrap(), vec!["resolve", "extract", "remove", "extract"]);
+
+    Ok(())
+}
+
+/// Counts how many tarballs are being read at once, each taking a while
+#[derive(Default)]
+struct SlowRegistry {
+    registry: MemoryRegistry,
+    /// Reading now, and the most at once
+    reading: Mutex<(usize, usize)>,
+}
+
+impl RegistryClient for SlowRegistry {
+    fn packument(&self, name: &PackageName) -> nary_lib::Result<Packument> {
+        self.registry.packument(name)
+    }
+
+    fn version_metadata(&self, name: &PackageName, version: &str) -> nary_lib::Result<serde_json::Value> {
+        self.registry.version_metadata(name, version)
+    }
+
+    fn tarball(
+        &self,
+        name: &PackageName,
+        version: &str,
+        tarball_url: &Url,
+        reporter: &dyn InstallReporter,
+    ) -> nary_lib::Result<Vec<u8>> {
+        {
+            let mut reading = self.reading.lock().unwrap();
+            reading.0 += 1;
+            reading.1 = reading.1.max(reading.0);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        self.reading.lock().unwrap().0 -= 1;
+        self.registry.tarball(name, version, tarball_url, reporter)
+    }
+
+    fn publish(&self, name: &PackageName, payload: &serde_json::Value) -> nary_lib::Result<()> {
+        self.registry.publish(name, payload)
+    }
+}
+
+#[test]
+fn it_will_unpack_packages_in_parallel() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = SlowRegistry::default();
+    let mut dependencies = Vec::new();
+    for i in 0..8 {
+        let name = format!("pkg-{}", i);
+        let mut manifest = serde_json::json!({"name": name, "version": "1.0.0", "bin": {"shared": "lib/deep/cli.js"}});
+        if i == 0 {
+            // Its tarball is broken, so it's left out with a warning as when installing one by one
+            manifest["optionalDependencies"] = serde_json::json!({"broken": "1"});
+        }
+        let package_json = manifest.to_string();
+        let files = [("package/package.json", package_json.as_str()), ("package/lib/deep/cli.js", "")];
+        registry.registry.add_manifest(&manifest, tarball(&files)?)?;
+        dependencies.push((Dependency::new(name, "1"), DependencyKind::Normal));
+    }
+    registry.registry.add_manifest(&serde_json::json!({"name": "broken", "version": "1.0.0"}), Vec::new())?;
+    let app = Dependency::new("app", "1.0.0");
+    let graph = calculate_depends(&app, &dependencies, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+
+    let options = InstallOptions {
+        parallelism: 4,
+        ..InstallOptions::default()
+    };
+    let reporter = WarningReporter::default();
+    let node_modules = tempfile::tempdir()?;
+    install_graph(node_modules.path(), &graph, &registry, &options, &reporter)?;
+    let most = registry.reading.lock().unwrap().1;
+    assert!(most > 1 && most <= 4, "{} tarballs read at once", most);
+    for i in 0..8 {
+        assert!(node_modules.path().join(format!("pkg-{}", i)).join("lib/deep/cli.js").is_file());
+    }
+    assert_eq!(reporter.warnings.lock().unwrap().len(), 1);
+
+    // The last package in install order links the bin they share, as it would one by one
+    let last = graph.install_order().filter(|node| node.name.starts_with("pkg-")).last().unwrap().name.clone();
+    let linked = fs::read_link(node_modules.path().join(".bin").join("shared"))?;
+    assert!(linked.to_string_lossy().contains(&last), "{:?} isn't {}'s", linked, last);
+
+    Ok(())
+}
+
+#[test]
+fn it_will_resolve_what_a_dependency_shrinkwraps_as_pinned() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for manifest in &[
+        serde_json::json!({"name": "dep", "version": "1.0.0", "dependencies": {"leaf": "^1.0.0"}}),
+        serde_json::json!({"name": "dep", "version": "1.1.0", "dependencies": {"leaf": "^1.0.0"}}),
+        serde_json::json!({"name": "leaf", "version": "1.0.0"}),
+        serde_json::json!({"name": "leaf", "version": "1.2.0"}),
+    ] {
+        registry.add_manifest(manifest, Vec::new())?;
+    }
+    let manifest = r#"{"name": "cli", "version": "1.0.0", "dependencies": {"dep": "^1.0.0"}}"#;
+    let shrinkwrap = r#"{
+        "name": "cli",
+        "version": "1.0.0",
+        "lockfileVersion": 3,
+        "packages": {
+            "": {"name": "cli", "version": "1.0.0", "dependencies": {"dep": "^1.0.0"}},
+            "node_modules/dep": {"version": "1.0.0", "dependencies": {"leaf": "^1.0.0"}},
+            "node_modules/leaf": {"version": "1.0.0"}
+        }
+    }"#;
+    let mut metadata: serde_json::Value = serde_json::from_str(manifest)?;
+    metadata["_hasShrinkwrap"] = true.into();
+    registry.add_manifest(
+        &metadata,
+        tarball(&[("package/package.json", manifest), ("package/npm-shrinkwrap.json", shrinkwrap)])?,
+    )?;
+
+    let root = Dependency::new("app", "1.0.0");
+    let dep =
+        |name: &str, version: &str| (Dependency::new(name.to_string(), version.to_string()), DependencyKind::Normal);
+    let deps = [dep("cli", "1.0.0"), dep("dep", "^1.0.0")];
+    let graph = calculate_depends(&root, &deps, &registry, &ResolutionOptions::default(), &SilentReporter)?;
+
+    // The root's own dep is resolved against the registry, while everything below cli is as its shrinkwrap says
+    assert_eq!(graph.versions_of("dep"), vec!["1.0.0", "1.1.0"]);
+    assert_eq!(graph.versions_of("leaf"), vec!["1.0.0", "1.2.0"]);
+    let pinned = graph.find("dep").find(|id| graph.node(*id).unwrap().version == "1.0.0").unwrap();
+    let leaves: Vec<&str> =
+        graph.dependencies(pinned).map(|edge| graph.node(edge.to).unwrap().version.as_str()).collect();
+    assert_eq!(leaves, vec!["1.0.0"]);
+
+    // A shrinkwrap that can't be imported is ignored
+    let broken = r#"{"lockfileVersion": 1, "dependencies": {}}"#;
+    let mut metadata: serde_json::Value = serde_json::from_str(&manifest.replace("1.0.0", "1.0.1"))?;
+    metadata["_hasShrinkwrap"] = true.into();
+    registry.add_manifest(
+        &metadata,
+        tarball(&[("package/package.json", manifest), ("package/npm-shrinkwrap.json", broken)])?,
+    )?;
+    let reporter = WarningReporter::default();
+    let graph = calculate_depends(&root, &[dep("cli", "1.0.1")], &registry, &ResolutionOptions::default(), &reporter)?;
+    assert_eq!(graph.versions_of("dep"), vec!["1.1.0"]);
+    assert_eq!(graph.versions_of("leaf"), vec!["1.2.0"]);
+    let warnings = reporter.warnings.lock().unwrap();
+    assert!(warnings[0].starts_with("Ignoring the npm-shrinkwrap.json of cli@1.0.1: "), "{:?}", warnings);
+
+    Ok(())
+}
+
+/// Refuses every install once its packages are in place
+struct RefusingHooks;
+
+impl InstallHooks for RefusingHooks {
+    fn on_complete(&self, _node_modules: &std::path::Path, _graph: &ResolvedGraph) -> nary_lib::Result<()> {
+        Err(NaryError::Refused { reason: "not today".to_string() })
+    }
+}
+
+#[test]
+fn it_will_stage_installs_and_roll_them_back() -> Result<()> {
+    let (_guard, _cache) = common::isolated_cache()?;
+    let registry = MemoryRegistry::new();
+    for version in &["1.0.0", "1.1.0"] {
+        let manifest = format!(r#"{{"name": "a", "version": "{}"}}"#, version);
+        registry.add_manifest(&serde_json::from_str(&manifest)?, tarball(&[("package/package.json", &manifest)])?)?;
+    }
+    registry.add_manifest(&serde_json::json!({"name": "broken", "version": "1.0.0"}), b"truncated".to_vec())?;
+    let root = Dependency::new("app", "1.0.0");
+    let dep =
+        |name: &str, version: &str| (Dependency::new(name.to_string(), version.to_string()), DependencyKind::Normal);
+    let resolution = ResolutionOptions::default();
+    let graph_of =
+        |deps: &[(Dependency, DependencyKind)]| calculate_depends(&root, deps, &registry, &resolution, &SilentReporter);
+    let options = InstallOptions::default();
+    let node_modules = tempfile::tempdir()?;
+    let installed = node_modules.path().join("a/package.json");
+    install_graph(node_modules.path(), &graph_of(&[dep("a", "1.0.0")])?, &registry, &options, &SilentReporter)?;
+
+    // Nothing is moved into place while a package fails to unpack
+    let graph = graph_of(&[dep("a", "1.1.0"), dep("broken", "1.0.0")])?;
+    assert!(install_graph(node_modules.path(), &graph, &registry, &options, &SilentReporter).is_err());
+    assert!(fs::read_to_string(&installed)?.contains("1.0.0"));
+    assert!(!node_modules.path().join("broken").exists());
+    assert!(!node_modules.path().join(STAGING_DIR).exists());
+
+    // What was moved into place is put back when the install fails after that
+    let graph = graph_of(&[dep("a", "1.1.0")])?;
+    let refusing = InstallOptions {
+        hooks: Arc::new(RefusingHooks),
+        ..InstallOptions::default()
+    };
+    let refused = install_graph(node_modules.path(), &graph, &registry, &refusing, &SilentReporter);
+    assert!(matches!(refused, Err(NaryError::Refused { .. })));
+    assert!(fs::read_to_string(&installed)?.contains("1.0.0"));
+    assert!(!node_modules.path().join(STAGING_DIR).exists());
+
+    // An install that stopped halfway through moving packages into place is undone by the next
+    let staging = node_modules.path().join(STAGING_DIR);
+    fs::create_dir(&staging)?;
+    fs::rename(node_modules.path().join("a"), staging.join("0.old"))?;
+    fs::create_dir(node_modules.path().join("a"))?;
+    fs::write(&installed, "half written")?;
+    fs::write(staging.join("journal.json"), r#"[{"name": "0", "package": "a", "staged": true, "replaces": true}]"#)?;
+    let reporter = WarningReporter::default();
+    let stats = install_graph(node_modules.path(), &graph_of(&[dep("a", "1.0.0")])?, &registry, &options, &reporter)?;
+    assert_eq!(stats.packages_up_to_date, 1);
+    assert!(fs::read_to_string(&installed)?.contains("1.0.0"));
+    let warnings = reporter.warnings.lock().unwrap();
+    assert!(warnings[0].ends_with("stopped halfway, and was rolled back"), "{:?}", warnings);
+
+    Ok(())
+}